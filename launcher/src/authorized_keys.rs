@@ -0,0 +1,376 @@
+// Parsing of ~/.ssh/authorized_keys lines, options included
+//
+// The server reads the same file as sshd, so lines may start with options:
+// `from="10.0.0.0/8",no-pty ssh-ed25519 AAAA... me@laptop`. `from=` is enforced
+// against the connecting address and `expiry-time=` against the clock. Options that
+// only govern shells and forwarding, which the launcher doesn't offer, are ignored.
+// A key with any other option (a forced `command=`, `restrict`, `cert-authority`,
+// `principals=`, ...) is not accepted at all, since the launcher can't hold it to
+// what sshd would.
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use russh::keys::ssh_key::PublicKey;
+use std::net::IpAddr;
+
+/// Options that only restrict features the launcher doesn't offer (shells, forwarding,
+/// user rc files), or only loosen checks, so a key keeps working with them ignored
+const IGNORED_OPTIONS: &[&str] = &[
+    "agent-forwarding",
+    "no-agent-forwarding",
+    "port-forwarding",
+    "no-port-forwarding",
+    "pty",
+    "no-pty",
+    "user-rc",
+    "no-user-rc",
+    "x11-forwarding",
+    "no-x11-forwarding",
+    "environment",
+    "permitopen",
+    "permitlisten",
+    "tunnel",
+    "no-touch-required",
+];
+
+/// Latest UTC offset in use; an `expiry-time=` without a zone is taken to be there,
+/// so the key never outlives the time in the server's own zone
+const MAX_UTC_OFFSET_SECS: u64 = 14 * 3600;
+
+/// A key from authorized_keys with the restrictions the server enforces
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    pub key: PublicKey,
+    /// From `from=`; None allows every address
+    pub from: Option<Vec<AddressPattern>>,
+    /// From `expiry-time=`, in unix seconds; None never expires
+    pub expires: Option<u64>,
+}
+
+impl AuthorizedKey {
+    /// Parse a non-comment line, with or without options
+    pub fn parse(line: &str) -> Result<Self> {
+        // Options never start with a key type, so a line that parses whole has none
+        if let Ok(key) = PublicKey::from_openssh(line) {
+            return Ok(Self {
+                key,
+                from: None,
+                expires: None,
+            });
+        }
+
+        let (options, key) = split_options(line);
+        let key = PublicKey::from_openssh(key.trim_start()).context("Invalid public key")?;
+
+        let mut from = None;
+        let mut expires = None;
+        for (name, value) in parse_options(options)? {
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("from", Some(patterns)) => {
+                    let patterns = patterns
+                        .split(',')
+                        .map(AddressPattern::parse)
+                        .collect::<Result<Vec<_>>>()?;
+                    from = Some(patterns);
+                }
+                ("expiry-time", Some(time)) => expires = Some(parse_expiry_time(&time)?),
+                (option, _) if IGNORED_OPTIONS.contains(&option) => {
+                    log::debug!("Ignoring authorized_keys option {}", name)
+                }
+                (_, _) => {
+                    anyhow::bail!("Restricted by the {} option, which the launcher can't enforce", name)
+                }
+            }
+        }
+
+        Ok(Self { key, from, expires })
+    }
+
+    /// Whether the key's `expiry-time=` has passed
+    pub fn expired(&self) -> bool {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        self.expires.is_some_and(|expires| now >= expires)
+    }
+
+    /// Whether a client at `addr` may use this key; with `from=` set, a client whose
+    /// address is unknown may not
+    pub fn allows(&self, addr: Option<IpAddr>) -> bool {
+        let Some(patterns) = &self.from else {
+            return true;
+        };
+        let Some(addr) = addr else {
+            return false;
+        };
+        // IPv4 clients of a dual-stack listener arrive as ::ffff:a.b.c.d
+        let addr = addr.to_canonical();
+
+        // As in sshd, a matching negated pattern refuses the address outright
+        let mut allowed = false;
+        for pattern in patterns {
+            if pattern.matches(addr) {
+                if pattern.negated {
+                    return false;
+                }
+                allowed = true;
+            }
+        }
+        allowed
+    }
+}
+
+/// One entry of a `from=` list: an address, a CIDR network, or a wildcard pattern
+/// (`10.0.0.*`), optionally negated with `!`
+///
+/// Entries are matched against the client's IP address only; host name patterns
+/// never match, as with sshd's default `UseDNS no`.
+#[derive(Debug, Clone)]
+pub struct AddressPattern {
+    negated: bool,
+    matcher: AddressMatcher,
+}
+
+#[derive(Debug, Clone)]
+enum AddressMatcher {
+    Network(IpAddr, u8),
+    Wildcard(GlobMatcher),
+}
+
+impl AddressPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        if pattern.is_empty() {
+            anyhow::bail!("Empty from= pattern");
+        }
+
+        let matcher = match pattern.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr: IpAddr = addr
+                    .parse()
+                    .with_context(|| format!("Invalid network address in from= pattern {}", pattern))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix: u8 = prefix
+                    .parse()
+                    .ok()
+                    .filter(|prefix| *prefix <= max)
+                    .with_context(|| format!("Invalid prefix length in from= pattern {}", pattern))?;
+                AddressMatcher::Network(addr, prefix)
+            }
+            None => AddressMatcher::Wildcard(
+                Glob::new(pattern)
+                    .with_context(|| format!("Invalid from= pattern {}", pattern))?
+                    .compile_matcher(),
+            ),
+        };
+        Ok(Self { negated, matcher })
+    }
+
+    fn matches(&self, addr: IpAddr) -> bool {
+        match &self.matcher {
+            AddressMatcher::Network(network, prefix) => in_network(addr, *network, *prefix),
+            AddressMatcher::Wildcard(glob) => glob.is_match(addr.to_string()),
+        }
+    }
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// An `expiry-time=` value, `YYYYMMDD[HHMM[SS]]` with an optional `Z` for UTC, in
+/// unix seconds
+fn parse_expiry_time(time: &str) -> Result<u64> {
+    let (digits, utc) = match time.strip_suffix(['Z', 'z']) {
+        Some(digits) => (digits, true),
+        None => (time, false),
+    };
+    let invalid = || anyhow::anyhow!("Invalid expiry-time {}", time);
+    if !matches!(digits.len(), 8 | 12 | 14) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let field = |range: std::ops::Range<usize>| digits.get(range).map_or(0, |d| d.parse::<u64>().unwrap_or(0));
+    let (year, month, day) = (field(0..4), field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, counting years from
+    // March so the leap day falls at the end
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468).ok_or_else(invalid)?;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Ok(if utc { secs } else { secs.saturating_sub(MAX_UTC_OFFSET_SECS) })
+}
+
+/// Split a line at the first whitespace outside double quotes: (options, key)
+fn split_options(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => return (&line[..i], &line[i..]),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Comma-separated `name` or `name="value"` options, with quotes removed
+fn parse_options(options: &str) -> Result<Vec<(String, Option<String>)>> {
+    let mut parsed = Vec::new();
+    let mut name = String::new();
+    let mut value: Option<String> = None;
+    let mut chars = options.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, value.as_mut()) {
+            (',', _) => parsed.push((std::mem::take(&mut name), value.take())),
+            ('=', None) => value = Some(String::new()),
+            ('"', Some(value)) => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => anyhow::bail!("Unterminated quote in option {}", name),
+                }
+            },
+            (c, Some(value)) => value.push(c),
+            (c, None) => name.push(c),
+        }
+    }
+    parsed.push((name, value));
+
+    if parsed.iter().any(|(name, _)| name.is_empty()) {
+        anyhow::bail!("Malformed options: {}", options);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+    use russh::keys::{Algorithm, PrivateKey};
+
+    fn public_key() -> String {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        key.public_key().to_openssh().unwrap()
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_plain_and_option_lines() {
+        let key = public_key();
+
+        let plain = AuthorizedKey::parse(&format!("{} me@laptop", key)).unwrap();
+        assert!(plain.from.is_none());
+        assert_eq!(plain.key.comment(), "me@laptop");
+
+        let line = format!(r#"no-pty,from="10.0.0.0/8,192.168.1.*",environment="A=b, c" {} me@laptop"#, key);
+        let restricted = AuthorizedKey::parse(&line).unwrap();
+        assert_eq!(restricted.key.key_data(), plain.key.key_data());
+        assert_eq!(restricted.key.comment(), "me@laptop");
+        assert_eq!(restricted.from.as_ref().map(Vec::len), Some(2));
+
+        assert!(AuthorizedKey::parse(&format!(r#"from="10.0.0.0/8 {}"#, key)).is_err());
+        assert!(AuthorizedKey::parse(r#"from="10.0.0.0/8" not-a-key"#).is_err());
+        assert!(AuthorizedKey::parse(&format!(r#"from="10.0.0.0/33" {}"#, key)).is_err());
+    }
+
+    #[test]
+    fn test_forced_command_keys_are_refused() {
+        let key = public_key();
+        let forced = format!(r#"command="rsync --server -e.LsfxC . /backup",no-pty {}"#, key);
+        assert!(AuthorizedKey::parse(&forced).is_err());
+        assert!(AuthorizedKey::parse(&format!("restrict {}", key)).is_err());
+    }
+
+    #[test]
+    fn test_unknown_options_refuse_the_key() {
+        let key = public_key();
+        for options in [
+            "cert-authority",
+            r#"principals="deploy""#,
+            "verify-required",
+            "no-pty,some-future-option",
+        ] {
+            assert!(AuthorizedKey::parse(&format!("{} {}", options, key)).is_err(), "{}", options);
+        }
+
+        let ignored = r#"no-pty,no-port-forwarding,no-agent-forwarding,no-X11-forwarding,no-user-rc,permitopen="localhost:80""#;
+        assert!(AuthorizedKey::parse(&format!("{} {}", ignored, key)).is_ok());
+    }
+
+    #[test]
+    fn test_expiry_time_is_enforced() {
+        let key = public_key();
+
+        let expired = AuthorizedKey::parse(&format!(r#"expiry-time="20200101" {}"#, key)).unwrap();
+        assert!(expired.expired());
+        let current = AuthorizedKey::parse(&format!(r#"expiry-time="99991231235959Z" {}"#, key)).unwrap();
+        assert!(!current.expired());
+        assert!(!AuthorizedKey::parse(&key).unwrap().expired());
+
+        assert_eq!(parse_expiry_time("19700101Z").unwrap(), 0);
+        assert_eq!(parse_expiry_time("20240229Z").unwrap(), 1_709_164_800);
+        assert_eq!(parse_expiry_time("202402291230Z").unwrap(), 1_709_209_800);
+        // Without a zone, the earliest moment it could mean
+        assert_eq!(parse_expiry_time("20240229").unwrap(), 1_709_164_800 - 14 * 3600);
+
+        for invalid in ["2024", "20241301", "2024022912", "20240229250000", "yesterday"] {
+            assert!(parse_expiry_time(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_from_restricts_addresses() {
+        let key = public_key();
+        let line = format!(r#"from="10.0.0.0/8,!10.0.0.66,192.168.1.*,fd00::/8" {}"#, key);
+        let restricted = AuthorizedKey::parse(&line).unwrap();
+
+        assert!(restricted.allows(ip("10.1.2.3")));
+        assert!(!restricted.allows(ip("10.0.0.66")));
+        assert!(restricted.allows(ip("192.168.1.20")));
+        assert!(!restricted.allows(ip("192.168.2.20")));
+        assert!(restricted.allows(ip("fd00::1")));
+        assert!(!restricted.allows(ip("2001:db8::1")));
+        // A dual-stack listener sees IPv4 clients as mapped IPv6 addresses
+        assert!(restricted.allows(ip("::ffff:10.1.2.3")));
+        assert!(!restricted.allows(None));
+
+        let unrestricted = AuthorizedKey::parse(&key).unwrap();
+        assert!(unrestricted.allows(ip("203.0.113.9")));
+        assert!(unrestricted.allows(None));
+    }
+}
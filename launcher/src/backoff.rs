@@ -0,0 +1,155 @@
+// Exponential backoff between retries
+//
+// Each delay is the previous one times `multiplier`, up to `max`, until `reset` after a
+// success starts over from `base`. Jitter spreads the delays of many clients that
+// failed at the same moment, such as when the server restarts, so they don't all
+// retry together.
+
+use rand_core::{OsRng, RngCore};
+use std::time::Duration;
+
+/// Retry policy and the delay it has reached
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    multiplier: f64,
+    /// Fraction of each delay it may randomly be lengthened or shortened by
+    jitter: f64,
+    /// Delay before the next retry, before jitter
+    current: Duration,
+}
+
+impl Backoff {
+    /// Double from `base` up to `max`, without jitter
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            multiplier: 2.0,
+            jitter: 0.0,
+            current: base,
+        }
+    }
+
+    /// Grow each delay by `multiplier` (at least 1) instead of doubling it
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Vary each delay randomly by up to `fraction` of it in either direction, e.g. 0.2
+    /// for ±20%; clamped to 0..=1
+    pub fn with_jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How long to wait before the next retry; each call backs off further
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = self.grow(delay);
+        self.jittered(delay)
+    }
+
+    /// The delay `next_delay` will return next, without jitter
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Start over from `base`, e.g. after a success
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+
+    /// Carry on from a `delay` that was already waited out, as if `next_delay` had just
+    /// returned it; used to pick up a backoff persisted by an earlier process
+    pub fn resume_from(&mut self, delay: Duration) {
+        self.current = self.grow(delay.clamp(self.base, self.max));
+    }
+
+    fn grow(&self, delay: Duration) -> Duration {
+        delay.mul_f64(self.multiplier).min(self.max)
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        // Uniform in [-1, 1]
+        let unit = OsRng.next_u64() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+        delay.mul_f64(1.0 + unit * self.jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn test_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(secs(5), secs(60));
+        let delays: Vec<_> = (0..7).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, [secs(5), secs(10), secs(20), secs(40), secs(60), secs(60), secs(60)]);
+    }
+
+    #[test]
+    fn test_reset_starts_over_from_base() {
+        let mut backoff = Backoff::new(secs(5), secs(60));
+        backoff.next_delay();
+        backoff.next_delay();
+        assert_eq!(backoff.current(), secs(20));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), secs(5));
+        assert_eq!(backoff.next_delay(), secs(10));
+    }
+
+    #[test]
+    fn test_custom_multiplier() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), secs(1)).with_multiplier(3.0);
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay()).collect();
+        assert_eq!(
+            delays,
+            [
+                Duration::from_millis(100),
+                Duration::from_millis(300),
+                Duration::from_millis(900),
+                secs(1)
+            ]
+        );
+
+        // Shrinking delays would retry faster the longer something is down
+        let mut backoff = Backoff::new(secs(5), secs(60)).with_multiplier(0.5);
+        assert_eq!(backoff.next_delay(), secs(5));
+        assert_eq!(backoff.next_delay(), secs(5));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_its_fraction() {
+        let mut backoff = Backoff::new(secs(10), secs(10)).with_jitter(0.2);
+        for _ in 0..200 {
+            let delay = backoff.next_delay();
+            assert!(delay >= secs(8) && delay <= secs(12), "{:?}", delay);
+        }
+        // The un-jittered delay is what grows
+        assert_eq!(backoff.current(), secs(10));
+    }
+
+    #[test]
+    fn test_resume_from_continues_the_sequence() {
+        let mut backoff = Backoff::new(secs(5), secs(60));
+        backoff.resume_from(secs(20));
+        assert_eq!(backoff.next_delay(), secs(40));
+
+        // Out-of-range delays from an older configuration are clamped first
+        backoff.resume_from(secs(600));
+        assert_eq!(backoff.next_delay(), secs(60));
+        backoff.resume_from(Duration::ZERO);
+        assert_eq!(backoff.next_delay(), secs(10));
+    }
+}
@@ -1,21 +1,277 @@
 use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
 use halfremembered_protocol::{
-    ClientMessage, ClientState, Frame, ServerMessage, MSG_RSYNC_DELTA, MSG_RSYNC_SIGNATURE,
+    ChecksumAlgo, ClientMessage, ClientState, ExecExit, ExecProcess, Frame, ManifestEntry, ServerMessage, WireFormat, MSG_EXEC_EXIT,
+    MSG_EXEC_STDERR, MSG_EXEC_STDOUT, MSG_RSYNC_DELTA, MSG_RSYNC_SIGNATURE,
 };
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{mpsc, oneshot};
 use tokio::time;
 
-use crate::rsync_utils;
+use crate::backoff::Backoff;
+use crate::config::within_scope;
+use crate::rsync_utils::{self, AppliedContent, AppliedDelta};
 use crate::ssh_client::SshClientConnection;
+use crate::sync_log::{self, SyncLogEntry};
+
+/// Hidden subcommand a staged launcher binary runs to install itself over the daemon
+pub const APPLY_UPDATE_SUBCOMMAND: &str = "apply-update";
+
+/// Environment variable carrying the daemon's executable path to executed children
+pub const CLIENT_EXE_ENV: &str = "HRL_CLIENT_EXE";
+
+/// Exit code reported in ExecComplete when a command is killed for exceeding --exec-timeout
+pub const EXEC_TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Exit code reported in ExecComplete when a command is stopped by a Kill request
+pub const EXEC_KILLED_EXIT_CODE: i32 = 137;
+
+/// Exit code reported in ExecComplete when a binary is refused by --exec-allowlist
+pub const EXEC_NOT_PERMITTED_EXIT_CODE: i32 = 126;
+
+/// Default cap on captured stdout/stderr per stream
+pub const DEFAULT_EXEC_OUTPUT_LIMIT: usize = 1024 * 1024;
+
+/// Default space left free on the destination filesystem after a sync: none, so only
+/// files that don't fit are refused
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 0;
+
+/// Default time to wait for each piece of a sync's data before abandoning the transfer
+pub const DEFAULT_RSYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit code of `client` once --max-retries consecutive connection attempts have failed
+/// (EX_TEMPFAIL, so supervisors can tell an outage from a crash)
+pub const RETRIES_EXHAUSTED_EXIT_CODE: i32 = 75;
+
+/// Connection state transitions reported to a daemon's event handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DaemonEvent {
+    /// Connected and registered with the server
+    Connected,
+    /// The connection failed, dropped, or was closed for shutdown
+    Disconnected { reason: String },
+    /// Waiting `delay` before retry number `attempt` (counted since the last connection)
+    Reconnecting { attempt: u32, delay: Duration },
+}
+
+type EventHandler = Box<dyn Fn(&DaemonEvent) + Send + Sync>;
+
+/// Returned by `ClientDaemon::run` when it gives up after --max-retries
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+}
+
+impl std::fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Giving up after {} failed connection attempts", self.attempts)
+    }
+}
+
+impl std::error::Error for RetriesExhausted {}
+
+/// Longest wait between reconnect attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How often a client with deferred syncs checks whether it has become idle
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a destination directory that failed the writability check is refused
+/// without checking again, so a fixed permission problem doesn't need a restart
+const DIR_CHECK_RETRY: Duration = Duration::from_secs(60);
+
+/// How often checksums noted from finished syncs are written out while syncs keep
+/// arriving, so a big batch rewrites the file a few times rather than once per file
+const SYNCED_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An RsyncStart to apply, or to hold back with --defer-while-busy until the client is idle
+struct PendingSync {
+    request_id: String,
+    relative_path: String,
+    size: u64,
+    checksum: String,
+    block_size: u32,
+    mode: u32,
+    dir_mode: Option<u32>,
+    whole_file: bool,
+    checksum_algo: ChecksumAlgo,
+    atomic_replace: bool,
+    force: bool,
+}
+
+/// Asks a running ClientDaemon to fetch a fresh copy of a file from the server
+#[derive(Clone)]
+pub struct ResyncHandle {
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl ResyncHandle {
+    /// Request `relative_path`, named as the server sends it (e.g. `bin/game.exe`);
+    /// requests made while disconnected go out once the daemon reconnects. Returns
+    /// false if the daemon is gone.
+    pub fn request(&self, relative_path: impl Into<String>) -> bool {
+        self.sender.send(relative_path.into()).is_ok()
+    }
+}
+
+/// Reconnect backoff persisted under --state-dir, so a daemon restarted by a supervisor
+/// continues where the last process left off instead of retrying immediately
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct BackoffState {
+    /// Unix time of the last connection attempt that has not (yet) registered
+    last_attempt: u64,
+    /// Seconds to wait after `last_attempt` before trying again
+    delay_secs: u64,
+}
+
+impl BackoffState {
+    fn load(path: &Path) -> Option<Self> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        match toml::from_str(&contents) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                log::warn!("Ignoring unreadable backoff state {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create state directory: {}", parent.display()))?;
+        }
+        let contents = toml::to_string(self).context("Failed to serialize backoff state")?;
+        std::fs::write(path, contents)
+            .context(format!("Failed to write backoff state: {}", path.display()))
+    }
+
+    /// How much of the delay is still left at unix time `now`
+    fn remaining(&self, now: u64) -> Duration {
+        Duration::from_secs((self.last_attempt + self.delay_secs).saturating_sub(now))
+    }
+}
+
+/// Checksums of files as this client last synced them, kept under --state-dir
+///
+/// With --no-clobber-local a file that no longer matches its entry was edited here.
+/// A manifest entry whose file still has the recorded size and mtime is trusted
+/// without reading it, so a big initial sync cut off by a disconnect or restart
+/// resumes cheaply, requesting only what wasn't delivered yet.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SyncedChecksums {
+    /// Keyed by the file's resolved local path
+    files: std::collections::BTreeMap<String, SyncedChecksum>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncedChecksum {
+    checksum: String,
+    algo: ChecksumAlgo,
+    /// Size and mtime (unix nanoseconds) the file had once synced; entries written
+    /// before these were kept have None and are always re-read
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    modified: Option<u64>,
+}
+
+impl SyncedChecksum {
+    /// Whether the file at `path` still holds `checksum`, judged from its metadata alone
+    fn vouches_for(&self, path: &Path, checksum: &str, algo: ChecksumAlgo) -> bool {
+        if self.checksum != checksum || self.algo != algo {
+            return false;
+        }
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return false;
+        };
+        self.size == Some(metadata.len()) && self.modified.is_some() && self.modified == modified_nanos(&metadata)
+    }
+}
+
+/// A file's mtime in unix nanoseconds, if the platform reports one
+fn modified_nanos(metadata: &std::fs::Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+impl SyncedChecksums {
+    fn load(path: &Path) -> Self {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        toml::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Ignoring unreadable synced checksums {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create state directory: {}", parent.display()))?;
+        }
+        let contents = toml::to_string(self).context("Failed to serialize synced checksums")?;
+        std::fs::write(path, contents)
+            .context(format!("Failed to write synced checksums: {}", path.display()))
+    }
+}
+
+/// Commands started for Execute requests, keyed by request_id
+type RunningExecs = Arc<Mutex<std::collections::HashMap<String, RunningExec>>>;
+
+struct RunningExec {
+    process: ExecProcess,
+    /// Taken by the first Kill for the process
+    kill: Option<oneshot::Sender<()>>,
+}
+
+/// Lists a process in RunningExecs until dropped
+struct RunningExecGuard {
+    running: RunningExecs,
+    request_id: String,
+}
+
+impl RunningExecGuard {
+    fn insert(running: &RunningExecs, process: ExecProcess, kill: oneshot::Sender<()>) -> Self {
+        let request_id = process.request_id.clone();
+        let exec = RunningExec {
+            process,
+            kill: Some(kill),
+        };
+        running.lock().unwrap().insert(request_id.clone(), exec);
+        Self {
+            running: running.clone(),
+            request_id,
+        }
+    }
+}
+
+impl Drop for RunningExecGuard {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
 
 /// Expand tilde (~) in paths to the user's home directory
-fn expand_tilde(path: &str) -> PathBuf {
-    if path.starts_with("~/") {
+pub fn expand_tilde(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
         if let Ok(home) = std::env::var("HOME") {
-            PathBuf::from(home).join(&path[2..])
+            PathBuf::from(home).join(rest)
         } else {
             PathBuf::from(path)
         }
@@ -30,19 +286,214 @@ fn expand_tilde(path: &str) -> PathBuf {
     }
 }
 
+/// Install a staged launcher binary over the target executable.
+///
+/// The staged file may live on a different filesystem, so it is first copied next to
+/// the target and then renamed into place: the rename is the only step that touches
+/// the live path, so a crash leaves either the old or the new binary, never a partial one.
+/// Windows refuses to overwrite a running executable but allows renaming it aside.
+pub fn apply_staged_update(staged: &Path, target: &Path) -> Result<()> {
+    let parent = target
+        .parent()
+        .context(format!("Target has no parent directory: {}", target.display()))?;
+    let file_name = target
+        .file_name()
+        .context(format!("Target has no file name: {}", target.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let incoming = parent.join(format!("{}.new", file_name));
+    std::fs::copy(staged, &incoming).context(format!(
+        "Failed to copy {} to {}",
+        staged.display(),
+        incoming.display()
+    ))?;
+
+    swap_into_place(&incoming, target)
+}
+
+/// Rename `incoming` over `target`, moving a Windows target aside to `.old` first
+fn swap_into_place(incoming: &Path, target: &Path) -> Result<()> {
+    #[cfg(windows)]
+    if target.exists() {
+        let mut retired = target.as_os_str().to_owned();
+        retired.push(".old");
+        let retired = PathBuf::from(retired);
+        if retired.exists()
+            && let Err(e) = std::fs::remove_file(&retired)
+        {
+            log::warn!("Failed to remove {}: {:#}", retired.display(), e);
+        }
+        std::fs::rename(target, &retired)
+            .context(format!("Failed to move {} aside", target.display()))?;
+    }
+
+    std::fs::rename(incoming, target).context(format!(
+        "Failed to rename {} over {}",
+        incoming.display(),
+        target.display()
+    ))?;
+
+    Ok(())
+}
+
+/// Binaries the client agrees to execute, loaded from an --exec-allowlist file.
+///
+/// Each line that is neither blank nor a `#` comment is a glob, matched against the
+/// absolute path the binary resolves to (and a bare name also as sent). `*` does not
+/// cross `/`, so `ls` permits only a bare `ls` looked up on PATH and `/usr/bin/*` does
+/// not reach subdirectories.
+pub struct ExecAllowlist {
+    patterns: GlobSet,
+}
+
+impl ExecAllowlist {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .context(format!("Failed to read exec allowlist: {}", path.display()))?;
+        let patterns: Vec<String> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        Self::from_patterns(&patterns)
+    }
+
+    pub fn from_patterns(patterns: &[String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let expanded = expand_tilde(pattern);
+            let glob = GlobBuilder::new(&expanded.to_string_lossy())
+                .literal_separator(true)
+                .build()
+                .context(format!("Invalid exec allowlist pattern: {}", pattern))?;
+            builder.add(glob);
+        }
+        let patterns = builder.build().context("Failed to compile exec allowlist")?;
+        Ok(Self { patterns })
+    }
+
+    /// The absolute path `binary` runs as, if it may be executed
+    ///
+    /// A bare name is looked up on this process's own PATH and a relative path resolved
+    /// against `working_dir`, so what's matched is what runs whatever the request sets.
+    /// A path with a `..` component is never permitted.
+    pub fn resolve(&self, binary: &str, working_dir: Option<&str>) -> Option<PathBuf> {
+        let expanded = expand_tilde(binary);
+        if expanded.components().any(|c| c == Component::ParentDir) {
+            return None;
+        }
+
+        let mut components = expanded.components();
+        if let (Some(Component::Normal(_)), None) = (components.next(), components.next()) {
+            let resolved = find_on_path(&expanded)?;
+            return (self.patterns.is_match(&expanded) || self.patterns.is_match(&resolved)).then_some(resolved);
+        }
+
+        let resolved = if expanded.is_absolute() {
+            expanded
+        } else {
+            let cwd = working_dir.map(expand_tilde);
+            std::env::current_dir().ok()?.join(cwd.unwrap_or_default()).join(expanded)
+        };
+        if resolved.components().any(|c| c == Component::ParentDir) {
+            return None;
+        }
+        self.patterns.is_match(&resolved).then_some(resolved)
+    }
+}
+
+/// Where `name` is found on this process's PATH, trying `.exe` too on Windows
+fn find_on_path(name: &Path) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .flat_map(|dir| {
+            let candidate = dir.join(name);
+            let exe = (cfg!(windows) && candidate.extension().is_none()).then(|| candidate.with_extension("exe"));
+            std::iter::once(candidate).chain(exe)
+        })
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether an Execute's `key` override changes which program or libraries run, which
+/// an exec allowlist can't vouch for
+fn overrides_program_lookup(key: &str) -> bool {
+    key.eq_ignore_ascii_case("PATH") || key.starts_with("LD_") || key.starts_with("DYLD_")
+}
+
 pub struct ClientDaemon {
     server_host: String,
     server_port: u16,
     server_user: String,
     hostname: String,
     heartbeat_interval: Duration,
-    reconnect_delay: Duration,
+    /// Sequence of the next heartbeat; restarts at 0 with each registration
+    heartbeat_sequence: u32,
+    /// Token from the last Welcome, sent with the next registration to resume that session
+    resume_token: Option<String>,
+    /// Delay between reconnect attempts
+    backoff: Backoff,
+    /// Consecutive failed connections allowed before `run` gives up; None retries forever
+    max_retries: Option<u32>,
+    /// Failed connections since the last successful one
+    failed_attempts: u32,
+    /// File the reconnect backoff is kept in between runs; None keeps it in memory only
+    backoff_path: Option<PathBuf>,
+    event_handler: Option<EventHandler>,
     agent_socket: Option<String>,
     working_dir: Option<std::path::PathBuf>,
     initial_sync: bool,
+    /// Encoding of messages to the server
+    wire_format: WireFormat,
+    exec_timeout: Option<Duration>,
+    exec_output_limit: usize,
+    /// When set, Execute requests for binaries it does not permit are refused
+    exec_allowlist: Option<ExecAllowlist>,
+    /// Largest base + delta applied in memory; bigger syncs are mmapped and streamed
+    memory_budget: usize,
+    /// Bytes a sync must leave free on its destination's filesystem
+    min_free_space: u64,
+    /// Longest wait for the next delta chunk before a transfer is abandoned as failed
+    rsync_timeout: Duration,
+    /// Longest silence from the server before the connection is presumed dead and
+    /// replaced; None leaves it to the SSH inactivity timeout
+    idle_reconnect: Option<Duration>,
+    /// Write absolute destinations where they point instead of refusing them
+    allow_absolute_destinations: bool,
+    /// Process names that make the client busy: while any of them runs, syncs are
+    /// queued instead of applied
+    defer_while_busy: Vec<String>,
+    /// Syncs queued while busy, oldest first; at most one per path
+    pending_syncs: VecDeque<PendingSync>,
+    /// File each finished sync is recorded in, one JSON line apiece
+    sync_log: Option<PathBuf>,
+    /// Refuse syncs over files edited here since they were last synced, unless forced
+    no_clobber_local: bool,
+    /// Checksums of synced files, for `no_clobber_local` and resuming initial syncs
+    synced: SyncedChecksums,
+    /// File `synced` is kept in between runs; None keeps it in memory only
+    synced_path: Option<PathBuf>,
+    /// Whether `synced` has changed since it was last saved
+    synced_unsaved: bool,
+    /// Paths ResyncHandles asked to be sent again
+    resync_requests: mpsc::UnboundedReceiver<String>,
+    resync_sender: mpsc::UnboundedSender<String>,
+    /// Destination directories already checked for writability; failures are kept
+    /// with when they were found, and checked again after DIR_CHECK_RETRY
+    checked_dirs: std::collections::HashMap<PathBuf, Result<(), (Instant, String)>>,
     shutdown: Arc<AtomicBool>,
     state: Arc<Mutex<ClientState>>,
+    /// Execute commands still running; they run in background tasks so a Kill can
+    /// reach them
+    running_execs: RunningExecs,
+    /// The background tasks, aborted (killing their commands) with the connection
+    /// they report on
+    exec_tasks: tokio::task::JoinSet<()>,
     connection: Option<SshClientConnection>,
+    // Captured at startup: once a self-update replaces the file, current_exe() on
+    // Linux reports the old inode as "<path> (deleted)"
+    executable: Option<PathBuf>,
 }
 
 impl ClientDaemon {
@@ -56,6 +507,7 @@ impl ClientDaemon {
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
+        let (resync_sender, resync_requests) = mpsc::unbounded_channel();
 
         Self {
             server_host,
@@ -63,18 +515,48 @@ impl ClientDaemon {
             server_user,
             hostname,
             heartbeat_interval: Duration::from_secs(30),
-            reconnect_delay: Duration::from_secs(5),
+            heartbeat_sequence: 0,
+            resume_token: None,
+            backoff: Backoff::new(Duration::from_secs(5), MAX_RECONNECT_DELAY),
+            max_retries: None,
+            failed_attempts: 0,
+            backoff_path: None,
+            event_handler: None,
             agent_socket: None,
             working_dir: None,
             initial_sync: true,
+            wire_format: WireFormat::default(),
+            exec_timeout: None,
+            exec_output_limit: DEFAULT_EXEC_OUTPUT_LIMIT,
+            exec_allowlist: None,
+            memory_budget: rsync_utils::DEFAULT_MEMORY_BUDGET,
+            min_free_space: DEFAULT_MIN_FREE_SPACE,
+            rsync_timeout: DEFAULT_RSYNC_TIMEOUT,
+            idle_reconnect: None,
+            allow_absolute_destinations: false,
+            defer_while_busy: Vec::new(),
+            pending_syncs: VecDeque::new(),
+            sync_log: None,
+            no_clobber_local: false,
+            synced: SyncedChecksums::default(),
+            synced_path: None,
+            synced_unsaved: false,
+            resync_requests,
+            resync_sender,
+            checked_dirs: std::collections::HashMap::new(),
             shutdown: Arc::new(AtomicBool::new(false)),
             state: Arc::new(Mutex::new(ClientState {
                 connected_since,
                 last_sync: None,
                 running_processes: Vec::new(),
                 pending_transfers: 0,
+                exec_processes: Vec::new(),
+                free_space: None,
             })),
+            running_execs: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            exec_tasks: tokio::task::JoinSet::new(),
             connection: None,
+            executable: std::env::current_exe().ok(),
         }
     }
 
@@ -83,8 +565,33 @@ impl ClientDaemon {
         self
     }
 
+    /// Wait `delay` before the first reconnect attempt, doubling it for each further one
     pub fn with_reconnect_delay(mut self, delay: Duration) -> Self {
-        self.reconnect_delay = delay;
+        self.backoff = Backoff::new(delay, MAX_RECONNECT_DELAY);
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: Option<u32>) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Keep the reconnect backoff, and the checksums of synced files that
+    /// --no-clobber-local and resumed initial syncs rely on, in files under
+    /// `state_dir` so they survive restarts
+    pub fn with_state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        let server: String = format!("{}-{}", self.server_host, self.server_port)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        self.backoff_path = state_dir.as_ref().map(|dir| dir.join(format!("backoff-{}.toml", server)));
+        self.synced_path = state_dir.map(|dir| dir.join(format!("synced-{}.toml", server)));
+        self
+    }
+
+    /// Call `handler` at every connection state transition
+    pub fn with_event_handler(mut self, handler: impl Fn(&DaemonEvent) + Send + Sync + 'static) -> Self {
+        self.event_handler = Some(Box::new(handler));
         self
     }
 
@@ -103,9 +610,88 @@ impl ClientDaemon {
         self
     }
 
+    pub fn with_wire_format(mut self, format: WireFormat) -> Self {
+        self.wire_format = format;
+        self
+    }
+
+    pub fn with_exec_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.exec_timeout = timeout;
+        self
+    }
+
+    pub fn with_exec_output_limit(mut self, limit: usize) -> Self {
+        self.exec_output_limit = limit;
+        self
+    }
+
+    pub fn with_exec_allowlist(mut self, allowlist: Option<ExecAllowlist>) -> Self {
+        self.exec_allowlist = allowlist;
+        self
+    }
+
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = bytes;
+        self
+    }
+
+    /// Refuse syncs that would leave less than `bytes` free on the destination's filesystem
+    pub fn with_min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = bytes;
+        self
+    }
+
+    pub fn with_rsync_timeout(mut self, timeout: Duration) -> Self {
+        self.rsync_timeout = timeout;
+        self
+    }
+
+    /// Reconnect once nothing has arrived from the server for `window`
+    pub fn with_idle_reconnect(mut self, window: Option<Duration>) -> Self {
+        self.idle_reconnect = window;
+        self
+    }
+
+    pub fn with_allow_absolute_destinations(mut self, allow: bool) -> Self {
+        self.allow_absolute_destinations = allow;
+        self
+    }
+
+    /// Queue syncs while a process with one of these names is running
+    pub fn with_defer_while_busy(mut self, process_names: Vec<String>) -> Self {
+        self.defer_while_busy = process_names;
+        self
+    }
+
+    /// Append a line per finished sync to `path`
+    pub fn with_sync_log(mut self, path: Option<PathBuf>) -> Self {
+        self.sync_log = path;
+        self
+    }
+
+    /// Refuse to sync over a file that no longer matches the checksum it was last
+    /// synced with, reporting a conflict instead
+    pub fn with_no_clobber_local(mut self, enabled: bool) -> Self {
+        self.no_clobber_local = enabled;
+        self
+    }
+
+    /// Handle for asking the server to send files again, e.g. after finding a local
+    /// copy corrupt or changed outside the launcher
+    pub fn resync_handle(&self) -> ResyncHandle {
+        ResyncHandle {
+            sender: self.resync_sender.clone(),
+        }
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         log::info!("Starting client daemon for {}", self.hostname);
 
+        self.resume_backoff().await;
+        if let Some(ref path) = self.synced_path {
+            self.synced = SyncedChecksums::load(path);
+        }
+
         loop {
             if self.shutdown.load(Ordering::Relaxed) {
                 log::info!("Shutdown requested, exiting");
@@ -115,18 +701,36 @@ impl ClientDaemon {
             match self.connect_and_run().await {
                 Ok(_) => {
                     log::info!("Control loop exited normally");
+                    self.emit(DaemonEvent::Disconnected {
+                        reason: "shutdown requested".to_string(),
+                    });
                     break;
                 }
                 Err(e) => {
                     log::error!("Connection error: {:#}", e);
-                    log::info!(
-                        "Reconnecting in {} seconds...",
-                        self.reconnect_delay.as_secs()
-                    );
-                    time::sleep(self.reconnect_delay).await;
-
-                    self.reconnect_delay =
-                        std::cmp::min(self.reconnect_delay * 2, Duration::from_secs(60));
+                    self.connection = None;
+                    self.exec_tasks.abort_all();
+                    self.emit(DaemonEvent::Disconnected {
+                        reason: format!("{:#}", e),
+                    });
+
+                    self.failed_attempts += 1;
+                    if let Some(max_retries) = self.max_retries
+                        && self.failed_attempts > max_retries
+                    {
+                        return Err(RetriesExhausted {
+                            attempts: self.failed_attempts,
+                        }
+                        .into());
+                    }
+
+                    let delay = self.backoff.next_delay();
+                    log::info!("Reconnecting in {} seconds...", delay.as_secs());
+                    self.emit(DaemonEvent::Reconnecting {
+                        attempt: self.failed_attempts,
+                        delay,
+                    });
+                    time::sleep(delay).await;
                 }
             }
         }
@@ -134,6 +738,23 @@ impl ClientDaemon {
         Ok(())
     }
 
+    /// Wait out the backoff a previous process recorded, then carry on doubling from it
+    async fn resume_backoff(&mut self) {
+        let Some(state) = self.backoff_path.as_deref().and_then(BackoffState::load) else {
+            return;
+        };
+
+        let remaining = state.remaining(unix_now());
+        if !remaining.is_zero() {
+            log::info!(
+                "Previous run was still backing off, reconnecting in {} seconds...",
+                remaining.as_secs()
+            );
+            time::sleep(remaining).await;
+        }
+        self.backoff.resume_from(Duration::from_secs(state.delay_secs));
+    }
+
     async fn connect_and_run(&mut self) -> Result<()> {
         log::info!(
             "Connecting to {}@{}:{}",
@@ -142,42 +763,113 @@ impl ClientDaemon {
             self.server_port
         );
 
+        // Recorded before connecting, so a process that dies mid-attempt still counts
+        if let Some(ref path) = self.backoff_path {
+            let state = BackoffState {
+                last_attempt: unix_now(),
+                delay_secs: self.backoff.current().as_secs(),
+            };
+            if let Err(e) = state.save(path) {
+                log::warn!("{:#}", e);
+            }
+        }
+
         let connection = SshClientConnection::connect(
             &self.server_host,
             self.server_port,
             &self.server_user,
             self.agent_socket.as_deref(),
         )
-        .await?;
+        .await?
+        .with_wire_format(self.wire_format);
 
         connection
-            .send_register(&self.hostname, self.initial_sync)
+            .send_register(&self.hostname, self.initial_sync, self.resume_token.as_deref())
             .await
             .context("Failed to send registration")?;
 
         log::info!("Sent registration message");
+        self.heartbeat_sequence = 0;
 
         self.connection = Some(connection);
-        self.reconnect_delay = Duration::from_secs(5);
+        self.backoff.reset();
+        self.failed_attempts = 0;
+        if let Some(ref path) = self.backoff_path
+            && let Err(e) = std::fs::remove_file(path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::warn!("Failed to remove backoff state {}: {}", path.display(), e);
+        }
+        self.emit(DaemonEvent::Connected);
+
+        // Deferred syncs belong to the last session, whose sources the server has
+        // already dropped; an initial sync offers the files again
+        if !self.pending_syncs.is_empty() {
+            log::info!("Dropping {} syncs deferred before reconnecting", self.pending_syncs.len());
+            self.pending_syncs.clear();
+            self.state.lock().unwrap().pending_transfers = 0;
+        }
 
-        self.control_loop().await
+        let result = self.control_loop().await;
+        self.save_synced().await;
+        result
+    }
+
+    fn emit(&self, event: DaemonEvent) {
+        log::debug!("Daemon event: {:?}", event);
+        if let Some(ref handler) = self.event_handler {
+            handler(&event);
+        }
     }
 
     async fn control_loop(&mut self) -> Result<()> {
         let mut heartbeat_timer = time::interval(self.heartbeat_interval);
         heartbeat_timer.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        let mut idle_timer = time::interval(IDLE_CHECK_INTERVAL);
+        idle_timer.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        let mut synced_timer = time::interval(SYNCED_SAVE_INTERVAL);
+        synced_timer.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
         log::info!("Entering control loop");
 
+        // Also reset after handling each message, so a long transfer (which blocks
+        // this loop) isn't mistaken for silence
+        let mut last_received = Instant::now();
+
         loop {
             tokio::select! {
                 _ = heartbeat_timer.tick() => {
                     self.handle_heartbeat().await?;
                 }
 
+                _ = idle_timer.tick(), if !self.pending_syncs.is_empty() => {
+                    self.apply_pending_syncs().await?;
+                    last_received = Instant::now();
+                }
+
+                _ = synced_timer.tick(), if self.synced_unsaved => {
+                    self.save_synced().await;
+                }
+
+                Some(relative_path) = self.resync_requests.recv() => {
+                    self.request_resync(relative_path).await?;
+                }
+
                 _ = time::sleep(Duration::from_millis(100)) => {
                     if let Some(msg) = self.poll_server_message().await? {
                         self.handle_server_message(msg).await?;
+                        last_received = Instant::now();
+                    } else {
+                        // Nothing waiting, so any batch of syncs has ended
+                        self.save_synced().await;
+                        if let Some(window) = self.idle_reconnect
+                            && last_received.elapsed() > window
+                        {
+                            anyhow::bail!(
+                                "Nothing received from the server in {}s; presuming the connection dead",
+                                window.as_secs_f64()
+                            );
+                        }
                     }
 
                     if self.shutdown.load(Ordering::Relaxed) {
@@ -199,12 +891,23 @@ impl ClientDaemon {
         }
     }
 
+    async fn request_resync(&mut self, relative_path: String) -> Result<()> {
+        log::info!("🩹 Asking the server to resync {}", relative_path);
+        if let Some(ref conn) = self.connection {
+            conn.send_message(&ClientMessage::RequestResync { relative_path })
+                .await
+                .context("Failed to send resync request")?;
+        }
+        Ok(())
+    }
+
     async fn handle_heartbeat(&mut self) -> Result<()> {
         if let Some(ref conn) = self.connection {
-            conn.send_heartbeat(0)
+            conn.send_heartbeat(self.heartbeat_sequence)
                 .await
                 .context("Failed to send heartbeat")?;
-            log::trace!("Sent heartbeat");
+            log::trace!("Sent heartbeat {}", self.heartbeat_sequence);
+            self.heartbeat_sequence = self.heartbeat_sequence.wrapping_add(1);
         }
         Ok(())
     }
@@ -216,48 +919,78 @@ impl ClientDaemon {
             ServerMessage::Welcome {
                 server_version,
                 session_id,
+                resume_token,
+                resumed,
             } => {
                 log::info!(
-                    "Server welcomed us: version={}, session={}",
+                    "Server welcomed us: version={}, session={}, resumed={}",
                     server_version,
-                    session_id
+                    session_id,
+                    resumed
                 );
+                self.resume_token = Some(resume_token);
             }
 
             ServerMessage::Ping { request_id } => {
                 log::debug!("Received ping: {}", request_id);
                 if let Some(ref conn) = self.connection {
-                    let state = self.state.lock().unwrap().clone();
+                    let mut state = self.state.lock().unwrap().clone();
+                    state.exec_processes = self.exec_processes();
+                    state.free_space =
+                        fs4::available_space(self.working_dir.as_deref().unwrap_or(Path::new("."))).ok();
                     let msg = ClientMessage::Status { request_id, state };
                     conn.send_message(&msg).await?;
                 }
             }
 
+            ServerMessage::Keepalive { nonce } => {
+                log::trace!("Received keepalive: {}", nonce);
+                if let Some(ref conn) = self.connection {
+                    conn.send_message(&ClientMessage::Pong { nonce }).await?;
+                }
+            }
+
             ServerMessage::RsyncStart {
                 request_id,
                 relative_path,
                 size,
                 checksum,
-                mtime,
+                mtime: _,
                 block_size,
                 mode,
+                dir_mode,
+                whole_file,
+                checksum_algo,
+                atomic_replace,
+                priority,
+                force,
             } => {
                 log::info!(
-                    "Rsync request: {} ({} bytes, block_size: {})",
+                    "Rsync request: {} ({} bytes, block_size: {}, priority: {})",
                     relative_path,
                     size,
-                    block_size
+                    block_size,
+                    priority
                 );
-                self.handle_rsync_start(
+                let sync = PendingSync {
                     request_id,
                     relative_path,
                     size,
                     checksum,
-                    mtime,
                     block_size,
                     mode,
-                )
-                .await?;
+                    dir_mode,
+                    whole_file,
+                    checksum_algo,
+                    atomic_replace,
+                    force,
+                };
+                let busy = self.busy_processes().await;
+                if busy.is_empty() {
+                    self.handle_rsync_start(sync).await?;
+                } else {
+                    self.defer_sync(sync, &busy).await?;
+                }
             }
 
             ServerMessage::Execute {
@@ -266,12 +999,17 @@ impl ClientDaemon {
                 args,
                 working_dir,
                 env,
+                self_update,
             } => {
                 log::info!("Execute request: {} {:?}", binary, args);
-                self.handle_execute(request_id, binary, args, working_dir, env)
+                self.handle_execute(request_id, binary, args, working_dir, env, self_update)
                     .await?;
             }
 
+            ServerMessage::Kill { request_id } => {
+                self.handle_kill(request_id).await?;
+            }
+
             ServerMessage::Shutdown { message } => {
                 if let Some(msg) = message {
                     log::info!("Server requested shutdown: {}", msg);
@@ -280,168 +1018,402 @@ impl ClientDaemon {
                 }
                 self.shutdown.store(true, Ordering::Relaxed);
             }
+
+            ServerMessage::DeleteFile {
+                request_id,
+                path,
+                scope,
+            } => {
+                log::info!("Delete request: {} (scope: {})", path, scope);
+                if let Err(e) = self.handle_delete_file(&path, &scope).await {
+                    log::error!("Failed to delete {}: {:#}", path, e);
+                    if let Some(ref conn) = self.connection {
+                        let msg = ClientMessage::Error {
+                            request_id: Some(request_id),
+                            message: format!("{:#}", e),
+                        };
+                        conn.send_message(&msg).await?;
+                    }
+                }
+            }
+
+            ServerMessage::CreateDir {
+                request_id,
+                relative_path,
+                mode,
+            } => {
+                log::debug!("Create directory request: {}", relative_path);
+                if let Err(e) = self.handle_create_dir(&relative_path, mode).await {
+                    log::error!("Failed to create {}: {:#}", relative_path, e);
+                    if let Some(ref conn) = self.connection {
+                        let msg = ClientMessage::Error {
+                            request_id: Some(request_id),
+                            message: format!("{:#}", e),
+                        };
+                        conn.send_message(&msg).await?;
+                    }
+                }
+            }
+
+            ServerMessage::Manifest {
+                request_id,
+                entries,
+                checksum_algo,
+            } => {
+                let offered = entries.len();
+                // Paths this client refuses are still requested, so the sync reports why
+                let mut refused = Vec::new();
+                let mut local = Vec::new();
+                for entry in entries {
+                    match self.local_path(&entry.path) {
+                        Ok(path) => {
+                            let synced = self.synced.files.get(path.to_string_lossy().as_ref()).cloned();
+                            local.push((path, entry, synced));
+                        }
+                        Err(_) => refused.push(entry.path),
+                    }
+                }
+                let memory_budget = self.memory_budget;
+                let mut needed = tokio::task::spawn_blocking(move || stale_entries(local, checksum_algo, memory_budget))
+                    .await
+                    .context("Manifest comparison task failed")?;
+                needed.extend(refused);
+                log::info!("📋 Requesting {} of {} manifest files", needed.len(), offered);
+
+                if let Some(ref conn) = self.connection {
+                    let msg = ClientMessage::ManifestDiff { request_id, needed };
+                    conn.send_message(&msg).await?;
+                }
+            }
+
+            ServerMessage::VerifyFile {
+                request_id,
+                relative_path,
+                expected_checksum,
+                checksum_algo,
+            } => {
+                log::info!("Verify request: {}", relative_path);
+                let actual = match self.local_path(&relative_path) {
+                    Ok(path) => {
+                        let memory_budget = self.memory_budget;
+                        tokio::task::spawn_blocking(move || {
+                            rsync_utils::file_checksum(&path, checksum_algo, memory_budget)
+                        })
+                        .await
+                        .context("Verify task failed")?
+                    }
+                    Err(e) => Err(e),
+                };
+                let msg = match actual {
+                    Ok(actual) => ClientMessage::VerifyResult {
+                        request_id,
+                        matches: actual.eq_ignore_ascii_case(&expected_checksum),
+                        actual_checksum: Some(actual),
+                        error: None,
+                    },
+                    Err(e) => ClientMessage::VerifyResult {
+                        request_id,
+                        matches: false,
+                        actual_checksum: None,
+                        error: Some(format!("{:#}", e)),
+                    },
+                };
+                if let Some(ref conn) = self.connection {
+                    conn.send_message(&msg).await?;
+                }
+            }
         }
 
         Ok(())
     }
 
-    async fn handle_rsync_start(
-        &mut self,
-        request_id: String,
-        relative_path: String,
-        _size: u64,
-        expected_checksum: String,
-        _mtime: u64,
-        block_size: u32,
-        mode: u32,
-    ) -> Result<()> {
-        log::info!("Rsync start: {} (block_size: {})", relative_path, block_size);
-
-        let start_time = std::time::Instant::now();
-
-        // Expand tilde in the relative path
-        let expanded_path = expand_tilde(&relative_path);
+    /// Resolve a server-sent path: expand tilde, then anchor relative paths at the working dir
+    ///
+    /// No path may contain `..`, and the result must stay under the working dir (or home,
+    /// for `~` paths). Absolute paths are refused unless --allow-absolute-destinations is set.
+    fn local_path(&self, path: &str) -> Result<PathBuf> {
+        let raw = Path::new(path);
+        let expanded = expand_tilde(path);
+        if expanded.components().any(|c| c == Component::ParentDir) {
+            anyhow::bail!("Refusing destination {}: contains '..'", path);
+        }
+        if raw.has_root() || raw.is_absolute() {
+            if !self.allow_absolute_destinations {
+                anyhow::bail!(
+                    "Refusing absolute destination {} (start the client with --allow-absolute-destinations to allow it)",
+                    path
+                );
+            }
+            return Ok(expanded);
+        }
 
-        // Construct local path relative to working directory if set
-        let local_path = if let Some(ref working_dir) = self.working_dir {
-            working_dir.join(&expanded_path)
+        // A ~ path lands under HOME; anything else under the working directory
+        let root = if expanded != raw {
+            std::env::var("HOME").ok().map(PathBuf::from)
         } else {
-            expanded_path
+            self.working_dir.clone()
         };
-
-        // Create parent directory if needed
-        if let Some(parent) = local_path.parent()
-            && !parent.exists()
-        {
-            tokio::fs::create_dir_all(parent)
-                .await
-                .context("Failed to create parent directory")?;
+        let Some(root) = root else {
+            return Ok(expanded);
+        };
+        let local = root.join(&expanded);
+        if !local.starts_with(&root) {
+            anyhow::bail!("Refusing destination {}: resolves outside {}", path, root.display());
         }
+        Ok(local)
+    }
 
-        // Spawn rsync task
-        let conn_ref = self
-            .connection
-            .as_ref()
-            .context("No active connection")?;
+    /// Delete a synced file whose source was removed, refusing anything outside `scope`
+    ///
+    /// The server already scopes deletions; checking again here means a confused or
+    /// misconfigured server still can't remove files outside a rule's destination.
+    async fn handle_delete_file(&self, path: &str, scope: &str) -> Result<()> {
+        if !within_scope(Path::new(scope), Path::new(path)) {
+            anyhow::bail!("Refusing to delete {}: outside {}", path, scope);
+        }
 
-        log::debug!("Opening rsync channel for {}", relative_path);
-
-        // Open dedicated rsync channel
-        let mut rsync_channel = conn_ref
-            .open_rsync_channel()
-            .await
-            .context("Failed to open rsync channel")?;
+        let local_path = self.local_path(path)?;
+        match tokio::fs::remove_file(&local_path).await {
+            Ok(()) => log::info!("🗑️  Deleted {}", local_path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("{} already absent", local_path.display());
+            }
+            Err(e) => {
+                return Err(e).context(format!("Failed to delete {}", local_path.display()));
+            }
+        }
 
-        log::debug!("Successfully opened rsync channel for {}", relative_path);
+        Ok(())
+    }
 
-        // Send request_id as handshake
-        let handshake_frame = Frame::new(MSG_RSYNC_SIGNATURE, request_id.clone().into_bytes());
-        SshClientConnection::write_frame_to_channel(&mut rsync_channel, &handshake_frame)
+    /// Create an empty directory the server has under a synced tree
+    async fn handle_create_dir(&self, relative_path: &str, mode: Option<u32>) -> Result<()> {
+        let local_path = self.local_path(relative_path)?;
+        if local_path.is_dir() {
+            log::debug!("{} already exists", local_path.display());
+            return Ok(());
+        }
+        create_dirs(&local_path, mode)
             .await
-            .context("Failed to send handshake")?;
-
-        log::debug!("Sent handshake for {}", relative_path);
+            .context(format!("Failed to create {}", local_path.display()))?;
+        log::info!("📁 Created {}", local_path.display());
+        Ok(())
+    }
 
-        // Generate signature from local file (if it exists)
-        let signature_data = if local_path.exists() {
-            log::debug!(
-                "Generating signature for existing file: {}",
-                local_path.display()
-            );
-            rsync_utils::generate_signature(&local_path, block_size)
-                .await
-                .context("Failed to generate signature")?
-        } else {
-            log::debug!("No existing file, sending empty signature");
-            Vec::new()
+    /// Tracked processes running right now, which are also reported as the client's
+    /// running processes; none when --defer-while-busy is off
+    async fn busy_processes(&self) -> Vec<String> {
+        if self.defer_while_busy.is_empty() {
+            return Vec::new();
+        }
+        // A client that can't list processes applies syncs rather than holding them forever
+        let running = match list_processes().await {
+            Ok(listing) => matching_processes(&listing, &self.defer_while_busy),
+            Err(e) => {
+                log::warn!("Failed to list processes, treating the client as idle: {:#}", e);
+                Vec::new()
+            }
         };
+        self.state.lock().unwrap().running_processes = running.clone();
+        running
+    }
 
-        log::debug!("Signature size: {} bytes", signature_data.len());
+    /// Queue a sync until the client is idle and tell the server it is waiting
+    ///
+    /// A newer sync of the same path replaces the queued one, which is reported failed
+    /// so the server can let go of its source.
+    async fn defer_sync(&mut self, sync: PendingSync, busy: &[String]) -> Result<()> {
+        log::info!("⏸️  Deferring sync of {} while {} is running", sync.relative_path, busy.join(", "));
 
-        // Send signature on rsync channel
-        let sig_frame = Frame::new(MSG_RSYNC_SIGNATURE, signature_data);
-        SshClientConnection::write_frame_to_channel(&mut rsync_channel, &sig_frame)
-            .await
-            .context("Failed to send signature")?;
+        let mut superseded = None;
+        if let Some(index) = self.pending_syncs.iter().position(|p| p.relative_path == sync.relative_path) {
+            superseded = self.pending_syncs.remove(index);
+        }
+        let request_id = sync.request_id.clone();
+        let relative_path = sync.relative_path.clone();
+        self.pending_syncs.push_back(sync);
+        self.state.lock().unwrap().pending_transfers = self.pending_syncs.len() as u32;
+
+        if let Some(ref conn) = self.connection {
+            if let Some(old) = superseded {
+                let msg = ClientMessage::RsyncComplete {
+                    request_id: old.request_id,
+                    path: old.relative_path,
+                    success: false,
+                    checksum: String::new(),
+                    bytes_transferred: 0,
+                    error: Some("Superseded by a newer sync while deferred".to_string()),
+                    duration_ms: 0,
+                    queued: false,
+                };
+                conn.send_message(&msg).await?;
+            }
+            let msg = ClientMessage::RsyncComplete {
+                request_id,
+                path: relative_path,
+                success: false,
+                checksum: String::new(),
+                bytes_transferred: 0,
+                error: Some(format!("Deferred while {} is running", busy.join(", "))),
+                duration_ms: 0,
+                queued: true,
+            };
+            conn.send_message(&msg).await?;
+        }
+        Ok(())
+    }
 
-        log::debug!("Sent signature for {}", relative_path);
+    /// Apply every deferred sync once no tracked process is running
+    async fn apply_pending_syncs(&mut self) -> Result<()> {
+        if !self.busy_processes().await.is_empty() {
+            return Ok(());
+        }
+        log::info!("▶️  Idle; applying {} deferred syncs", self.pending_syncs.len());
+        while let Some(sync) = self.pending_syncs.pop_front() {
+            self.state.lock().unwrap().pending_transfers = self.pending_syncs.len() as u32;
+            self.handle_rsync_start(sync).await?;
+        }
+        Ok(())
+    }
 
-        // Receive delta on rsync channel (may be multiple chunks for large files)
-        let mut delta_data = Vec::new();
-        let mut chunk_count = 0;
-        loop {
-            let delta_frame = SshClientConnection::read_frame_from_channel(&mut rsync_channel)
-                .await
-                .context("Failed to receive delta chunk")?;
+    async fn handle_rsync_start(&mut self, sync: PendingSync) -> Result<()> {
+        let PendingSync {
+            request_id,
+            relative_path,
+            size,
+            checksum: expected_checksum,
+            block_size,
+            mode,
+            dir_mode,
+            whole_file,
+            checksum_algo,
+            atomic_replace,
+            force,
+        } = sync;
+        log::info!("Rsync start: {} (block_size: {})", relative_path, block_size);
 
-            if delta_frame.message_type != MSG_RSYNC_DELTA {
-                anyhow::bail!(
-                    "Expected delta frame, got message type: {}",
-                    delta_frame.message_type
-                );
+        let start_time = std::time::Instant::now();
+
+        let local_path = match self.local_path(&relative_path) {
+            Ok(path) => path,
+            Err(e) => {
+                log::error!("{:#}", e);
+                return self.refuse_rsync(request_id, relative_path, format!("{:#}", e), start_time).await;
             }
+        };
 
-            // Zero-length frame signals end of delta stream
-            if delta_frame.payload.is_empty() {
-                log::debug!("Received end-of-delta marker after {} chunks, {} bytes total",
-                    chunk_count, delta_data.len());
-                break;
+        // Create the parent directory if needed, failing fast if it can't be written
+        if let Some(parent) = local_path.parent()
+            && let Err(error) = self.check_destination_dir(parent, dir_mode).await
+        {
+            return self.refuse_rsync(request_id, relative_path, error, start_time).await;
+        }
+
+        // Already holds this content: nothing is transferred, so a run_on = "changed"
+        // execute is skipped; the mode may still differ
+        if tokio::fs::metadata(&local_path).await.is_ok_and(|metadata| metadata.len() == size)
+            && rsync_utils::current_checksum(&local_path, checksum_algo, self.memory_budget).as_deref()
+                == Some(expected_checksum.as_str())
+        {
+            log::info!("{} is already up to date", relative_path);
+            self.remember_synced(&local_path, &expected_checksum, checksum_algo);
+            let error = set_file_mode(&local_path, mode).await.err().map(|e| format!("{:#}", e));
+            let msg = ClientMessage::RsyncComplete {
+                request_id,
+                path: relative_path,
+                success: error.is_none(),
+                checksum: expected_checksum,
+                bytes_transferred: 0,
+                error,
+                duration_ms: start_time.elapsed().as_millis() as u64,
+                queued: false,
+            };
+            self.record_sync(&msg).await;
+            if let Some(ref conn) = self.connection {
+                conn.send_message(&msg).await?;
             }
+            return Ok(());
+        }
 
-            delta_data.extend_from_slice(&delta_frame.payload);
-            chunk_count += 1;
-            log::trace!("Received delta chunk {}: {} bytes (total: {} bytes)",
-                chunk_count, delta_frame.payload.len(), delta_data.len());
+        // An edit made here since the last sync would be lost
+        if self.no_clobber_local
+            && !force
+            && let Some(error) = self.local_edit(&local_path)
+        {
+            log::warn!("⚠️  Not syncing {}: {}", relative_path, error);
+            return self.refuse_rsync(request_id, relative_path, error, start_time).await;
+        }
+
+        // Refused up front, rather than failing at a write once the disk fills
+        if let Some(parent) = local_path.parent()
+            && let Err(error) = check_free_space(parent, size, self.min_free_space)
+        {
+            log::error!("❌ Not syncing {}: {}", relative_path, error);
+            return self.refuse_rsync(request_id, relative_path, error, start_time).await;
         }
 
-        let delta_size = delta_data.len();
-        log::debug!("Received delta: {} bytes total in {} chunks", delta_size, chunk_count);
+        // Spawn rsync task
+        let conn_ref = self
+            .connection
+            .as_ref()
+            .context("No active connection")?;
 
-        // Apply delta to produce new file
-        let base_path = if local_path.exists() {
-            Some(local_path.as_path())
+        // Each attempt opens a dedicated rsync channel; the server keeps the source
+        // available until RsyncComplete, so a retry can request a second delta
+        let request_id_ref = request_id.as_str();
+        let relative_path_ref = relative_path.as_str();
+        let timeout = self.rsync_timeout;
+        let fetched = if whole_file {
+            // Small file: the content itself comes back, no signature needed
+            Self::request_delta(conn_ref, request_id_ref, relative_path_ref, None, timeout)
+                .await
+                .map(|data| AppliedDelta {
+                    checksum: rsync_utils::compute_checksum(checksum_algo, &data),
+                    bytes_transferred: data.len(),
+                    content: AppliedContent::Memory(data),
+                })
         } else {
-            None
+            rsync_utils::fetch_and_apply_delta(
+                &local_path,
+                block_size,
+                &expected_checksum,
+                checksum_algo,
+                self.memory_budget,
+                move |signature| {
+                    Self::request_delta(conn_ref, request_id_ref, relative_path_ref, Some(signature), timeout)
+                },
+            )
+            .await
         };
 
-        let new_content = rsync_utils::apply_delta(base_path, &delta_data)
-            .await
-            .context("Failed to apply delta")?;
+        // A failed transfer is reported like a refused one; the connection itself is
+        // still good, so the control loop carries on with the next message
+        let applied = match fetched {
+            Ok(applied) => applied,
+            Err(e) => {
+                log::error!("❌ Sync of {} failed: {:#}", relative_path, e);
+                return self.refuse_rsync(request_id, relative_path, format!("{:#}", e), start_time).await;
+            }
+        };
 
-        // Verify checksum
-        let actual_checksum = rsync_utils::compute_checksum(&new_content);
+        let delta_size = applied.bytes_transferred;
+        let new_content = applied.content;
+        let actual_checksum = applied.checksum;
         let success = actual_checksum == expected_checksum;
 
         if success {
             log::debug!("Checksum verified for {}", relative_path);
 
-            // Write the file
-            tokio::fs::write(&local_path, &new_content)
-                .await
-                .context("Failed to write file")?;
-
-            // Apply file permissions from server
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                let permissions = std::fs::Permissions::from_mode(mode);
-                tokio::fs::set_permissions(&local_path, permissions)
-                    .await
-                    .context("Failed to set file permissions")?;
-                log::debug!("Set permissions {:o} on {}", mode, relative_path);
-            }
-            #[cfg(not(unix))]
-            {
-                // Windows doesn't use Unix permissions, so we just log it
-                log::trace!("Ignoring Unix permissions {:o} on Windows", mode);
-            }
+            install_file(new_content, &local_path, mode, atomic_replace).await?;
+            self.remember_synced(&local_path, &actual_checksum, checksum_algo);
 
             let elapsed = start_time.elapsed();
             log::info!(
-                "Successfully synced {} ({} bytes transferred in {:.2}s)",
+                "Successfully synced {} ({} bytes transferred in {:.2}s, {})",
                 relative_path,
                 delta_size,
-                elapsed.as_secs_f64()
+                elapsed.as_secs_f64(),
+                rsync_utils::format_rate(delta_size as f64 / elapsed.as_secs_f64())
             );
         } else {
             log::error!(
@@ -450,11 +1422,11 @@ impl ClientDaemon {
                 expected_checksum,
                 actual_checksum
             );
+            if let AppliedContent::File(partial) = new_content {
+                let _ = tokio::fs::remove_file(&partial).await;
+            }
         }
 
-        // Close rsync channel
-        drop(rsync_channel);
-
         // Send RsyncComplete message on control channel
         let msg = ClientMessage::RsyncComplete {
             request_id,
@@ -467,7 +1439,10 @@ impl ClientDaemon {
             } else {
                 Some("Checksum mismatch".to_string())
             },
+            duration_ms: start_time.elapsed().as_millis() as u64,
+            queued: false,
         };
+        self.record_sync(&msg).await;
 
         if let Some(ref conn) = self.connection {
             conn.send_message(&msg).await?;
@@ -476,6 +1451,240 @@ impl ClientDaemon {
         Ok(())
     }
 
+    /// Why syncing over `local_path` would lose an edit made on this client, if it would
+    ///
+    /// Only files synced before have a checksum to compare; one that is missing now
+    /// was deleted, and has nothing to lose.
+    fn local_edit(&self, local_path: &Path) -> Option<String> {
+        let synced = self.synced.files.get(local_path.to_string_lossy().as_ref())?;
+        let current = rsync_utils::current_checksum(local_path, synced.algo, self.memory_budget)?;
+        (current != synced.checksum).then(|| {
+            format!(
+                "Conflict: {} was changed on this client since it was last synced; keeping the local copy \
+                 (sync --force replaces it)",
+                local_path.display()
+            )
+        })
+    }
+
+    /// Note the checksum `local_path` now has from a sync; the control loop saves it
+    /// once the batch ends, or within SYNCED_SAVE_INTERVAL, so an interrupted batch
+    /// keeps most of what it delivered
+    fn remember_synced(&mut self, local_path: &Path, checksum: &str, algo: ChecksumAlgo) {
+        let metadata = std::fs::metadata(local_path).ok();
+        let entry = SyncedChecksum {
+            checksum: checksum.to_string(),
+            algo,
+            size: metadata.as_ref().map(|metadata| metadata.len()),
+            modified: metadata.as_ref().and_then(modified_nanos),
+        };
+        self.synced.files.insert(local_path.to_string_lossy().to_string(), entry);
+        self.synced_unsaved = self.synced_path.is_some();
+    }
+
+    /// Write out the synced checksums if they changed, off the async runtime
+    async fn save_synced(&mut self) {
+        let Some(path) = self.synced_path.clone() else {
+            return;
+        };
+        if !self.synced_unsaved {
+            return;
+        }
+        self.synced_unsaved = false;
+        let synced = self.synced.clone();
+        let saved = tokio::task::spawn_blocking(move || synced.save(&path))
+            .await
+            .context("Synced checksum save task failed")
+            .and_then(|saved| saved);
+        if let Err(e) = saved {
+            log::warn!("{:#}", e);
+            self.synced_unsaved = true;
+        }
+    }
+
+    /// Answer an RsyncStart that won't be attempted with a failed RsyncComplete
+    async fn refuse_rsync(
+        &self,
+        request_id: String,
+        relative_path: String,
+        error: String,
+        start_time: std::time::Instant,
+    ) -> Result<()> {
+        let msg = ClientMessage::RsyncComplete {
+            request_id,
+            path: relative_path,
+            success: false,
+            checksum: String::new(),
+            bytes_transferred: 0,
+            error: Some(error),
+            duration_ms: start_time.elapsed().as_millis() as u64,
+            queued: false,
+        };
+        self.record_sync(&msg).await;
+        if let Some(ref conn) = self.connection {
+            conn.send_message(&msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Append a finished sync's RsyncComplete to --sync-log; failing to write it is
+    /// logged but doesn't fail the sync
+    async fn record_sync(&self, complete: &ClientMessage) {
+        let Some(ref path) = self.sync_log else {
+            return;
+        };
+        let ClientMessage::RsyncComplete {
+            request_id,
+            path: relative_path,
+            success,
+            checksum,
+            bytes_transferred,
+            error,
+            duration_ms,
+            ..
+        } = complete
+        else {
+            return;
+        };
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = SyncLogEntry {
+            timestamp_ms,
+            request_id: request_id.clone(),
+            relative_path: relative_path.clone(),
+            success: *success,
+            bytes: *bytes_transferred,
+            checksum: checksum.clone(),
+            duration_ms: *duration_ms,
+            error: error.clone(),
+        };
+        if let Err(e) = sync_log::append(path, &entry).await {
+            log::warn!("{:#}", e);
+        }
+    }
+
+    /// Create `dir` and make sure files can be written in it, the first time a sync
+    /// lands there
+    ///
+    /// A failure is logged once and returned for every sync into `dir` until
+    /// DIR_CHECK_RETRY has passed, so a tree sync into an unwritable directory fails
+    /// each file fast with the same error instead of at its final write.
+    async fn check_destination_dir(&mut self, dir: &Path, dir_mode: Option<u32>) -> Result<(), String> {
+        match self.checked_dirs.get(dir) {
+            // Still recreated if something removed it since
+            Some(Ok(())) => {
+                return create_dirs(dir, dir_mode).await.map_err(|e| format!("{:#}", e));
+            }
+            Some(Err((found, error))) if found.elapsed() < DIR_CHECK_RETRY => {
+                log::debug!("Skipping sync into {}: {}", dir.display(), error);
+                return Err(error.clone());
+            }
+            _ => {}
+        }
+
+        let result = match create_dirs(dir, dir_mode).await {
+            Ok(()) => probe_writable(dir).await,
+            Err(e) => Err(match e.downcast_ref::<std::io::Error>() {
+                Some(io_error) => describe_unwritable(dir, io_error),
+                None => format!("{:#}", e),
+            }),
+        };
+        match &result {
+            Ok(()) => {
+                self.checked_dirs.insert(dir.to_path_buf(), Ok(()));
+            }
+            Err(error) => {
+                log::error!("❌ {}", error);
+                self.checked_dirs
+                    .insert(dir.to_path_buf(), Err((Instant::now(), error.clone())));
+            }
+        }
+        result
+    }
+
+    /// Send `signature` for `request_id` over a new rsync channel and collect the delta;
+    /// with no signature (a whole_file request) the server sends the file content instead
+    ///
+    /// Gives up, closing the channel, if the server sends nothing for `timeout` while a
+    /// chunk is outstanding.
+    async fn request_delta(
+        conn: &SshClientConnection,
+        request_id: &str,
+        relative_path: &str,
+        signature: Option<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        log::debug!("Opening rsync channel for {}", relative_path);
+
+        // Open dedicated rsync channel
+        let mut rsync_channel = conn
+            .open_rsync_channel()
+            .await
+            .context("Failed to open rsync channel")?;
+
+        log::debug!("Successfully opened rsync channel for {}", relative_path);
+
+        // Send request_id as handshake
+        let handshake_frame = Frame::new(MSG_RSYNC_SIGNATURE, request_id.as_bytes().to_vec());
+        SshClientConnection::write_frame_to_channel(&mut rsync_channel, &handshake_frame)
+            .await
+            .context("Failed to send handshake")?;
+
+        log::debug!("Sent handshake for {}", relative_path);
+
+        // Send signature on rsync channel
+        if let Some(signature) = signature {
+            let sig_frame = Frame::new(MSG_RSYNC_SIGNATURE, signature);
+            SshClientConnection::write_frame_to_channel(&mut rsync_channel, &sig_frame)
+                .await
+                .context("Failed to send signature")?;
+
+            log::debug!("Sent signature for {}", relative_path);
+        }
+
+        // Receive delta on rsync channel (may be multiple chunks for large files)
+        let mut delta_data = Vec::new();
+        let mut chunk_count = 0;
+        loop {
+            let read = time::timeout(timeout, SshClientConnection::read_frame_from_channel(&mut rsync_channel));
+            let Ok(delta_frame) = read.await else {
+                let _ = rsync_channel.close().await;
+                anyhow::bail!(
+                    "No delta data from the server for {}s after {} chunks; abandoning the transfer",
+                    timeout.as_secs_f64(),
+                    chunk_count
+                );
+            };
+            let delta_frame = delta_frame.context("Failed to receive delta chunk")?;
+
+            if delta_frame.message_type != MSG_RSYNC_DELTA {
+                anyhow::bail!(
+                    "Expected delta frame, got message type: {}",
+                    delta_frame.message_type
+                );
+            }
+
+            // Zero-length frame signals end of delta stream
+            if delta_frame.payload.is_empty() {
+                log::debug!("Received end-of-delta marker after {} chunks, {} bytes total",
+                    chunk_count, delta_data.len());
+                break;
+            }
+
+            delta_data.extend_from_slice(&delta_frame.payload);
+            chunk_count += 1;
+            log::trace!("Received delta chunk {}: {} bytes (total: {} bytes)",
+                chunk_count, delta_frame.payload.len(), delta_data.len());
+        }
+
+        log::debug!("Received delta: {} bytes total in {} chunks", delta_data.len(), chunk_count);
+        Ok(delta_data)
+    }
+
+    /// Start an Execute's command in the background, so the control loop keeps
+    /// serving messages (including a Kill for it) while the command runs
     async fn handle_execute(
         &mut self,
         request_id: String,
@@ -483,41 +1692,288 @@ impl ClientDaemon {
         args: Vec<String>,
         working_dir: Option<String>,
         env: std::collections::HashMap<String, String>,
+        self_update: bool,
     ) -> Result<()> {
         log::info!("Executing: {} {:?}", binary, args);
 
-        let result = self
-            .execute_command(&binary, &args, working_dir.as_deref(), &env)
-            .await;
+        let Some(conn) = self.connection.clone() else {
+            return Ok(());
+        };
 
-        if let Some(ref conn) = self.connection {
-            let (exit_code, stdout, stderr) = match result {
-                Ok((code, out, err)) => (code, out, err),
+        // With an allowlist, what runs is the path it matched, not a name looked up again
+        let (binary, refusal) = match self.exec_permission(&binary, working_dir.as_deref(), &env) {
+            Ok(Some(resolved)) => (resolved.to_string_lossy().into_owned(), None),
+            Ok(None) => (binary, None),
+            Err(refusal) => (binary, Some(refusal)),
+        };
+        let runner = self.exec_runner();
+
+        // The update replaces this process once it succeeds, so nothing else may start
+        // in the meantime. Only the server's self-update sets the flag; an ordinary exec
+        // that happens to pass `apply-update` never restarts the daemon.
+        if self_update {
+            let exit_code = runner
+                .run(&conn, request_id, binary, args, working_dir, env, refusal)
+                .await?;
+            if exit_code == 0 {
+                log::info!("Update applied, restarting client daemon");
+                self.restart_in_place().await?;
+            }
+            return Ok(());
+        }
+
+        // Reap finished tasks so the set only holds running ones
+        while self.exec_tasks.try_join_next().is_some() {}
+        self.exec_tasks.spawn(async move {
+            if let Err(e) = runner
+                .run(&conn, request_id.clone(), binary, args, working_dir, env, refusal)
+                .await
+            {
+                log::error!("Failed to report exec {}: {:#}", request_id, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Stop the command running for `request_id`, or tell the server there is none
+    async fn handle_kill(&mut self, request_id: String) -> Result<()> {
+        let kill = self
+            .running_execs
+            .lock()
+            .unwrap()
+            .get_mut(&request_id)
+            .and_then(|exec| exec.kill.take());
+        match kill {
+            Some(kill) => {
+                log::info!("🛑 Killing the process for {}", request_id);
+                let _ = kill.send(());
+            }
+            None => {
+                log::warn!("Kill requested for {}, which is not running", request_id);
+                if let Some(ref conn) = self.connection {
+                    let msg = ClientMessage::Error {
+                        request_id: Some(request_id.clone()),
+                        message: format!("No running process for {}", request_id),
+                    };
+                    conn.send_message(&msg).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes started for Execute requests that are still running, oldest first
+    fn exec_processes(&self) -> Vec<ExecProcess> {
+        let mut processes: Vec<ExecProcess> = self
+            .running_execs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|exec| exec.process.clone())
+            .collect();
+        processes.sort_by(|a, b| (a.started_at, &a.request_id).cmp(&(b.started_at, &b.request_id)));
+        processes
+    }
+
+    fn exec_runner(&self) -> ExecRunner {
+        ExecRunner {
+            timeout: self.exec_timeout,
+            output_limit: self.exec_output_limit,
+            executable: self.executable.clone(),
+            running: self.running_execs.clone(),
+        }
+    }
+
+    /// Replace this process with a fresh copy of the (now updated) executable.
+    ///
+    /// The update's exit status has already been sent; disconnecting first
+    /// flushes it and lets the server unregister this session before the new process
+    /// registers under a new one.
+    async fn restart_in_place(&mut self) -> Result<()> {
+        let executable = self
+            .executable
+            .clone()
+            .context("Cannot restart: executable path unknown")?;
+        let args: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+
+        if let Some(conn) = self.connection.take() {
+            conn.disconnect().await;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let error = std::process::Command::new(&executable).args(&args).exec();
+            Err(error).context(format!("Failed to re-exec {}", executable.display()))
+        }
+
+        #[cfg(not(unix))]
+        {
+            std::process::Command::new(&executable)
+                .args(&args)
+                .spawn()
+                .context(format!("Failed to spawn {}", executable.display()))?;
+            std::process::exit(0);
+        }
+    }
+
+    /// Check an Execute against the exec allowlist: the absolute path to run if it
+    /// permits the binary (None without an allowlist), or the ExecComplete output
+    /// refusing it
+    ///
+    /// Requests that override PATH or the dynamic loader's variables are refused, since
+    /// they would change what the permitted path loads.
+    fn exec_permission(
+        &self,
+        binary: &str,
+        working_dir: Option<&str>,
+        env: &std::collections::HashMap<String, String>,
+    ) -> std::result::Result<Option<PathBuf>, ExecOutput> {
+        let Some(allowlist) = self.exec_allowlist.as_ref() else {
+            return Ok(None);
+        };
+        let refused = |stderr: String, error: String| {
+            log::warn!("🚫 Refusing to execute {}: {}", binary, error);
+            ExecOutput {
+                exit_code: EXEC_NOT_PERMITTED_EXIT_CODE,
+                stdout: String::new(),
+                stderr,
+                error: Some(error),
+            }
+        };
+
+        let mut overrides: Vec<&String> = env.keys().filter(|key| overrides_program_lookup(key)).collect();
+        if !overrides.is_empty() {
+            overrides.sort();
+            return Err(refused(
+                format!("{} may not be set while the client has an exec allowlist\n", overrides[0]),
+                format!("Not permitted: setting {}", overrides[0]),
+            ));
+        }
+
+        allowlist.resolve(binary, working_dir).map(Some).ok_or_else(|| {
+            refused(
+                format!("{} is not permitted by the client's exec allowlist\n", binary),
+                format!("Not permitted: {}", binary),
+            )
+        })
+    }
+}
+
+/// What a background exec needs from the daemon
+#[derive(Clone)]
+struct ExecRunner {
+    timeout: Option<Duration>,
+    output_limit: usize,
+    executable: Option<PathBuf>,
+    running: RunningExecs,
+}
+
+impl ExecRunner {
+    /// Run an Execute to completion and report its output and exit status, on its
+    /// exec channel when one opens and in ExecComplete otherwise; returns the exit code
+    #[allow(clippy::too_many_arguments)]
+    async fn run(
+        &self,
+        conn: &SshClientConnection,
+        request_id: String,
+        binary: String,
+        args: Vec<String>,
+        working_dir: Option<String>,
+        env: std::collections::HashMap<String, String>,
+        refusal: Option<ExecOutput>,
+    ) -> Result<i32> {
+        // Output goes to a dedicated exec channel as it is read; without one the
+        // result is reported in ExecComplete on the control channel
+        let (sink, forwarder) = match conn.open_exec_channel(&request_id).await {
+            Ok(channel) => {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                (Some(sender), Some(tokio::spawn(forward_exec_frames(channel, receiver))))
+            }
+            Err(e) => {
+                log::warn!("Falling back to the control channel for exec: {:#}", e);
+                (None, None)
+            }
+        };
+
+        let (output, streamed) = match refusal {
+            Some(refusal) => (refusal, false),
+            None => match self
+                .execute_command(&request_id, &binary, &args, working_dir.as_deref(), &env, sink.clone())
+                .await
+            {
+                Ok(output) => (output, true),
                 Err(e) => {
                     log::error!("Failed to execute {}: {:#}", binary, e);
-                    (-1, String::new(), format!("Execution failed: {:#}", e))
+                    let output = ExecOutput {
+                        exit_code: -1,
+                        stdout: String::new(),
+                        stderr: format!("Execution failed: {:#}", e),
+                        error: Some(format!("{:#}", e)),
+                    };
+                    (output, false)
                 }
-            };
+            },
+        };
+        let exit_code = output.exit_code;
 
-            let msg = ClientMessage::ExecComplete {
-                request_id,
-                exit_code,
-                stdout,
-                stderr,
-            };
+        if let Some(ref sink) = sink
+            && !streamed
+        {
+            let streams = [(MSG_EXEC_STDOUT, &output.stdout), (MSG_EXEC_STDERR, &output.stderr)];
+            for (message_type, text) in streams {
+                if !text.is_empty() {
+                    let _ = sink.send(Frame::new(message_type, text.as_bytes().to_vec()));
+                }
+            }
+        }
+        // The forwarder stops once its last sender is gone
+        drop(sink);
+
+        let exit = ExecExit {
+            exit_code,
+            error: output.error.clone(),
+        };
+        let sent = match forwarder {
+            Some(forwarder) => match finish_exec_channel(forwarder, &exit).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Exec channel failed, reporting on the control channel: {:#}", e);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if !sent {
+            let captured = output.stdout.len() + output.stderr.len();
+            let msg =
+                ClientMessage::exec_complete(request_id, exit_code, output.stdout, output.stderr, output.error)?;
+            if let ClientMessage::ExecComplete { ref stdout, ref stderr, .. } = msg
+                && stdout.len() + stderr.len() < captured
+            {
+                log::warn!(
+                    "Output of {} truncated from {} bytes to fit in one message",
+                    binary,
+                    captured
+                );
+            }
             conn.send_message(&msg).await?;
         }
 
-        Ok(())
+        Ok(exit_code)
     }
 
     async fn execute_command(
         &self,
+        request_id: &str,
         binary: &str,
         args: &[String],
         working_dir: Option<&str>,
         env: &std::collections::HashMap<String, String>,
-    ) -> Result<(i32, String, String)> {
+        sink: Option<mpsc::UnboundedSender<Frame>>,
+    ) -> Result<ExecOutput> {
         // Expand tilde in binary path
         let expanded_binary = expand_tilde(binary);
 
@@ -530,6 +1986,11 @@ impl ClientDaemon {
             command.current_dir(expanded_dir);
         }
 
+        // Let children (notably a staged self-update) find the daemon's executable
+        if let Some(ref executable) = self.executable {
+            command.env(CLIENT_EXE_ENV, executable);
+        }
+
         // Add environment variables
         for (key, value) in env {
             command.env(key, value);
@@ -538,17 +1999,79 @@ impl ClientDaemon {
         // Capture output
         command.stdout(std::process::Stdio::piped());
         command.stderr(std::process::Stdio::piped());
+        command.kill_on_drop(true);
 
         log::info!("Spawning process: {} {:?}", binary, args);
 
-        let output = command
-            .output()
-            .await
+        let mut child = command
+            .spawn()
             .context(format!("Failed to spawn process: {}", binary))?;
+        let child_stdout = child.stdout.take().context("Child stdout not captured")?;
+        let child_stderr = child.stderr.take().context("Child stderr not captured")?;
+
+        // Listed for ListProcesses until this returns, however it returns
+        let (kill_sender, mut kill_receiver) = oneshot::channel();
+        let process = ExecProcess {
+            request_id: request_id.to_string(),
+            binary: binary.to_string(),
+            args: args.to_vec(),
+            pid: child.id(),
+            started_at: unix_now(),
+        };
+        let _running = RunningExecGuard::insert(&self.running, process, kill_sender);
+
+        // The buffers outlive the capture future, so output read before a timeout is kept
+        let mut stdout = CappedOutput::new(self.output_limit);
+        let mut stderr = CappedOutput::new(self.output_limit);
+        if let Some(sink) = sink {
+            stdout = stdout.with_sink(sink.clone(), MSG_EXEC_STDOUT);
+            stderr = stderr.with_sink(sink, MSG_EXEC_STDERR);
+        }
+
+        let capture = async {
+            let (stdout_result, stderr_result, status) = tokio::join!(
+                stdout.read_from(child_stdout),
+                stderr.read_from(child_stderr),
+                child.wait()
+            );
+            stdout_result.context("Failed to read stdout")?;
+            stderr_result.context("Failed to read stderr")?;
+            status.context(format!("Failed to wait for process: {}", binary))
+        };
+
+        let deadline = async {
+            match self.timeout {
+                Some(limit) => time::sleep(limit).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let (exit_code, error) = tokio::select! {
+            status = capture => (status?.code().unwrap_or(-1), None),
+            _ = deadline => {
+                let limit = self.timeout.unwrap_or_default();
+                log::warn!("Process {} exceeded {:?}, killing it", binary, limit);
+                if let Err(e) = child.kill().await {
+                    log::warn!("Failed to kill {}: {}", binary, e);
+                }
+                (
+                    EXEC_TIMEOUT_EXIT_CODE,
+                    Some(format!("Timed out after {}s", limit.as_secs())),
+                )
+            }
+            Ok(()) = &mut kill_receiver => {
+                log::warn!("Killing {} on request", binary);
+                if let Err(e) = child.kill().await {
+                    log::warn!("Failed to kill {}: {}", binary, e);
+                }
+                (EXEC_KILLED_EXIT_CODE, Some("Killed on request".to_string()))
+            }
+        };
 
-        let exit_code = output.status.code().unwrap_or(-1);
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        stdout.finish();
+        stderr.finish();
+        let stdout = stdout.into_string();
+        let stderr = stderr.into_string();
 
         log::info!(
             "Process completed: {} (exit: {}, stdout: {} bytes, stderr: {} bytes)",
@@ -558,6 +2081,958 @@ impl ClientDaemon {
             stderr.len()
         );
 
-        Ok((exit_code, stdout, stderr))
+        Ok(ExecOutput {
+            exit_code,
+            stdout,
+            stderr,
+            error,
+        })
+    }
+}
+
+/// Result of an executed command, as reported in ExecComplete
+struct ExecOutput {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+    error: Option<String>,
+}
+
+/// Output stream buffer that keeps at most `limit` bytes and counts the rest
+struct CappedOutput {
+    data: Vec<u8>,
+    limit: usize,
+    truncated: usize,
+    /// Exec channel frames of this type also carry each kept chunk as it is read
+    sink: Option<(mpsc::UnboundedSender<Frame>, u16)>,
+}
+
+impl CappedOutput {
+    fn new(limit: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            limit,
+            truncated: 0,
+            sink: None,
+        }
+    }
+
+    fn with_sink(mut self, sender: mpsc::UnboundedSender<Frame>, message_type: u16) -> Self {
+        self.sink = Some((sender, message_type));
+        self
+    }
+
+    /// Read the stream to EOF; bytes past the limit are drained, not buffered, so the
+    /// child never blocks on a full pipe
+    async fn read_from<R: AsyncRead + Unpin>(&mut self, mut reader: R) -> std::io::Result<()> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            let keep = n.min(self.limit.saturating_sub(self.data.len()));
+            self.data.extend_from_slice(&chunk[..keep]);
+            self.truncated += n - keep;
+            if keep > 0
+                && let Some((ref sender, message_type)) = self.sink
+            {
+                // A closed exec channel is reported when the exit frame fails to send
+                let _ = sender.send(Frame::new(message_type, chunk[..keep].to_vec()));
+            }
+        }
+    }
+
+    fn truncation_marker(&self) -> Option<String> {
+        (self.truncated > 0).then(|| format!("\n[... truncated {} bytes ...]\n", self.truncated))
+    }
+
+    /// Stream the truncation marker, if any, so the sink sees what into_string returns
+    fn finish(&mut self) {
+        if let Some((sender, message_type)) = self.sink.take()
+            && let Some(marker) = self.truncation_marker()
+        {
+            let _ = sender.send(Frame::new(message_type, marker.into_bytes()));
+        }
+    }
+
+    fn into_string(self) -> String {
+        let mut text = String::from_utf8_lossy(&self.data).to_string();
+        if let Some(marker) = self.truncation_marker() {
+            text.push_str(&marker);
+        }
+        text
+    }
+}
+
+/// Write queued output frames to an exec channel until every sender is dropped, then
+/// hand the channel back for the exit frame
+async fn forward_exec_frames(
+    mut channel: russh::Channel<russh::client::Msg>,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+) -> Result<russh::Channel<russh::client::Msg>> {
+    while let Some(frame) = frames.recv().await {
+        SshClientConnection::write_frame_to_channel(&mut channel, &frame).await?;
+    }
+    Ok(channel)
+}
+
+/// Wait for the forwarder to drain, then end the exec channel with `exit`
+async fn finish_exec_channel(
+    forwarder: tokio::task::JoinHandle<Result<russh::Channel<russh::client::Msg>>>,
+    exit: &ExecExit,
+) -> Result<()> {
+    let mut channel = forwarder.await.context("Exec output forwarder panicked")??;
+    let frame = Frame::new(MSG_EXEC_EXIT, exit.to_bytes()?);
+    SshClientConnection::write_frame_to_channel(&mut channel, &frame).await?;
+    channel
+        .eof()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to close exec channel: {:?}", e))
+}
+
+/// Put a verified sync result at `local_path` with the server's `mode`
+///
+/// With `atomic_replace` the result is staged at `<local_path>.new` and given its mode
+/// there, then renamed over the destination. Writing in place fails on a binary that
+/// is running (ETXTBSY), while a rename only swaps the directory entry, and a post-sync
+/// Execute never sees a half-written or not yet executable file.
+async fn install_file(content: AppliedContent, local_path: &Path, mode: u32, atomic_replace: bool) -> Result<()> {
+    let target = if atomic_replace {
+        let mut staged = local_path.as_os_str().to_owned();
+        staged.push(".new");
+        PathBuf::from(staged)
+    } else {
+        local_path.to_path_buf()
+    };
+
+    match content {
+        AppliedContent::Memory(data) => {
+            tokio::fs::write(&target, &data)
+                .await
+                .context(format!("Failed to write {}", target.display()))?;
+        }
+        AppliedContent::File(partial) => {
+            tokio::fs::rename(&partial, &target).await.context(format!(
+                "Failed to rename {} over {}",
+                partial.display(),
+                target.display()
+            ))?;
+        }
+    }
+
+    if let Err(e) = set_file_mode(&target, mode).await {
+        if atomic_replace {
+            let _ = tokio::fs::remove_file(&target).await;
+        }
+        return Err(e);
+    }
+
+    if atomic_replace {
+        let result = swap_into_place(&target, local_path);
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&target).await;
+        }
+        result?;
+        log::debug!("Swapped {} into place", local_path.display());
+    }
+
+    Ok(())
+}
+
+/// Apply file permissions from the server; a mode with no permission bits at all can
+/// only come from a source that had none, so keep the default instead
+async fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    if mode & 0o777 == 0 {
+        log::warn!("Server sent mode {:o} for {}; keeping default permissions", mode, path.display());
+    } else {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(mode);
+        tokio::fs::set_permissions(path, permissions)
+            .await
+            .context("Failed to set file permissions")?;
+        log::debug!("Set permissions {:o} on {}", mode, path.display());
+    }
+    #[cfg(not(unix))]
+    {
+        // Windows doesn't use Unix permissions, so we just log it
+        log::trace!("Ignoring Unix permissions {:o} on {}", mode, path.display());
+    }
+    Ok(())
+}
+
+/// Create `dir` and any missing ancestors, giving each one created here `dir_mode`
+async fn create_dirs(dir: &Path, dir_mode: Option<u32>) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut current = Some(dir);
+    while let Some(path) = current
+        && !path.exists()
+    {
+        missing.push(path.to_path_buf());
+        current = path.parent();
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(dir)
+        .await
+        .context("Failed to create parent directory")?;
+
+    // Deepest first, so a mode without owner search permission can't lock us out
+    #[cfg(unix)]
+    if let Some(mode) = dir_mode {
+        use std::os::unix::fs::PermissionsExt;
+        for path in &missing {
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .await
+                .context(format!("Failed to set permissions on {}", path.display()))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = dir_mode;
+
+    Ok(())
+}
+
+/// Create and remove a scratch file in `dir`, describing why that failed if it did
+async fn probe_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".hrl-write-check-{}", std::process::id()));
+    let created = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .await;
+    match created {
+        Ok(file) => {
+            drop(file);
+            let _ = tokio::fs::remove_file(&probe).await;
+            Ok(())
+        }
+        Err(e) => Err(describe_unwritable(dir, &e)),
+    }
+}
+
+/// Make sure `size` bytes fit in `dir`'s filesystem with `margin` to spare
+///
+/// The new copy is written beside the old one before replacing it, so all of `size`
+/// is needed even when a file is only updated. Filesystems that can't report their
+/// free space are let through.
+fn check_free_space(dir: &Path, size: u64, margin: u64) -> Result<(), String> {
+    let available = match fs4::available_space(dir) {
+        Ok(available) => available,
+        Err(e) => {
+            log::debug!("Couldn't check free space in {}: {}", dir.display(), e);
+            return Ok(());
+        }
+    };
+    let needed = size.saturating_add(margin);
+    if available < needed {
+        return Err(format!(
+            "Insufficient disk space in {}: need {} bytes ({} for the file, {} kept free), have {}",
+            dir.display(),
+            needed,
+            size,
+            margin,
+            available
+        ));
+    }
+    Ok(())
+}
+
+/// Name the permission problem behind a failed write into `dir`
+fn describe_unwritable(dir: &Path, error: &std::io::Error) -> String {
+    match error.kind() {
+        std::io::ErrorKind::PermissionDenied => format!(
+            "Destination directory {} is not writable: permission denied for this user",
+            dir.display()
+        ),
+        std::io::ErrorKind::ReadOnlyFilesystem => format!(
+            "Destination directory {} is not writable: it is on a read-only filesystem",
+            dir.display()
+        ),
+        _ => format!("Destination directory {} is not writable: {}", dir.display(), error),
+    }
+}
+
+/// Names of every running process, one per line
+///
+/// `ps` on Unix (where Linux truncates names to 15 bytes) and `tasklist` on Windows.
+async fn list_processes() -> Result<String> {
+    #[cfg(unix)]
+    let output = tokio::process::Command::new("ps").args(["-A", "-o", "comm="]).output().await;
+    #[cfg(not(unix))]
+    let output = tokio::process::Command::new("tasklist").args(["/FO", "CSV", "/NH"]).output().await;
+
+    let output = output.context("Failed to run the process lister")?;
+    if !output.status.success() {
+        anyhow::bail!("Process lister exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The tracked `names` with a process in `listing`, in the order given
+///
+/// Listed entries may be paths, quoted CSV rows (`tasklist`) or names truncated to
+/// 15 bytes (Linux). Names are compared without case, and a Windows `.exe` suffix is
+/// optional in `names`.
+fn matching_processes(listing: &str, names: &[String]) -> Vec<String> {
+    let running: Vec<String> = listing
+        .lines()
+        .filter_map(|line| {
+            let first = line.split("\",\"").next()?.trim().trim_matches('"');
+            let name = Path::new(first).file_name()?.to_string_lossy().to_lowercase();
+            Some(name.strip_suffix(".exe").map(str::to_string).unwrap_or(name))
+        })
+        .collect();
+
+    names
+        .iter()
+        .filter(|name| {
+            let wanted = name.to_lowercase();
+            let wanted = wanted.strip_suffix(".exe").unwrap_or(&wanted);
+            running
+                .iter()
+                .any(|name| name == wanted || (name.len() == 15 && wanted.starts_with(name.as_str())))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Paths of manifest entries whose local copy is missing or differs from the server's
+///
+/// A size mismatch settles it without reading the file, as does an untouched file this
+/// client synced with the entry's checksum; otherwise the checksum decides.
+fn stale_entries(
+    entries: Vec<(PathBuf, ManifestEntry, Option<SyncedChecksum>)>,
+    checksum_algo: ChecksumAlgo,
+    memory_budget: usize,
+) -> Vec<String> {
+    entries
+        .into_iter()
+        .filter(|(local_path, entry, synced)| {
+            let current = std::fs::metadata(local_path).is_ok_and(|m| m.is_file() && m.len() == entry.size)
+                && (synced
+                    .as_ref()
+                    .is_some_and(|synced| synced.vouches_for(local_path, &entry.checksum, checksum_algo))
+                    || rsync_utils::current_checksum(local_path, checksum_algo, memory_budget).as_deref()
+                        == Some(entry.checksum.as_str()));
+            !current
+        })
+        .map(|(_, entry, _)| entry.path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_apply_staged_update_replaces_target() {
+        let temp = tempdir().unwrap();
+        let staged = temp.path().join("staging").join("launcher.staged");
+        std::fs::create_dir_all(staged.parent().unwrap()).unwrap();
+        std::fs::write(&staged, b"new binary").unwrap();
+
+        let target = temp.path().join("bin").join("launcher");
+        std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+        std::fs::write(&target, b"old binary").unwrap();
+
+        apply_staged_update(&staged, &target).unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"new binary");
+        assert!(!target.with_file_name("launcher.new").exists());
+        // The staged copy is left for the daemon's next sync to diff against
+        assert!(staged.exists());
+    }
+
+    // Linux refuses writes to an executable that is running, but not renames over it
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_atomic_replace_swaps_busy_binary() {
+        let temp = tempdir().unwrap();
+        let binary = temp.path().join("busy");
+        std::fs::copy("/bin/sleep", &binary).unwrap();
+        let mut running = std::process::Command::new(&binary).arg("30").spawn().unwrap();
+
+        let in_place = install_file(AppliedContent::Memory(b"new build".to_vec()), &binary, 0o755, false).await;
+        let error = in_place.unwrap_err();
+        let io_error = error.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_error.kind(), std::io::ErrorKind::ExecutableFileBusy);
+
+        install_file(AppliedContent::Memory(b"new build".to_vec()), &binary, 0o755, true)
+            .await
+            .unwrap();
+        running.kill().unwrap();
+        let _ = running.wait();
+
+        assert_eq!(std::fs::read(&binary).unwrap(), b"new build");
+        assert!(!temp.path().join("busy.new").exists());
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&binary).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_backoff_state_round_trip() {
+        let temp = tempdir().unwrap();
+        let daemon = ClientDaemon::new("game-server".into(), 20222, "user".into(), "host".into())
+            .with_state_dir(Some(temp.path().join("state")));
+        let path = daemon.backoff_path.unwrap();
+        assert_eq!(path, temp.path().join("state").join("backoff-game-server-20222.toml"));
+
+        let state = BackoffState {
+            last_attempt: 1_000,
+            delay_secs: 20,
+        };
+        state.save(&path).unwrap();
+        let loaded = BackoffState::load(&path).unwrap();
+        assert_eq!(loaded, state);
+
+        assert_eq!(loaded.remaining(1_005), Duration::from_secs(15));
+        assert_eq!(loaded.remaining(2_000), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_local_edit_compares_with_last_synced_checksum() {
+        let temp = tempdir().unwrap();
+        let state = temp.path().join("state");
+        let mut daemon = ClientDaemon::new("game-server".into(), 20222, "user".into(), "host".into())
+            .with_state_dir(Some(state.clone()))
+            .with_no_clobber_local(true);
+        let file = temp.path().join("config.ini");
+
+        // Never synced: nothing to compare with
+        std::fs::write(&file, b"local").unwrap();
+        assert_eq!(daemon.local_edit(&file), None);
+
+        let checksum = rsync_utils::compute_checksum(ChecksumAlgo::Blake3, b"synced");
+        std::fs::write(&file, b"synced").unwrap();
+        daemon.remember_synced(&file, &checksum, ChecksumAlgo::Blake3);
+        assert_eq!(daemon.local_edit(&file), None);
+
+        std::fs::write(&file, b"edited").unwrap();
+        assert!(daemon.local_edit(&file).unwrap().starts_with("Conflict:"));
+
+        // Deleted since: nothing to lose
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(daemon.local_edit(&file), None);
+
+        // Saved in batches, not as each sync finishes
+        let saved_path = state.join("synced-game-server-20222.toml");
+        assert!(!saved_path.exists());
+        daemon.save_synced().await;
+        assert!(!daemon.synced_unsaved);
+        let saved = SyncedChecksums::load(&saved_path);
+        assert_eq!(saved.files[file.to_string_lossy().as_ref()].checksum, checksum);
+    }
+
+    #[tokio::test]
+    async fn test_destination_dir_check_reports_once_and_caches() {
+        let temp = tempdir().unwrap();
+        let mut daemon = ClientDaemon::new("localhost".into(), 20222, "user".into(), "host".into());
+
+        // A file where a parent directory should be can't be created through
+        let blocker = temp.path().join("blocker");
+        std::fs::write(&blocker, b"not a directory").unwrap();
+        let blocked = blocker.join("bin");
+        let error = daemon.check_destination_dir(&blocked, None).await.unwrap_err();
+        assert!(error.contains(&blocked.display().to_string()), "{}", error);
+
+        // The failure is remembered rather than retried for each file
+        std::fs::remove_file(&blocker).unwrap();
+        assert_eq!(daemon.check_destination_dir(&blocked, None).await.unwrap_err(), error);
+        assert!(!blocked.exists());
+
+        let good = temp.path().join("game/bin");
+        daemon.check_destination_dir(&good, None).await.unwrap();
+        assert!(good.is_dir());
+        assert_eq!(std::fs::read_dir(&good).unwrap().count(), 0, "probe file left behind");
+    }
+
+    #[tokio::test]
+    async fn test_create_dir_under_working_dir() {
+        let temp = tempdir().unwrap();
+        let daemon = ClientDaemon::new("localhost".into(), 20222, "user".into(), "host".into())
+            .with_working_dir(temp.path().to_path_buf());
+
+        daemon.handle_create_dir("game/logs", Some(0o750)).await.unwrap();
+        let created = temp.path().join("game/logs");
+        assert!(created.is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&created).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o750);
+        }
+
+        // Already there is fine; absolute paths are refused like any other destination
+        daemon.handle_create_dir("game/logs", None).await.unwrap();
+        assert!(daemon.handle_create_dir("/srv/outside", None).await.is_err());
+    }
+
+    #[test]
+    fn test_describe_unwritable_names_the_problem() {
+        let dir = Path::new("/mnt/games");
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            describe_unwritable(dir, &denied),
+            "Destination directory /mnt/games is not writable: permission denied for this user"
+        );
+        let read_only = std::io::Error::from(std::io::ErrorKind::ReadOnlyFilesystem);
+        assert!(describe_unwritable(dir, &read_only).contains("read-only filesystem"));
+    }
+
+    #[test]
+    fn test_check_free_space_counts_the_margin() {
+        let temp = tempdir().unwrap();
+        let available = fs4::available_space(temp.path()).unwrap();
+        assert!(check_free_space(temp.path(), 0, 0).is_ok());
+
+        // A file that fits, but not with the margin on top
+        let error = check_free_space(temp.path(), available / 2, available).unwrap_err();
+        assert!(error.starts_with("Insufficient disk space in "), "{}", error);
+        assert!(error.contains(&format!("{} kept free", available)), "{}", error);
+
+        assert!(check_free_space(temp.path(), u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn test_stale_entries_skip_current_files() {
+        let temp = tempdir().unwrap();
+        let entry = |path: &str, content: &[u8]| ManifestEntry {
+            path: path.to_string(),
+            size: content.len() as u64,
+            checksum: rsync_utils::compute_checksum(ChecksumAlgo::Sha256, content),
+            mtime: 0,
+        };
+        std::fs::write(temp.path().join("current.txt"), b"same").unwrap();
+        std::fs::write(temp.path().join("resized.txt"), b"old").unwrap();
+        std::fs::write(temp.path().join("edited.txt"), b"abcd").unwrap();
+
+        let entries = ["current.txt", "resized.txt", "edited.txt", "missing.txt"]
+            .into_iter()
+            .zip([&b"same"[..], b"new content", b"wxyz", b"anything"])
+            .map(|(path, content)| (temp.path().join(path), entry(path, content), None))
+            .collect();
+
+        assert_eq!(
+            stale_entries(entries, ChecksumAlgo::Sha256, rsync_utils::DEFAULT_MEMORY_BUDGET),
+            vec!["resized.txt", "edited.txt", "missing.txt"]
+        );
+    }
+
+    #[test]
+    fn test_stale_entries_trust_untouched_synced_files() {
+        let temp = tempdir().unwrap();
+        let checksum = rsync_utils::compute_checksum(ChecksumAlgo::Sha256, b"server");
+        let entry = |path: &str| ManifestEntry {
+            path: path.to_string(),
+            size: 6,
+            checksum: checksum.clone(),
+            mtime: 0,
+        };
+        // Both hold other content of the same size, so only a trusted record passes
+        let untouched = temp.path().join("untouched.txt");
+        let touched = temp.path().join("touched.txt");
+        std::fs::write(&untouched, b"client").unwrap();
+        std::fs::write(&touched, b"client").unwrap();
+
+        let record = |modified: Option<u64>| SyncedChecksum {
+            checksum: checksum.clone(),
+            algo: ChecksumAlgo::Sha256,
+            size: Some(6),
+            modified,
+        };
+        let modified = modified_nanos(&std::fs::metadata(&untouched).unwrap());
+        let entries = vec![
+            (untouched.clone(), entry("untouched.txt"), Some(record(modified))),
+            (touched.clone(), entry("touched.txt"), Some(record(Some(1)))),
+        ];
+
+        assert_eq!(
+            stale_entries(entries, ChecksumAlgo::Sha256, rsync_utils::DEFAULT_MEMORY_BUDGET),
+            vec!["touched.txt"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_create_dirs_applies_mode_to_new_dirs_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempdir().unwrap();
+        let existing = temp.path().join("games");
+        std::fs::create_dir(&existing).unwrap();
+        std::fs::set_permissions(&existing, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let nested = existing.join("mygame").join("bin");
+        create_dirs(&nested, Some(0o700)).await.unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&existing), 0o755);
+        assert_eq!(mode(&existing.join("mygame")), 0o700);
+        assert_eq!(mode(&nested), 0o700);
+    }
+
+    #[tokio::test]
+    async fn test_max_retries_reports_each_transition() {
+        // Nothing listens on a port the OS just handed out and released
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+
+        let mut daemon = ClientDaemon::new("127.0.0.1".to_string(), port, "test".to_string(), "host".to_string())
+            .with_reconnect_delay(Duration::from_millis(10))
+            .with_max_retries(Some(2))
+            .with_event_handler(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        let err = daemon.run().await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RetriesExhausted>().unwrap().attempts, 3);
+
+        let events = events.lock().unwrap();
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                DaemonEvent::Connected => "connected".to_string(),
+                DaemonEvent::Disconnected { .. } => "disconnected".to_string(),
+                DaemonEvent::Reconnecting { attempt, .. } => format!("reconnecting {}", attempt),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["disconnected", "reconnecting 1", "disconnected", "reconnecting 2", "disconnected"]
+        );
+        assert_eq!(
+            events[1],
+            DaemonEvent::Reconnecting {
+                attempt: 1,
+                delay: Duration::from_millis(10),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_file_stays_in_scope() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join("assets/ui/icons")).unwrap();
+        std::fs::write(root.join("game.exe"), b"exe").unwrap();
+        std::fs::write(root.join("assets/ui/icons/close.png"), b"png").unwrap();
+        std::fs::write(root.join("save.dat"), b"keep me").unwrap();
+
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        )
+        .with_working_dir(root.to_path_buf());
+
+        // Root-level destination
+        daemon.handle_delete_file("game.exe", ".").await.unwrap();
+        assert!(!root.join("game.exe").exists());
+
+        // Nested subdirectory under the destination; the directories are kept
+        daemon
+            .handle_delete_file("assets/ui/icons/close.png", "assets/")
+            .await
+            .unwrap();
+        assert!(!root.join("assets/ui/icons/close.png").exists());
+        assert!(root.join("assets/ui/icons").is_dir());
+
+        // Already gone is fine
+        daemon.handle_delete_file("game.exe", ".").await.unwrap();
+
+        // Anything outside the scope is refused and left alone
+        assert!(daemon.handle_delete_file("save.dat", "assets/").await.is_err());
+        assert!(daemon.handle_delete_file("assets/../save.dat", "assets/").await.is_err());
+        assert!(root.join("save.dat").exists());
+    }
+
+    #[test]
+    fn test_local_path_absolute_destinations() {
+        let temp = tempdir().unwrap();
+        let absolute = temp.path().join("game.exe");
+        let absolute = absolute.to_str().unwrap();
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        )
+        .with_working_dir(PathBuf::from("/srv/games"));
+
+        // Relative paths are anchored at the working dir as before
+        assert_eq!(daemon.local_path("bin/game.exe").unwrap(), PathBuf::from("/srv/games/bin/game.exe"));
+
+        // Absolute paths are refused by default, with a hint at the flag
+        let err = daemon.local_path(absolute).unwrap_err().to_string();
+        assert!(err.contains("--allow-absolute-destinations"), "{}", err);
+
+        let daemon = daemon.with_allow_absolute_destinations(true);
+        assert_eq!(daemon.local_path(absolute).unwrap(), PathBuf::from(absolute));
+        assert!(daemon.local_path("/srv/games/../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_local_path_stays_under_working_dir_and_home() {
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        )
+        .with_working_dir(PathBuf::from("/srv/games"));
+
+        // Relative paths can't climb out of the working dir
+        let err = daemon.local_path("../../etc/passwd").unwrap_err().to_string();
+        assert!(err.contains("'..'"), "{}", err);
+        assert!(daemon.local_path("bin/../../etc/passwd").is_err());
+
+        // Nor can ~ paths climb out of home, which would make them absolute paths
+        // that get past the refusal of absolute destinations
+        let err = daemon.local_path("~/../../etc/passwd").unwrap_err().to_string();
+        assert!(err.contains("'..'"), "{}", err);
+
+        if let Ok(home) = std::env::var("HOME") {
+            assert_eq!(daemon.local_path("~/.config/game.ini").unwrap(), Path::new(&home).join(".config/game.ini"));
+        }
+
+        // A drive-relative or rooted path joined on would replace the working dir
+        #[cfg(windows)]
+        assert!(daemon.local_path("C:Windows\\system.ini").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_capped_output_truncates_with_marker() {
+        let mut output = CappedOutput::new(10);
+        output.read_from(&b"0123456789abcdef"[..]).await.unwrap();
+        assert_eq!(output.into_string(), "0123456789\n[... truncated 6 bytes ...]\n");
+
+        let mut output = CappedOutput::new(10);
+        output.read_from(&b"short"[..]).await.unwrap();
+        assert_eq!(output.into_string(), "short");
+    }
+
+    #[tokio::test]
+    async fn test_capped_output_streams_kept_bytes_and_marker() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut output = CappedOutput::new(10).with_sink(sender, MSG_EXEC_STDERR);
+        output.read_from(&b"0123456789abcdef"[..]).await.unwrap();
+        output.finish();
+
+        let mut streamed = Vec::new();
+        while let Ok(frame) = receiver.try_recv() {
+            assert_eq!(frame.message_type, MSG_EXEC_STDERR);
+            streamed.extend_from_slice(&frame.payload);
+        }
+        assert_eq!(String::from_utf8(streamed).unwrap(), output.into_string());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_command_times_out() {
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        )
+        .with_exec_timeout(Some(Duration::from_millis(200)));
+
+        let args = vec!["-c".to_string(), "echo started; sleep 10".to_string()];
+        let started = std::time::Instant::now();
+        let output = daemon
+            .exec_runner()
+            .execute_command("exec-1", "sh", &args, None, &std::collections::HashMap::new(), None)
+            .await
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(output.exit_code, EXEC_TIMEOUT_EXIT_CODE);
+        assert_eq!(output.stdout, "started\n");
+        assert!(output.error.unwrap().contains("Timed out"));
+        assert!(daemon.exec_processes().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_kill_stops_running_exec() {
+        let mut daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        );
+
+        let runner = daemon.exec_runner();
+        let exec = tokio::spawn(async move {
+            let args = vec!["-c".to_string(), "echo started; sleep 10".to_string()];
+            runner
+                .execute_command("exec-1", "sh", &args, None, &std::collections::HashMap::new(), None)
+                .await
+        });
+
+        let started = std::time::Instant::now();
+        while daemon.exec_processes().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "exec never listed");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let processes = daemon.exec_processes();
+        assert_eq!(processes[0].request_id, "exec-1");
+        assert_eq!(processes[0].binary, "sh");
+        assert!(processes[0].pid.is_some());
+
+        daemon.handle_kill("exec-1".to_string()).await.unwrap();
+        let output = exec.await.unwrap().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(output.exit_code, EXEC_KILLED_EXIT_CODE);
+        assert_eq!(output.error.as_deref(), Some("Killed on request"));
+        assert!(daemon.exec_processes().is_empty());
+    }
+
+    #[test]
+    fn test_exec_allowlist_permits() {
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        let allowlist = ExecAllowlist::from_patterns(&[
+            "ls".to_string(),
+            "/usr/bin/*".to_string(),
+            "~/games/*.exe".to_string(),
+        ])
+        .unwrap();
+
+        let permits = |binary: &str| allowlist.resolve(binary, None).is_some();
+
+        assert!(permits("ls"));
+        assert!(permits("/usr/bin/env"));
+        assert!(permits("~/games/doom.exe"));
+        assert!(permits(&home.join("games/doom.exe").to_string_lossy()));
+        assert_eq!(allowlist.resolve("~/games/doom.exe", None), Some(home.join("games/doom.exe")));
+
+        // Bare names don't match paths, and * stays within one directory
+        assert!(!permits("/tmp/ls"));
+        assert!(!permits("/usr/bin/sub/tool"));
+        assert!(!permits("/usr/bin/../../tmp/evil"));
+        assert!(!permits("~/games/doom.sh"));
+
+        // Bare names are matched where they resolve too, so sh is allowed from /usr/bin only
+        let sh = find_on_path(Path::new("sh"));
+        assert_eq!(permits("sh"), sh.is_some_and(|sh| sh.parent() == Some(Path::new("/usr/bin"))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exec_allowlist_resolves_bare_names_on_own_path() {
+        let allowlist = ExecAllowlist::from_patterns(&["ls".to_string()]).unwrap();
+
+        // What runs is the daemon's own ls, however the request would have looked it up
+        let resolved = allowlist.resolve("ls", None).unwrap();
+        assert!(resolved.is_absolute(), "{}", resolved.display());
+        assert_eq!(Some(resolved.clone()), find_on_path(Path::new("ls")));
+        assert_eq!(allowlist.resolve("ls", Some("/tmp")), Some(resolved));
+
+        // A name not on PATH can't be resolved, so isn't run
+        let allowlist = ExecAllowlist::from_patterns(&["no-such-binary-hrl".to_string()]).unwrap();
+        assert_eq!(allowlist.resolve("no-such-binary-hrl", None), None);
+    }
+
+    #[test]
+    fn test_exec_allowlist_resolves_relative_paths_against_working_dir() {
+        let allowlist = ExecAllowlist::from_patterns(&["/opt/game/bin/*".to_string()]).unwrap();
+
+        assert_eq!(
+            allowlist.resolve("bin/game", Some("/opt/game")),
+            Some(PathBuf::from("/opt/game/bin/game"))
+        );
+        // The same relative path from a directory the server picked elsewhere isn't allowed
+        assert_eq!(allowlist.resolve("bin/game", Some("/tmp/synced")), None);
+        assert_eq!(allowlist.resolve("./game", Some("/tmp/synced/bin")), None);
+    }
+
+    #[test]
+    fn test_exec_allowlist_load_skips_comments() {
+        let temp = tempdir().unwrap();
+        let path = temp.path().join("allowlist");
+        std::fs::write(&path, "# launchers\n\n  /opt/game/bin/*  \n").unwrap();
+
+        let allowlist = ExecAllowlist::load(&path).unwrap();
+        assert!(allowlist.resolve("/opt/game/bin/game", None).is_some());
+        assert!(allowlist.resolve("# launchers", None).is_none());
+    }
+
+    #[test]
+    fn test_exec_refusal_without_match() {
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        );
+        let no_env = std::collections::HashMap::new();
+        assert!(
+            matches!(daemon.exec_permission("rm", None, &no_env), Ok(None)),
+            "no allowlist permits everything"
+        );
+
+        let daemon = daemon.with_exec_allowlist(Some(
+            ExecAllowlist::from_patterns(&["echo".to_string()]).unwrap(),
+        ));
+        assert!(matches!(daemon.exec_permission("echo", None, &no_env), Ok(Some(_))));
+
+        let Err(refusal) = daemon.exec_permission("rm", None, &no_env) else {
+            panic!("rm was permitted");
+        };
+        assert_eq!(refusal.exit_code, EXEC_NOT_PERMITTED_EXIT_CODE);
+        assert_eq!(refusal.error.as_deref(), Some("Not permitted: rm"));
+    }
+
+    #[test]
+    fn test_exec_refusal_of_lookup_overrides() {
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            20222,
+            "user".to_string(),
+            "test-host".to_string(),
+        )
+        .with_exec_allowlist(Some(ExecAllowlist::from_patterns(&["echo".to_string()]).unwrap()));
+
+        // A PATH pointing at synced files would swap in another echo; the loader
+        // variables would inject a library into the real one
+        for key in ["PATH", "LD_PRELOAD", "LD_LIBRARY_PATH", "DYLD_INSERT_LIBRARIES"] {
+            let env = std::collections::HashMap::from([(key.to_string(), "/tmp/synced".to_string())]);
+            let Err(refusal) = daemon.exec_permission("echo", None, &env) else {
+                panic!("{} was allowed", key);
+            };
+            assert_eq!(refusal.error, Some(format!("Not permitted: setting {}", key)));
+        }
+
+        let env = std::collections::HashMap::from([("GAME_MODE".to_string(), "1".to_string())]);
+        assert!(matches!(daemon.exec_permission("echo", None, &env), Ok(Some(_))));
+    }
+
+    #[test]
+    fn test_matching_processes() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+
+        let ps = "systemd\nbash\n/usr/lib/firefox/firefox\nhalfremembered-\n";
+        assert_eq!(matching_processes(ps, &names(&["firefox", "game"])), names(&["firefox"]));
+        assert!(matching_processes(ps, &names(&["bas"])).is_empty());
+        // Linux truncates process names to 15 bytes
+        assert_eq!(
+            matching_processes(ps, &names(&["halfremembered-launcher"])),
+            names(&["halfremembered-launcher"])
+        );
+
+        let tasklist = "\"System\",\"4\",\"Services\",\"0\",\"144 K\"\n\"Game.exe\",\"5120\",\"Console\",\"1\",\"1,024 K\"\n";
+        assert_eq!(matching_processes(tasklist, &names(&["game"])), names(&["game"]));
+        assert_eq!(matching_processes(tasklist, &names(&["game.exe"])), names(&["game.exe"]));
+        assert!(matching_processes(tasklist, &names(&["4"])).is_empty());
     }
 }
@@ -1,12 +1,209 @@
 use anyhow::{Context, Result};
-use halfremembered_protocol::ServerMessage;
-use russh::server::Handle;
-use russh::ChannelId;
+use halfremembered_protocol::{ClientState, ClientVerifyResult, ErrorCounts, LocalResponse, ServerMessage, TransferInfo, TransferRates, WireFormat};
+use russh::server::Msg;
+use russh::ChannelWriteHalf;
 use std::collections::HashMap;
-use std::time::Instant;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+
+/// Framed messages that may wait for a client's SSH window before it is evicted
+pub const DEFAULT_SEND_QUEUE_DEPTH: usize = 64;
+
+/// How long `ControlWriter::send` waits for room in a client's send queue before giving up
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long after a session ends its resume token is still accepted
+pub const RESUME_WINDOW: Duration = Duration::from_secs(300);
 
 pub struct ClientRegistry {
     clients: HashMap<String, ConnectedClient>,
+    /// Most recent send failure per hostname; kept across reconnects so a client that
+    /// was dropped for stalling still shows why
+    last_errors: HashMap<String, String>,
+    /// Control callers waiting for a client's Status reply, by request_id
+    pending_status: HashMap<String, oneshot::Sender<ClientState>>,
+    /// Control callers waiting for a client's VerifyResult, by request_id
+    pending_verify: HashMap<String, oneshot::Sender<ClientVerifyResult>>,
+    /// Control callers streaming a command's output, by request_id, with the session
+    /// running it; dropped when that session goes away
+    exec_relays: HashMap<String, (String, mpsc::UnboundedSender<LocalResponse>)>,
+    /// Rates of every transfer any session completed, kept across disconnects
+    transfer_rates: TransferRates,
+    /// Ended sessions a reconnecting client may resume, by resume token
+    resumable: HashMap<String, ResumableSession>,
+    /// Drops and rejections by cause; sessions hold a clone to count their own
+    error_counters: Arc<ErrorCounters>,
+}
+
+/// What a resumed session carries over from the one it continues
+struct ResumableSession {
+    hostname: String,
+    auth_key_label: Option<String>,
+    connected_at: Instant,
+    last_transfer: Option<TransferInfo>,
+    transfer_rates: TransferRates,
+    heartbeat_gaps: u64,
+    ended_at: Instant,
+}
+
+/// Why the server dropped a connection or a transfer failed, for `ErrorCounters`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCause {
+    ParseError,
+    ChecksumFailure,
+    AuthReject,
+    Eviction,
+}
+
+/// Server-wide counts of each `ErrorCause`, shared with every session
+///
+/// Atomic rather than behind the registry's lock so that error paths which can't
+/// wait on it, such as a rejected stream or auth check, still count.
+#[derive(Debug, Default)]
+pub struct ErrorCounters {
+    parse_errors: AtomicU64,
+    checksum_failures: AtomicU64,
+    auth_rejects: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ErrorCounters {
+    pub fn record(&self, cause: ErrorCause) {
+        let counter = match cause {
+            ErrorCause::ParseError => &self.parse_errors,
+            ErrorCause::ChecksumFailure => &self.checksum_failures,
+            ErrorCause::AuthReject => &self.auth_rejects,
+            ErrorCause::Eviction => &self.evictions,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts so far, for `status`
+    pub fn snapshot(&self) -> ErrorCounts {
+        ErrorCounts {
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+            auth_rejects: self.auth_rejects.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Ordered, bounded send queue for a client's control channel
+///
+/// A dedicated task drains the queue through the channel's write half, which waits
+/// for the client's SSH window to open, so each client is written at its own pace
+/// while senders only enqueue. A client that stops reading fills its queue and is
+/// evicted: the task closes the control channel instead of letting russh's unbounded
+/// pending buffer grow. Whole frames are queued and written by a single task, so
+/// messages never interleave.
+#[derive(Clone)]
+pub struct ControlWriter {
+    queue: mpsc::Sender<Vec<u8>>,
+    /// Most frames ever waiting in the queue at once
+    peak: Arc<AtomicUsize>,
+    /// Tells the writer task to close the channel
+    evicted: Arc<Notify>,
+}
+
+impl ControlWriter {
+    pub fn spawn(writer: ChannelWriteHalf<Msg>, depth: usize) -> Self {
+        let (queue, mut outbound) = mpsc::channel::<Vec<u8>>(depth);
+        let evicted = Arc::new(Notify::new());
+
+        let eviction = evicted.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    frame = outbound.recv() => frame,
+                    _ = eviction.notified() => break,
+                };
+                let Some(frame) = frame else {
+                    return;
+                };
+                tokio::select! {
+                    written = writer.data(frame.as_slice()) => {
+                        if let Err(e) = written {
+                            log::debug!("Control channel write failed: {:?}", e);
+                            return;
+                        }
+                    }
+                    _ = eviction.notified() => break,
+                }
+            }
+            // Closing the control channel makes the client reconnect with a fresh session
+            if let Err(e) = writer.close().await {
+                log::debug!("Failed to close control channel: {:?}", e);
+            }
+        });
+
+        Self {
+            queue,
+            peak: Arc::new(AtomicUsize::new(0)),
+            evicted,
+        }
+    }
+
+    /// Frames waiting to be written
+    pub fn queued(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    /// Most frames that have waited at once
+    pub fn peak_queued(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Frames that may wait before the client is evicted
+    pub fn depth(&self) -> usize {
+        self.queue.max_capacity()
+    }
+
+    fn record_depth(&self) {
+        self.peak.fetch_max(self.queued(), Ordering::Relaxed);
+    }
+
+    /// Close the control channel without waiting for queued messages
+    pub fn close(&self) {
+        self.evicted.notify_one();
+    }
+
+    /// Queue a framed message, waiting up to SEND_TIMEOUT for room; for tasks that
+    /// send more than the queue holds and can afford to wait for the client
+    pub async fn send(&self, frame: Vec<u8>) -> Result<()> {
+        match self.queue.send_timeout(frame, SEND_TIMEOUT).await {
+            Ok(()) => {
+                self.record_depth();
+                Ok(())
+            }
+            Err(SendTimeoutError::Timeout(_)) => anyhow::bail!(
+                "client stalled: send queue still full after {}s",
+                SEND_TIMEOUT.as_secs()
+            ),
+            Err(SendTimeoutError::Closed(_)) => anyhow::bail!("control channel closed"),
+        }
+    }
+
+    /// Queue a framed message without waiting
+    ///
+    /// A full queue evicts the client: its control channel is closed, and this and
+    /// every later send fails.
+    pub fn try_send(&self, frame: Vec<u8>) -> Result<()> {
+        match self.queue.try_send(frame) {
+            Ok(()) => {
+                self.record_depth();
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                self.evicted.notify_one();
+                anyhow::bail!("client stalled: send queue full ({} messages); evicted", self.depth())
+            }
+            Err(TrySendError::Closed(_)) => anyhow::bail!("control channel closed"),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -16,17 +213,106 @@ pub struct ConnectedClient {
     pub platform: String,
     pub connected_at: Instant,
     pub last_heartbeat: Instant,
-    pub session_handle: Handle,
-    pub channel_id: ChannelId,
+    pub control_writer: ControlWriter,
+    /// Comment of the authorized_keys entry that authenticated this session, for auditing
+    pub auth_key_label: Option<String>,
+    pub last_transfer: Option<TransferInfo>,
+    /// Average and peak rates of this session's transfers
+    pub transfer_rates: TransferRates,
+    /// Sequence number of the last heartbeat received on this session
+    pub last_heartbeat_sequence: Option<u32>,
+    /// Heartbeats that skipped ahead or restarted, hinting at a missed reconnect
+    pub heartbeat_gaps: u64,
+    /// Format the client registered in, which messages to it are sent in too
+    pub wire_format: WireFormat,
+    /// Secret the client presents to resume this session after reconnecting
+    pub resume_token: String,
+    /// What the client advertised it can handle in Register, from `capabilities`
+    pub capabilities: Vec<String>,
+}
+
+/// Outcome of sending a broadcast message to one client
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub hostname: String,
+    pub session_id: String,
+    pub error: Option<String>,
 }
 
 impl ClientRegistry {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            last_errors: HashMap::new(),
+            pending_status: HashMap::new(),
+            pending_verify: HashMap::new(),
+            exec_relays: HashMap::new(),
+            transfer_rates: TransferRates::default(),
+            resumable: HashMap::new(),
+            error_counters: Arc::default(),
         }
     }
 
+    /// Carry the session that issued `token` over to `client`, which is about to register
+    ///
+    /// The token must be one a session with the same hostname and key was given within
+    /// RESUME_WINDOW of now. If that session is still registered, as when the server
+    /// hasn't yet noticed its connection drop, it is closed and replaced; a token
+    /// presented by another hostname or key leaves it alone. Each token resumes at most
+    /// once. Returns whether the session was resumed.
+    pub fn resume(&mut self, client: &mut ConnectedClient, token: &str) -> bool {
+        self.resumable.retain(|_, session| session.ended_at.elapsed() < RESUME_WINDOW);
+
+        let live = self
+            .clients
+            .values()
+            .find(|c| c.resume_token == token)
+            .map(|c| (c.session_id.clone(), c.control_writer.clone(), c.hostname.clone(), c.auth_key_label.clone()));
+        if let Some((session_id, writer, hostname, auth_key_label)) = live {
+            if hostname != client.hostname || auth_key_label != client.auth_key_label {
+                log::warn!(
+                    "Resume token of live session {} for {} ({:?}) presented by {} ({:?}); registering as a new client",
+                    session_id,
+                    hostname,
+                    auth_key_label,
+                    client.hostname,
+                    client.auth_key_label
+                );
+                return false;
+            }
+            log::info!("Replacing session {} resumed by a new connection", session_id);
+            self.unregister(&session_id);
+            writer.close();
+        }
+
+        let Some(session) = self.resumable.remove(token) else {
+            log::info!("Unknown or expired resume token from {}; registering as a new client", client.hostname);
+            return false;
+        };
+        if session.hostname != client.hostname || session.auth_key_label != client.auth_key_label {
+            log::warn!(
+                "Resume token for {} ({:?}) presented by {} ({:?}); registering as a new client",
+                session.hostname,
+                session.auth_key_label,
+                client.hostname,
+                client.auth_key_label
+            );
+            return false;
+        }
+
+        client.connected_at = session.connected_at;
+        client.last_transfer = session.last_transfer;
+        client.transfer_rates = session.transfer_rates;
+        client.heartbeat_gaps = session.heartbeat_gaps;
+        log::info!(
+            "Resumed {} (session: {}) after {:?} away",
+            client.hostname,
+            client.session_id,
+            session.ended_at.elapsed()
+        );
+        true
+    }
+
     pub fn register(&mut self, client: ConnectedClient) -> Result<()> {
         log::info!(
             "Registering client: {} (session: {}, platform: {})",
@@ -34,59 +320,283 @@ impl ClientRegistry {
             client.session_id,
             client.platform
         );
+
+        let duplicates = self.session_ids_for(&client.hostname);
+        if !duplicates.is_empty() {
+            log::warn!(
+                "⚠️  {} is already connected as session {}; commands for it need a session id until one disconnects",
+                client.hostname,
+                duplicates.join(", ")
+            );
+        }
+
         self.clients.insert(client.session_id.clone(), client);
         Ok(())
     }
 
+    /// Sessions registered under `hostname`, sorted
+    fn session_ids_for(&self, hostname: &str) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .clients
+            .values()
+            .filter(|c| c.hostname == hostname)
+            .map(|c| c.session_id.clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Find the client a command targets: the given session, which must belong to
+    /// `hostname`, or else the only client with that hostname
+    pub fn resolve(&self, hostname: &str, session_id: Option<&str>) -> Result<&ConnectedClient> {
+        if let Some(session_id) = session_id {
+            let client = self
+                .clients
+                .get(session_id)
+                .context(format!("Session not found: {}", session_id))?;
+            if client.hostname != hostname {
+                anyhow::bail!("Session {} belongs to {}, not {}", session_id, client.hostname, hostname);
+            }
+            return Ok(client);
+        }
+
+        let mut matches = self.clients.values().filter(|c| c.hostname == hostname);
+        let client = matches
+            .next()
+            .context(format!("Client not found: {}", hostname))?;
+        if matches.next().is_some() {
+            anyhow::bail!(
+                "{} is ambiguous: connected as sessions {}; pick one with --session",
+                hostname,
+                self.session_ids_for(hostname).join(", ")
+            );
+        }
+        Ok(client)
+    }
+
     pub fn unregister(&mut self, session_id: &str) {
-        if self.clients.remove(session_id).is_some() {
+        if let Some(client) = self.clients.remove(session_id) {
             log::info!("Unregistered client session: {}", session_id);
+            self.resumable.retain(|_, session| session.ended_at.elapsed() < RESUME_WINDOW);
+            self.resumable.insert(
+                client.resume_token,
+                ResumableSession {
+                    hostname: client.hostname,
+                    auth_key_label: client.auth_key_label,
+                    connected_at: client.connected_at,
+                    last_transfer: client.last_transfer,
+                    transfer_rates: client.transfer_rates,
+                    heartbeat_gaps: client.heartbeat_gaps,
+                    ended_at: Instant::now(),
+                },
+            );
         }
+        // Closing the relays tells their callers the command won't finish
+        self.exec_relays.retain(|_, (owner, _)| owner != session_id);
     }
 
-    pub async fn send_to_client(&mut self, hostname: &str, msg: &ServerMessage) -> Result<()> {
-        let client = self
-            .clients
-            .values()
-            .find(|c| c.hostname == hostname)
-            .context(format!("Client not found: {}", hostname))?;
+    /// Queue a message for the client `resolve` picks for `hostname` and `session_id`
+    pub fn send_to_client(&mut self, hostname: &str, session_id: Option<&str>, msg: &ServerMessage) -> Result<()> {
+        let client = self.resolve(hostname, session_id)?;
+        let session_id = client.session_id.clone();
 
         let mut full_message = Vec::new();
-        msg.write_framed(&mut full_message)
+        msg.write_framed_as(&mut full_message, client.wire_format)
             .context("Failed to serialize server message")?;
 
-        client
-            .session_handle
-            .data(client.channel_id, full_message.into())
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to send message to client: {:?}", e))?;
+        if let Err(e) = client.control_writer.try_send(full_message) {
+            self.evict(&session_id, format!("{:#}", e));
+            return Err(e.context(format!("Failed to send message to {}", hostname)));
+        }
 
         log::debug!("Sent {} to {}", msg.message_type(), hostname);
         Ok(())
     }
 
-    pub async fn broadcast(&mut self, msg: &ServerMessage) -> Result<()> {
-        let mut full_message = Vec::new();
-        msg.write_framed(&mut full_message)
-            .context("Failed to serialize server message")?;
+    /// Queue a message for every client, reporting the outcome for each recipient
+    ///
+    /// Nothing here waits on a client's link, so a stalled client can't hold up the
+    /// others; one whose queue is full is evicted instead.
+    pub fn broadcast(&mut self, msg: &ServerMessage) -> Result<Vec<Delivery>> {
+        self.broadcast_to(msg, |_| true)
+    }
 
-        for (hostname, client) in &self.clients {
-            if let Err(e) = client
-                .session_handle
-                .data(client.channel_id, full_message.clone().into())
-                .await
-            {
-                log::error!("Failed to broadcast to {}: {:?}", hostname, e);
-            } else {
-                log::debug!("Broadcast {} to {}", msg.message_type(), hostname);
+    /// Send `msg` to the connected clients `include` accepts, e.g. those able to take
+    /// the encoding it was built for
+    pub fn broadcast_to(&mut self, msg: &ServerMessage, include: impl Fn(&ConnectedClient) -> bool) -> Result<Vec<Delivery>> {
+        // Framed once per format in use
+        let mut framed: HashMap<WireFormat, Vec<u8>> = HashMap::new();
+        for client in self.clients.values().filter(|client| include(client)) {
+            if let std::collections::hash_map::Entry::Vacant(entry) = framed.entry(client.wire_format) {
+                let mut full_message = Vec::new();
+                msg.write_framed_as(&mut full_message, client.wire_format)
+                    .context("Failed to serialize server message")?;
+                entry.insert(full_message);
             }
         }
 
-        Ok(())
+        let mut deliveries = Vec::with_capacity(self.clients.len());
+        for (session_id, client) in self.clients.iter().filter(|(_, client)| include(client)) {
+            let error = match client.control_writer.try_send(framed[&client.wire_format].clone()) {
+                Ok(()) => {
+                    log::debug!("Broadcast {} to {}", msg.message_type(), client.hostname);
+                    None
+                }
+                Err(e) => {
+                    log::error!("Failed to broadcast to {}: {:#}", client.hostname, e);
+                    Some(format!("{:#}", e))
+                }
+            };
+
+            deliveries.push(Delivery {
+                hostname: client.hostname.clone(),
+                session_id: session_id.clone(),
+                error,
+            });
+        }
+
+        for delivery in &deliveries {
+            if let Some(ref error) = delivery.error {
+                self.evict(&delivery.session_id, error.clone());
+            }
+        }
+
+        Ok(deliveries)
+    }
+
+    /// Drop a session whose sends failed, keeping the error for its hostname
+    ///
+    /// A stalled session's writer is already closing its control channel, so the
+    /// client reconnects as a new session rather than lingering half-registered.
+    pub fn evict(&mut self, session_id: &str, error: String) {
+        let Some(client) = self.clients.get(session_id) else {
+            return;
+        };
+        log::warn!("🚪 Evicting {} (session: {}): {}", client.hostname, session_id, error);
+        self.error_counters.record(ErrorCause::Eviction);
+        self.last_errors.insert(client.hostname.clone(), error);
+        self.unregister(session_id);
+    }
+
+    /// Most recent send failure for a hostname, if any
+    pub fn last_error(&self, hostname: &str) -> Option<String> {
+        self.last_errors.get(hostname).cloned()
+    }
+
+    /// Wait for the Status reply to `request_id`; forget it with `cancel_status` on timeout
+    pub fn expect_status(&mut self, request_id: &str) -> oneshot::Receiver<ClientState> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending_status.insert(request_id.to_string(), sender);
+        receiver
+    }
+
+    pub fn cancel_status(&mut self, request_id: &str) {
+        self.pending_status.remove(request_id);
+    }
+
+    /// Hand a Status reply to the caller waiting on it; false if nobody asked for it
+    pub fn complete_status(&mut self, request_id: &str, state: ClientState) -> bool {
+        match self.pending_status.remove(request_id) {
+            Some(sender) => sender.send(state).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Wait for the VerifyResult reply to `request_id`; forget it with `cancel_verify` on timeout
+    pub fn expect_verify(&mut self, request_id: &str) -> oneshot::Receiver<ClientVerifyResult> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending_verify.insert(request_id.to_string(), sender);
+        receiver
+    }
+
+    pub fn cancel_verify(&mut self, request_id: &str) {
+        self.pending_verify.remove(request_id);
+    }
+
+    /// Hand a VerifyResult to the caller waiting on it; false if nobody asked for it
+    pub fn complete_verify(&mut self, request_id: &str, result: ClientVerifyResult) -> bool {
+        match self.pending_verify.remove(request_id) {
+            Some(sender) => sender.send(result).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Send an Execute to the client `resolve` picks and relay its output for
+    /// `request_id` to the returned receiver
+    ///
+    /// The receiver ends after ExecExit, or without one if the client disconnects first.
+    pub fn send_streaming_exec(
+        &mut self,
+        hostname: &str,
+        session_id: Option<&str>,
+        request_id: &str,
+        msg: &ServerMessage,
+    ) -> Result<mpsc::UnboundedReceiver<LocalResponse>> {
+        let owner = self.resolve(hostname, session_id)?.session_id.clone();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.exec_relays.insert(request_id.to_string(), (owner.clone(), sender));
+        if let Err(e) = self.send_to_client(hostname, Some(&owner), msg) {
+            self.exec_relays.remove(request_id);
+            return Err(e);
+        }
+        Ok(receiver)
+    }
+
+    /// Hand output for `request_id` to the caller streaming it, if any; an ExecExit
+    /// ends the relay
+    pub fn relay_exec(&mut self, request_id: &str, response: LocalResponse) {
+        let Some((_, sender)) = self.exec_relays.get(request_id) else {
+            return;
+        };
+        let finished = matches!(response, LocalResponse::ExecExit { .. });
+        // A caller that went away stops the relay, not the command
+        if sender.send(response).is_err() || finished {
+            self.exec_relays.remove(request_id);
+        }
     }
 
-    pub fn update_heartbeat(&mut self, hostname: &str) {
-        if let Some(client) = self.clients.values_mut().find(|c| c.hostname == hostname) {
+    /// Record a completed rsync transfer reported by a session
+    pub fn record_transfer(&mut self, session_id: &str, transfer: TransferInfo) {
+        self.transfer_rates.record(&transfer);
+        if let Some(client) = self.clients.get_mut(session_id) {
+            client.transfer_rates.record(&transfer);
+            client.last_transfer = Some(transfer);
+        }
+    }
+
+    /// Average and peak rates of all transfers since the server started
+    pub fn transfer_rates(&self) -> &TransferRates {
+        &self.transfer_rates
+    }
+
+    /// Counters of drops and rejections since the server started
+    pub fn error_counters(&self) -> &Arc<ErrorCounters> {
+        &self.error_counters
+    }
+
+    /// Record a heartbeat, counting a gap when its sequence doesn't follow the last one
+    pub fn record_heartbeat(&mut self, session_id: &str, sequence: u32) {
+        let Some(client) = self.clients.get_mut(session_id) else {
+            return;
+        };
+
+        client.last_heartbeat = Instant::now();
+        if let Some(gap) = heartbeat_gap(client.last_heartbeat_sequence, sequence) {
+            client.heartbeat_gaps += 1;
+            log::warn!(
+                "💔 Heartbeat gap from {} (session: {}): {} ({} gaps so far)",
+                client.hostname,
+                session_id,
+                gap,
+                client.heartbeat_gaps
+            );
+        }
+        client.last_heartbeat_sequence = Some(sequence);
+    }
+
+    /// Record a Pong: the connection is alive, though it says nothing about heartbeat order
+    pub fn record_pong(&mut self, session_id: &str) {
+        if let Some(client) = self.clients.get_mut(session_id) {
             client.last_heartbeat = Instant::now();
         }
     }
@@ -100,8 +610,68 @@ impl ClientRegistry {
     }
 }
 
+/// Describe how `sequence` breaks from the heartbeat after `last`, if it does
+///
+/// Clients count heartbeats from 0 on each registration, so a session's first heartbeat
+/// should be 0 and each one after it one more than the last.
+fn heartbeat_gap(last: Option<u32>, sequence: u32) -> Option<String> {
+    let expected = last.map_or(0, |last| last.wrapping_add(1));
+    if sequence == expected {
+        None
+    } else if last.is_some_and(|last| sequence <= last) {
+        Some(format!("sequence reset from {} to {}", last.unwrap_or(0), sequence))
+    } else {
+        Some(format!(
+            "expected {}, got {} ({} missed)",
+            expected,
+            sequence,
+            sequence.wrapping_sub(expected)
+        ))
+    }
+}
+
 impl Default for ClientRegistry {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_gap() {
+        // In order, starting from 0 and wrapping around
+        assert_eq!(heartbeat_gap(None, 0), None);
+        assert_eq!(heartbeat_gap(Some(0), 1), None);
+        assert_eq!(heartbeat_gap(Some(u32::MAX), 0), None);
+
+        // Skipped ahead
+        assert_eq!(
+            heartbeat_gap(Some(3), 6),
+            Some("expected 4, got 6 (2 missed)".to_string())
+        );
+        assert!(heartbeat_gap(None, 5).is_some());
+
+        // Restarted or repeated
+        assert_eq!(
+            heartbeat_gap(Some(41), 0),
+            Some("sequence reset from 41 to 0".to_string())
+        );
+        assert!(heartbeat_gap(Some(7), 7).is_some());
+    }
+
+    #[test]
+    fn test_error_counters_count_each_cause() {
+        let counters = ErrorCounters::default();
+        counters.record(ErrorCause::ParseError);
+        counters.record(ErrorCause::ParseError);
+        counters.record(ErrorCause::AuthReject);
+        counters.record(ErrorCause::Eviction);
+        assert_eq!(
+            counters.snapshot(),
+            ErrorCounts { parse_errors: 2, checksum_failures: 0, auth_rejects: 1, evictions: 1 }
+        );
+    }
+}
@@ -6,7 +6,10 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+
+use crate::file_watcher::compile_globs;
+use crate::rsync_utils::ChecksumAlgo;
 
 /// Root configuration structure for .hrlauncher.toml
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +31,26 @@ pub struct ProjectConfig {
     /// Optional description
     #[serde(default)]
     pub description: Option<String>,
+
+    /// Environment variables inherited by every rule's execute
+    /// A rule's `[sync.execute.env]` overrides these on key collision
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// Optional: Unix mode for synced files when the server's platform has no
+    /// permission bits to send (e.g. `0o755`); `server --default-mode` overrides it
+    #[serde(default)]
+    pub default_mode: Option<u32>,
+
+    /// Optional: hash for file checksums, `"blake3"` (default) or `"sha256"`;
+    /// `server --checksum-algo` overrides it
+    #[serde(default)]
+    pub checksum_algo: Option<ChecksumAlgo>,
+
+    /// Optional: client paths syncs may write under; a destination outside all of
+    /// them is refused. Empty allows any. `server --allowed-destination-root` overrides it
+    #[serde(default)]
+    pub allowed_destination_roots: Vec<String>,
 }
 
 /// A sync rule defines what files to watch and where to sync them
@@ -64,11 +87,119 @@ pub struct SyncRule {
     #[serde(default)]
     pub mirror: bool,
 
+    /// Optional: Propagate deletions of watched files, confined to `destination`
+    /// Examples: "off" (default), "subtree"
+    #[serde(default)]
+    pub mirror_scope: MirrorScope,
+
+    /// Optional: Match include/exclude patterns ignoring case, for case-insensitive
+    /// filesystems where `*.exe` should also match `GAME.EXE`
+    #[serde(default)]
+    pub case_insensitive: bool,
+
+    /// Optional: Watch subdirectories of the rule's watch base (default: true); when
+    /// false only files directly inside it are synced
+    #[serde(default = "default_recursive")]
+    pub recursive: bool,
+
+    /// Optional: Milliseconds a newly created file must stay unchanged before it is
+    /// synced, so build outputs written in several steps sync once complete (default: 0)
+    #[serde(default)]
+    pub settle_ms: u64,
+
+    /// Optional: Unix mode for this rule's files on clients, replacing the source's
+    /// Example: 0o755
+    #[serde(default)]
+    pub file_mode: Option<u32>,
+
+    /// Optional: Unix mode for directories clients create to hold this rule's files
+    /// Example: 0o750
+    #[serde(default)]
+    pub dir_mode: Option<u32>,
+
+    /// Optional: Write each file to `<destination>.new` on clients, then rename it over
+    /// the destination, so a binary that is still running can be replaced (default: false)
+    #[serde(default)]
+    pub atomic_replace: bool,
+
+    /// Optional: When many files change at once, this rule's are sent before those of
+    /// rules with a lower priority (default: 0; negative values go last)
+    #[serde(default)]
+    pub priority: i32,
+
+    /// Optional: What syncs the rule's files: "change" (default) on each write, or
+    /// "git-commit" when a commit in the project's repository changes them
+    #[serde(default)]
+    pub trigger: Trigger,
+
     /// Optional: Execute configuration to run after files are synced
     #[serde(default)]
     pub execute: Option<ExecuteConfig>,
 }
 
+fn default_recursive() -> bool {
+    true
+}
+
+/// Permission overrides a sync rule applies to the files it delivers, how clients
+/// put those files in place, and how soon they are sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileModes {
+    pub file: Option<u32>,
+    pub dir: Option<u32>,
+    pub atomic_replace: bool,
+    pub priority: i32,
+    /// Replace files even where a --no-clobber-local client sees local edits
+    pub force: bool,
+}
+
+/// Which deletions of watched files a sync rule propagates to clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MirrorScope {
+    /// Removing a source file leaves the clients' copies alone
+    #[default]
+    Off,
+    /// Delete the clients' copy, but only if it lies under the rule's destination
+    Subtree,
+}
+
+/// What makes a sync rule send its files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    /// Every change the file watcher sees
+    #[default]
+    Change,
+    /// Commits that change the file, sending the committed state and skipping files
+    /// with uncommitted edits; needs the `git` feature
+    GitCommit,
+}
+
+/// Which successful syncs run a rule's execute
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunOn {
+    /// Every one, even if the client already had the file
+    #[default]
+    Always,
+    /// Only those that changed the client's copy
+    Changed,
+    /// None; the command is kept in the config but not run
+    Never,
+}
+
+impl RunOn {
+    /// Whether a sync that sent `bytes_transferred` bytes runs the command
+    pub fn runs_after(self, bytes_transferred: u64) -> bool {
+        match self {
+            RunOn::Always => true,
+            RunOn::Changed => bytes_transferred > 0,
+            RunOn::Never => false,
+        }
+    }
+}
+
 /// Configuration for executing a binary after sync
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExecuteConfig {
@@ -87,6 +218,16 @@ pub struct ExecuteConfig {
     /// Optional working directory (defaults to destination if not specified)
     #[serde(default)]
     pub working_dir: Option<String>,
+
+    /// Optional: `"changed"` skips syncs the client already had the file for, `"never"`
+    /// disables the command (default: `"always"`)
+    #[serde(default)]
+    pub run_on: RunOn,
+
+    /// Set only by self-update, never from a config file: the client restarts once the
+    /// command succeeds
+    #[serde(skip)]
+    pub self_update: bool,
 }
 
 impl Config {
@@ -95,10 +236,11 @@ impl Config {
         let contents = std::fs::read_to_string(path)
             .context(format!("Failed to read config file: {}", path.display()))?;
 
-        let config: Config = toml::from_str(&contents)
+        let mut config: Config = toml::from_str(&contents)
             .context(format!("Failed to parse config file: {}", path.display()))?;
 
         config.validate()?;
+        config.inherit_project_env();
         Ok(config)
     }
 
@@ -142,10 +284,194 @@ impl Config {
             if rule.destination.is_empty() {
                 anyhow::bail!("{}: destination cannot be empty", rule_name);
             }
+
+            // Compile globs here so a bad pattern is reported before anything is watched
+            compile_globs(&rule.include, "include", rule.case_insensitive)
+                .context(format!("{}: invalid glob", rule_name))?;
+            compile_globs(&rule.exclude, "exclude", rule.case_insensitive)
+                .context(format!("{}: invalid glob", rule_name))?;
+
+            if rule.trigger == Trigger::GitCommit && !cfg!(feature = "git") {
+                anyhow::bail!(
+                    "{}: trigger = \"git-commit\" needs a launcher built with the `git` feature",
+                    rule_name
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prefix every rule's destination with `base`, so `/opt/game` + `bin/` → `/opt/game/bin/`
+    ///
+    /// A `~/` base still expands on each client. Rule destinations must be relative:
+    /// absolute and `~/` ones already name their own location, and `..` would climb
+    /// out of the base.
+    pub fn apply_destination_base(&mut self, base: &str) -> Result<()> {
+        if base.is_empty() {
+            anyhow::bail!("Destination base cannot be empty");
+        }
+
+        for (idx, rule) in self.sync_rules.iter_mut().enumerate() {
+            let default_name = format!("sync rule {}", idx + 1);
+            let rule_name = rule.name.as_deref().unwrap_or(&default_name);
+
+            let destination = Path::new(&rule.destination);
+            if rule.destination.starts_with('~') || destination.has_root() || destination.is_absolute() {
+                anyhow::bail!(
+                    "{}: destination {} is absolute and can't be prefixed with {}",
+                    rule_name,
+                    rule.destination,
+                    base
+                );
+            }
+            if destination.components().any(|c| c == Component::ParentDir) {
+                anyhow::bail!(
+                    "{}: destination {} climbs out of {} with '..'",
+                    rule_name,
+                    rule.destination,
+                    base
+                );
+            }
+
+            let relative = rule.destination.trim_start_matches("./");
+            rule.destination = if relative.is_empty() || relative == "." {
+                base.to_string()
+            } else {
+                format!("{}/{}", base.trim_end_matches('/'), relative)
+            };
         }
 
         Ok(())
     }
+
+    /// Merge `[project.env]` into each rule's execute env, keeping the rule's value on collision
+    fn inherit_project_env(&mut self) {
+        for execute in self.sync_rules.iter_mut().filter_map(|rule| rule.execute.as_mut()) {
+            for (key, value) in &self.project.env {
+                execute
+                    .env
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+}
+
+impl SyncRule {
+    /// The rule's file and directory mode overrides and replacement strategy
+    pub fn modes(&self) -> FileModes {
+        FileModes {
+            file: self.file_mode,
+            dir: self.dir_mode,
+            atomic_replace: self.atomic_replace,
+            priority: self.priority,
+            force: false,
+        }
+    }
+
+    /// Directory (relative to the project root) that covers every include pattern
+    pub fn watch_base(&self) -> PathBuf {
+        common_glob_base(&self.include)
+    }
+
+    /// Whether a file at `relative` (to the project root) is within the rule's watch
+    /// depth: anywhere below the watch base, or directly in it for non-recursive rules
+    pub fn reaches(&self, relative: &Path) -> bool {
+        self.recursive || relative.parent() == Some(self.watch_base().as_path())
+    }
+}
+
+/// Literal directory prefix of a glob pattern
+///
+/// Stops at the first component containing a glob character; an exact path
+/// yields its parent. `src/**/*.rs` → `src`, `target/release/app` → `target/release`,
+/// `*.exe` → `` (the root itself).
+pub fn glob_base(pattern: &str) -> PathBuf {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let mut base = PathBuf::new();
+
+    for component in &components[..components.len() - 1] {
+        if component.contains(['*', '?', '[', '{']) || *component == ".." {
+            break;
+        }
+        if component.is_empty() || *component == "." {
+            continue;
+        }
+        base.push(component);
+    }
+
+    base
+}
+
+/// Tightest common ancestor of the literal bases of a set of glob patterns
+pub fn common_glob_base(patterns: &[String]) -> PathBuf {
+    let mut bases = patterns.iter().map(|pattern| glob_base(pattern));
+    let Some(first) = bases.next() else {
+        return PathBuf::new();
+    };
+
+    bases.fold(first, |common, base| {
+        common
+            .components()
+            .zip(base.components())
+            .take_while(|(left, right)| left == right)
+            .map(|(component, _)| component)
+            .collect()
+    })
+}
+
+/// Strip the pattern's base directory from the relative path to avoid duplication.
+///
+/// For example:
+/// - Pattern: "assets/**/*", Path: "assets/data/file.json" -> "data/file.json"
+/// - Pattern: "target/release/binary", Path: "target/release/binary" -> "binary"
+pub fn strip_pattern_base(pattern: &str, relative_path: &Path) -> PathBuf {
+    // Find the first glob character in the pattern
+    let glob_pos = pattern.find(&['*', '?', '[', '{'][..]);
+
+    if let Some(pos) = glob_pos {
+        // Extract the base path before the glob
+        let base = &pattern[..pos];
+        // Remove trailing slashes and wildcards
+        let base = base.trim_end_matches('/');
+
+        if !base.is_empty() {
+            // Try to strip this base from the relative path
+            if let Ok(stripped) = relative_path.strip_prefix(base) {
+                return stripped.to_path_buf();
+            }
+        }
+    } else {
+        // No globs in pattern - it's an exact file match
+        // Return just the filename
+        if let Some(filename) = relative_path.file_name() {
+            return PathBuf::from(filename);
+        }
+    }
+
+    // Fallback: return the path as-is
+    relative_path.to_path_buf()
+}
+
+/// Whether `path` lies strictly below `scope`
+///
+/// Judged lexically on the paths as sent to clients: `.` components are ignored and any
+/// `..` after the scope prefix disqualifies the path, so it can't climb back out.
+pub fn within_scope(scope: &Path, path: &Path) -> bool {
+    fn normal(path: &Path) -> Vec<Component<'_>> {
+        path.components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .collect()
+    }
+    let scope = normal(scope);
+    let path = normal(path);
+
+    path.len() > scope.len()
+        && path.starts_with(&scope)
+        && path[scope.len()..]
+            .iter()
+            .all(|component| matches!(component, Component::Normal(_)))
 }
 
 #[cfg(test)]
@@ -199,5 +525,324 @@ mirror = true
         assert_eq!(rule2.include, vec!["assets/**/*"]);
         assert_eq!(rule2.exclude, vec!["**/*.psd"]);
         assert!(rule2.mirror);
+        assert!(rule2.recursive);
+    }
+
+    #[test]
+    fn test_non_recursive_rule_reaches_top_level_only() {
+        let toml = r#"
+[project]
+name = "test-project"
+
+[[sync]]
+include = ["bin/*"]
+destination = "bin/"
+recursive = false
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        let rule = &config.sync_rules[0];
+        assert!(!rule.recursive);
+        assert!(rule.reaches(Path::new("bin/game.exe")));
+        assert!(!rule.reaches(Path::new("bin/tools/editor.exe")));
+        assert!(!rule.reaches(Path::new("game.exe")));
+    }
+
+    #[test]
+    fn test_checksum_algo_parses() {
+        let toml = r#"
+[project]
+name = "checksums"
+checksum_algo = "sha256"
+
+[[sync]]
+include = ["bin/*"]
+destination = "bin/"
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        assert_eq!(config.project.checksum_algo, Some(ChecksumAlgo::Sha256));
+        assert!(toml::from_str::<Config>(&toml.replace("sha256", "md5")).is_err());
+    }
+
+    #[test]
+    fn test_mode_overrides_parse_as_octal() {
+        let toml = r#"
+[project]
+name = "modes"
+default_mode = 0o755
+
+[[sync]]
+include = ["bin/*"]
+destination = "bin/"
+file_mode = 0o750
+dir_mode = 0o700
+atomic_replace = true
+priority = 10
+
+[[sync]]
+include = ["docs/*"]
+destination = "docs/"
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        assert_eq!(config.project.default_mode, Some(0o755));
+        assert_eq!(config.project.checksum_algo, None);
+        assert_eq!(
+            config.sync_rules[0].modes(),
+            FileModes {
+                file: Some(0o750),
+                dir: Some(0o700),
+                atomic_replace: true,
+                priority: 10,
+                force: false,
+            }
+        );
+        assert_eq!(config.sync_rules[1].modes(), FileModes::default());
+    }
+
+    #[test]
+    fn test_project_env_inheritance() {
+        let toml = r#"
+[project]
+name = "env-project"
+
+[project.env]
+RUST_LOG = "info"
+RUST_BACKTRACE = "1"
+
+[[sync]]
+include = ["game.exe"]
+destination = "bin/"
+
+[sync.execute]
+command = "bin/game.exe"
+
+[sync.execute.env]
+RUST_LOG = "debug"
+GAME_MODE = "windowed"
+
+[[sync]]
+include = ["tool.exe"]
+destination = "bin/"
+
+[sync.execute]
+command = "bin/tool.exe"
+
+[[sync]]
+include = ["assets/**/*"]
+destination = "assets/"
+"#;
+
+        let mut config: Config = toml::from_str(toml).expect("Failed to parse config");
+        config.inherit_project_env();
+
+        // Rule env overrides the project value and adds its own keys
+        let game = config.sync_rules[0].execute.as_ref().unwrap();
+        assert_eq!(game.env.get("RUST_LOG").map(String::as_str), Some("debug"));
+        assert_eq!(game.env.get("RUST_BACKTRACE").map(String::as_str), Some("1"));
+        assert_eq!(game.env.get("GAME_MODE").map(String::as_str), Some("windowed"));
+
+        // A rule without its own env inherits the project env unchanged
+        let tool = config.sync_rules[1].execute.as_ref().unwrap();
+        assert_eq!(tool.env, config.project.env);
+
+        // Rules without execute are left alone
+        assert!(config.sync_rules[2].execute.is_none());
+    }
+
+    #[test]
+    fn test_invalid_glob_names_rule_and_pattern() {
+        let toml = r#"
+[project]
+name = "bad-globs"
+
+[[sync]]
+name = "binaries"
+include = ["*.exe"]
+destination = "bin/"
+
+[[sync]]
+name = "assets"
+include = ["assets/**/*"]
+exclude = ["**/*.{psd"]
+destination = "assets/"
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        let error = format!("{:#}", config.validate().unwrap_err());
+        assert!(error.contains("assets"), "{}", error);
+        assert!(error.contains("Invalid exclude pattern: **/*.{psd"), "{}", error);
+    }
+
+    #[test]
+    fn test_mirror_scope_parsing() {
+        let toml = r#"
+[project]
+name = "mirror-project"
+
+[[sync]]
+include = ["assets/**/*"]
+destination = "assets/"
+mirror_scope = "subtree"
+
+[[sync]]
+include = ["*.exe"]
+destination = "bin/"
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        assert_eq!(config.sync_rules[0].mirror_scope, MirrorScope::Subtree);
+        assert_eq!(config.sync_rules[1].mirror_scope, MirrorScope::Off);
+    }
+
+    #[test]
+    fn test_trigger_parsing() {
+        let toml = r#"
+[project]
+name = "git-project"
+
+[[sync]]
+include = ["bin/*"]
+destination = "bin/"
+trigger = "git-commit"
+
+[[sync]]
+include = ["assets/**/*"]
+destination = "assets/"
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        assert_eq!(config.sync_rules[0].trigger, Trigger::GitCommit);
+        assert_eq!(config.sync_rules[1].trigger, Trigger::Change);
+        // Refused up front rather than silently never syncing
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "git"));
+    }
+
+    #[test]
+    fn test_run_on_parsing() {
+        let toml = r#"
+[project]
+name = "service"
+
+[[sync]]
+include = ["bin/server"]
+destination = "bin/"
+
+[sync.execute]
+command = "bin/restart.sh"
+run_on = "changed"
+
+[[sync]]
+include = ["bin/tool"]
+destination = "bin/"
+
+[sync.execute]
+command = "bin/tool"
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        let run_on = |idx: usize| config.sync_rules[idx].execute.as_ref().unwrap().run_on;
+        assert_eq!(run_on(0), RunOn::Changed);
+        assert_eq!(run_on(1), RunOn::Always);
+
+        assert!(RunOn::Always.runs_after(0));
+        assert!(!RunOn::Changed.runs_after(0));
+        assert!(RunOn::Changed.runs_after(12));
+        assert!(!RunOn::Never.runs_after(12));
+    }
+
+    #[test]
+    fn test_config_cannot_mark_execute_as_self_update() {
+        let toml = r#"
+[project]
+name = "service"
+
+[[sync]]
+include = ["bin/tool"]
+destination = "bin/"
+
+[sync.execute]
+command = "bin/tool"
+args = ["apply-update"]
+self_update = true
+"#;
+
+        let config: Config = toml::from_str(toml).expect("Failed to parse config");
+        assert!(!config.sync_rules[0].execute.as_ref().unwrap().self_update);
+    }
+
+    #[test]
+    fn test_within_scope() {
+        assert!(within_scope(Path::new("assets/"), Path::new("assets/a.png")));
+        assert!(within_scope(Path::new("assets"), Path::new("./assets/ui/icons/a.png")));
+        assert!(within_scope(Path::new("."), Path::new("game.exe")));
+        assert!(within_scope(Path::new("~/games/"), Path::new("~/games/data/level1.pak")));
+
+        // The scope itself, siblings, and escapes are all outside
+        assert!(!within_scope(Path::new("assets/"), Path::new("assets")));
+        assert!(!within_scope(Path::new("assets/"), Path::new("assets-old/a.png")));
+        assert!(!within_scope(Path::new("assets/"), Path::new("assets/../bin/game.exe")));
+        assert!(!within_scope(Path::new("."), Path::new("../outside.txt")));
+        assert!(!within_scope(Path::new("assets/"), Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn test_apply_destination_base() {
+        let toml = r#"
+[project]
+name = "test"
+
+[[sync]]
+include = ["*.exe"]
+destination = "bin/"
+
+[[sync]]
+include = ["assets/**/*"]
+destination = "./assets"
+
+[[sync]]
+include = ["README.md"]
+destination = "."
+"#;
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.apply_destination_base("/opt/game/").unwrap();
+        let destinations: Vec<&str> = config.sync_rules.iter().map(|r| r.destination.as_str()).collect();
+        assert_eq!(destinations, vec!["/opt/game/bin/", "/opt/game/assets", "/opt/game/"]);
+
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.apply_destination_base("~/games").unwrap();
+        assert_eq!(config.sync_rules[0].destination, "~/games/bin/");
+
+        for destination in ["/usr/bin/", "~/bin/", "../bin/", "bin/../../etc"] {
+            let toml = format!(
+                "[project]\nname = \"test\"\n[[sync]]\ninclude = [\"*.exe\"]\ndestination = \"{}\"\n",
+                destination
+            );
+            let mut config: Config = toml::from_str(&toml).unwrap();
+            assert!(config.apply_destination_base("/opt/game").is_err(), "{} should be rejected", destination);
+        }
+    }
+
+    #[test]
+    fn test_glob_base() {
+        assert_eq!(glob_base("src/**/*.rs"), PathBuf::from("src"));
+        assert_eq!(glob_base("assets/textures/*.png"), PathBuf::from("assets/textures"));
+        assert_eq!(glob_base("target/release/app"), PathBuf::from("target/release"));
+        assert_eq!(glob_base("./docs/*.md"), PathBuf::from("docs"));
+        assert_eq!(glob_base("*.exe"), PathBuf::new());
+        assert_eq!(glob_base("**/*.rs"), PathBuf::new());
+        assert_eq!(glob_base("../outside/*.rs"), PathBuf::new());
+    }
+
+    #[test]
+    fn test_common_glob_base() {
+        let patterns = vec!["src/net/**/*.rs".to_string(), "src/ui/*.rs".to_string()];
+        assert_eq!(common_glob_base(&patterns), PathBuf::from("src"));
+
+        let patterns = vec!["src/**/*.rs".to_string(), "Cargo.toml".to_string()];
+        assert_eq!(common_glob_base(&patterns), PathBuf::new());
+
+        assert_eq!(common_glob_base(&[]), PathBuf::new());
     }
 }
@@ -0,0 +1,263 @@
+// Semantic checks for .hrlauncher.toml beyond what loading validates
+//
+// A config can parse fine and still do the wrong thing: a rule that never matches,
+// a mirror that deletes half a client, two rules fighting over one file. Linting walks
+// the project root with the same matching config-sync's watches use and flags rules
+// that look ineffective or dangerous.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, SyncRule, glob_base};
+use crate::file_watcher::WatchConfig;
+
+/// A mirror rule matching at least this many files is flagged even with narrow patterns
+pub const BROAD_MIRROR_FILES: usize = 500;
+
+/// Top-level directories of Unix, macOS and Windows systems that a destination
+/// shouldn't write into, compared case-insensitively; install locations such as
+/// `/opt` and `C:/Program Files` are fine
+const SYSTEM_DIRS: &[&str] = &[
+    "bin",
+    "boot",
+    "dev",
+    "etc",
+    "lib",
+    "lib64",
+    "proc",
+    "root",
+    "sbin",
+    "sys",
+    "usr",
+    "var",
+    "system",
+    "library",
+    "windows",
+];
+
+/// How much a finding matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The rule probably doesn't do what was meant
+    Warning,
+    /// The rule could destroy or overwrite files on clients
+    Danger,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Danger => write!(f, "danger"),
+        }
+    }
+}
+
+/// One problem found with a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub rule: String,
+    pub message: String,
+}
+
+/// Check every rule in `config` against the files under `project_root`, most severe first
+pub fn lint(config: &Config, project_root: &Path) -> Result<Vec<Finding>> {
+    let root = project_root
+        .canonicalize()
+        .context(format!("Failed to canonicalize project root: {}", project_root.display()))?;
+
+    let mut findings = Vec::new();
+    let mut matched = Vec::new();
+    for (idx, rule) in config.sync_rules.iter().enumerate() {
+        let rule_name = rule.name.clone().unwrap_or_else(|| format!("rule-{}", idx + 1));
+        let mut finding = |severity, message| {
+            findings.push(Finding {
+                severity,
+                rule: rule_name.clone(),
+                message,
+            })
+        };
+
+        let files = matching_files(rule, &root)?;
+        if files.is_empty() {
+            finding(
+                Severity::Warning,
+                format!("include patterns {:?} match no files under {}", rule.include, root.display()),
+            );
+        }
+
+        if rule.mirror {
+            let broad: Vec<&String> = rule.include.iter().filter(|pattern| is_broad_pattern(pattern)).collect();
+            if !broad.is_empty() || files.len() >= BROAD_MIRROR_FILES {
+                let reason = match broad.first() {
+                    Some(pattern) => format!("broad include {}", pattern),
+                    None => format!("{} matched files", files.len()),
+                };
+                finding(
+                    Severity::Danger,
+                    format!(
+                        "mirror = true with {}: client files under {} that aren't among them would be deleted",
+                        reason, rule.destination
+                    ),
+                );
+            }
+        }
+
+        if is_system_path(&rule.destination) {
+            finding(
+                Severity::Danger,
+                format!("destination {} is a system directory", rule.destination),
+            );
+        }
+
+        matched.push((rule_name, files));
+    }
+
+    // A file two rules deliver to different places ends up wherever the last sync put it
+    for (idx, (first_name, first_files)) in matched.iter().enumerate() {
+        for (second_name, second_files) in &matched[idx + 1..] {
+            let conflicts: Vec<_> = first_files
+                .iter()
+                .filter_map(|(file, first_dest)| {
+                    let second_dest = second_files.get(file)?;
+                    (second_dest != first_dest).then_some((file, first_dest, second_dest))
+                })
+                .collect();
+            if let Some((file, first_dest, second_dest)) = conflicts.first() {
+                findings.push(Finding {
+                    severity: Severity::Warning,
+                    rule: first_name.clone(),
+                    message: format!(
+                        "{} files are also matched by [{}] with a different destination, e.g. {} goes to {} and {}",
+                        conflicts.len(),
+                        second_name,
+                        file.display(),
+                        first_dest.display(),
+                        second_dest.display()
+                    ),
+                });
+            }
+        }
+    }
+
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    Ok(findings)
+}
+
+/// Files under `root` the rule syncs, mapped to their client paths, matched the way
+/// config-sync's watch for it does
+fn matching_files(rule: &SyncRule, root: &Path) -> Result<BTreeMap<PathBuf, PathBuf>> {
+    let mut watch_dir = root.join(rule.watch_base());
+    while !watch_dir.is_dir() && watch_dir != root {
+        watch_dir.pop();
+    }
+
+    let mut watch = WatchConfig::new(
+        root.to_path_buf(),
+        rule.recursive,
+        rule.include.clone(),
+        rule.exclude.clone(),
+        rule.case_insensitive,
+    )?;
+    if !rule.recursive {
+        watch.top_level = Some(watch_dir.clone());
+    }
+    watch.destination = Some(rule.destination.clone());
+
+    Ok(watch
+        .matching_files(&watch_dir)
+        .into_iter()
+        .map(|(relative, _)| {
+            let destination = watch.destination_path(&relative).unwrap_or_else(|| relative.clone());
+            (relative, destination)
+        })
+        .collect())
+}
+
+/// A pattern with no literal directory that matches at any depth, or every top-level file
+fn is_broad_pattern(pattern: &str) -> bool {
+    glob_base(pattern).as_os_str().is_empty() && (pattern.contains("**") || pattern == "*" || pattern == "*.*")
+}
+
+/// Whether `destination` is an absolute path at or inside a system directory, judged
+/// by its first component so it holds for either platform's paths on any server
+fn is_system_path(destination: &str) -> bool {
+    let normalized = destination.replace('\\', "/");
+    let rest = match normalized.strip_prefix('/') {
+        Some(rest) => rest,
+        None => match normalized.as_bytes() {
+            [drive, b':', b'/', ..] if drive.is_ascii_alphabetic() => &normalized[3..],
+            _ => return false,
+        },
+    };
+
+    match rest.split('/').find(|component| !component.is_empty() && *component != ".") {
+        Some(first) => SYSTEM_DIRS.contains(&first.to_ascii_lowercase().as_str()),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config(rules: &str) -> Config {
+        toml::from_str(&format!("[project]\nname = \"lint\"\n\n{}", rules)).unwrap()
+    }
+
+    #[test]
+    fn test_lint_flags_each_heuristic() {
+        let root = TempDir::new().unwrap();
+        std::fs::create_dir_all(root.path().join("bin")).unwrap();
+        std::fs::write(root.path().join("bin/game.exe"), b"exe").unwrap();
+        std::fs::write(root.path().join("readme.txt"), b"hi").unwrap();
+
+        let config = config(
+            r#"
+[[sync]]
+name = "binaries"
+include = ["bin/*.exe"]
+destination = "game/"
+
+[[sync]]
+name = "everything"
+include = ["**/*"]
+destination = "."
+mirror = true
+
+[[sync]]
+name = "docs"
+include = ["docs/*.md"]
+destination = "/etc/game/"
+"#,
+        );
+        let findings = lint(&config, root.path()).unwrap();
+        let found = |rule: &str, severity, text: &str| {
+            findings
+                .iter()
+                .any(|f| f.rule == rule && f.severity == severity && f.message.contains(text))
+        };
+
+        assert!(found("everything", Severity::Danger, "broad include **/*"));
+        assert!(found("docs", Severity::Danger, "system directory"));
+        assert!(found("docs", Severity::Warning, "match no files"));
+        assert!(found("binaries", Severity::Warning, "also matched by [everything]"));
+        assert!(!found("binaries", Severity::Warning, "match no files"));
+        assert_eq!(findings.len(), 4, "{:?}", findings);
+        assert_eq!(findings[0].severity, Severity::Danger);
+    }
+
+    #[test]
+    fn test_system_paths() {
+        for path in ["/", "/etc/game", "/usr/bin/", "C:\\Windows\\System32", "c:/windows", "/./sbin"] {
+            assert!(is_system_path(path), "{}", path);
+        }
+        for path in ["game/", "~/bin/", ".", "/opt/game", "C:/Program Files/game", "D:\\Games\\mine", "etc/"] {
+            assert!(!is_system_path(path), "{}", path);
+        }
+    }
+}
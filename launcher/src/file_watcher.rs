@@ -4,13 +4,17 @@
 // Integrates with the rsync-based file syncing system.
 
 use anyhow::{Context, Result};
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use halfremembered_protocol::WatchInfo;
-use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, event::ModifyKind};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use halfremembered_protocol::{ChecksumAlgo, WatchInfo};
+use notify::{
+    Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{MetadataKind, ModifyKind},
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak, mpsc};
 use std::time::{Duration, Instant};
 
 /// Configuration for a single watch
@@ -21,12 +25,212 @@ pub struct WatchConfig {
     /// Whether to watch recursively
     pub recursive: bool,
     /// Compiled include patterns (empty = include all)
-    pub include: GlobSet,
+    pub include: PatternSet,
     /// Compiled exclude patterns
-    pub exclude: GlobSet,
+    pub exclude: PatternSet,
+    /// Directory prefixes of exclude patterns that cover a whole subtree (`dir/**`),
+    /// used to prune walks instead of rejecting every file underneath
+    pub exclude_dirs: PatternSet,
     /// Original pattern strings for reporting
     pub include_patterns: Vec<String>,
     pub exclude_patterns: Vec<String>,
+    /// Whether patterns were compiled to ignore case
+    pub case_insensitive: bool,
+    /// Exclude patterns keep their case even when include patterns ignore it
+    pub exclude_case_sensitive: bool,
+    /// Client-side directory that matched files are synced into (default: their relative path)
+    pub destination: Option<String>,
+    /// Directories kept in front of relative paths on clients when there's no
+    /// destination: the part of the watch root below its base
+    pub base_prefix: PathBuf,
+    /// For a non-recursive directory watch, the only directory whose files match
+    pub top_level: Option<PathBuf>,
+    /// How long a newly created file must stay unchanged before it is synced (zero = sync at once)
+    pub settle: Duration,
+    /// Syncs of this watch's files start before those of lower-priority watches
+    pub priority: i32,
+    /// Order in which the watch was added; with dedup the earliest matching watch wins
+    pub added: u64,
+    /// Changes are held instead of synced until the watch is resumed
+    pub paused: bool,
+    /// The watched directory was removed; the watch resumes if it reappears
+    pub broken: bool,
+    /// Identity of the watched directory when it was last watched, to notice it being
+    /// swapped for a new directory of the same name
+    pub root_identity: Option<(u64, u64)>,
+    /// Files changed while paused, with the checksum each had before its first held
+    /// change (None if not seen before); resuming syncs only those that differ now
+    pub held: HashMap<PathBuf, Option<String>>,
+    /// Activity since the watch was added
+    pub stats: WatchStats,
+    /// Further watches on the same directory with patterns and a destination of their
+    /// own, as config-sync sets up for rules sharing a watch directory; they share this
+    /// watch's notify registration, pause state and stats
+    pub siblings: Vec<WatchConfig>,
+}
+
+/// Settings of a watch beyond its patterns, applied as it is added
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Client-side directory matched files are synced into
+    pub destination: Option<String>,
+    /// Ancestor of the watch root that client paths are kept relative to
+    pub base: Option<PathBuf>,
+    /// How long a new file must stay unchanged before it is synced
+    pub settle: Duration,
+    /// Syncs of this watch's files start before those of lower-priority watches
+    pub priority: i32,
+    /// Match exclude patterns with their case even when include patterns ignore it, as
+    /// a watch serving rules with different case settings does
+    pub exclude_case_sensitive: bool,
+}
+
+/// Activity counters for a watch, reported by list-watches
+#[derive(Debug, Clone, Default)]
+pub struct WatchStats {
+    /// Raw notify events for files this watch matches
+    pub events_seen: u64,
+    /// Events that survived debouncing and checksum deduplication
+    pub events_passed: u64,
+    /// Change or removal callbacks fired on behalf of this watch
+    pub syncs_triggered: u64,
+    pub last_triggered: Option<Instant>,
+}
+
+impl WatchStats {
+    fn record_trigger(&mut self) {
+        self.syncs_triggered += 1;
+        self.last_triggered = Some(Instant::now());
+    }
+}
+
+/// Compile glob patterns into a set; `kind` ("include" or "exclude") names them in errors
+pub fn compile_globs(patterns: &[String], kind: &str, case_insensitive: bool) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .context(format!("Invalid {} pattern: {}", kind, pattern))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .context(format!("Failed to compile {} patterns", kind))
+}
+
+/// Relative patterns, matched like .gitignore: one with a `/` against the whole
+/// relative path, one without against the file name at any depth as well
+#[derive(Debug, Clone)]
+pub struct RelativePatterns {
+    paths: GlobSet,
+    names: GlobSet,
+}
+
+impl RelativePatterns {
+    pub fn compile(patterns: &[String], kind: &str, case_insensitive: bool) -> Result<Self> {
+        let names: Vec<String> = patterns.iter().filter(|pattern| !pattern.contains('/')).cloned().collect();
+        Ok(Self {
+            paths: compile_globs(patterns, kind, case_insensitive)?,
+            names: compile_globs(&names, kind, case_insensitive)?,
+        })
+    }
+
+    /// Patterns matched only against whole paths, even without a `/`
+    fn compile_paths(patterns: &[String], kind: &str, case_insensitive: bool) -> Result<Self> {
+        Ok(Self {
+            paths: compile_globs(patterns, kind, case_insensitive)?,
+            names: GlobSet::empty(),
+        })
+    }
+
+    pub fn is_match(&self, relative: &Path) -> bool {
+        self.paths.is_match(relative) || relative.file_name().is_some_and(|name| self.names.is_match(name))
+    }
+}
+
+/// Watch patterns split by how they are matched: relative ones against the path under
+/// the watch root (see [`RelativePatterns`]), absolute ones (`/home/me/proj/src/*.rs`)
+/// against the full path
+#[derive(Debug, Clone)]
+pub struct PatternSet {
+    relative: RelativePatterns,
+    absolute: GlobSet,
+}
+
+impl PatternSet {
+    pub fn compile(patterns: &[String], kind: &str, case_insensitive: bool) -> Result<Self> {
+        let (absolute, relative): (Vec<String>, Vec<String>) =
+            patterns.iter().cloned().partition(|pattern| is_absolute_pattern(pattern));
+        Ok(Self {
+            relative: RelativePatterns::compile(&relative, kind, case_insensitive)?,
+            absolute: compile_globs(&absolute, kind, case_insensitive)?,
+        })
+    }
+
+    /// Directory prefixes from `subtree_prefixes`: `target` from `target/**` covers only
+    /// the top-level `target/`, as its pattern does, not every directory of that name
+    fn compile_subtrees(prefixes: &[String], kind: &str, case_insensitive: bool) -> Result<Self> {
+        let (absolute, relative): (Vec<String>, Vec<String>) =
+            prefixes.iter().cloned().partition(|prefix| is_absolute_pattern(prefix));
+        Ok(Self {
+            relative: RelativePatterns::compile_paths(&relative, kind, case_insensitive)?,
+            absolute: compile_globs(&absolute, kind, case_insensitive)?,
+        })
+    }
+
+    /// Whether `relative` (the path under the watch root) or `absolute` matches a pattern
+    pub fn is_match(&self, relative: &Path, absolute: &Path) -> bool {
+        self.relative.is_match(relative) || self.absolute.is_match(absolute)
+    }
+}
+
+/// Patterns rooted at `/` (or a drive on Windows) match the full path, not the relative one
+fn is_absolute_pattern(pattern: &str) -> bool {
+    Path::new(pattern).has_root()
+}
+
+/// Report a watch keyed by `watch_root` for list-watches and update-watch
+fn watch_info(watch_root: &Path, config: &WatchConfig) -> WatchInfo {
+    WatchInfo {
+        // Directory watches may be narrower than the root their patterns resolve against
+        path: if config.is_directory_watch() { watch_root } else { &config.path }
+            .to_string_lossy()
+            .to_string(),
+        recursive: config.recursive,
+        include_patterns: config.include_patterns.clone(),
+        exclude_patterns: config.exclude_patterns.clone(),
+        destination: config.destination.clone(),
+        events_seen: config.stats.events_seen,
+        events_passed: config.stats.events_passed,
+        syncs_triggered: config.stats.syncs_triggered,
+        last_triggered: config.stats.last_triggered.map(|at| at.elapsed().as_secs()),
+        paused: config.paused,
+        broken: config.broken,
+    }
+}
+
+/// The canonical `relative_to` a directory watch on `canonical` resolves patterns
+/// against, which must be an ancestor of it
+fn watch_base(canonical: &Path, relative_to: &Path) -> Result<PathBuf> {
+    let base = relative_to
+        .canonicalize()
+        .context(format!("Failed to canonicalize path: {}", relative_to.display()))?;
+    if !canonical.starts_with(&base) {
+        anyhow::bail!("{} is not under {}", canonical.display(), base.display());
+    }
+    Ok(base)
+}
+
+/// The key of the watch on `path`, including one whose directory no longer exists
+fn watch_key(watches: &HashMap<PathBuf, WatchConfig>, path: &Path) -> Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(canonical) => Ok(canonical),
+        Err(e) => match std::path::absolute(path) {
+            Ok(absolute) if watches.get(&absolute).is_some_and(|config| config.broken) => Ok(absolute),
+            _ => Err(e).context(format!("Failed to canonicalize path: {}", path.display())),
+        },
+    }
 }
 
 impl WatchConfig {
@@ -36,61 +240,242 @@ impl WatchConfig {
         recursive: bool,
         include_patterns: Vec<String>,
         exclude_patterns: Vec<String>,
+        case_insensitive: bool,
     ) -> Result<Self> {
-        // Compile include patterns
-        let mut include_builder = GlobSetBuilder::new();
-        for pattern in &include_patterns {
-            let glob = Glob::new(pattern)
-                .context(format!("Invalid include pattern: {}", pattern))?;
-            include_builder.add(glob);
-        }
-        let include = include_builder
-            .build()
-            .context("Failed to compile include patterns")?;
-
-        // Compile exclude patterns
-        let mut exclude_builder = GlobSetBuilder::new();
-        for pattern in &exclude_patterns {
-            let glob = Glob::new(pattern)
-                .context(format!("Invalid exclude pattern: {}", pattern))?;
-            exclude_builder.add(glob);
-        }
-        let exclude = exclude_builder
-            .build()
-            .context("Failed to compile exclude patterns")?;
+        let include = PatternSet::compile(&include_patterns, "include", case_insensitive)?;
+        let exclude = PatternSet::compile(&exclude_patterns, "exclude", case_insensitive)?;
+        let exclude_dirs = PatternSet::compile_subtrees(
+            &subtree_prefixes(&exclude_patterns),
+            "exclude",
+            case_insensitive,
+        )?;
 
         Ok(Self {
             path,
             recursive,
             include,
             exclude,
+            exclude_dirs,
             include_patterns,
             exclude_patterns,
+            case_insensitive,
+            exclude_case_sensitive: false,
+            destination: None,
+            base_prefix: PathBuf::new(),
+            top_level: None,
+            settle: Duration::ZERO,
+            priority: 0,
+            added: 0,
+            paused: false,
+            broken: false,
+            root_identity: None,
+            held: HashMap::new(),
+            stats: WatchStats::default(),
+            siblings: Vec::new(),
         })
     }
 
-    /// Check if a path matches this watch's filters
+    /// This watch followed by its siblings
+    fn routes(&self) -> impl Iterator<Item = &WatchConfig> {
+        std::iter::once(self).chain(&self.siblings)
+    }
+
+    /// Where a file reported under this watch goes, once per route (this watch or a
+    /// sibling) matching it: (client path if not the relative path as-is, priority)
+    pub fn targets(&self, relative: &Path) -> Vec<(Option<PathBuf>, i32)> {
+        let absolute = self.path.join(relative);
+        self.routes()
+            .filter(|route| route.matches_alone(&absolute))
+            .map(|route| (route.destination_path(relative), route.priority))
+            .collect()
+    }
+
+    /// Take the settings in `options`; `base` must be an ancestor of the watch root
+    fn apply(&mut self, options: WatchOptions) -> Result<()> {
+        if let Some(base) = options.base {
+            self.set_base(&base)?;
+        }
+        self.destination = options.destination;
+        self.settle = options.settle;
+        self.priority = options.priority;
+        if options.exclude_case_sensitive {
+            self.exclude_case_sensitive = true;
+            self.compile_excludes()?;
+        }
+        Ok(())
+    }
+
+    /// Compile the exclude patterns again, ignoring case only if the watch's excludes do
+    fn compile_excludes(&mut self) -> Result<()> {
+        let case_insensitive = self.case_insensitive && !self.exclude_case_sensitive;
+        self.exclude = PatternSet::compile(&self.exclude_patterns, "exclude", case_insensitive)?;
+        self.exclude_dirs = PatternSet::compile_subtrees(
+            &subtree_prefixes(&self.exclude_patterns),
+            "exclude",
+            case_insensitive,
+        )?;
+        Ok(())
+    }
+
+    /// Client path for a matched file: under `destination` with the include pattern's
+    /// literal base stripped, as for config sync rules, or under the watch's base prefix.
+    /// None when the relative path is used as-is.
+    pub fn destination_path(&self, relative: &Path) -> Option<PathBuf> {
+        let Some(destination) = self.destination.as_ref() else {
+            return (!self.base_prefix.as_os_str().is_empty()).then(|| self.base_prefix.join(relative));
+        };
+        let pattern = self.include_patterns.first().map(|s| s.as_str()).unwrap_or("");
+        // An absolute pattern's literal base is an absolute directory
+        let stripped = if is_absolute_pattern(pattern) {
+            crate::config::strip_pattern_base(pattern, &self.path.join(relative))
+        } else {
+            crate::config::strip_pattern_base(pattern, relative)
+        };
+        Some(PathBuf::from(destination).join(stripped))
+    }
+
+    /// Keep client paths relative to `base`, an ancestor of the watch root
+    fn set_base(&mut self, base: &Path) -> Result<()> {
+        let base = base
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", base.display()))?;
+        let prefix = self
+            .path
+            .strip_prefix(&base)
+            .context(format!("{} is not under {}", self.path.display(), base.display()))?;
+        self.base_prefix = prefix.to_path_buf();
+        Ok(())
+    }
+
+    /// Whether this watches a directory rather than a single file in it
+    pub fn is_directory_watch(&self) -> bool {
+        self.recursive || self.top_level.is_some()
+    }
+
+    /// Check if a path matches the filters of this watch or one of its siblings
     pub fn matches(&self, path: &Path) -> bool {
+        self.routes().any(|route| route.matches_alone(path))
+    }
+
+    /// Check if a path matches this watch's own filters
+    fn matches_alone(&self, path: &Path) -> bool {
         // Get relative path from watch root
         let relative = match path.strip_prefix(&self.path) {
             Ok(rel) => rel,
             Err(_) => return false, // Not under this watch root
         };
 
-        let relative_str = relative.to_string_lossy().to_string();
+        // Another watch's events can reach below a single-level watch
+        if let Some(top_level) = &self.top_level
+            && path.parent() != Some(top_level.as_path())
+        {
+            return false;
+        }
 
         // If include patterns specified, must match at least one
-        if !self.include_patterns.is_empty() && !self.include.is_match(&relative_str) {
+        if !self.include_patterns.is_empty() && !self.include.is_match(relative, path) {
             return false;
         }
 
         // Must not match any exclude pattern
-        if self.exclude.is_match(&relative_str) {
+        if self.exclude.is_match(relative, path) {
             return false;
         }
 
         true
     }
+
+    /// Check if a directory can be skipped entirely because an exclude pattern covers
+    /// everything beneath it, for this watch and every sibling
+    pub fn excludes_dir(&self, path: &Path) -> bool {
+        self.routes().all(|route| match path.strip_prefix(&route.path) {
+            Ok(relative) if !relative.as_os_str().is_empty() => route.exclude_dirs.is_match(relative, path),
+            _ => false,
+        })
+    }
+
+    /// Walk a watched directory, pruning excluded subtrees rather than descending into them
+    fn walk(&self, root: &Path) -> impl Iterator<Item = walkdir::DirEntry> {
+        let walker = if self.recursive {
+            walkdir::WalkDir::new(root)
+        } else {
+            walkdir::WalkDir::new(root).max_depth(1)
+        };
+
+        walker
+            .into_iter()
+            .filter_entry(|entry| !(entry.file_type().is_dir() && self.excludes_dir(entry.path())))
+            .filter_map(|e| e.ok())
+    }
+
+    /// Files under the directory `dir` that this watch matches, as (relative_path, absolute_path)
+    pub fn matching_files(&self, dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+        let mut files = Vec::new();
+        for entry in self.walk(dir) {
+            let p = entry.path();
+            if p.is_file() && self.matches(p) {
+                match p.strip_prefix(&self.path) {
+                    Ok(rel) => files.push((rel.to_path_buf(), p.to_path_buf())),
+                    Err(_) => log::warn!("Failed to compute relative path for: {}", p.display()),
+                }
+            }
+        }
+        files
+    }
+
+    /// Directories below `dir` that hold no files this watch matches and no
+    /// subdirectories, as (relative_path, absolute_path)
+    ///
+    /// Creating these on a client, along with the matched files, recreates the whole
+    /// directory layout. A non-recursive watch doesn't cover subdirectories, so it has none.
+    pub fn empty_dirs(&self, dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+        if !self.recursive {
+            return Vec::new();
+        }
+
+        let mut dirs = Vec::new();
+        let mut occupied = HashSet::new();
+        for entry in self.walk(dir) {
+            let p = entry.path();
+            if entry.depth() == 0 {
+                continue;
+            }
+            if entry.file_type().is_dir() {
+                dirs.push(p.to_path_buf());
+            } else if !(p.is_file() && self.matches(p)) {
+                continue;
+            }
+            if let Some(parent) = p.parent() {
+                occupied.insert(parent.to_path_buf());
+            }
+        }
+
+        dirs.into_iter()
+            .filter(|p| !occupied.contains(p))
+            .filter_map(|p| match p.strip_prefix(&self.path) {
+                Ok(rel) => Some((rel.to_path_buf(), p.clone())),
+                Err(_) => {
+                    log::warn!("Failed to compute relative path for: {}", p.display());
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Prefixes of patterns shaped `prefix/**` or `prefix/**/*`, which match every path under
+/// a directory matching `prefix`. Other patterns can't safely prune a directory.
+fn subtree_prefixes(patterns: &[String]) -> Vec<String> {
+    patterns
+        .iter()
+        .filter_map(|pattern| {
+            pattern
+                .strip_suffix("/**/*")
+                .or_else(|| pattern.strip_suffix("/**"))
+        })
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 /// State tracking for each watched file
@@ -102,21 +487,516 @@ struct FileState {
     last_checksum: String,
 }
 
-/// Compute SHA-256 checksum of file data (synchronous version for std::thread context)
-fn compute_checksum_sync(data: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    format!("{:x}", hasher.finalize())
+/// Checksum-deduplicate a change to `path` and report it for every watch that matches
+fn process_change(path: PathBuf, shared: &ChangeState, on_change: &Mutex<ChangeCallback>) {
+    // Filter 4: Checksum-based deduplication
+    let algo = *shared.checksum_algo.lock().unwrap();
+    let current_checksum = match std::fs::read(&path) {
+        Ok(data) => crate::rsync_utils::compute_checksum(algo, &data),
+        Err(e) => {
+            log::warn!("Failed to read {} for checksum: {:#}", path.display(), e);
+            return;
+        }
+    };
+
+    // Checksum before this change, for paused watches to hold
+    let previous = {
+        let mut states = shared.file_states.lock().unwrap();
+        if let Some(state) = states.get_mut(&path) {
+            state.last_event_time = Instant::now();
+            if state.last_checksum == current_checksum {
+                log::trace!("⏭️  Skipping {} (checksum unchanged: {})", path.display(), &current_checksum[..8]);
+                return;
+            }
+            Some(std::mem::replace(&mut state.last_checksum, current_checksum.clone()))
+        } else {
+            states.insert(path.clone(), FileState {
+                last_event_time: Instant::now(),
+                last_checksum: current_checksum.clone(),
+            });
+            None
+        }
+    };
+
+    // Check if file matches any watch pattern before logging/syncing
+    let mut watches = shared.watches.lock().unwrap();
+    let mut matched: Vec<(u64, PathBuf, PathBuf)> = Vec::new();
+    let mut held = false;
+    for (watch_root, config) in watches.iter_mut() {
+        if config.matches(&path) {
+            config.stats.events_passed += 1;
+
+            // Checksums stay current while paused, so resuming syncs only net changes
+            if config.paused {
+                config.held.entry(path.clone()).or_insert_with(|| previous.clone());
+                held = true;
+                continue;
+            }
+
+            // Compute relative path using config.path (not watch_root key)
+            // For single files, watch_root is the file itself, but config.path is the parent
+            if let Ok(relative) = path.strip_prefix(&config.path) {
+                matched.push((config.added, watch_root.clone(), relative.to_path_buf()));
+            }
+        }
+    }
+
+    if matched.is_empty() {
+        if held {
+            log::debug!("⏸️  Holding {} for a paused watch", path.display());
+        } else {
+            log::trace!("⏭️  Skipping {} (no matching patterns)", path.display());
+        }
+        return;
+    }
+
+    // Log only files that match patterns
+    if let Some(previous) = &previous {
+        log::info!("📝 File changed: {} (checksum: {} → {})", path.display(), &previous[..8], &current_checksum[..8]);
+    } else {
+        log::info!("📝 New file: {} (checksum: {})", path.display(), &current_checksum[..8]);
+    }
+
+    matched.sort();
+    if matched.len() > 1 {
+        let roots: Vec<String> = matched
+            .iter()
+            .map(|(_, watch_root, _)| watch_root.display().to_string())
+            .collect();
+        if shared.dedup.load(Ordering::Relaxed) {
+            log::warn!(
+                "📎 {} matches {} watches ({}); dedup is on, syncing only for {}",
+                path.display(),
+                matched.len(),
+                roots.join(", "),
+                roots[0]
+            );
+            matched.truncate(1);
+        } else {
+            log::info!(
+                "📎 {} matches {} watches, syncing for each: {}",
+                path.display(),
+                matched.len(),
+                roots.join(", ")
+            );
+        }
+    }
+
+    for (_, watch_root, _) in &matched {
+        if let Some(config) = watches.get_mut(watch_root) {
+            config.stats.record_trigger();
+        }
+    }
+    // Release the table before calling out so a callback that adds or
+    // removes watches can't deadlock against this handler
+    drop(watches);
+
+    for (_, watch_root, relative) in matched {
+        (on_change.lock().unwrap())(watch_root, relative, path.clone());
+    }
+}
+
+/// Longest settle period among the watches matching `path`
+fn settle_for(watches: &Mutex<HashMap<PathBuf, WatchConfig>>, path: &Path) -> Duration {
+    watches
+        .lock()
+        .unwrap()
+        .values()
+        .flat_map(WatchConfig::routes)
+        .filter(|route| route.matches_alone(path))
+        .map(|route| route.settle)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Settle periods a new file may keep changing over before it is synced as it is, so
+/// one that is written to constantly (a log, say) isn't waited on forever
+const MAX_SETTLE_ROUNDS: u32 = 10;
+
+/// A look at a file that is due later, taken on the watcher's timer thread
+enum DelayedCheck {
+    /// A change's debounce window has ended
+    Debounced(PathBuf),
+    /// A new file is settling: its checksum at the last look (None before the first)
+    /// and how many looks it has had
+    Settling {
+        path: PathBuf,
+        settle: Duration,
+        previous: Option<String>,
+        round: u32,
+    },
+}
+
+/// Delayed checks of every debouncing or settling file, run in deadline order on one
+/// timer thread rather than a sleeping thread per file
+struct DelayQueue {
+    sender: mpsc::Sender<(Instant, DelayedCheck)>,
+}
+
+impl DelayQueue {
+    fn spawn(shared: ChangeState, on_change: Arc<Mutex<ChangeCallback>>, workers: Arc<Option<ChangeWorkers>>) -> Self {
+        let (sender, receiver) = mpsc::channel::<(Instant, DelayedCheck)>();
+        // Ends once the FileWatcher, and with it the notify handler holding the sender, is dropped
+        std::thread::spawn(move || {
+            // Keyed by deadline, then arrival, so checks due at once keep their order
+            let mut pending: BTreeMap<(Instant, u64), DelayedCheck> = BTreeMap::new();
+            let mut arrivals = 0u64;
+            loop {
+                let received = match pending.keys().next() {
+                    Some((due, _)) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+                    None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok((due, check)) => {
+                        pending.insert((due, arrivals), check);
+                        arrivals += 1;
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+
+                while let Some(entry) = pending.first_entry() {
+                    if entry.key().0 > Instant::now() {
+                        break;
+                    }
+                    if let Some((due, check)) = run_delayed_check(entry.remove(), &shared, &on_change, &workers) {
+                        pending.insert((due, arrivals), check);
+                        arrivals += 1;
+                    }
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    /// Take `check` once `due` has passed, unless its file already has one pending
+    fn schedule(&self, due: Instant, check: DelayedCheck, shared: &ChangeState) {
+        let path = match &check {
+            DelayedCheck::Debounced(path) | DelayedCheck::Settling { path, .. } => path.clone(),
+        };
+        if !shared.settling.lock().unwrap().insert(path.clone()) {
+            return;
+        }
+        if self.sender.send((due, check)).is_err() {
+            shared.settling.lock().unwrap().remove(&path);
+            log::error!("Timer for delayed checks has stopped; not checking {}", path.display());
+        }
+    }
+}
+
+/// Take a delayed check that is due; a file still settling comes back to be looked at
+/// again when it is next due
+fn run_delayed_check(
+    check: DelayedCheck,
+    shared: &ChangeState,
+    on_change: &Mutex<ChangeCallback>,
+    workers: &Option<ChangeWorkers>,
+) -> Option<(Instant, DelayedCheck)> {
+    let (path, settle, previous, round) = match check {
+        DelayedCheck::Debounced(path) => {
+            shared.settling.lock().unwrap().remove(&path);
+            check_change(path, shared, on_change, workers);
+            return None;
+        }
+        DelayedCheck::Settling { path, settle, previous, round } => (path, settle, previous, round),
+    };
+
+    let algo = *shared.checksum_algo.lock().unwrap();
+    let Some(current) = std::fs::read(&path)
+        .ok()
+        .map(|data| crate::rsync_utils::compute_checksum(algo, &data))
+    else {
+        // Gone before it settled
+        shared.settling.lock().unwrap().remove(&path);
+        return None;
+    };
+
+    if previous.as_ref() != Some(&current) {
+        if round < MAX_SETTLE_ROUNDS {
+            if previous.is_some() {
+                log::debug!("⏳ {} still changing, waiting another {:?}", path.display(), settle);
+            }
+            let check = DelayedCheck::Settling {
+                path,
+                settle,
+                previous: Some(current),
+                round: round + 1,
+            };
+            return Some((Instant::now() + settle, check));
+        }
+        log::warn!(
+            "⏳ {} is still changing after {} settle periods of {:?}; syncing it as it is",
+            path.display(),
+            MAX_SETTLE_ROUNDS,
+            settle
+        );
+    }
+    shared.settling.lock().unwrap().remove(&path);
+    check_change(path, shared, on_change, workers);
+    None
+}
+
+/// Events for a file this soon after the last one are folded into a single check
+const DEBOUNCE_WINDOW: Duration = Duration::from_millis(100);
+
+/// How often directory watch roots are checked for removal and reappearance
+const ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The notify watcher, shared with the thread that re-establishes broken watches
+type SharedWatcher = Arc<Mutex<Box<dyn Watcher + Send + Sync>>>;
+
+/// Device and inode of the directory at `path`, or None if there is no directory.
+/// Elsewhere than Unix only existence is tracked.
+fn root_identity(path: &Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::metadata(path).ok().filter(|metadata| metadata.is_dir())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Some((0, 0))
+    }
+}
+
+/// Mark directory watches whose root was removed as broken, and watch the directory
+/// again once it reappears or is swapped for a new one (as atomic rebuilds do),
+/// syncing the files it holds. Runs until the FileWatcher is dropped.
+///
+/// Notify stops delivering events for a removed directory, and after a rename keeps
+/// following the old one, so neither case can be caught from events alone.
+fn monitor_roots(
+    shared: ChangeState,
+    on_change: Arc<Mutex<ChangeCallback>>,
+    workers: Arc<Option<ChangeWorkers>>,
+    watcher: Weak<Mutex<Box<dyn Watcher + Send + Sync>>>,
+) {
+    loop {
+        std::thread::sleep(ROOT_CHECK_INTERVAL);
+        let Some(watcher) = watcher.upgrade() else {
+            return;
+        };
+
+        // Notify is called without the table locked: its event thread may be waiting
+        // on the table in the handler
+        let mut removed = Vec::new();
+        let mut restored = Vec::new();
+        for (watch_root, config) in shared.watches.lock().unwrap().iter_mut() {
+            if !config.is_directory_watch() {
+                continue;
+            }
+            let identity = root_identity(watch_root);
+            match identity {
+                None if !config.broken => {
+                    config.broken = true;
+                    removed.push(watch_root.clone());
+                }
+                Some(_) if config.broken || identity != config.root_identity => {
+                    restored.push((watch_root.clone(), config.recursive));
+                }
+                _ => {}
+            }
+        }
+
+        for watch_root in removed {
+            log::warn!(
+                "⚠️  Watched directory {} was removed; watching resumes if it reappears",
+                watch_root.display()
+            );
+            let _ = watcher.lock().unwrap().unwatch(&watch_root);
+        }
+
+        for (watch_root, recursive) in restored {
+            let mode = if recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            let result = {
+                let mut watcher = watcher.lock().unwrap();
+                let _ = watcher.unwatch(&watch_root);
+                watcher.watch(&watch_root, mode)
+            };
+
+            let files = {
+                let mut watches = shared.watches.lock().unwrap();
+                let Some(config) = watches.get_mut(&watch_root) else {
+                    // Removed meanwhile
+                    let _ = watcher.lock().unwrap().unwatch(&watch_root);
+                    continue;
+                };
+                if let Err(e) = result {
+                    // Retried on the next check
+                    log::warn!("Failed to watch {} again: {:?}", watch_root.display(), e);
+                    config.broken = true;
+                    continue;
+                }
+                config.broken = false;
+                config.root_identity = root_identity(&watch_root);
+                config.matching_files(&watch_root)
+            };
+            log::info!(
+                "👁️  Watching {} again ({} matching files)",
+                watch_root.display(),
+                files.len()
+            );
+            // Checksums from before still apply, so only files that differ are synced
+            for (_, absolute) in files {
+                check_change(absolute, &shared, &on_change, &workers);
+            }
+        }
+    }
+}
+
+/// Check a change to `path` on the worker that owns it if there are change workers,
+/// else on this thread; either way one file is never checked on two threads at once
+/// when workers are in use
+fn check_change(path: PathBuf, shared: &ChangeState, on_change: &Mutex<ChangeCallback>, workers: &Option<ChangeWorkers>) {
+    match workers {
+        Some(workers) => workers.dispatch(path, shared),
+        None => process_change(path, shared, on_change),
+    }
+}
+
+/// Callback for removed files: (watch_root, relative_path, absolute_path)
+type RemoveCallback = Box<dyn FnMut(PathBuf, PathBuf, PathBuf) + Send>;
+
+/// Callback for changed files, with the same arguments; shared with the timer thread
+/// and change workers
+type ChangeCallback = Box<dyn FnMut(PathBuf, PathBuf, PathBuf) + Send>;
+
+/// State the notify handler, timer thread and change workers process changes against
+#[derive(Clone)]
+struct ChangeState {
+    watches: Arc<Mutex<HashMap<PathBuf, WatchConfig>>>,
+    file_states: Arc<Mutex<HashMap<PathBuf, FileState>>>,
+    dedup: Arc<AtomicBool>,
+    /// Files with a delayed check pending: new files settling, or debounced changes
+    settling: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Hash used to tell real changes from rewrites of the same content
+    checksum_algo: Arc<Mutex<ChecksumAlgo>>,
+    /// Files waiting for a change worker
+    queued: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Threads that read, checksum and match changed files so notify's event thread doesn't
+/// have to, keeping it free to take events during a burst of large writes
+///
+/// A file always goes to the same worker, so its changes are handled in the order they
+/// happened, and isn't queued again while a check is already waiting: that check reads
+/// whatever the file holds once it runs.
+struct ChangeWorkers {
+    senders: Vec<mpsc::Sender<PathBuf>>,
+}
+
+impl ChangeWorkers {
+    fn spawn(count: usize, shared: &ChangeState, on_change: &Arc<Mutex<ChangeCallback>>) -> Self {
+        let senders = (0..count)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel::<PathBuf>();
+                let shared = shared.clone();
+                let on_change = Arc::clone(on_change);
+                // Ends once the FileWatcher, and with it the sender, is dropped
+                std::thread::spawn(move || {
+                    for path in receiver {
+                        shared.queued.lock().unwrap().remove(&path);
+                        process_change(path, &shared, &on_change);
+                    }
+                });
+                sender
+            })
+            .collect();
+        Self { senders }
+    }
+
+    /// Queue a check of `path` on the worker that owns it
+    fn dispatch(&self, path: PathBuf, shared: &ChangeState) {
+        if !shared.queued.lock().unwrap().insert(path.clone()) {
+            log::trace!("⏭️  {} already queued for a check", path.display());
+            return;
+        }
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let worker = (hasher.finish() % self.senders.len() as u64) as usize;
+        if let Err(mpsc::SendError(path)) = self.senders[worker].send(path) {
+            shared.queued.lock().unwrap().remove(&path);
+            log::error!("Change worker for {} has stopped", path.display());
+        }
+    }
+}
+
+/// File name prefix of the temporary files `probe_events` writes; their events are never synced
+const PROBE_PREFIX: &str = ".hrlauncher-probe-";
+
+/// Pending event probes: probe file path to the sender signalled when its event arrives
+type ProbeMap = Arc<Mutex<HashMap<PathBuf, tokio::sync::oneshot::Sender<()>>>>;
+
+/// A probe file written by `FileWatcher::probe_events`, removed when dropped
+pub struct EventProbe {
+    path: PathBuf,
+    receiver: tokio::sync::oneshot::Receiver<()>,
+    probes: ProbeMap,
+}
+
+impl EventProbe {
+    /// Wait up to `timeout` for the probe's event; it needs no access to the watcher
+    pub async fn wait(mut self, timeout: Duration) -> Result<()> {
+        tokio::time::timeout(timeout, &mut self.receiver)
+            .await
+            .map_err(|_| anyhow::anyhow!("No filesystem event arrived within {:?}", timeout))?
+            .context("Filesystem watcher stopped")
+    }
+}
+
+impl Drop for EventProbe {
+    fn drop(&mut self) {
+        self.probes.lock().unwrap().remove(&self.path);
+        if let Err(e) = std::fs::remove_file(&self.path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::warn!("Failed to remove probe file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn is_probe(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(PROBE_PREFIX))
+}
+
+/// How the watcher learns that files changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatchMode {
+    /// Platform-native events (inotify, ReadDirectoryChangesW, FSEvents)
+    #[default]
+    Native,
+    /// Rescan watched paths at this interval, for filesystems that don't deliver events
+    /// (NFS, SMB, some container overlays)
+    Poll(Duration),
 }
 
 /// Filesystem watcher that triggers automatic file syncing
 pub struct FileWatcher {
     /// Active watch configurations indexed by canonical path
     watches: Arc<Mutex<HashMap<PathBuf, WatchConfig>>>,
+    /// Callback for watched files that change, kept to replay held changes on resume
+    on_change: Arc<Mutex<ChangeCallback>>,
+    /// Optional callback for watched files that are deleted
+    on_remove: Arc<Mutex<Option<RemoveCallback>>>,
+    /// Outstanding `probe_events` probes
+    probes: ProbeMap,
+    /// Sync a file matched by several watches only for the first one added
+    dedup: Arc<AtomicBool>,
+    /// Hash for change detection
+    checksum_algo: Arc<Mutex<ChecksumAlgo>>,
+    /// `added` value for the next watch
+    next_added: u64,
     /// Per-file state for debouncing and checksum tracking
     file_states: Arc<Mutex<HashMap<PathBuf, FileState>>>,
-    /// The underlying notify watcher
-    _watcher: RecommendedWatcher,
+    /// The underlying notify watcher, native or polling
+    watcher: SharedWatcher,
 }
 
 impl FileWatcher {
@@ -124,140 +1004,283 @@ impl FileWatcher {
     ///
     /// The callback receives (watch_root, relative_path, absolute_path) for each
     /// file that changes and passes filters (time-based debouncing + checksum verification).
-    pub fn new<F>(mut on_change: F) -> Result<Self>
+    ///
+    /// With `change_workers` above 0, changed files are read, checksummed and matched on
+    /// that many worker threads instead of notify's event thread, so a burst of large
+    /// writes doesn't delay the events behind it.
+    pub fn new<F>(mode: WatchMode, change_workers: usize, on_change: F) -> Result<Self>
     where
         F: FnMut(PathBuf, PathBuf, PathBuf) + Send + 'static,
     {
         let watches: Arc<Mutex<HashMap<PathBuf, WatchConfig>>> = Arc::new(Mutex::new(HashMap::new()));
-        let watches_clone = Arc::clone(&watches);
 
         let file_states: Arc<Mutex<HashMap<PathBuf, FileState>>> = Arc::new(Mutex::new(HashMap::new()));
-        let file_states_clone = Arc::clone(&file_states);
-
-        // Create raw notify watcher with custom event handler
-        let watcher = RecommendedWatcher::new(
-            move |result: Result<Event, notify::Error>| {
-                match result {
-                    Ok(event) => {
-                        // Filter 1: Only process data modification and file creation events
-                        // Create events are needed because cargo uses hardlinks for final binaries
-                        if !matches!(event.kind, EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(_)) {
-                            log::trace!("Ignoring non-data/create event: {:?}", event.kind);
-                            return;
-                        }
 
-                        for path in event.paths {
-                            // Only process regular files
-                            if !path.is_file() {
-                                continue;
-                            }
+        let on_change: Arc<Mutex<ChangeCallback>> = Arc::new(Mutex::new(Box::new(on_change)));
+        let on_change_clone = Arc::clone(&on_change);
 
-                            // Filter 2: Time-based debounce (100ms window)
-                            let should_process = {
-                                let states = file_states_clone.lock().unwrap();
-                                if let Some(state) = states.get(&path) {
-                                    if state.last_event_time.elapsed() < Duration::from_millis(100) {
-                                        log::trace!("⏱️  Debouncing {}", path.display());
-                                        return;
-                                    }
-                                }
-                                true
-                            };
+        let on_remove: Arc<Mutex<Option<RemoveCallback>>> = Arc::new(Mutex::new(None));
+        let on_remove_clone = Arc::clone(&on_remove);
+
+        let probes: ProbeMap = Arc::new(Mutex::new(HashMap::new()));
+        let probes_clone = Arc::clone(&probes);
+
+        let dedup = Arc::new(AtomicBool::new(false));
+        let checksum_algo = Arc::new(Mutex::new(ChecksumAlgo::default()));
 
-                            if !should_process {
+        let shared = ChangeState {
+            watches: Arc::clone(&watches),
+            file_states: Arc::clone(&file_states),
+            dedup: Arc::clone(&dedup),
+            settling: Arc::new(Mutex::new(HashSet::new())),
+            checksum_algo: Arc::clone(&checksum_algo),
+            queued: Arc::new(Mutex::new(HashSet::new())),
+        };
+        let workers = Arc::new((change_workers > 0).then(|| ChangeWorkers::spawn(change_workers, &shared, &on_change)));
+        let workers_clone = Arc::clone(&workers);
+        let delayed = DelayQueue::spawn(shared.clone(), Arc::clone(&on_change), Arc::clone(&workers));
+
+        let root_shared = shared.clone();
+        let root_on_change = Arc::clone(&on_change);
+        let root_workers = Arc::clone(&workers);
+
+        // Write-time changes from the poller still pass through the checksum filter below
+        let polling = matches!(mode, WatchMode::Poll(_));
+
+        // Raw notify event handler shared by both watcher kinds
+        let handler = move |result: Result<Event, notify::Error>| {
+            match result {
+                Ok(event) => {
+                    // Self-test probes only confirm that events are delivered
+                    if event.paths.iter().any(|path| is_probe(path)) {
+                        let mut probes = probes_clone.lock().unwrap();
+                        for path in &event.paths {
+                            if let Some(sender) = probes.remove(path) {
+                                let _ = sender.send(());
+                            }
+                        }
+                        return;
+                    }
+
+                    if matches!(event.kind, EventKind::Remove(_)) {
+                        for path in &event.paths {
+                            // Replaced before the event arrived (e.g. an atomic save): not gone
+                            if path.exists() {
                                 continue;
                             }
 
-                            // Filter 3: Checksum-based deduplication
-                            let current_checksum = match std::fs::read(&path) {
-                                Ok(data) => compute_checksum_sync(&data),
-                                Err(e) => {
-                                    log::warn!("Failed to read {} for checksum: {:#}", path.display(), e);
+                            // Forget the checksum so a recreated file syncs again
+                            let previous = shared
+                                .file_states
+                                .lock()
+                                .unwrap()
+                                .remove(path)
+                                .map(|state| state.last_checksum);
+
+                            // Reported for every matching watch, like changes, and deduped the same way
+                            let mut watches = shared.watches.lock().unwrap();
+                            let mut matched: Vec<(u64, PathBuf, PathBuf)> = Vec::new();
+                            let mut held = false;
+                            for (watch_root, config) in watches.iter_mut() {
+                                if !config.matches(path) {
                                     continue;
                                 }
-                            };
-
-                            let should_callback = {
-                                let mut states = file_states_clone.lock().unwrap();
-                                if let Some(state) = states.get_mut(&path) {
-                                    if state.last_checksum == current_checksum {
-                                        log::trace!("⏭️  Skipping {} (checksum unchanged: {})", path.display(), &current_checksum[..8]);
-                                        state.last_event_time = Instant::now();
-                                        false
-                                    } else {
-                                        state.last_event_time = Instant::now();
-                                        state.last_checksum = current_checksum.clone();
-                                        true
-                                    }
-                                } else {
-                                    states.insert(path.clone(), FileState {
-                                        last_event_time: Instant::now(),
-                                        last_checksum: current_checksum.clone(),
-                                    });
-                                    true
+                                config.stats.events_seen += 1;
+                                config.stats.events_passed += 1;
+                                if config.paused {
+                                    config.held.entry(path.clone()).or_insert_with(|| previous.clone());
+                                    held = true;
+                                } else if let Ok(relative) = path.strip_prefix(&config.path) {
+                                    matched.push((config.added, watch_root.clone(), relative.to_path_buf()));
                                 }
-                            };
-
-                            if !should_callback {
+                            }
+                            if matched.is_empty() && !held {
                                 continue;
                             }
+                            log::info!("🗑️  File removed: {}", path.display());
 
-                            // Check if file matches any watch pattern before logging/syncing
-                            let watches = watches_clone.lock().unwrap();
-                            let mut matched = false;
-                            for (watch_root, config) in watches.iter() {
-                                if config.matches(&path) {
-                                    matched = true;
-
-                                    // Compute relative path using config.path (not watch_root key)
-                                    // For single files, watch_root is the file itself, but config.path is the parent
-                                    let relative = match path.strip_prefix(&config.path) {
-                                        Ok(rel) => rel.to_path_buf(),
-                                        Err(_) => continue,
-                                    };
-
-                                    // Log only files that match patterns
-                                    let states = file_states_clone.lock().unwrap();
-                                    if let Some(state) = states.get(&path) {
-                                        log::info!("📝 File changed: {} (checksum: {} → {})", path.display(), &state.last_checksum[..8], &current_checksum[..8]);
-                                    } else {
-                                        log::info!("📝 New file: {} (checksum: {})", path.display(), &current_checksum[..8]);
-                                    }
-
-                                    // Call the sync callback
-                                    on_change(watch_root.clone(), relative, path.clone());
-                                    break; // Only process once per file
+                            matched.sort();
+                            if shared.dedup.load(Ordering::Relaxed) {
+                                matched.truncate(1);
+                            }
+                            let mut on_remove = on_remove_clone.lock().unwrap();
+                            let Some(on_remove) = on_remove.as_mut() else {
+                                continue;
+                            };
+                            for (_, watch_root, _) in &matched {
+                                if let Some(config) = watches.get_mut(watch_root) {
+                                    config.stats.record_trigger();
                                 }
                             }
+                            // Release the table before calling out, as for changes
+                            drop(watches);
 
-                            if !matched {
-                                log::trace!("⏭️  Skipping {} (no matching patterns)", path.display());
+                            for (_, watch_root, relative) in matched {
+                                on_remove(watch_root, relative, path.clone());
                             }
                         }
+                        return;
                     }
-                    Err(e) => {
-                        log::error!("Filesystem watch error: {:?}", e);
+
+                    // Filter 1: Only process data modification and file creation events
+                    // Create events are needed because cargo uses hardlinks for final binaries
+                    // The poller reports rewrites as write-time changes instead of data events
+                    let relevant = match event.kind {
+                        EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(_) => true,
+                        EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)) => polling,
+                        _ => false,
+                    };
+                    if !relevant {
+                        log::trace!("Ignoring non-data/create event: {:?}", event.kind);
+                        return;
+                    }
+
+                    for path in event.paths {
+                        // Only process regular files
+                        if !path.is_file() {
+                            continue;
+                        }
+
+                        // Count the raw event against every watch it matches, before filtering
+                        for config in shared.watches.lock().unwrap().values_mut() {
+                            if config.matches(&path) {
+                                config.stats.events_seen += 1;
+                            }
+                        }
+
+                        // Filter 2: Time-based debounce (100ms window). The event may be the
+                        // tail of a write burst, so look at the file again once the window ends
+                        let remaining = shared
+                            .file_states
+                            .lock()
+                            .unwrap()
+                            .get(&path)
+                            .and_then(|state| DEBOUNCE_WINDOW.checked_sub(state.last_event_time.elapsed()));
+                        if let Some(remaining) = remaining {
+                            log::trace!("⏱️  Debouncing {}", path.display());
+                            delayed.schedule(Instant::now() + remaining, DelayedCheck::Debounced(path), &shared);
+                            continue;
+                        }
+
+                        // Filter 3: New files wait out the watch's settle period, so a
+                        // build tool's create-then-write doesn't sync half a file
+                        let settle = settle_for(&shared.watches, &path);
+                        if !settle.is_zero() && !shared.file_states.lock().unwrap().contains_key(&path) {
+                            // Looked at right away, then every settle period until two looks agree
+                            let check = DelayedCheck::Settling {
+                                path,
+                                settle,
+                                previous: None,
+                                round: 0,
+                            };
+                            delayed.schedule(Instant::now(), check, &shared);
+                            continue;
+                        }
+
+                        check_change(path, &shared, &on_change_clone, &workers_clone);
                     }
                 }
-            },
-            notify::Config::default(),
-        )
-        .context("Failed to create filesystem watcher")?;
+                Err(e) => {
+                    log::error!("Filesystem watch error: {:?}", e);
+                }
+            }
+        };
+
+        let watcher: Box<dyn Watcher + Send + Sync> = match mode {
+            WatchMode::Native => Box::new(
+                RecommendedWatcher::new(handler, notify::Config::default())
+                    .context("Failed to create filesystem watcher")?,
+            ),
+            WatchMode::Poll(interval) => {
+                log::info!("👁️  Polling watched paths every {:?}", interval);
+                // The poller only tracks whole-second mtimes, so a rewrite within the second
+                // of the last scan would be missed without comparing contents
+                let config = notify::Config::default()
+                    .with_poll_interval(interval)
+                    .with_compare_contents(true);
+                Box::new(PollWatcher::new(handler, config).context("Failed to create polling watcher")?)
+            }
+        };
+
+        let watcher: SharedWatcher = Arc::new(Mutex::new(watcher));
+        let root_watcher = Arc::downgrade(&watcher);
+        std::thread::spawn(move || monitor_roots(root_shared, root_on_change, root_workers, root_watcher));
 
         Ok(Self {
             watches,
+            on_change,
+            on_remove,
+            probes,
+            dedup,
+            checksum_algo,
+            next_added: 0,
             file_states,
-            _watcher: watcher,
+            watcher,
         })
     }
 
+    /// Also report watched files that are deleted, with the same arguments as `on_change`
+    pub fn with_on_remove<F>(self, on_remove: F) -> Self
+    where
+        F: FnMut(PathBuf, PathBuf, PathBuf) + Send + 'static,
+    {
+        *self.on_remove.lock().unwrap() = Some(Box::new(on_remove));
+        self
+    }
+
+    /// When a file matches several watches, sync it only for the earliest added instead
+    /// of once per watch
+    pub fn with_dedup(self, dedup: bool) -> Self {
+        self.dedup.store(dedup, Ordering::Relaxed);
+        self
+    }
+
+    /// Hash file contents with `algo` when deciding whether a change is real
+    pub fn with_checksum_algo(self, algo: ChecksumAlgo) -> Self {
+        *self.checksum_algo.lock().unwrap() = algo;
+        self
+    }
+
     /// Add a file or directory to watch
+    ///
+    /// For directories, `relative_to` (an ancestor of `path`) is the root that patterns
+    /// and reported relative paths are resolved against, so a narrow subdirectory can be
+    /// watched without changing where its files land on clients.
     pub fn add_watch(
         &mut self,
         path: PathBuf,
         recursive: bool,
         include_patterns: Vec<String>,
         exclude_patterns: Vec<String>,
+        relative_to: Option<PathBuf>,
+        case_insensitive: bool,
+    ) -> Result<()> {
+        self.add_watch_with(
+            path,
+            recursive,
+            include_patterns,
+            exclude_patterns,
+            relative_to,
+            case_insensitive,
+            WatchOptions::default(),
+        )
+    }
+
+    /// Add a watch as `add_watch` does, with `options` set from the start
+    ///
+    /// A directory already watched to the same depth and relative to the same root
+    /// takes this watch as a sibling if it has a destination of its own, so rules
+    /// sharing a directory each sync to theirs. Anything else already watched is refused.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_watch_with(
+        &mut self,
+        path: PathBuf,
+        recursive: bool,
+        include_patterns: Vec<String>,
+        exclude_patterns: Vec<String>,
+        relative_to: Option<PathBuf>,
+        case_insensitive: bool,
+        options: WatchOptions,
     ) -> Result<()> {
         // Canonicalize path
         let canonical = path
@@ -271,6 +1294,42 @@ impl FileWatcher {
             anyhow::bail!("Path is neither a file nor directory: {}", canonical.display());
         }
 
+        // Symlinks to one place share a key; replacing the watch would lose its patterns
+        if self.watches.lock().unwrap().contains_key(&canonical) {
+            let base = match relative_to {
+                Some(ref relative_to) if is_dir => watch_base(&canonical, relative_to)?,
+                _ => canonical.clone(),
+            };
+            let mut watches = self.watches.lock().unwrap();
+            if let Some(existing) = watches.get_mut(&canonical)
+                && is_dir
+                && existing.is_directory_watch()
+                && existing.recursive == recursive
+                && existing.path == base
+                && options.destination.is_some()
+                && existing.routes().all(|route| route.destination != options.destination)
+            {
+                let mut sibling =
+                    WatchConfig::new(base, recursive, include_patterns, exclude_patterns, case_insensitive)?;
+                sibling.top_level = existing.top_level.clone();
+                sibling.apply(options)?;
+                log::info!(
+                    "Adding include: {:?}, exclude: {:?} -> {:?} to the watch on {}",
+                    sibling.include_patterns,
+                    sibling.exclude_patterns,
+                    sibling.destination,
+                    canonical.display()
+                );
+                existing.siblings.push(sibling);
+                return Ok(());
+            }
+            anyhow::bail!(
+                "{} is already watched{}; change its patterns with update-watch, or unwatch it first",
+                canonical.display(),
+                if path == canonical { String::new() } else { format!(" ({} resolves to it)", path.display()) }
+            );
+        }
+
         if is_file {
             log::info!(
                 "Adding watch for file: {} (resolved: {})",
@@ -289,19 +1348,25 @@ impl FileWatcher {
                 .to_string();
 
             // Create watch configuration for the parent directory with file filter
-            let config = WatchConfig::new(
+            let mut config = WatchConfig::new(
                 parent.clone(),
                 false, // Non-recursive for single file
                 vec![file_name.clone()], // Only watch this specific file
                 exclude_patterns,
+                case_insensitive,
             )?;
+            config.apply(options)?;
 
             // Watch the parent directory non-recursively
-            self._watcher
+            self.watcher
+                .lock()
+                .unwrap()
                 .watch(&parent, RecursiveMode::NonRecursive)
                 .context(format!("Failed to watch parent directory: {}", parent.display()))?;
 
             // Store configuration keyed by the actual file path, not parent
+            config.added = self.next_added;
+            self.next_added += 1;
             let mut watches = self.watches.lock().unwrap();
             watches.insert(canonical, config);
         } else {
@@ -314,13 +1379,23 @@ impl FileWatcher {
                 exclude_patterns
             );
 
+            let base = match relative_to {
+                Some(relative_to) => watch_base(&canonical, &relative_to)?,
+                None => canonical.clone(),
+            };
+
             // Create watch configuration
-            let config = WatchConfig::new(
-                canonical.clone(),
+            let mut config = WatchConfig::new(
+                base,
                 recursive,
                 include_patterns,
                 exclude_patterns,
+                case_insensitive,
             )?;
+            if !recursive {
+                config.top_level = Some(canonical.clone());
+            }
+            config.apply(options)?;
 
             // Add to watcher
             let mode = if recursive {
@@ -329,11 +1404,16 @@ impl FileWatcher {
                 RecursiveMode::NonRecursive
             };
 
-            self._watcher
+            self.watcher
+                .lock()
+                .unwrap()
                 .watch(&canonical, mode)
                 .context(format!("Failed to watch directory: {}", canonical.display()))?;
 
             // Store configuration
+            config.root_identity = root_identity(&canonical);
+            config.added = self.next_added;
+            self.next_added += 1;
             let mut watches = self.watches.lock().unwrap();
             watches.insert(canonical, config);
         }
@@ -341,20 +1421,295 @@ impl FileWatcher {
         Ok(())
     }
 
-    /// Remove a watch
-    pub fn remove_watch(&mut self, path: &Path) -> Result<()> {
+    /// Sync files matched by the watch on `path` into `destination` on clients
+    pub fn set_destination(&mut self, path: &Path, destination: String) -> Result<()> {
         let canonical = path
             .canonicalize()
             .context(format!("Failed to canonicalize path: {}", path.display()))?;
 
-        log::info!("Removing watch for: {}", canonical.display());
+        let mut watches = self.watches.lock().unwrap();
+        let config = watches
+            .get_mut(&canonical)
+            .context(format!("Not watching {}", canonical.display()))?;
+        config.destination = Some(destination);
+        Ok(())
+    }
+
+    /// Sync files matched by the watch on `path` to client paths relative to `base`,
+    /// an ancestor of the watch root, instead of to the root itself
+    pub fn set_base(&mut self, path: &Path, base: &Path) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
+
+        let mut watches = self.watches.lock().unwrap();
+        let config = watches
+            .get_mut(&canonical)
+            .context(format!("Not watching {}", canonical.display()))?;
+        config.set_base(base)
+    }
+
+    /// Wait until new files under the watch on `path` have been unchanged for `settle` before syncing them
+    pub fn set_settle(&mut self, path: &Path, settle: Duration) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
+
+        let mut watches = self.watches.lock().unwrap();
+        let config = watches
+            .get_mut(&canonical)
+            .context(format!("Not watching {}", canonical.display()))?;
+        config.settle = settle;
+        Ok(())
+    }
+
+    /// Sync changes under the watch on `path` ahead of lower-priority watches' changes
+    pub fn set_priority(&mut self, path: &Path, priority: i32) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
 
         let mut watches = self.watches.lock().unwrap();
+        let config = watches
+            .get_mut(&canonical)
+            .context(format!("Not watching {}", canonical.display()))?;
+        config.priority = priority;
+        Ok(())
+    }
+
+    /// Hold changes under the watch on `path` instead of syncing them
+    ///
+    /// Checksums keep updating while paused, so `resume_watch` syncs only files whose
+    /// content differs from before the pause.
+    pub fn pause_watch(&mut self, path: &Path) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
+
+        let mut watches = self.watches.lock().unwrap();
+        let config = watches
+            .get_mut(&canonical)
+            .context(format!("Not watching {}", canonical.display()))?;
+        if config.paused {
+            anyhow::bail!("Watch on {} is already paused", canonical.display());
+        }
+        config.paused = true;
+        log::info!("⏸️  Paused watch for {}", canonical.display());
+        Ok(())
+    }
+
+    /// Resume the paused watch on `path` and sync the net changes held since the pause
+    ///
+    /// Returns the number of files synced or reported removed.
+    pub fn resume_watch(&mut self, path: &Path) -> Result<usize> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
+
+        let (config_path, held) = {
+            let mut watches = self.watches.lock().unwrap();
+            let config = watches
+                .get_mut(&canonical)
+                .context(format!("Not watching {}", canonical.display()))?;
+            if !config.paused {
+                anyhow::bail!("Watch on {} is not paused", canonical.display());
+            }
+            config.paused = false;
+            (config.path.clone(), std::mem::take(&mut config.held))
+        };
+
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        {
+            let states = self.file_states.lock().unwrap();
+            for (file, before) in held {
+                let Ok(relative) = file.strip_prefix(&config_path).map(Path::to_path_buf) else {
+                    continue;
+                };
+                if !file.exists() {
+                    removed.push((relative, file));
+                } else if let Some(state) = states.get(&file)
+                    && before.as_deref() != Some(state.last_checksum.as_str())
+                {
+                    changed.push((relative, file));
+                }
+            }
+        }
+
+        let synced = changed.len() + removed.len();
+        log::info!(
+            "▶️  Resumed watch for {} ({} changed, {} removed while paused)",
+            canonical.display(),
+            changed.len(),
+            removed.len()
+        );
+        if let Some(config) = self.watches.lock().unwrap().get_mut(&canonical) {
+            for _ in 0..synced {
+                config.stats.record_trigger();
+            }
+        }
+
+        for (relative, file) in changed {
+            (self.on_change.lock().unwrap())(canonical.clone(), relative, file);
+        }
+        if let Some(on_remove) = self.on_remove.lock().unwrap().as_mut() {
+            for (relative, file) in removed {
+                on_remove(canonical.clone(), relative, file);
+            }
+        }
+        Ok(synced)
+    }
+
+    /// Change the destination and/or patterns of the watch on `path` in place
+    ///
+    /// The notify registration, stats and added order are kept; patterns are recompiled
+    /// and validated before anything changes. Returns the updated watch.
+    pub fn update_watch(
+        &mut self,
+        path: &Path,
+        destination: Option<String>,
+        include_patterns: Option<Vec<String>>,
+        exclude_patterns: Option<Vec<String>>,
+    ) -> Result<WatchInfo> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
+
+        let mut watches = self.watches.lock().unwrap();
+        let config = watches
+            .get_mut(&canonical)
+            .context(format!("Not watching {}", canonical.display()))?;
+
+        if !config.siblings.is_empty() {
+            anyhow::bail!(
+                "{} is watched by {} sets of patterns with their own destinations; unwatch it and add them again",
+                canonical.display(),
+                config.siblings.len() + 1
+            );
+        }
+        if include_patterns.is_some() && !canonical.is_dir() {
+            anyhow::bail!(
+                "{} is a single-file watch; its include pattern is the file itself",
+                canonical.display()
+            );
+        }
+
+        if include_patterns.is_some() || exclude_patterns.is_some() {
+            let recompiled = WatchConfig::new(
+                config.path.clone(),
+                config.recursive,
+                include_patterns.unwrap_or_else(|| config.include_patterns.clone()),
+                exclude_patterns.unwrap_or_else(|| config.exclude_patterns.clone()),
+                config.case_insensitive,
+            )?;
+            config.include = recompiled.include;
+            config.exclude = recompiled.exclude;
+            config.exclude_dirs = recompiled.exclude_dirs;
+            config.include_patterns = recompiled.include_patterns;
+            config.exclude_patterns = recompiled.exclude_patterns;
+            if config.exclude_case_sensitive {
+                config.compile_excludes()?;
+            }
+        }
+        if let Some(destination) = destination {
+            config.destination = Some(destination);
+        }
+
+        log::info!(
+            "Updated watch for {} (include: {:?}, exclude: {:?}, destination: {:?})",
+            canonical.display(),
+            config.include_patterns,
+            config.exclude_patterns,
+            config.destination
+        );
+        Ok(watch_info(&canonical, config))
+    }
+
+    /// Client path for a file reported under `watch_root`, if that watch has a destination
+    pub fn destination_for(&self, watch_root: &Path, relative: &Path) -> Option<PathBuf> {
+        self.watches
+            .lock()
+            .unwrap()
+            .get(watch_root)
+            .and_then(|config| config.destination_path(relative))
+    }
+
+    /// Where a file reported under `watch_root` goes, once for the watch and each sibling
+    /// matching it (only the first with dedup): (client path if not the relative path
+    /// as-is, priority)
+    pub fn targets_for(&self, watch_root: &Path, relative: &Path) -> Vec<(Option<PathBuf>, i32)> {
+        let mut targets = self
+            .watches
+            .lock()
+            .unwrap()
+            .get(watch_root)
+            .map(|config| config.targets(relative))
+            .unwrap_or_default();
+        if self.dedup.load(Ordering::Relaxed) {
+            targets.truncate(1);
+        }
+        targets
+    }
+
+    /// Whether `path` is watched already
+    pub fn is_watched(&self, path: &Path) -> bool {
+        path.canonicalize()
+            .is_ok_and(|canonical| self.watches.lock().unwrap().contains_key(&canonical))
+    }
+
+    /// Sync priority of the watch on `watch_root` (0 if it isn't watched)
+    pub fn priority_for(&self, watch_root: &Path) -> i32 {
+        self.watches
+            .lock()
+            .unwrap()
+            .get(watch_root)
+            .map_or(0, |config| config.priority)
+    }
+
+    /// Start checking that filesystem events are actually delivered for a watched path
+    ///
+    /// Writes a temporary probe file next to (or inside) `path`; wait on the returned
+    /// probe for its event. Some filesystems (network mounts, container overlays) accept
+    /// a watch but never report changes, which otherwise fails silently. An error means
+    /// the probe couldn't be written, so nothing was learned about events.
+    pub fn probe_events(&self, path: &Path) -> Result<EventProbe> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("Failed to canonicalize path: {}", path.display()))?;
+        let dir = if canonical.is_file() {
+            canonical
+                .parent()
+                .context(format!("File has no parent directory: {}", canonical.display()))?
+                .to_path_buf()
+        } else {
+            canonical
+        };
+
+        let path = dir.join(format!("{}{}", PROBE_PREFIX, uuid::Uuid::new_v4()));
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.probes.lock().unwrap().insert(path.clone(), sender);
+        let probe = EventProbe {
+            path,
+            receiver,
+            probes: self.probes.clone(),
+        };
+
+        std::fs::write(&probe.path, b"probe")
+            .context(format!("Failed to write probe file {}", probe.path.display()))?;
+        Ok(probe)
+    }
+
+    /// Remove a watch, including one whose directory no longer exists
+    pub fn remove_watch(&mut self, path: &Path) -> Result<()> {
+        let mut watches = self.watches.lock().unwrap();
+        let canonical = watch_key(&watches, path)?;
+
+        log::info!("Removing watch for: {}", canonical.display());
 
         // Find the config for the watch being removed
         if let Some(config_to_remove) = watches.get(&canonical) {
             // This is the actual path that was passed to notify::watch
-            let watched_path = if canonical.is_dir() {
+            let watched_path = if config_to_remove.is_directory_watch() {
                 &canonical
             } else {
                 // For files, we watched the parent
@@ -371,7 +1726,7 @@ impl FileWatcher {
                 }
 
                 // Determine the underlying watched path for this other watch
-                let other_watched_path = if watch_key.is_dir() {
+                let other_watched_path = if config.is_directory_watch() {
                     watch_key
                 } else {
                     &config.path
@@ -380,14 +1735,18 @@ impl FileWatcher {
                 other_watched_path == watched_path
             });
 
-            if is_shared {
+            if config_to_remove.broken {
+                log::debug!("{} was already unwatched when it was removed", watched_path.display());
+            } else if is_shared {
                 log::debug!(
                     "Not unwatching {}. It's shared by other watches.",
                     watched_path.display()
                 );
             } else {
                 log::debug!("Unwatching {}", watched_path.display());
-                self._watcher
+                self.watcher
+                    .lock()
+                    .unwrap()
                     .unwatch(watched_path)
                     .context(format!("Failed to unwatch path: {}", watched_path.display()))?;
             }
@@ -395,25 +1754,111 @@ impl FileWatcher {
             // Always remove the specific watch config from our map
             watches.remove(&canonical);
 
-            Ok(())
-        } else {
-            // Don't error if watch doesn't exist, just log it.
-            // This can happen in tests or if a remove is duplicated.
-            log::warn!("Watch not found for path: {}", canonical.display());
-            Ok(())
-        }
+            Ok(())
+        } else {
+            // Don't error if watch doesn't exist, just log it.
+            // This can happen in tests or if a remove is duplicated.
+            log::warn!("Watch not found for path: {}", canonical.display());
+            Ok(())
+        }
+    }
+
+    /// Stop the watch on `path` syncing to `destination`, keeping its other routes; the
+    /// watch itself is removed once it has no route left
+    pub fn remove_route(&mut self, path: &Path, destination: &str) -> Result<()> {
+        let mut watches = self.watches.lock().unwrap();
+        let canonical = watch_key(&watches, path)?;
+        let Some(config) = watches.get_mut(&canonical) else {
+            anyhow::bail!("Not watching {}", canonical.display());
+        };
+
+        if let Some(idx) = config
+            .siblings
+            .iter()
+            .position(|sibling| sibling.destination.as_deref() == Some(destination))
+        {
+            config.siblings.remove(idx);
+        } else if config.destination.as_deref() != Some(destination) {
+            anyhow::bail!("{} has no route to {}", canonical.display(), destination);
+        } else if config.siblings.is_empty() {
+            drop(watches);
+            return self.remove_watch(path);
+        } else {
+            // The first sibling takes over the watch's own patterns and destination,
+            // along with what every route shares
+            let mut promoted = config.siblings.remove(0);
+            promoted.siblings = std::mem::take(&mut config.siblings);
+            promoted.added = config.added;
+            promoted.paused = config.paused;
+            promoted.broken = config.broken;
+            promoted.root_identity = config.root_identity;
+            promoted.held = std::mem::take(&mut config.held);
+            promoted.stats = std::mem::take(&mut config.stats);
+            *config = promoted;
+        }
+
+        log::info!(
+            "Removed the route to {} from the watch on {}",
+            destination,
+            canonical.display()
+        );
+        Ok(())
+    }
+
+    /// The files removing the watch on `path` would affect, without removing it
+    ///
+    /// Returns (stops_syncing, still_covered): files only this watch matches, and files
+    /// another watch matches too and would keep syncing.
+    pub fn preview_remove(&self, path: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let watches = self.watches.lock().unwrap();
+        let canonical = watch_key(&watches, path)?;
+        let Some(config) = watches.get(&canonical) else {
+            anyhow::bail!("Not watching {}", canonical.display());
+        };
+
+        let files: Vec<PathBuf> = if config.is_directory_watch() {
+            config
+                .matching_files(&canonical)
+                .into_iter()
+                .map(|(_, absolute)| absolute)
+                .collect()
+        } else if canonical.is_file() {
+            vec![canonical.clone()]
+        } else {
+            Vec::new()
+        };
+
+        let (mut still_covered, mut stops_syncing): (Vec<_>, Vec<_>) = files.into_iter().partition(|file| {
+            watches.iter().any(|(watch_key, other)| {
+                if watch_key == &canonical {
+                    return false;
+                }
+                if other.is_directory_watch() {
+                    file.starts_with(watch_key) && other.matches(file)
+                } else {
+                    watch_key == file && other.matches(file)
+                }
+            })
+        });
+        stops_syncing.sort();
+        still_covered.sort();
+
+        Ok((stops_syncing, still_covered))
     }
 
-    /// List all active watches
+    /// List all active watches, a directory with siblings once per set of patterns
     pub fn list_watches(&self) -> Vec<WatchInfo> {
         let watches = self.watches.lock().unwrap();
         watches
-            .values()
-            .map(|config| WatchInfo {
-                path: config.path.to_string_lossy().to_string(),
-                recursive: config.recursive,
-                include_patterns: config.include_patterns.clone(),
-                exclude_patterns: config.exclude_patterns.clone(),
+            .iter()
+            .flat_map(|(watch_root, config)| {
+                let info = watch_info(watch_root, config);
+                config.routes().map(move |route| WatchInfo {
+                    include_patterns: route.include_patterns.clone(),
+                    exclude_patterns: route.exclude_patterns.clone(),
+                    destination: route.destination.clone(),
+                    ..info.clone()
+                })
             })
             .collect()
     }
@@ -422,11 +1867,17 @@ impl FileWatcher {
     ///
     /// Returns (watch_root, relative_path, absolute_path) for each file.
     /// This is used for initial sync when a client connects.
+    ///
+    /// A file matched by several watches is listed once per watch, or only for the
+    /// earliest added with dedup.
     pub fn get_all_watched_files(&self) -> Vec<(PathBuf, PathBuf, PathBuf)> {
         let watches = self.watches.lock().unwrap();
         let mut files = Vec::new();
 
-        for (watch_root, config) in watches.iter() {
+        let mut ordered: Vec<_> = watches.iter().collect();
+        ordered.sort_by_key(|(_, config)| config.added);
+
+        for (watch_root, config) in ordered {
             if watch_root.is_file() {
                 // Single file watch - just return the file itself
                 let relative = match watch_root.strip_prefix(&config.path) {
@@ -439,13 +1890,7 @@ impl FileWatcher {
                 files.push((watch_root.clone(), relative, watch_root.clone()));
             } else if watch_root.is_dir() {
                 // Directory watch - walk the tree and find matching files
-                let walker = if config.recursive {
-                    walkdir::WalkDir::new(watch_root)
-                } else {
-                    walkdir::WalkDir::new(watch_root).max_depth(1)
-                };
-
-                for entry in walker.into_iter().filter_map(|e| e.ok()) {
+                for entry in config.walk(watch_root) {
                     let path = entry.path();
 
                     // Only process files
@@ -468,10 +1913,33 @@ impl FileWatcher {
             }
         }
 
+        if self.dedup.load(Ordering::Relaxed) {
+            let mut seen = std::collections::HashSet::new();
+            files.retain(|(_, _, absolute)| seen.insert(absolute.clone()));
+        }
+
         log::debug!("Found {} watched files for initial sync", files.len());
         files
     }
 
+    /// Every directory watch's `empty_dirs`, as (watch_root, relative_path, absolute_path)
+    /// in the order the watches were added
+    pub fn get_all_empty_dirs(&self) -> Vec<(PathBuf, PathBuf, PathBuf)> {
+        let watches = self.watches.lock().unwrap();
+        let mut ordered: Vec<_> = watches.iter().filter(|(watch_root, _)| watch_root.is_dir()).collect();
+        ordered.sort_by_key(|(_, config)| config.added);
+
+        ordered
+            .into_iter()
+            .flat_map(|(watch_root, config)| {
+                config
+                    .empty_dirs(watch_root)
+                    .into_iter()
+                    .map(|(relative, absolute)| (watch_root.clone(), relative, absolute))
+            })
+            .collect()
+    }
+
     /// Get all files matching a specific watch path
     pub fn get_files_for_path(&self, path: &Path) -> Vec<(PathBuf, PathBuf, PathBuf)> {
         let watches = self.watches.lock().unwrap();
@@ -491,24 +1959,8 @@ impl FileWatcher {
                 files.push((watch_root.to_path_buf(), relative, watch_root.to_path_buf()));
             } else if watch_root.is_dir() {
                 // Directory watch
-                let walker = if config.recursive {
-                    walkdir::WalkDir::new(watch_root)
-                } else {
-                    walkdir::WalkDir::new(watch_root).max_depth(1)
-                };
-
-                for entry in walker.into_iter().filter_map(|e| e.ok()) {
-                    let p = entry.path();
-                    if p.is_file() && config.matches(p) {
-                        let relative = match p.strip_prefix(&config.path) {
-                            Ok(rel) => rel.to_path_buf(),
-                            Err(_) => {
-                                log::warn!("Failed to compute relative path for: {}", p.display());
-                                continue;
-                            }
-                        };
-                        files.push((watch_root.to_path_buf(), relative, p.to_path_buf()));
-                    }
+                for (relative, absolute) in config.matching_files(watch_root) {
+                    files.push((watch_root.to_path_buf(), relative, absolute));
                 }
             }
         }
@@ -519,7 +1971,6 @@ impl FileWatcher {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
     use tempfile::tempdir;
 
     #[test]
@@ -532,6 +1983,7 @@ mod tests {
             true,
             vec!["*.rs".to_string(), "*.toml".to_string()],
             vec!["target/**".to_string()],
+            false,
         )
         .unwrap();
 
@@ -552,6 +2004,39 @@ mod tests {
         assert!(!config.matches(&txt_file));
     }
 
+    #[test]
+    fn test_watch_config_basename_patterns() {
+        let temp = tempdir().unwrap();
+        let watch_root = temp.path().to_path_buf();
+
+        let config = WatchConfig::new(
+            watch_root.clone(),
+            true,
+            vec!["*.rs".to_string(), "Cargo.toml".to_string(), "assets/*.png".to_string()],
+            vec!["target/**".to_string(), "scratch.rs".to_string()],
+            false,
+        )
+        .unwrap();
+
+        // Without a slash, a pattern matches the file name at any depth
+        assert!(config.matches(&watch_root.join("src/foo.rs")));
+        assert!(config.matches(&watch_root.join("crates/net/src/lib.rs")));
+        assert!(config.matches(&watch_root.join("Cargo.toml")));
+        assert!(config.matches(&watch_root.join("crates/net/Cargo.toml")));
+        assert!(!config.matches(&watch_root.join("crates/net/Cargo.toml.orig")));
+
+        // With one, it matches the path from the watch root
+        assert!(config.matches(&watch_root.join("assets/logo.png")));
+        assert!(!config.matches(&watch_root.join("vendor/assets/logo.png")));
+
+        // Excludes follow the same rule
+        assert!(!config.matches(&watch_root.join("src/bin/scratch.rs")));
+        assert!(!config.matches(&watch_root.join("target/debug/build.rs")));
+        assert!(config.matches(&watch_root.join("crates/net/target/gen.rs")));
+        assert!(config.excludes_dir(&watch_root.join("target")));
+        assert!(!config.excludes_dir(&watch_root.join("crates/net/target")));
+    }
+
     #[test]
     fn test_watch_config_no_include_patterns() {
         let temp = tempdir().unwrap();
@@ -563,6 +2048,7 @@ mod tests {
             true,
             vec![],
             vec!["*.tmp".to_string()],
+            false,
         )
         .unwrap();
 
@@ -570,4 +2056,863 @@ mod tests {
         assert!(config.matches(&watch_root.join("any/file.txt")));
         assert!(!config.matches(&watch_root.join("temp.tmp")));
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watch_config_absolute_patterns() {
+        let temp = tempdir().unwrap();
+        let watch_root = temp.path().to_path_buf();
+        let file = watch_root.join("src/main.rs");
+        let root = watch_root.to_string_lossy();
+
+        let relative = WatchConfig::new(watch_root.clone(), true, vec!["src/*.rs".to_string()], vec![], false).unwrap();
+        let mut absolute =
+            WatchConfig::new(watch_root.clone(), true, vec![format!("{}/src/*.rs", root)], vec![], false).unwrap();
+        assert!(relative.matches(&file));
+        assert!(absolute.matches(&file));
+        assert!(!absolute.matches(&watch_root.join("main.rs")));
+
+        // The absolute pattern's literal base is stripped for destinations, like a relative one
+        absolute.destination = Some("code".to_string());
+        assert_eq!(absolute.destination_path(Path::new("src/main.rs")), Some(PathBuf::from("code/main.rs")));
+
+        // Absolute excludes apply too, including pruning whole directories
+        let excluding = WatchConfig::new(
+            watch_root.clone(),
+            true,
+            vec!["**/*.rs".to_string()],
+            vec![format!("{}/vendor/**", root)],
+            false,
+        )
+        .unwrap();
+        assert!(excluding.matches(&file));
+        assert!(!excluding.matches(&watch_root.join("vendor/lib.rs")));
+        assert!(excluding.excludes_dir(&watch_root.join("vendor")));
+    }
+
+    #[test]
+    fn test_watch_config_case_insensitive() {
+        let temp = tempdir().unwrap();
+        let watch_root = temp.path().to_path_buf();
+        let include = vec!["*.exe".to_string()];
+        let exclude = vec!["**/*.tmp".to_string()];
+
+        let sensitive =
+            WatchConfig::new(watch_root.clone(), true, include.clone(), exclude.clone(), false).unwrap();
+        assert!(sensitive.matches(&watch_root.join("game.exe")));
+        assert!(!sensitive.matches(&watch_root.join("GAME.EXE")));
+
+        let insensitive = WatchConfig::new(watch_root.clone(), true, include, exclude, true).unwrap();
+        assert!(insensitive.matches(&watch_root.join("GAME.EXE")));
+        assert!(insensitive.matches(&watch_root.join("Game.Exe")));
+        assert!(!insensitive.matches(&watch_root.join("BUILD.TMP")));
+    }
+
+    #[test]
+    fn test_exclude_case_sensitive_keeps_exclude_case() {
+        let temp = tempdir().unwrap();
+        let watch_root = temp.path().to_path_buf();
+        let mut config = WatchConfig::new(
+            watch_root.clone(),
+            true,
+            vec!["**/*.exe".to_string()],
+            vec!["build/**".to_string()],
+            true,
+        )
+        .unwrap();
+        config
+            .apply(WatchOptions {
+                exclude_case_sensitive: true,
+                ..WatchOptions::default()
+            })
+            .unwrap();
+
+        assert!(config.matches(&watch_root.join("GAME.EXE")));
+        assert!(!config.matches(&watch_root.join("build/game.exe")));
+        assert!(config.matches(&watch_root.join("Build/game.exe")));
+        assert!(!config.excludes_dir(&watch_root.join("Build")));
+    }
+
+    #[test]
+    fn test_add_watch_relative_to_ancestor() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("src/net")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("src/net/socket.rs"), b"fn main() {}").unwrap();
+        std::fs::write(root.join("target/app.rs"), b"fn main() {}").unwrap();
+
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher
+            .add_watch(
+                root.join("src"),
+                true,
+                vec!["src/**/*.rs".to_string()],
+                vec![],
+                Some(root.clone()),
+                false,
+            )
+            .unwrap();
+
+        let files = watcher.get_files_for_path(&root.join("src"));
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].1, PathBuf::from("src/net/socket.rs"));
+
+        let watches = watcher.list_watches();
+        assert_eq!(watches[0].path, root.join("src").to_string_lossy());
+
+        // relative_to must contain the watched directory
+        assert!(watcher
+            .add_watch(root.clone(), true, vec![], vec![], Some(root.join("src")), false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_non_recursive_watch_skips_subdirectories() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("bin/sub")).unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, relative, _| {
+            changes_clone.lock().unwrap().push(relative);
+        })
+        .unwrap();
+        watcher
+            .add_watch(root.join("bin"), false, vec!["bin/*".to_string()], vec![], Some(root.clone()), false)
+            .unwrap();
+        // A recursive watch elsewhere delivers events from bin/sub too
+        watcher
+            .add_watch(root.clone(), true, vec!["**/*.dll".to_string()], vec![], None, false)
+            .unwrap();
+
+        std::fs::write(root.join("bin/sub/deep.exe"), b"deep").unwrap();
+        std::fs::write(root.join("bin/top.exe"), b"top").unwrap();
+
+        let start = Instant::now();
+        while changes.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::thread::sleep(Duration::from_millis(300));
+
+        // The create and the write can each be reported once the debounce window ends
+        let mut changes = changes.lock().unwrap().clone();
+        changes.dedup();
+        assert_eq!(changes, vec![PathBuf::from("bin/top.exe")]);
+
+        let files: Vec<PathBuf> = watcher
+            .get_files_for_path(&root.join("bin"))
+            .into_iter()
+            .map(|(_, relative, _)| relative)
+            .collect();
+        assert_eq!(files, vec![PathBuf::from("bin/top.exe")]);
+    }
+
+    /// Watch `root` and then `root/bin` for the same file, returning the watch roots
+    /// each change was reported for and the watch roots of the initial-sync listing
+    fn overlapping_watch_roots(dedup: bool) -> (Vec<PathBuf>, Vec<PathBuf>, Vec<PathBuf>, PathBuf) {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::write(root.join("bin/game.exe"), b"v1").unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let removals = Arc::new(Mutex::new(Vec::new()));
+        let removals_clone = Arc::clone(&removals);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |watch_root, _, _| {
+            changes_clone.lock().unwrap().push(watch_root);
+        })
+        .unwrap()
+        .with_on_remove(move |watch_root, _, _| {
+            removals_clone.lock().unwrap().push(watch_root);
+        })
+        .with_dedup(dedup);
+        watcher
+            .add_watch(root.clone(), true, vec!["**/*.exe".to_string()], vec![], None, false)
+            .unwrap();
+        watcher
+            .add_watch(root.join("bin"), true, vec!["bin/*".to_string()], vec![], Some(root.clone()), false)
+            .unwrap();
+
+        let initial: Vec<PathBuf> = watcher
+            .get_all_watched_files()
+            .into_iter()
+            .map(|(watch_root, _, _)| watch_root)
+            .collect();
+
+        let mut file = std::fs::OpenOptions::new().write(true).open(root.join("bin/game.exe")).unwrap();
+        std::io::Write::write_all(&mut file, b"v2").unwrap();
+
+        let start = Instant::now();
+        while changes.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::thread::sleep(Duration::from_millis(300));
+
+        drop(file);
+        std::fs::remove_file(root.join("bin/game.exe")).unwrap();
+        let start = Instant::now();
+        while removals.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::thread::sleep(Duration::from_millis(300));
+
+        let changes = changes.lock().unwrap().clone();
+        let mut removals = removals.lock().unwrap().clone();
+        removals.sort();
+        (changes, removals, initial, root)
+    }
+
+    #[test]
+    fn test_overlapping_watches_each_sync() {
+        let (changes, removals, initial, root) = overlapping_watch_roots(false);
+        assert_eq!(changes, vec![root.clone(), root.join("bin")]);
+        assert_eq!(removals, vec![root.clone(), root.join("bin")]);
+        assert_eq!(initial, vec![root.clone(), root.join("bin")]);
+    }
+
+    #[test]
+    fn test_overlapping_watches_dedup_to_first() {
+        let (changes, removals, initial, root) = overlapping_watch_roots(true);
+        assert_eq!(changes, vec![root.clone()]);
+        assert_eq!(removals, vec![root.clone()]);
+        assert_eq!(initial, vec![root]);
+    }
+
+    #[test]
+    fn test_walk_prunes_excluded_subtrees() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), b"fn main() {}").unwrap();
+        for dir in 0..50 {
+            let deps = root.join(format!("target/debug/deps/{}", dir));
+            std::fs::create_dir_all(&deps).unwrap();
+            for file in 0..20 {
+                std::fs::write(deps.join(format!("{}.rs", file)), b"").unwrap();
+            }
+        }
+        std::fs::create_dir_all(root.join("vendor/.git")).unwrap();
+        std::fs::write(root.join("vendor/.git/HEAD.rs"), b"").unwrap();
+        std::fs::write(root.join("vendor/lib.rs"), b"").unwrap();
+
+        let config = WatchConfig::new(
+            root.clone(),
+            true,
+            vec!["**/*.rs".to_string()],
+            vec!["target/**".to_string(), "**/.git/**/*".to_string()],
+            false,
+        )
+        .unwrap();
+
+        let visited: Vec<PathBuf> = config.walk(&root).map(|e| e.into_path()).collect();
+        assert!(visited.contains(&root.join("src/main.rs")));
+        assert!(!visited.iter().any(|p| p.starts_with(root.join("target"))));
+        assert!(!visited.iter().any(|p| p.starts_with(root.join("vendor/.git"))));
+
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher
+            .add_watch(
+                root.clone(),
+                true,
+                vec!["**/*.rs".to_string()],
+                vec!["target/**".to_string(), "**/.git/**/*".to_string()],
+                None,
+                false,
+            )
+            .unwrap();
+
+        let mut files: Vec<PathBuf> = watcher.get_all_watched_files().into_iter().map(|f| f.1).collect();
+        files.sort();
+        assert_eq!(files, vec![PathBuf::from("src/main.rs"), PathBuf::from("vendor/lib.rs")]);
+    }
+
+    #[test]
+    fn test_empty_dirs_are_leaves_without_matching_files() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        for dir in ["logs", "cache/shaders", "bin", "docs", "target/debug"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("bin/game.exe"), b"exe").unwrap();
+        std::fs::write(root.join("docs/notes.txt"), b"not matched").unwrap();
+
+        let config = WatchConfig::new(
+            root.clone(),
+            true,
+            vec!["**/*.exe".to_string()],
+            vec!["target/**".to_string()],
+            false,
+        )
+        .unwrap();
+
+        // cache/ is created along with cache/shaders; docs/ holds nothing that syncs
+        let mut dirs: Vec<PathBuf> = config.empty_dirs(&root).into_iter().map(|(relative, _)| relative).collect();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            vec![PathBuf::from("cache/shaders"), PathBuf::from("docs"), PathBuf::from("logs")]
+        );
+
+        let single_level = WatchConfig::new(root.clone(), false, vec![], vec![], false).unwrap();
+        assert!(single_level.empty_dirs(&root).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe_events_sees_probe_without_syncing_it() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, relative, _| {
+            changes_clone.lock().unwrap().push(relative);
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+
+        watcher.probe_events(&root).unwrap().wait(Duration::from_secs(5)).await.unwrap();
+
+        // The probe file is cleaned up and never reported as a change
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(changes.lock().unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_settle_syncs_new_file_once_complete() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, _, absolute| {
+            changes_clone.lock().unwrap().push(std::fs::read(absolute).unwrap());
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+        watcher.set_settle(&root, Duration::from_millis(300)).unwrap();
+
+        // Written in pieces, the way a linker or downloader produces a file
+        let mut file = std::fs::File::create(root.join("game.exe")).unwrap();
+        for chunk in [&b"header "[..], b"body ", b"footer"] {
+            std::io::Write::write_all(&mut file, chunk).unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        drop(file);
+
+        let start = Instant::now();
+        while changes.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::thread::sleep(Duration::from_millis(300));
+
+        assert_eq!(*changes.lock().unwrap(), vec![b"header body footer".to_vec()]);
+    }
+
+    #[test]
+    fn test_settle_gives_up_on_a_file_that_keeps_changing() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+
+        let changes = Arc::new(Mutex::new(0));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, _, _| {
+            *changes_clone.lock().unwrap() += 1;
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+        watcher.set_settle(&root, Duration::from_millis(50)).unwrap();
+
+        // Appended to faster than it can settle, like a log, for far longer than the rounds allowed
+        let mut file = std::fs::File::create(root.join("server.log")).unwrap();
+        let start = Instant::now();
+        while *changes.lock().unwrap() == 0 && start.elapsed() < Duration::from_secs(5) {
+            std::io::Write::write_all(&mut file, b"line\n").unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(*changes.lock().unwrap() > 0, "never synced while still being written");
+    }
+
+    #[test]
+    fn test_debounced_trailing_write_is_synced() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, _, absolute| {
+            changes_clone.lock().unwrap().push(std::fs::read(absolute).unwrap());
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+
+        // The second write lands inside the first one's debounce window
+        let mut file = std::fs::File::create(root.join("game.exe")).unwrap();
+        std::io::Write::write_all(&mut file, b"first").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        std::io::Write::write_all(&mut file, b" second").unwrap();
+        drop(file);
+
+        let start = Instant::now();
+        while changes.lock().unwrap().last().is_none_or(|last| last != b"first second")
+            && start.elapsed() < Duration::from_secs(5)
+        {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        assert_eq!(changes.lock().unwrap().last().unwrap(), b"first second");
+    }
+
+    #[test]
+    fn test_change_workers_handle_a_burst() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 3, move |_, relative, absolute| {
+            changes_clone.lock().unwrap().push((relative, std::fs::read(absolute).unwrap()));
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+
+        for idx in 0..20 {
+            std::fs::write(root.join(format!("file-{}.bin", idx)), format!("v1 {}", idx)).unwrap();
+        }
+        // Rewritten after its first change was seen, which must not be overtaken by it,
+        // and again inside the debounce window, which the follow-up check must catch
+        std::thread::sleep(Duration::from_millis(200));
+        std::fs::write(root.join("file-0.bin"), b"v2").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(root.join("file-0.bin"), b"v3").unwrap();
+
+        let latest = |changes: &[(PathBuf, Vec<u8>)]| {
+            let mut latest = HashMap::new();
+            for (relative, content) in changes {
+                latest.insert(relative.clone(), content.clone());
+            }
+            latest
+        };
+        let start = Instant::now();
+        loop {
+            let latest = latest(&changes.lock().unwrap());
+            if latest.len() == 20 && latest[Path::new("file-0.bin")] == b"v3" {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "missing changes: {:?}", latest);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let latest = latest(&changes.lock().unwrap());
+        for idx in 1..20 {
+            assert_eq!(latest[&PathBuf::from(format!("file-{}.bin", idx))], format!("v1 {}", idx).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_removed_watch_root_is_rewatched_when_it_reappears() {
+        let temp = tempdir().unwrap();
+        let build = temp.path().canonicalize().unwrap().join("build");
+        std::fs::create_dir(&build).unwrap();
+        std::fs::write(build.join("game.exe"), b"v1").unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, _, absolute| {
+            changes_clone.lock().unwrap().push(std::fs::read(absolute).unwrap());
+        })
+        .unwrap();
+        watcher.add_watch(build.clone(), true, vec![], vec![], None, false).unwrap();
+
+        let wait_for = |broken: bool| {
+            let start = Instant::now();
+            while watcher.list_watches()[0].broken != broken && start.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            assert_eq!(watcher.list_watches()[0].broken, broken);
+        };
+
+        std::fs::remove_dir_all(&build).unwrap();
+        wait_for(true);
+
+        // Rebuilt in a staging directory and renamed into place
+        let staging = temp.path().join("build.new");
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::write(staging.join("game.exe"), b"v2").unwrap();
+        std::fs::rename(&staging, &build).unwrap();
+        wait_for(false);
+
+        // Events arrive from the new directory
+        std::fs::write(build.join("game.exe"), b"v3").unwrap();
+        let start = Instant::now();
+        while changes.lock().unwrap().last().is_none_or(|last| last != b"v3") && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(*changes.lock().unwrap(), vec![b"v2".to_vec(), b"v3".to_vec()]);
+
+        // A broken watch can still be removed
+        std::fs::remove_dir_all(&build).unwrap();
+        wait_for(true);
+        watcher.remove_watch(&build).unwrap();
+        assert!(watcher.list_watches().is_empty());
+    }
+
+    #[test]
+    fn test_poll_mode_detects_changes() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::write(root.join("game.exe"), b"v1").unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Poll(Duration::from_millis(50)), 0, move |_, relative, _| {
+            changes_clone.lock().unwrap().push(relative);
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+
+        // Let the first scan record the existing file, then change it and add another
+        std::thread::sleep(Duration::from_millis(200));
+        std::fs::write(root.join("game.exe"), b"v2 with new content").unwrap();
+        std::fs::write(root.join("new.dll"), b"dll").unwrap();
+
+        let start = Instant::now();
+        while changes.lock().unwrap().len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let mut changes = changes.lock().unwrap().clone();
+        changes.sort();
+        assert_eq!(changes, vec![PathBuf::from("game.exe"), PathBuf::from("new.dll")]);
+    }
+
+    #[test]
+    fn test_watch_stats_count_deduplicated_events() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::write(root.join("game.exe"), b"v1").unwrap();
+
+        // Overwrite in place with one write, so the watcher never reads a truncated file
+        let overwrite = |content: &[u8]| {
+            let mut file = std::fs::OpenOptions::new().write(true).open(root.join("game.exe")).unwrap();
+            std::io::Write::write_all(&mut file, content).unwrap();
+        };
+
+        let changes = Arc::new(Mutex::new(0));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, _, _| {
+            *changes_clone.lock().unwrap() += 1;
+        })
+        .unwrap();
+        watcher
+            .add_watch(root.clone(), true, vec!["*.exe".to_string()], vec![], None, false)
+            .unwrap();
+
+        let stats = || watcher.list_watches().pop().unwrap();
+        assert_eq!(stats().events_seen, 0);
+        assert_eq!(stats().last_triggered, None);
+
+        overwrite(b"v2");
+        let start = Instant::now();
+        while *changes.lock().unwrap() < 1 && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // Rewriting identical content is seen but deduplicated by checksum
+        std::thread::sleep(Duration::from_millis(200));
+        overwrite(b"v2");
+        std::fs::write(root.join("notes.txt"), b"not watched").unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+
+        let info = stats();
+        assert_eq!(*changes.lock().unwrap(), 1);
+        assert_eq!(info.syncs_triggered, 1);
+        assert_eq!(info.events_passed, 1);
+        assert!(info.events_seen >= 2, "saw {} events", info.events_seen);
+        assert_eq!(info.last_triggered, Some(0));
+    }
+
+    #[test]
+    fn test_resume_syncs_only_net_changes() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::write(root.join("game.exe"), b"v1").unwrap();
+
+        // Overwrite in place with one write, so the watcher never reads a truncated file
+        let overwrite = |content: &[u8]| {
+            let mut file = std::fs::OpenOptions::new().write(true).open(root.join("game.exe")).unwrap();
+            std::io::Write::write_all(&mut file, content).unwrap();
+        };
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = Arc::clone(&changes);
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, move |_, relative, _| {
+            changes_clone.lock().unwrap().push(relative);
+        })
+        .unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+
+        let wait_for = |count: usize| {
+            let start = Instant::now();
+            while changes.lock().unwrap().len() < count && start.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+
+        // Let the watcher learn game.exe's checksum before pausing
+        overwrite(b"v2");
+        wait_for(1);
+        std::thread::sleep(Duration::from_millis(200));
+        changes.lock().unwrap().clear();
+
+        watcher.pause_watch(&root).unwrap();
+        assert!(watcher.list_watches()[0].paused);
+
+        // game.exe ends up where it started; new.dll is a net change
+        overwrite(b"v3");
+        std::thread::sleep(Duration::from_millis(300));
+        overwrite(b"v2");
+        std::fs::write(root.join("new.dll"), b"dll").unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(changes.lock().unwrap().is_empty());
+
+        assert_eq!(watcher.resume_watch(&root).unwrap(), 1);
+        assert!(!watcher.list_watches()[0].paused);
+        assert_eq!(*changes.lock().unwrap(), vec![PathBuf::from("new.dll")]);
+        assert!(watcher.resume_watch(&root).is_err());
+    }
+
+    #[test]
+    fn test_base_keeps_leading_directories_on_clients() {
+        let temp = tempdir().unwrap();
+        let project = temp.path().canonicalize().unwrap().join("project");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        std::fs::write(project.join("src/main.rs"), b"fn main() {}").unwrap();
+        std::fs::write(project.join("README"), b"readme").unwrap();
+
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        let src = project.join("src");
+        watcher.add_watch(src.clone(), true, vec![], vec![], None, false).unwrap();
+        watcher.add_watch(project.join("README"), false, vec![], vec![], None, false).unwrap();
+        let main_rs = Path::new("main.rs");
+
+        // Without a base, files land relative to the watch root
+        assert_eq!(watcher.destination_for(&src, main_rs), None);
+
+        watcher.set_base(&src, &project).unwrap();
+        assert_eq!(watcher.destination_for(&src, main_rs), Some(PathBuf::from("src/main.rs")));
+        watcher.set_base(&src, temp.path()).unwrap();
+        assert_eq!(
+            watcher.destination_for(&src, main_rs),
+            Some(PathBuf::from("project/src/main.rs"))
+        );
+
+        // Single-file watches resolve against their directory
+        watcher.set_base(&project.join("README"), temp.path()).unwrap();
+        assert_eq!(
+            watcher.destination_for(&project.join("README"), Path::new("README")),
+            Some(PathBuf::from("project/README"))
+        );
+
+        // A destination takes precedence, and the base must contain the watch
+        watcher.set_destination(&src, "code".to_string()).unwrap();
+        assert_eq!(watcher.destination_for(&src, main_rs), Some(PathBuf::from("code/main.rs")));
+        std::fs::create_dir(project.join("docs")).unwrap();
+        assert!(watcher.set_base(&src, &project.join("docs")).is_err());
+    }
+
+    #[test]
+    fn test_update_watch_in_place() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::write(root.join("game.exe"), b"exe").unwrap();
+        std::fs::write(root.join("game.pdb"), b"pdb").unwrap();
+
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher
+            .add_watch(root.clone(), true, vec!["*.exe".to_string()], vec![], None, false)
+            .unwrap();
+        watcher.set_destination(&root, "wrong/".to_string()).unwrap();
+
+        let info = watcher
+            .update_watch(&root, Some("games/".to_string()), Some(vec!["*.pdb".to_string()]), None)
+            .unwrap();
+        assert_eq!(info.destination.as_deref(), Some("games/"));
+        assert_eq!(info.include_patterns, vec!["*.pdb".to_string()]);
+
+        let files: Vec<PathBuf> = watcher.get_all_watched_files().into_iter().map(|f| f.1).collect();
+        assert_eq!(files, vec![PathBuf::from("game.pdb")]);
+        assert_eq!(
+            watcher.destination_for(&root, Path::new("game.pdb")),
+            Some(PathBuf::from("games/game.pdb"))
+        );
+
+        // A bad pattern is rejected without touching the watch
+        assert!(watcher.update_watch(&root, None, None, Some(vec!["[".to_string()])).is_err());
+        assert!(watcher.list_watches()[0].exclude_patterns.is_empty());
+
+        // Single-file watches keep the file as their only include
+        let single = root.join("game.exe");
+        watcher.add_watch(single.clone(), false, vec![], vec![], None, false).unwrap();
+        assert!(watcher.update_watch(&single, None, Some(vec!["*".to_string()]), None).is_err());
+        assert!(watcher.update_watch(Path::new("/nonexistent/watch"), None, None, None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_to_a_watched_directory_are_rejected() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let real = root.join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, root.join("first")).unwrap();
+        std::os::unix::fs::symlink(&real, root.join("second")).unwrap();
+
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher
+            .add_watch(root.join("first"), true, vec!["*.exe".to_string()], vec![], None, false)
+            .unwrap();
+        let err = watcher
+            .add_watch(root.join("second"), true, vec!["*.dll".to_string()], vec![], None, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("already watched"), "{:#}", err);
+        assert!(watcher.add_watch(real.clone(), true, vec![], vec![], None, false).is_err());
+
+        // The first watch keeps its patterns
+        let watches = watcher.list_watches();
+        assert_eq!(watches.len(), 1);
+        assert_eq!(watches[0].path, real.to_string_lossy());
+        assert_eq!(watches[0].include_patterns, vec!["*.exe".to_string()]);
+    }
+
+    #[test]
+    fn test_rules_sharing_a_directory_each_sync_to_their_destination() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::write(root.join("game.exe"), b"exe").unwrap();
+        std::fs::write(root.join("game.pdb"), b"pdb").unwrap();
+
+        let options = |destination: &str| WatchOptions {
+            destination: Some(destination.to_string()),
+            ..WatchOptions::default()
+        };
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher
+            .add_watch_with(root.clone(), true, vec!["*.exe".to_string()], vec![], None, false, options("bin/"))
+            .unwrap();
+        watcher
+            .add_watch_with(root.clone(), true, vec!["*.pdb".to_string()], vec![], None, false, options("symbols/"))
+            .unwrap();
+
+        // Same destination again, or no destination at all, is still a duplicate
+        assert!(watcher
+            .add_watch_with(root.clone(), true, vec!["*.dll".to_string()], vec![], None, false, options("bin/"))
+            .is_err());
+        assert!(watcher.add_watch(root.clone(), true, vec![], vec![], None, false).is_err());
+
+        assert_eq!(
+            watcher.targets_for(&root, Path::new("game.exe")),
+            vec![(Some(PathBuf::from("bin/game.exe")), 0)]
+        );
+        assert_eq!(
+            watcher.targets_for(&root, Path::new("game.pdb")),
+            vec![(Some(PathBuf::from("symbols/game.pdb")), 0)]
+        );
+
+        let mut files: Vec<PathBuf> = watcher.get_all_watched_files().into_iter().map(|f| f.1).collect();
+        files.sort();
+        assert_eq!(files, vec![PathBuf::from("game.exe"), PathBuf::from("game.pdb")]);
+        assert_eq!(watcher.list_watches().len(), 2);
+
+        // Unwatching the directory drops every set of patterns on it
+        watcher.remove_watch(&root).unwrap();
+        assert!(watcher.list_watches().is_empty());
+    }
+
+    #[test]
+    fn test_remove_route_keeps_the_other_routes() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::write(root.join("game.exe"), b"exe").unwrap();
+        std::fs::write(root.join("game.pdb"), b"pdb").unwrap();
+
+        let options = |destination: &str| WatchOptions {
+            destination: Some(destination.to_string()),
+            ..WatchOptions::default()
+        };
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher
+            .add_watch_with(root.clone(), true, vec!["*.exe".to_string()], vec![], None, false, options("bin/"))
+            .unwrap();
+        watcher
+            .add_watch_with(root.clone(), true, vec!["*.pdb".to_string()], vec![], None, false, options("symbols/"))
+            .unwrap();
+
+        assert!(watcher.remove_route(&root, "docs/").is_err());
+
+        // Removing the watch's own route hands the watch to the sibling
+        watcher.remove_route(&root, "bin/").unwrap();
+        assert!(watcher.is_watched(&root));
+        assert!(watcher.targets_for(&root, Path::new("game.exe")).is_empty());
+        assert_eq!(
+            watcher.targets_for(&root, Path::new("game.pdb")),
+            vec![(Some(PathBuf::from("symbols/game.pdb")), 0)]
+        );
+
+        watcher.remove_route(&root, "symbols/").unwrap();
+        assert!(!watcher.is_watched(&root));
+    }
+
+    #[test]
+    fn test_preview_remove_splits_files_by_other_watches() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("bin")).unwrap();
+        std::fs::write(root.join("bin/game.exe"), b"exe").unwrap();
+        std::fs::write(root.join("bin/game.pdb"), b"pdb").unwrap();
+        std::fs::write(root.join("readme.txt"), b"readme").unwrap();
+
+        let mut watcher = FileWatcher::new(WatchMode::Native, 0, |_, _, _| {}).unwrap();
+        watcher.add_watch(root.clone(), true, vec![], vec![], None, false).unwrap();
+        watcher
+            .add_watch(root.join("bin"), true, vec!["*.exe".to_string()], vec![], None, false)
+            .unwrap();
+        watcher.add_watch(root.join("readme.txt"), false, vec![], vec![], None, false).unwrap();
+
+        let (stops_syncing, still_covered) = watcher.preview_remove(&root).unwrap();
+        assert_eq!(stops_syncing, vec![root.join("bin/game.pdb")]);
+        assert_eq!(still_covered, vec![root.join("bin/game.exe"), root.join("readme.txt")]);
+
+        let (stops_syncing, still_covered) = watcher.preview_remove(&root.join("readme.txt")).unwrap();
+        assert!(stops_syncing.is_empty());
+        assert_eq!(still_covered, vec![root.join("readme.txt")]);
+
+        // Previewing leaves every watch in place
+        assert_eq!(watcher.list_watches().len(), 3);
+        assert!(watcher.preview_remove(&root.join("bin/game.pdb")).is_err());
+    }
+
+    #[test]
+    fn test_only_subtree_excludes_prune() {
+        let temp = tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+
+        let config = WatchConfig::new(
+            root.clone(),
+            true,
+            vec![],
+            vec!["build/**".to_string(), "target/*.o".to_string(), "cache".to_string()],
+            false,
+        )
+        .unwrap();
+
+        assert!(config.excludes_dir(&root.join("build")));
+        assert!(!config.excludes_dir(&root.join("target")));
+        assert!(!config.excludes_dir(&root.join("cache")));
+        assert!(!config.excludes_dir(&root));
+    }
 }
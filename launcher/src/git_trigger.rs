@@ -0,0 +1,212 @@
+// Commit-granular syncing for rules with trigger = "git-commit"
+//
+// Rather than sending every write, the server polls the repository's HEAD and, when
+// it moves, syncs the files the new commit changed since the last one it saw. A file
+// edited again after being committed is skipped until that edit is committed too, so
+// clients never receive an intermediate state.
+
+use anyhow::{Context, Result};
+use git2::{Delta, Oid, Repository, Status};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often HEAD is checked for new commits
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files a move of HEAD touched, as absolute paths in the working tree
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CommitChanges {
+    /// The new HEAD
+    pub commit: String,
+    /// Added or modified, and unchanged on disk since the commit
+    pub changed: Vec<PathBuf>,
+    /// Removed (or renamed away from), and not recreated on disk
+    pub removed: Vec<PathBuf>,
+    /// Changed by the commit but edited since, so their committed state is gone
+    pub dirty: Vec<PathBuf>,
+}
+
+/// A repository's HEAD, and the commit it was at when last polled
+pub struct CommitWatch {
+    repo: Repository,
+    /// The working tree's real path, which reported files are under
+    workdir: PathBuf,
+    last: Option<Oid>,
+}
+
+impl CommitWatch {
+    /// Open the repository containing `path`, starting from its current HEAD
+    pub fn open(path: &Path) -> Result<Self> {
+        let repo = Repository::discover(path)
+            .context(format!("No git repository contains {}", path.display()))?;
+        let Some(workdir) = repo.workdir() else {
+            anyhow::bail!("{} is a bare repository, which has no files to sync", repo.path().display());
+        };
+        let workdir = workdir.canonicalize().unwrap_or_else(|_| workdir.to_path_buf());
+        let last = head_commit(&repo)?;
+        Ok(Self { repo, workdir, last })
+    }
+
+    /// Root of the repository's working tree
+    pub fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    /// What the commits since the last poll changed, or None if HEAD hasn't moved
+    pub fn poll(&mut self) -> Result<Option<CommitChanges>> {
+        let Some(head) = head_commit(&self.repo)? else {
+            return Ok(None);
+        };
+        if self.last == Some(head) {
+            return Ok(None);
+        }
+
+        let old_tree = match self.last {
+            Some(last) => Some(self.repo.find_commit(last)?.tree()?),
+            None => None,
+        };
+        let new_tree = self.repo.find_commit(head)?.tree()?;
+        let diff = self
+            .repo
+            .diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None)
+            .context("Failed to diff commits")?;
+
+        let workdir = &self.workdir;
+        let mut changes = CommitChanges {
+            commit: head.to_string(),
+            ..Default::default()
+        };
+        for delta in diff.deltas() {
+            if matches!(delta.status(), Delta::Deleted | Delta::Renamed)
+                && let Some(path) = delta.old_file().path()
+                && !workdir.join(path).exists()
+            {
+                changes.removed.push(workdir.join(path));
+            }
+            if delta.status() == Delta::Deleted {
+                continue;
+            }
+            let Some(path) = delta.new_file().path() else {
+                continue;
+            };
+            if self.is_clean(path) {
+                changes.changed.push(workdir.join(path));
+            } else {
+                changes.dirty.push(workdir.join(path));
+            }
+        }
+
+        self.last = Some(head);
+        Ok(Some(changes))
+    }
+
+    /// Whether the file at `relative` still matches what HEAD holds
+    fn is_clean(&self, relative: &Path) -> bool {
+        let edited = Status::WT_MODIFIED
+            | Status::WT_DELETED
+            | Status::WT_TYPECHANGE
+            | Status::WT_RENAMED
+            | Status::INDEX_MODIFIED
+            | Status::INDEX_DELETED
+            | Status::INDEX_TYPECHANGE
+            | Status::INDEX_RENAMED;
+        self.repo
+            .status_file(relative)
+            .is_ok_and(|status| !status.intersects(edited))
+    }
+}
+
+/// The commit HEAD points at, or None before the first commit
+fn head_commit(repo: &Repository) -> Result<Option<Oid>> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit().context("HEAD is not a commit")?.id())),
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch || e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to read HEAD"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn commit_all(repo: &Repository, message: &str) {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.update_all(["*"], None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_poll_reports_what_each_commit_changed() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        let repo = Repository::init(root).unwrap();
+        std::fs::create_dir(root.join("bin")).unwrap();
+        std::fs::write(root.join("bin/game.exe"), b"v1").unwrap();
+        std::fs::write(root.join("readme.txt"), b"hello").unwrap();
+        commit_all(&repo, "first");
+
+        let mut watch = CommitWatch::open(&root.join("bin")).unwrap();
+        let workdir = watch.workdir().to_path_buf();
+        assert_eq!(watch.poll().unwrap(), None);
+
+        // Writes alone don't count
+        std::fs::write(root.join("bin/game.exe"), b"v2").unwrap();
+        std::fs::write(root.join("bin/tool.exe"), b"tool").unwrap();
+        assert_eq!(watch.poll().unwrap(), None);
+
+        commit_all(&repo, "second");
+        let changes = watch.poll().unwrap().unwrap();
+        assert_eq!(changes.changed, [workdir.join("bin/game.exe"), workdir.join("bin/tool.exe")]);
+        assert!(changes.removed.is_empty());
+        assert!(changes.dirty.is_empty());
+        assert_eq!(watch.poll().unwrap(), None);
+
+        std::fs::remove_file(root.join("readme.txt")).unwrap();
+        commit_all(&repo, "third");
+        let changes = watch.poll().unwrap().unwrap();
+        assert!(changes.changed.is_empty());
+        assert_eq!(changes.removed, [workdir.join("readme.txt")]);
+    }
+
+    #[test]
+    fn test_files_edited_after_the_commit_are_dirty() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        let repo = Repository::init(root).unwrap();
+        std::fs::write(root.join("a.txt"), b"a1").unwrap();
+        commit_all(&repo, "first");
+        let mut watch = CommitWatch::open(root).unwrap();
+        let workdir = watch.workdir().to_path_buf();
+
+        std::fs::write(root.join("a.txt"), b"a2").unwrap();
+        std::fs::write(root.join("b.txt"), b"b1").unwrap();
+        commit_all(&repo, "second");
+        std::fs::write(root.join("a.txt"), b"a3, not committed yet").unwrap();
+
+        let changes = watch.poll().unwrap().unwrap();
+        assert_eq!(changes.changed, [workdir.join("b.txt")]);
+        assert_eq!(changes.dirty, [workdir.join("a.txt")]);
+    }
+
+    #[test]
+    fn test_first_commit_of_an_empty_repository() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        let repo = Repository::init(root).unwrap();
+        let mut watch = CommitWatch::open(root).unwrap();
+        assert_eq!(watch.poll().unwrap(), None);
+
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+        commit_all(&repo, "first");
+        let changes = watch.poll().unwrap().unwrap();
+        assert_eq!(changes.changed, [watch.workdir().join("a.txt")]);
+    }
+}
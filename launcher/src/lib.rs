@@ -3,10 +3,18 @@
 // This module exposes the core functionality for integration testing
 // and potential future library use.
 
+pub mod authorized_keys;
+pub mod backoff;
 pub mod client_daemon;
 pub mod client_registry;
 pub mod config;
+pub mod config_lint;
 pub mod file_watcher;
+#[cfg(feature = "git")]
+pub mod git_trigger;
+pub mod log_buffer;
 pub mod rsync_utils;
 pub mod ssh_client;
 pub mod ssh_server;
+pub mod sync_log;
+pub mod sync_queue;
@@ -0,0 +1,108 @@
+// In-memory copy of recent log lines
+//
+// The server answers `diagnostics` with its last few hundred log lines, so a support
+// report doesn't depend on where (or whether) the process's stderr was kept.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Log lines kept for diagnostics; older lines are dropped first
+pub const LOG_BUFFER_LINES: usize = 500;
+
+static BUFFER: OnceLock<Mutex<LogRing>> = OnceLock::new();
+
+/// Bounded queue of formatted log lines
+struct LogRing {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl LogRing {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// The kept lines, oldest first
+    fn lines(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
+/// Passes records to env_logger and keeps a copy of those it prints
+struct TeeLogger {
+    inner: env_logger::Logger,
+}
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!(
+            "[{}.{:03} {} {}] {}",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Some(buffer) = BUFFER.get() {
+            buffer.lock().unwrap().push(line);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install `builder`'s logger as the global logger, keeping the last LOG_BUFFER_LINES
+/// lines it prints for [`recent`]
+pub fn init(mut builder: env_logger::Builder) {
+    let inner = builder.build();
+    let max_level = inner.filter();
+    BUFFER.get_or_init(|| Mutex::new(LogRing::new(LOG_BUFFER_LINES)));
+    if log::set_boxed_logger(Box::new(TeeLogger { inner })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Recently logged lines, oldest first; empty unless [`init`] installed the logger
+pub fn recent() -> Vec<String> {
+    BUFFER
+        .get()
+        .map(|buffer| buffer.lock().unwrap().lines())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_ring_drops_oldest_lines() {
+        let mut ring = LogRing::new(3);
+        for i in 0..5 {
+            ring.push(format!("line {}", i));
+        }
+        assert_eq!(ring.lines(), vec!["line 2", "line 3", "line 4"]);
+    }
+}
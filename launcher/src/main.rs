@@ -1,8 +1,11 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use halfremembered_launcher::{client_daemon, config, ssh_client, ssh_server};
-use halfremembered_protocol::{LocalCommand, LocalResponse};
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use halfremembered_launcher::{client_daemon, config, config_lint, file_watcher, log_buffer, rsync_utils, ssh_client, ssh_server};
+use halfremembered_protocol::{ErrorCounts, ExecProcess, LocalCommand, LocalResponse, TransferInfo, TransferRates, WatchInfo, WireFormat};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Parser)]
 #[command(name = "halfremembered-launcher")]
@@ -10,6 +13,38 @@ use std::path::PathBuf;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Run async work on this many threads (default: one per CPU core)
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    worker_threads: Option<u64>,
+
+    /// Run async work on the main thread alone, for the smallest footprint
+    #[arg(long, global = true, conflicts_with = "worker_threads")]
+    current_thread: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WatchModeArg {
+    /// Platform-native filesystem events
+    Native,
+    /// Periodically rescan watched paths
+    Poll,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChecksumAlgoArg {
+    /// BLAKE3, fast on large files
+    Blake3,
+    /// SHA-256
+    Sha256,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WireFormatArg {
+    /// Compact bincode, for Rust peers
+    Bincode,
+    /// MessagePack with named fields, readable from other languages
+    Msgpack,
 }
 
 #[derive(Subcommand)]
@@ -19,6 +54,85 @@ enum Commands {
         /// Port to listen on
         #[arg(short, long, default_value = "20222")]
         port: u16,
+
+        /// Detach from the terminal and run in the background (Unix only)
+        #[arg(long)]
+        daemonize: bool,
+
+        /// Log file for the daemonized server
+        #[arg(long, default_value = "~/.halfremembered-launcher-server.log")]
+        log_file: String,
+
+        /// Pid file for the daemonized server
+        #[arg(long, default_value = "~/.halfremembered-launcher-server.pid")]
+        pid_file: String,
+
+        /// Write the bound address (e.g. 0.0.0.0:41234) to this file once listening
+        #[arg(long)]
+        port_file: Option<String>,
+
+        /// Also accept control commands on this Unix socket, without SSH or an agent;
+        /// point control commands at it with `--server unix:PATH` (Unix only)
+        #[arg(long)]
+        control_socket: Option<String>,
+
+        /// How watches detect changes; `poll` works on NFS/SMB/overlay filesystems
+        #[arg(long, value_enum, default_value = "native")]
+        watch_mode: WatchModeArg,
+
+        /// Seconds between rescans with `--watch-mode poll`
+        #[arg(long, default_value = "2")]
+        poll_interval: u64,
+
+        /// Read and checksum changed files on this many threads instead of the
+        /// filesystem event thread, so big build bursts don't delay later events
+        /// (default: 0, on the event thread)
+        #[arg(long)]
+        watch_workers: Option<usize>,
+
+        /// Sync a file matched by several watches or sync rules only for the first,
+        /// instead of once for each
+        #[arg(long)]
+        dedup: bool,
+
+        /// Octal mode (e.g. 755) for synced files when this platform has no Unix
+        /// permissions to send; overrides the config's default_mode
+        #[arg(long, value_parser = parse_mode)]
+        default_mode: Option<u32>,
+
+        /// Send files smaller than this many bytes whole, skipping the rsync
+        /// signature/delta exchange (0 always uses rsync)
+        #[arg(long, default_value_t = rsync_utils::WHOLE_FILE_THRESHOLD)]
+        whole_file_threshold: u64,
+
+        /// Hash for file checksums; overrides the config's checksum_algo (default: blake3)
+        #[arg(long, value_enum)]
+        checksum_algo: Option<ChecksumAlgoArg>,
+
+        /// Also create empty directories on clients during `sync --recursive` and
+        /// initial syncs, so they get the source's full directory layout
+        #[arg(long)]
+        sync_empty_dirs: bool,
+
+        /// Send clients a lightweight keepalive every this many seconds, answered with a
+        /// pong instead of a full status (default: off)
+        #[arg(long)]
+        keepalive: Option<u64>,
+
+        /// Messages queued for each client before one that stopped reading is
+        /// disconnected to reconnect afresh (default: 64)
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        send_queue: Option<u64>,
+
+        /// Only sync to client paths under this one (repeatable); overrides the config's
+        /// allowed_destination_roots (default: anywhere)
+        #[arg(long = "allowed-destination-root")]
+        allowed_destination_roots: Vec<String>,
+
+        /// Connections the listening socket queues before refusing more; raise it for
+        /// busy servers (default: 1024)
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        listen_backlog: Option<u32>,
     },
 
     /// Start the client daemon (connects to server)
@@ -38,14 +152,89 @@ enum Commands {
         #[arg(long, default_value = "5")]
         reconnect: u64,
 
+        /// Exit with code 75 after this many consecutive failed reconnects
+        /// (default: retry forever)
+        #[arg(long)]
+        max_retries: Option<u32>,
+
+        /// Directory for state kept across restarts, such as the reconnect backoff and
+        /// the checksums of synced files an interrupted initial sync resumes from
+        #[arg(long, default_value = "~/.halfremembered-launcher")]
+        state_dir: String,
+
         /// SSH agent socket path (Unix: socket path, Windows: named pipe path)
-        /// Defaults to SSH_AUTH_SOCK env var on Unix, \\.\pipe\openssh-ssh-agent on Windows
+        /// Defaults to HRL_AGENT_SOCKET, then SSH_AUTH_SOCK on Unix or
+        /// \\.\pipe\openssh-ssh-agent on Windows
         #[arg(long)]
         agent_socket: Option<String>,
 
         /// Disable initial sync of watched files on connection
         #[arg(long, default_value = "false")]
         no_initial_sync: bool,
+
+        /// Encoding of messages to the server; it answers in the same one
+        #[arg(long, value_enum, default_value = "bincode")]
+        wire_format: WireFormatArg,
+
+        /// Kill executed commands that run longer than this many seconds (default: no limit)
+        #[arg(long)]
+        exec_timeout: Option<u64>,
+
+        /// Maximum bytes of stdout and of stderr captured from an executed command
+        #[arg(long, default_value_t = client_daemon::DEFAULT_EXEC_OUTPUT_LIMIT)]
+        exec_output_limit: usize,
+
+        /// File of binary names/paths (globs, one per line) the client may execute;
+        /// anything else is refused (default: execute whatever the server sends)
+        #[arg(long)]
+        exec_allowlist: Option<PathBuf>,
+
+        /// Largest sync (base file plus delta, in bytes) applied in memory; larger files
+        /// are memory-mapped and streamed to disk
+        #[arg(long, default_value_t = rsync_utils::DEFAULT_MEMORY_BUDGET)]
+        memory_budget: usize,
+
+        /// Refuse a sync that would leave fewer than this many bytes free on the
+        /// destination's filesystem, instead of failing partway through writing it
+        /// (by default only files that don't fit are refused)
+        #[arg(long, default_value_t = client_daemon::DEFAULT_MIN_FREE_SPACE)]
+        min_free_space: u64,
+
+        /// Abandon a sync as failed when the server sends none of its data for this
+        /// many seconds
+        #[arg(long, default_value_t = client_daemon::DEFAULT_RSYNC_TIMEOUT.as_secs())]
+        rsync_timeout: u64,
+
+        /// Reconnect when nothing has arrived from the server for this many seconds;
+        /// pair with the server's --keepalive so a healthy link is never this quiet
+        /// (default: rely on the one-hour SSH inactivity timeout)
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        idle_reconnect: Option<u64>,
+
+        /// Write files to absolute destination paths sent by the server
+        /// (default: refuse them; `..` is refused either way)
+        #[arg(long)]
+        allow_absolute_destinations: bool,
+
+        /// Register under this hostname instead of the machine's own
+        #[arg(long, value_parser = parse_client_name)]
+        name: Option<String>,
+
+        /// Queue syncs while a process with this name is running (e.g. a game under
+        /// test) and apply them once it exits; repeat for several processes
+        #[arg(long, value_name = "PROCESS")]
+        defer_while_busy: Vec<String>,
+
+        /// Append a JSON line per finished sync (time, path, bytes, checksum, outcome)
+        /// to this file
+        #[arg(long, value_name = "PATH")]
+        sync_log: Option<String>,
+
+        /// Refuse syncs over files edited here since they were last synced, reporting
+        /// a conflict instead; `sync --force` on the server still replaces them. The
+        /// checksums compared against are kept under --state-dir
+        #[arg(long)]
+        no_clobber_local: bool,
     },
 
     /// Send ping to a connected client (server-side command)
@@ -61,6 +250,95 @@ enum Commands {
         /// Hostname of the client to ping
         hostname: String,
 
+        /// Session id (from `list`) of the client, when several share the hostname
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// Show one client's detailed state: running processes, pending transfers (server-side command)
+    ClientStatus {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// Hostname of the client to query
+        hostname: String,
+
+        /// Session id (from `list`) of the client, when several share the hostname
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// List the commands a client is running for `exec` (server-side command)
+    Processes {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// Hostname of the client to query
+        hostname: String,
+
+        /// Session id (from `list`) of the client, when several share the hostname
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// Terminate a command a client is running for `exec` (server-side command)
+    Kill {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// Hostname of the client to signal
+        hostname: String,
+
+        /// Request id of the exec, as shown by `processes`
+        request_id: String,
+
+        /// Session id (from `list`) of the client, when several share the hostname
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -76,6 +354,10 @@ enum Commands {
         #[arg(short = 'P', long, default_value = "20222")]
         port: u16,
 
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -94,12 +376,33 @@ enum Commands {
         /// Hostname of the client to execute on
         hostname: String,
 
+        /// Session id (from `list`) of the client, when several share the hostname
+        #[arg(long)]
+        session: Option<String>,
+
         /// Binary to execute
         binary: String,
 
         /// Arguments for the binary
         args: Vec<String>,
 
+        /// Show the command's output here as it runs and exit with its exit code;
+        /// waits for the command to finish regardless of --timeout
+        #[arg(long)]
+        stream: bool,
+
+        /// Directory on the client to run the command in
+        #[arg(long, value_name = "DIR")]
+        cwd: Option<String>,
+
+        /// Environment variable for the command, as KEY=VALUE (repeatable)
+        #[arg(long, value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -115,13 +418,48 @@ enum Commands {
         #[arg(short = 'P', long, default_value = "20222")]
         port: u16,
 
-        /// Local file path to sync
+        /// Local file path to sync (a directory with --recursive)
         file: PathBuf,
 
         /// Remote destination path on clients
         #[arg(short, long)]
         destination: Option<String>,
 
+        /// Sync every file under the directory, keeping its layout below the destination
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// With --recursive, only sync files matching these patterns (e.g., "**/*.exe")
+        #[arg(long, requires = "recursive")]
+        include: Vec<String>,
+
+        /// With --recursive, skip files matching these patterns (e.g., "*.pdb", "cache/**")
+        #[arg(long, requires = "recursive")]
+        exclude: Vec<String>,
+
+        /// Succeed if at least one client received the file. Without this, any
+        /// unreachable client fails the sync (exit code 2)
+        #[arg(long)]
+        allow_partial: bool,
+
+        /// Replace the file even on clients running with --no-clobber-local that
+        /// have edited their copy
+        #[arg(long)]
+        force: bool,
+
+        /// Transfer nothing; have clients hash their copy of the destination and
+        /// report whether it matches the local file
+        #[arg(long, conflicts_with_all = ["recursive", "allow_partial", "force"])]
+        checksum_only: bool,
+
+        /// With --checksum-only, check only this client instead of all of them
+        #[arg(long, requires = "checksum_only")]
+        target: Option<String>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -129,8 +467,10 @@ enum Commands {
 
     /// Push binary to remote host via scp
     Push {
-        /// Server connection string (user@host)
-        server: String,
+        /// Server connection strings (user@host, or user@host:port to give the sshd port);
+        /// several hosts are pushed to in parallel
+        #[arg(required = true)]
+        servers: Vec<String>,
 
         /// Local binary path to upload
         #[arg(
@@ -152,6 +492,32 @@ enum Commands {
         #[arg(short, long, default_value = "20222")]
         port: u16,
 
+        /// Port of the remote host's sshd, used for upload and remote commands
+        /// (a port in the connection string takes precedence)
+        #[arg(long, default_value = "22")]
+        ssh_port: u16,
+
+        /// Gzip the binary for upload and decompress it remotely (needs gzip on the remote host)
+        #[arg(long)]
+        compress: bool,
+
+        /// Bytes read from disk and written per step of the upload
+        #[arg(long, default_value_t = ssh_client::DEFAULT_UPLOAD_CHUNK_SIZE)]
+        upload_chunk_size: usize,
+
+        /// Continue an interrupted upload from the size of the remote file, once its
+        /// checksum shows it is the start of this one (needs sha256sum on the remote host)
+        #[arg(long, conflicts_with = "compress")]
+        resume: bool,
+
+        /// Extra argument for the server started by --start (repeatable, e.g. --server-arg=--watch-mode --server-arg=poll)
+        #[arg(long, allow_hyphen_values = true)]
+        server_arg: Vec<String>,
+
+        /// Environment variable for the server started by --start, as KEY=VALUE (repeatable)
+        #[arg(long, value_parser = parse_env_var)]
+        server_env: Vec<(String, String)>,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -167,6 +533,34 @@ enum Commands {
         #[arg(short = 'P', long, default_value = "20222")]
         port: u16,
 
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// Collect server state, clients, watches, in-flight transfers and recent server
+    /// log lines into one JSON report (server-side command)
+    Diagnostics {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// File to write the report to (default: print it)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -182,6 +576,30 @@ enum Commands {
         #[arg(short = 'P', long, default_value = "20222")]
         port: u16,
 
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// Stop accepting new clients and syncs, let in-flight transfers finish, then
+    /// shut down (server-side command)
+    Drain {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -200,10 +618,14 @@ enum Commands {
         /// File or directory to watch
         path: PathBuf,
 
-        /// Watch recursively (only applies to directories)
-        #[arg(short, long, default_value = "true")]
+        /// Watch recursively, the default (only applies to directories)
+        #[arg(short, long, overrides_with = "no_recursive")]
         recursive: bool,
 
+        /// Watch only the directory's top level, not its subdirectories
+        #[arg(long, overrides_with = "recursive")]
+        no_recursive: bool,
+
         /// Include patterns (e.g., "*.rs", "*.toml")
         #[arg(long)]
         include: Vec<String>,
@@ -212,6 +634,42 @@ enum Commands {
         #[arg(long)]
         exclude: Vec<String>,
 
+        /// Read include patterns from a file, one per line (`#` starts a comment);
+        /// merged with any --include
+        #[arg(long)]
+        include_from: Option<PathBuf>,
+
+        /// Read exclude patterns from a file, one per line (`#` starts a comment);
+        /// merged with any --exclude
+        #[arg(long)]
+        exclude_from: Option<PathBuf>,
+
+        /// Match include/exclude patterns ignoring case
+        #[arg(long)]
+        case_insensitive: bool,
+
+        /// Skip checking that the server's filesystem delivers change events for this path
+        #[arg(long)]
+        no_verify_events: bool,
+
+        /// Wait until a newly created file has been unchanged this many milliseconds before syncing it
+        #[arg(long, default_value = "0")]
+        settle_ms: u64,
+
+        /// When many files change at once, sync this watch's before those of watches
+        /// with a lower priority (negative values go last)
+        #[arg(long, default_value = "0", allow_negative_numbers = true)]
+        priority: i32,
+
+        /// Directory containing PATH that client paths are relative to (default: PATH
+        /// itself), e.g. `--base .` with `src` syncs `src/main.rs` rather than `main.rs`
+        #[arg(long)]
+        base: Option<PathBuf>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
@@ -230,13 +688,22 @@ enum Commands {
         /// File or directory to stop watching
         path: PathBuf,
 
+        /// List the files that would stop syncing, and those another watch still covers,
+        /// without removing the watch
+        #[arg(long)]
+        preview: bool,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
     },
 
-    /// List active filesystem watches (server-side command)
-    ListWatches {
+    /// Hold changes under a watch instead of syncing them (server-side command)
+    Pause {
         /// Server connection string (user@host or just host, defaults to $USER@localhost)
         #[arg(short, long)]
         server: Option<String>,
@@ -245,13 +712,20 @@ enum Commands {
         #[arg(short = 'P', long, default_value = "20222")]
         port: u16,
 
+        /// Watched file or directory to pause
+        path: PathBuf,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
     },
 
-    /// Sync files using .hrlauncher.toml config with automatic filesystem watching
-    ConfigSync {
+    /// Resume a paused watch, syncing only files that changed while it was paused (server-side command)
+    Resume {
         /// Server connection string (user@host or just host, defaults to $USER@localhost)
         #[arg(short, long)]
         server: Option<String>,
@@ -260,75 +734,382 @@ enum Commands {
         #[arg(short = 'P', long, default_value = "20222")]
         port: u16,
 
-        /// Path to config file (default: search for .hrlauncher.toml in current dir and parents)
-        #[arg(short, long)]
-        config: Option<PathBuf>,
+        /// Watched file or directory to resume
+        path: PathBuf,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
 
         /// SSH agent socket path
         #[arg(long)]
         agent_socket: Option<String>,
     },
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    /// Change the destination or patterns of an active watch in place (server-side command)
+    UpdateWatch {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
 
-    let cli = Cli::parse();
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
 
-    match cli.command {
-        Commands::Server { port } => {
-            log::info!("Starting HalfRemembered server on port {}", port);
-            ssh_server::SshServer::run(port).await?;
-        }
+        /// Watched file or directory, as given to `watch`
+        path: PathBuf,
 
-        Commands::Client {
-            server,
-            port,
-            heartbeat,
-            reconnect,
-            agent_socket,
-            no_initial_sync,
-        } => {
-            log::info!("Starting HalfRemembered client, connecting to {}", server);
+        /// New client-side directory for the watch's files
+        #[arg(long)]
+        destination: Option<String>,
 
-            let (user, host, conn_port) = parse_connection_string(&server)?;
-            let final_port = conn_port.unwrap_or(port);
-            let hostname = hostname::get()
-                .context("Failed to get hostname")?
-                .to_string_lossy()
-                .to_string();
+        /// Replace the include patterns (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
 
-            let mut daemon = client_daemon::ClientDaemon::new(host, final_port, user, hostname)
-                .with_heartbeat_interval(std::time::Duration::from_secs(heartbeat))
-                .with_reconnect_delay(std::time::Duration::from_secs(reconnect))
-                .with_agent_socket(agent_socket)
-                .with_initial_sync(!no_initial_sync);
+        /// Replace the exclude patterns (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
 
-            daemon.run().await?;
-        }
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
 
-        Commands::Ping {
-            server,
-            port,
-            hostname,
-            agent_socket,
-        } => {
-            log::info!("Pinging client: {}", hostname);
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
 
-            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
-            let (user, host, conn_port) = parse_connection_string(&server)?;
+    /// List active filesystem watches (server-side command)
+    ListWatches {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// Sync files using .hrlauncher.toml config with automatic filesystem watching
+    ConfigSync {
+        /// Server connection string (user@host or just host, defaults to $USER@localhost);
+        /// repeat to set up the same watches on several servers
+        #[arg(short, long)]
+        server: Vec<String>,
+
+        /// Server port
+        #[arg(short = 'P', long, default_value = "20222")]
+        port: u16,
+
+        /// Path to config file (default: search for .hrlauncher.toml in current dir and parents)
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+
+        /// If any server fails, remove the watches already set up on the others
+        #[arg(long)]
+        all_or_nothing: bool,
+
+        /// Skip checking that each server's filesystem delivers change events
+        #[arg(long)]
+        no_verify_events: bool,
+
+        /// Prefix every rule's destination, e.g. /opt/game/ turns bin/ into /opt/game/bin/
+        #[arg(long)]
+        destination_base: Option<String>,
+    },
+
+    /// Check .hrlauncher.toml rules against the project's files for ineffective or
+    /// dangerous settings; exits with 1 if any finding is dangerous
+    ConfigLint {
+        /// Path to config file (default: search for .hrlauncher.toml in current dir and parents)
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+
+    /// Upload a new launcher binary to the server and roll it out to every connected client
+    SelfUpdate {
+        /// Server connection string (user@host, or user@host:port to give the sshd port)
+        server: String,
+
+        /// Local binary path to upload
+        #[arg(
+            short,
+            long,
+            default_value = "./target/release/halfremembered-launcher"
+        )]
+        binary: PathBuf,
+
+        /// Path on the server to upload the binary to
+        #[arg(short, long, default_value = "~/halfremembered-launcher")]
+        destination: String,
+
+        /// Path on each client where the new binary is staged before it is applied
+        #[arg(long, default_value = "~/.halfremembered-launcher.staged")]
+        staging: String,
+
+        /// Server port for control connection
+        #[arg(short, long, default_value = "20222")]
+        port: u16,
+
+        /// Port of the server's sshd, used for upload
+        #[arg(long, default_value = "22")]
+        ssh_port: u16,
+
+        /// Seconds to wait for the server's response (0 waits indefinitely)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+
+        /// SSH agent socket path
+        #[arg(long)]
+        agent_socket: Option<String>,
+    },
+
+    /// Install this (staged) binary over the running client daemon; run by the daemon itself
+    #[command(name = "apply-update", hide = true)]
+    ApplyUpdate,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    // Forking is only safe while the process is single-threaded, so detach before
+    // the tokio runtime (and its worker threads) exists
+    if let Commands::Server {
+        daemonize: true,
+        ref log_file,
+        ref pid_file,
+        ..
+    } = cli.command
+    {
+        daemonize_server(log_file, pid_file)?;
+    }
+
+    log_buffer::init(env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")));
+
+    let mut runtime = if cli.current_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(threads) = cli.worker_threads {
+            builder.worker_threads(threads as usize);
+        }
+        builder
+    };
+    runtime
+        .enable_all()
+        .build()
+        .context("Failed to start tokio runtime")?
+        .block_on(run(cli))
+}
+
+/// Detach the server: fork into the background, redirect stdout/stderr (and so the
+/// log) to `log_file`, and record the daemon's pid in `pid_file`
+#[cfg(unix)]
+fn daemonize_server(log_file: &str, pid_file: &str) -> Result<()> {
+    let log_path = client_daemon::expand_tilde(log_file);
+    let pid_path = client_daemon::expand_tilde(pid_file);
+
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .context(format!("Failed to open log file: {}", log_path.display()))?;
+    let log_for_stderr = log.try_clone().context("Failed to duplicate log file handle")?;
+
+    println!("✓ Server daemonizing (log: {}, pid file: {})", log_path.display(), pid_path.display());
+
+    // Keep the working directory: the server looks for .hrlauncher.toml from there
+    let working_dir = std::env::current_dir().context("Failed to get current directory")?;
+
+    daemonize::Daemonize::new()
+        .pid_file(&pid_path)
+        .working_directory(working_dir)
+        .stdout(log)
+        .stderr(log_for_stderr)
+        .start()
+        .context("Failed to daemonize server")?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemonize_server(_log_file: &str, _pid_file: &str) -> Result<()> {
+    anyhow::bail!("--daemonize is only supported on Unix")
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Commands::Server {
+            port,
+            daemonize,
+            pid_file,
+            port_file,
+            control_socket,
+            watch_mode,
+            poll_interval,
+            watch_workers,
+            dedup,
+            default_mode,
+            whole_file_threshold,
+            checksum_algo,
+            sync_empty_dirs,
+            keepalive,
+            send_queue,
+            allowed_destination_roots,
+            listen_backlog,
+            ..
+        } => {
+            log::info!("Starting HalfRemembered server on port {}", port);
+
+            let pid_path = client_daemon::expand_tilde(&pid_file);
+            if daemonize {
+                ssh_server::set_pid_file(pid_path.clone());
+            }
+
+            let options = ssh_server::ServerOptions {
+                port_file: port_file.map(|path| client_daemon::expand_tilde(&path)),
+                watch_mode: match watch_mode {
+                    WatchModeArg::Native => file_watcher::WatchMode::Native,
+                    WatchModeArg::Poll => {
+                        file_watcher::WatchMode::Poll(std::time::Duration::from_secs(poll_interval))
+                    }
+                },
+                dedup,
+                default_mode,
+                whole_file_threshold: Some(whole_file_threshold),
+                checksum_algo: checksum_algo.map(|algo| match algo {
+                    ChecksumAlgoArg::Blake3 => rsync_utils::ChecksumAlgo::Blake3,
+                    ChecksumAlgoArg::Sha256 => rsync_utils::ChecksumAlgo::Sha256,
+                }),
+                sync_empty_dirs,
+                keepalive_interval: keepalive.filter(|&seconds| seconds > 0).map(std::time::Duration::from_secs),
+                send_queue_depth: send_queue.map(|depth| depth as usize),
+                control_socket: control_socket.map(|path| client_daemon::expand_tilde(&path)),
+                allowed_destination_roots,
+                listen_backlog,
+                watch_workers,
+            };
+            let result = ssh_server::SshServer::run_with_options(port, options).await;
+
+            if daemonize && let Err(e) = std::fs::remove_file(&pid_path) {
+                log::warn!("Failed to remove pid file {}: {}", pid_path.display(), e);
+            }
+
+            result?;
+        }
+
+        Commands::Client {
+            server,
+            port,
+            heartbeat,
+            reconnect,
+            max_retries,
+            state_dir,
+            agent_socket,
+            no_initial_sync,
+            wire_format,
+            exec_timeout,
+            exec_output_limit,
+            exec_allowlist,
+            memory_budget,
+            min_free_space,
+            rsync_timeout,
+            idle_reconnect,
+            allow_absolute_destinations,
+            name,
+            defer_while_busy,
+            sync_log,
+            no_clobber_local,
+        } => {
+            log::info!("Starting HalfRemembered client, connecting to {}", server);
+
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let hostname = match name {
+                Some(name) => name,
+                None => hostname::get()
+                    .context("Failed to get hostname")?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            let exec_allowlist = exec_allowlist
+                .map(|path| client_daemon::ExecAllowlist::load(&path))
+                .transpose()?;
+
+            let mut daemon = client_daemon::ClientDaemon::new(host, final_port, user, hostname)
+                .with_heartbeat_interval(std::time::Duration::from_secs(heartbeat))
+                .with_reconnect_delay(std::time::Duration::from_secs(reconnect))
+                .with_agent_socket(agent_socket)
+                .with_initial_sync(!no_initial_sync)
+                .with_wire_format(match wire_format {
+                    WireFormatArg::Bincode => WireFormat::Bincode,
+                    WireFormatArg::Msgpack => WireFormat::MessagePack,
+                })
+                .with_exec_timeout(exec_timeout.map(std::time::Duration::from_secs))
+                .with_exec_output_limit(exec_output_limit)
+                .with_exec_allowlist(exec_allowlist)
+                .with_memory_budget(memory_budget)
+                .with_min_free_space(min_free_space)
+                .with_rsync_timeout(std::time::Duration::from_secs(rsync_timeout))
+                .with_idle_reconnect(idle_reconnect.map(std::time::Duration::from_secs))
+                .with_allow_absolute_destinations(allow_absolute_destinations)
+                .with_defer_while_busy(defer_while_busy)
+                .with_sync_log(sync_log.as_deref().map(client_daemon::expand_tilde))
+                .with_no_clobber_local(no_clobber_local)
+                .with_max_retries(max_retries)
+                .with_state_dir(Some(client_daemon::expand_tilde(&state_dir)));
+
+            if let Err(e) = daemon.run().await {
+                if e.downcast_ref::<client_daemon::RetriesExhausted>().is_some() {
+                    log::error!("{:#}", e);
+                    std::process::exit(client_daemon::RETRIES_EXHAUSTED_EXIT_CODE);
+                }
+                return Err(e);
+            }
+        }
+
+        Commands::Ping {
+            server,
+            port,
+            hostname,
+            session,
+            timeout,
+            agent_socket,
+        } => {
+            log::info!("Pinging client: {}", hostname);
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
             let command = LocalCommand::Ping {
                 target: hostname.clone(),
+                session_id: session,
             };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
@@ -347,83 +1128,123 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::List {
+        Commands::ClientStatus {
             server,
             port,
+            hostname,
+            session,
+            timeout,
             agent_socket,
         } => {
-            log::debug!("Listing connected clients");
+            log::debug!("Querying client status: {}", hostname);
 
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
-            let command = LocalCommand::ListClients;
+            let command = LocalCommand::ClientStatus {
+                hostname,
+                session_id: session,
+            };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
             match response {
-                LocalResponse::ClientList { clients } => {
-                    if clients.is_empty() {
-                        println!("No clients connected");
+                LocalResponse::ClientState { hostname, state } => {
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or(0);
+
+                    println!("Client: {}", hostname);
+                    println!(
+                        "Connected: {}",
+                        format_duration(now.saturating_sub(state.connected_since))
+                    );
+                    match state.last_sync {
+                        Some(last_sync) => {
+                            println!("Last sync: {} ago", format_duration(now.saturating_sub(last_sync)));
+                        }
+                        None => println!("Last sync: never"),
+                    }
+                    println!("Pending transfers: {}", state.pending_transfers);
+                    if let Some(free_space) = state.free_space {
+                        println!("Free space: {} bytes", free_space);
+                    }
+                    if state.running_processes.is_empty() {
+                        println!("Running processes: none");
                     } else {
-                        println!("Connected clients ({}):", clients.len());
-                        for client in clients {
-                            println!(
-                                "  {} - {} ({})",
-                                client.hostname, client.platform, client.session_id
-                            );
+                        println!("Running processes ({}):", state.running_processes.len());
+                        for process in state.running_processes {
+                            println!("  {}", process);
                         }
                     }
+                    if state.exec_processes.is_empty() {
+                        println!("Exec processes: none");
+                    } else {
+                        println!("Exec processes ({}):", state.exec_processes.len());
+                        print_exec_processes(&state.exec_processes, now);
+                    }
                 }
                 LocalResponse::Error { message } => {
-                    eprintln!("Error: {}", message);
+                    eprintln!("✗ Error: {}", message);
                     std::process::exit(1);
                 }
                 _ => {
-                    eprintln!("Unexpected response: {:?}", response);
+                    eprintln!("✗ Unexpected response: {:?}", response);
                     std::process::exit(1);
                 }
             }
         }
 
-        Commands::Exec {
+        Commands::Processes {
             server,
             port,
             hostname,
-            binary,
-            args,
+            session,
+            timeout,
             agent_socket,
         } => {
-            log::info!("Executing {} on {}", binary, hostname);
+            log::debug!("Listing exec processes on: {}", hostname);
 
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
-            let command = LocalCommand::Execute {
-                target: hostname.clone(),
-                binary,
-                args,
+            let command = LocalCommand::ListProcesses {
+                target: hostname,
+                session_id: session,
             };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
             match response {
-                LocalResponse::Success { message } => {
-                    println!("✓ {}", message);
+                LocalResponse::ProcessList { hostname, processes } => {
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or(0);
+
+                    if processes.is_empty() {
+                        println!("{} is not running any exec processes", hostname);
+                    } else {
+                        println!("Exec processes on {} ({}):", hostname, processes.len());
+                        print_exec_processes(&processes, now);
+                    }
                 }
                 LocalResponse::Error { message } => {
                     eprintln!("✗ Error: {}", message);
@@ -436,31 +1257,33 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::Sync {
+        Commands::Kill {
             server,
             port,
-            file,
-            destination,
+            hostname,
+            request_id,
+            session,
+            timeout,
             agent_socket,
         } => {
-            log::info!("Syncing {} to all clients", file.display());
+            log::debug!("Killing {} on: {}", request_id, hostname);
 
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
-            let dest = destination.unwrap_or_else(|| file.to_string_lossy().to_string());
-
-            let command = LocalCommand::SyncFile {
-                file: file.to_string_lossy().to_string(),
-                destination: dest,
+            let command = LocalCommand::KillProcess {
+                target: hostname,
+                session_id: session,
+                request_id,
             };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
@@ -479,117 +1302,345 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::Push {
+        Commands::List {
             server,
-            binary,
-            destination,
-            start,
             port,
+            timeout,
             agent_socket,
         } => {
-            log::info!("Pushing {} to {}", binary.display(), server);
+            log::debug!("Listing connected clients");
 
-            let (user, host, _conn_port) = parse_connection_string(&server)?;
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let command = LocalCommand::ListClients;
 
-            // Upload binary via SFTP (uses host sshd on port 22)
-            ssh_client::SshClientConnection::upload_file_via_sftp(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
-                22, // Use standard SSH port for upload
+                final_port,
                 &user,
-                &binary,
-                &destination,
+                command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
-            println!(
-                "✓ Uploaded {} to {}@{}:{}",
-                binary.display(),
-                user,
-                host,
-                destination
-            );
-
-            if start {
-                log::info!("Starting server on remote host");
-
-                // Make the binary executable using russh
-                let chmod_cmd = format!("chmod +x {}", destination);
-                let (chmod_success, _, chmod_stderr) =
-                    ssh_client::SshClientConnection::execute_remote_command(
-                        &host,
-                        22, // Use standard SSH port
-                        &user,
-                        &chmod_cmd,
-                        agent_socket.as_deref(),
-                    )
-                    .await
-                    .context("Failed to set executable permission")?;
-
-                if !chmod_success && !chmod_stderr.is_empty() {
-                    log::warn!("chmod failed: {}", chmod_stderr);
+            match response {
+                LocalResponse::ClientList { clients } => {
+                    if clients.is_empty() {
+                        println!("No clients connected");
+                    } else {
+                        println!("Connected clients ({}):", clients.len());
+                        for client in clients {
+                            println!(
+                                "  {} - {} ({})",
+                                client.hostname, client.platform, client.session_id
+                            );
+                            if let Some(label) = client.auth_key_label {
+                                println!("    Key: {}", label);
+                            }
+                            if let Some(error) = client.last_error {
+                                println!("    Last error: {}", error);
+                            }
+                            if let Some(transfer) = client.last_transfer {
+                                println!("    Last transfer: {}", describe_transfer(&transfer));
+                            }
+                            if let Some(rates) = describe_rates(&client.transfer_rates) {
+                                println!("    Transfer rate: {}", rates);
+                            }
+                            if client.heartbeat_gaps > 0 {
+                                println!("    Heartbeat gaps: {}", client.heartbeat_gaps);
+                            }
+                            println!(
+                                "    Send queue: {}/{} (peak {})",
+                                client.send_queue_depth, client.send_queue_capacity, client.send_queue_peak
+                            );
+                        }
+                    }
                 }
-
-                // Start the server in the background using russh
-                let start_cmd = format!(
-                    "nohup {} server --port {} >/dev/null 2>&1 </dev/null &",
-                    destination, port
-                );
-                let (start_success, start_stdout, start_stderr) =
-                    ssh_client::SshClientConnection::execute_remote_command(
-                        &host,
-                        22, // Use standard SSH port
-                        &user,
-                        &start_cmd,
-                        agent_socket.as_deref(),
-                    )
-                    .await
-                    .context("Failed to start server")?;
-
-                if start_success || start_stdout.is_empty() && start_stderr.is_empty() {
-                    println!("✓ Started server on {}:{}", host, port);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                    println!("  Server should be listening on port {}", port);
-                } else if !start_stderr.is_empty() {
-                    eprintln!("✗ Failed to start server: {}", start_stderr);
-                } else {
-                    println!("✓ Server start command issued on {}:{}", host, port);
+                LocalResponse::Error { message } => {
+                    eprintln!("Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("Unexpected response: {:?}", response);
+                    std::process::exit(1);
                 }
             }
         }
 
-        Commands::Status {
+        Commands::Exec {
             server,
             port,
+            hostname,
+            session,
+            binary,
+            args,
+            stream,
+            cwd,
+            env,
+            timeout,
             agent_socket,
         } => {
-            log::debug!("Getting server status");
+            log::info!("Executing {} on {}", binary, hostname);
 
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
-            let command = LocalCommand::Status;
+            let command = LocalCommand::Execute {
+                target: hostname.clone(),
+                binary,
+                args,
+                session_id: session,
+                stream,
+                working_dir: cwd,
+                env: env.into_iter().collect(),
+            };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            if stream {
+                let exit_code = stream_exec(&host, final_port, &user, command, agent_socket.as_deref()).await?;
+                std::process::exit(exit_code);
+            }
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
             match response {
-                LocalResponse::Status {
-                    hostname,
+                LocalResponse::Success { message } => {
+                    println!("✓ {}", message);
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("✗ Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Sync {
+            server,
+            port,
+            file,
+            destination,
+            recursive,
+            include,
+            exclude,
+            allow_partial,
+            force,
+            checksum_only,
+            target,
+            timeout,
+            agent_socket,
+        } => {
+            log::info!("Syncing {} to all clients", file.display());
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let dest = destination.unwrap_or_else(|| file.to_string_lossy().to_string());
+
+            let command = if checksum_only {
+                let checksum_algo = rsync_utils::ChecksumAlgo::default();
+                let expected_checksum =
+                    rsync_utils::file_checksum(&file, checksum_algo, rsync_utils::DEFAULT_MEMORY_BUDGET)?;
+                LocalCommand::VerifyFile {
+                    target,
+                    relative_path: dest,
+                    expected_checksum,
+                    checksum_algo,
+                }
+            } else if recursive {
+                LocalCommand::SyncTree {
+                    root: file.to_string_lossy().to_string(),
+                    destination: dest,
+                    include_patterns: include,
+                    exclude_patterns: exclude,
+                    allow_partial,
+                    force,
+                }
+            } else {
+                LocalCommand::SyncFile {
+                    file: file.to_string_lossy().to_string(),
+                    destination: dest,
+                    allow_partial,
+                    force,
+                }
+            };
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::SyncReport {
+                    file,
+                    delivered,
+                    failed,
+                    accepted,
+                } => {
+                    print_sync_report(&file, &delivered, &failed, accepted);
+                }
+                LocalResponse::SyncTreeReport {
+                    root,
+                    files,
+                    accepted,
+                } => {
+                    print_sync_tree_report(&root, &files, accepted);
+                }
+                LocalResponse::VerifyReport { path, results } => {
+                    print_verify_report(&path, &results);
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("✗ Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Push {
+            servers,
+            binary,
+            destination,
+            start,
+            port,
+            ssh_port,
+            compress,
+            upload_chunk_size,
+            resume,
+            server_arg,
+            server_env,
+            agent_socket,
+        } => {
+            let options = Arc::new(PushOptions {
+                binary,
+                destination,
+                start,
+                port,
+                ssh_port,
+                compress,
+                upload: ssh_client::UploadOptions {
+                    chunk_size: upload_chunk_size,
+                    resume,
+                },
+                server_args: server_arg,
+                server_env,
+                agent_socket,
+            });
+
+            // Push to every host concurrently; a failure on one doesn't stop the others
+            let progress = Arc::new(UploadProgress::new(servers.len()));
+            let mut pushes = tokio::task::JoinSet::new();
+            for (idx, server) in servers.iter().cloned().enumerate() {
+                let options = Arc::clone(&options);
+                let progress = Arc::clone(&progress);
+                pushes.spawn(async move {
+                    let report = |uploaded, total| progress.update(idx, uploaded, total);
+                    (idx, push_to_host(server, &options, report).await)
+                });
+            }
+
+            let mut results: Vec<Option<HostPush>> = (0..servers.len()).map(|_| None).collect();
+            while let Some(joined) = pushes.join_next().await {
+                let (idx, result) = joined.context("Push task failed")?;
+                results[idx] = Some(result);
+            }
+            progress.finish();
+            let results: Vec<HostPush> = results.into_iter().flatten().collect();
+
+            for result in &results {
+                if results.len() > 1 {
+                    println!("{}:", result.server);
+                }
+                let indent = if results.len() > 1 { "  " } else { "" };
+                for step in &result.steps {
+                    println!("{}✓ {}", indent, step);
+                }
+                if let Some(ref error) = result.error {
+                    eprintln!("{}✗ {}", indent, error);
+                }
+            }
+
+            let failed = results.iter().filter(|r| r.error.is_some()).count();
+            if failed == results.len() {
+                if results.len() > 1 {
+                    eprintln!("✗ Push failed on every host");
+                }
+                std::process::exit(1);
+            }
+            if failed > 0 {
+                eprintln!("✗ Push failed on {} of {} hosts", failed, results.len());
+                std::process::exit(2);
+            }
+        }
+
+        Commands::Status {
+            server,
+            port,
+            timeout,
+            agent_socket,
+        } => {
+            log::debug!("Getting server status");
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let command = LocalCommand::Status;
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::Status {
+                    hostname,
                     version,
                     uptime,
                     clients,
+                    transfer_rates,
+                    error_counts,
                 } => {
                     println!("Server: {}", hostname);
                     println!("Version: {}", version);
                     println!("Uptime: {}", format_duration(uptime));
                     println!("Connected clients: {}", clients.len());
+                    if let Some(rates) = describe_rates(&transfer_rates) {
+                        println!("Transfer rate: {}", rates);
+                    }
+                    if error_counts != ErrorCounts::default() {
+                        println!(
+                            "Errors: {} parse, {} checksum, {} auth rejected, {} evicted",
+                            error_counts.parse_errors,
+                            error_counts.checksum_failures,
+                            error_counts.auth_rejects,
+                            error_counts.evictions
+                        );
+                    }
 
                     if !clients.is_empty() {
                         println!();
@@ -600,6 +1651,25 @@ async fn main() -> Result<()> {
                                 "  {} ({}) - uptime: {}, last heartbeat: {}s ago",
                                 client.hostname, client.platform, client_uptime, client.last_heartbeat
                             );
+                            if let Some(label) = client.auth_key_label {
+                                println!("    Key: {}", label);
+                            }
+                            if let Some(error) = client.last_error {
+                                println!("    Last error: {}", error);
+                            }
+                            if let Some(transfer) = client.last_transfer {
+                                println!("    Last transfer: {}", describe_transfer(&transfer));
+                            }
+                            if let Some(rates) = describe_rates(&client.transfer_rates) {
+                                println!("    Transfer rate: {}", rates);
+                            }
+                            if client.heartbeat_gaps > 0 {
+                                println!("    Heartbeat gaps: {}", client.heartbeat_gaps);
+                            }
+                            println!(
+                                "    Send queue: {}/{} (peak {})",
+                                client.send_queue_depth, client.send_queue_capacity, client.send_queue_peak
+                            );
                         }
                     }
                 }
@@ -614,9 +1684,62 @@ async fn main() -> Result<()> {
             }
         }
 
+        Commands::Diagnostics {
+            server,
+            port,
+            output,
+            timeout,
+            agent_socket,
+        } => {
+            log::debug!("Collecting diagnostics");
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                LocalCommand::Diagnostics,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::Diagnostics { report } => {
+                    let json = serde_json::to_string_pretty(&report).context("Failed to encode diagnostics")?;
+                    if let Some(output) = output {
+                        std::fs::write(&output, json + "\n")
+                            .context(format!("Failed to write {}", output.display()))?;
+                        println!(
+                            "Wrote diagnostics to {} ({} clients, {} watches, {} transfers in flight, {} log lines)",
+                            output.display(),
+                            report.clients.len(),
+                            report.watches.len(),
+                            report.transfers.len(),
+                            report.logs.len()
+                        );
+                    } else {
+                        println!("{}", json);
+                    }
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
         Commands::Shutdown {
             server,
             port,
+            timeout,
             agent_socket,
         } => {
             log::info!("Shutting down server");
@@ -626,12 +1749,51 @@ async fn main() -> Result<()> {
             let final_port = conn_port.unwrap_or(port);
             let command = LocalCommand::Shutdown;
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::Success { message } => {
+                    println!("✓ {}", message);
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("✗ Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Drain {
+            server,
+            port,
+            timeout,
+            agent_socket,
+        } => {
+            log::info!("Draining server");
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let command = LocalCommand::Drain;
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
@@ -654,34 +1816,58 @@ async fn main() -> Result<()> {
             server,
             port,
             path,
-            recursive,
-            include,
-            exclude,
+            recursive: _,
+            no_recursive,
+            mut include,
+            mut exclude,
+            include_from,
+            exclude_from,
+            case_insensitive,
+            no_verify_events,
+            settle_ms,
+            priority,
+            base,
+            timeout,
             agent_socket,
         } => {
             log::info!("Adding watch for path: {}", path.display());
 
+            if let Some(file) = include_from {
+                include.extend(read_pattern_file(&file)?);
+            }
+            if let Some(file) = exclude_from {
+                exclude.extend(read_pattern_file(&file)?);
+            }
+
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
             let command = LocalCommand::WatchDirectory {
                 path: path.to_string_lossy().to_string(),
-                recursive,
+                recursive: !no_recursive,
                 include_patterns: include,
                 exclude_patterns: exclude,
+                relative_to: None,
+                case_insensitive,
+                verify_events: !no_verify_events,
+                destination: None,
+                base: base.map(|base| base.to_string_lossy().to_string()),
+                settle_ms,
+                priority,
             };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
             match response {
-                LocalResponse::Success { message } => {
+                LocalResponse::Watching { message, .. } => {
                     println!("✓ {}", message);
                 }
                 LocalResponse::Error { message } => {
@@ -699,6 +1885,8 @@ async fn main() -> Result<()> {
             server,
             port,
             path,
+            preview,
+            timeout,
             agent_socket,
         } => {
             log::info!("Removing watch for path: {}", path.display());
@@ -706,16 +1894,20 @@ async fn main() -> Result<()> {
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
-            let command = LocalCommand::UnwatchDirectory {
-                path: path.to_string_lossy().to_string(),
+            let path = path.to_string_lossy().to_string();
+            let command = if preview {
+                LocalCommand::PreviewUnwatch { path }
+            } else {
+                LocalCommand::UnwatchDirectory { path, destination: None }
             };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
@@ -723,6 +1915,20 @@ async fn main() -> Result<()> {
                 LocalResponse::Success { message } => {
                     println!("✓ {}", message);
                 }
+                LocalResponse::UnwatchPreview {
+                    path,
+                    stops_syncing,
+                    still_covered,
+                } => {
+                    println!("Unwatching {} would stop syncing {} file(s):", path, stops_syncing.len());
+                    for file in &stops_syncing {
+                        println!("  - {}", file);
+                    }
+                    println!("Still covered by another watch, {} file(s):", still_covered.len());
+                    for file in &still_covered {
+                        println!("  = {}", file);
+                    }
+                }
                 LocalResponse::Error { message } => {
                     eprintln!("✗ Error: {}", message);
                     std::process::exit(1);
@@ -734,161 +1940,970 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::ListWatches {
+        Commands::Pause {
             server,
             port,
+            path,
+            timeout,
             agent_socket,
         } => {
-            log::debug!("Listing active watches");
+            log::info!("Pausing watch for path: {}", path.display());
 
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
-            let command = LocalCommand::ListWatches;
+            let command = LocalCommand::PauseWatch {
+                path: path.to_string_lossy().to_string(),
+            };
 
-            let response = ssh_client::SshClientConnection::send_control_command(
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
                 &host,
                 final_port,
                 &user,
                 command,
                 agent_socket.as_deref(),
+                control_timeout(timeout),
             )
             .await?;
 
             match response {
-                LocalResponse::WatchList { watches } => {
-                    if watches.is_empty() {
-                        println!("No active watches");
-                    } else {
-                        println!("Active watches ({}):", watches.len());
-                        for watch in watches {
-                            println!("  {} (recursive: {})", watch.path, watch.recursive);
-                            if !watch.include_patterns.is_empty() {
-                                println!("    Include: {:?}", watch.include_patterns);
-                            }
-                            if !watch.exclude_patterns.is_empty() {
-                                println!("    Exclude: {:?}", watch.exclude_patterns);
-                            }
-                        }
-                    }
+                LocalResponse::Success { message } => {
+                    println!("✓ {}", message);
                 }
                 LocalResponse::Error { message } => {
-                    eprintln!("Error: {}", message);
+                    eprintln!("✗ Error: {}", message);
                     std::process::exit(1);
                 }
                 _ => {
-                    eprintln!("Unexpected response: {:?}", response);
+                    eprintln!("✗ Unexpected response: {:?}", response);
                     std::process::exit(1);
                 }
             }
         }
 
-        Commands::ConfigSync {
+        Commands::Resume {
             server,
             port,
-            config,
+            path,
+            timeout,
             agent_socket,
         } => {
-            // Load config from specified path or search for it
-            let (config_path, config) = if let Some(path) = config {
-                let cfg = config::Config::from_file(&path)?;
-                (path, cfg)
-            } else {
-                config::Config::find_and_load()?
-            };
-
-            log::info!("Loaded config from: {}", config_path.display());
-            log::info!("Project: {}", config.project.name);
-            log::info!("Sync rules: {}", config.sync_rules.len());
-
-            println!("✓ Loaded config: {}", config.project.name);
-            println!("  Config file: {}", config_path.display());
-            println!("  Sync rules: {}", config.sync_rules.len());
-            println!();
-
-            // Get config file's parent directory (the project root)
-            let project_root = config_path
-                .parent()
-                .context("Config file has no parent directory")?
-                .to_path_buf();
-
-            log::info!("Project root: {}", project_root.display());
+            log::info!("Resuming watch for path: {}", path.display());
 
-            // Connect to server
             let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
             let (user, host, conn_port) = parse_connection_string(&server)?;
             let final_port = conn_port.unwrap_or(port);
+            let command = LocalCommand::ResumeWatch {
+                path: path.to_string_lossy().to_string(),
+            };
 
-            println!("Setting up watches on server {}@{}:{}...", user, host, final_port);
-            println!();
-
-            // For each sync rule, set up a watch on the server
-            for (idx, rule) in config.sync_rules.iter().enumerate() {
-                let default_name = format!("rule-{}", idx + 1);
-                let rule_name = rule.name.as_deref().unwrap_or(&default_name);
-
-                log::info!(
-                    "Setting up watch for [{}]: {} -> {}",
-                    rule_name,
-                    project_root.display(),
-                    rule.destination
-                );
-
-                // Send WatchDirectory command to server
-                // Watch the project root with include/exclude patterns
-                let command = LocalCommand::WatchDirectory {
-                    path: project_root.to_string_lossy().to_string(),
-                    recursive: true,
-                    include_patterns: rule.include.clone(),
-                    exclude_patterns: rule.exclude.clone(),
-                };
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
 
-                let response = ssh_client::SshClientConnection::send_control_command(
-                    &host,
-                    final_port,
-                    &user,
-                    command,
-                    agent_socket.as_deref(),
-                )
-                .await?;
-
-                match response {
-                    LocalResponse::Success { message } => {
-                        println!("  ✓ [{}] {}", rule_name, message);
-                        println!("      Include: {:?}", rule.include);
-                        if !rule.exclude.is_empty() {
-                            println!("      Exclude: {:?}", rule.exclude);
-                        }
-                        println!("      Destination: {}", rule.destination);
-                    }
-                    LocalResponse::Error { message } => {
-                        eprintln!("  ✗ [{}] Error: {}", rule_name, message);
-                        std::process::exit(1);
+            match response {
+                LocalResponse::Success { message } => {
+                    println!("✓ {}", message);
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("✗ Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::UpdateWatch {
+            server,
+            port,
+            path,
+            destination,
+            include,
+            exclude,
+            timeout,
+            agent_socket,
+        } => {
+            log::info!("Updating watch for path: {}", path.display());
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let command = LocalCommand::UpdateWatch {
+                path: path.to_string_lossy().to_string(),
+                destination,
+                include_patterns: (!include.is_empty()).then_some(include),
+                exclude_patterns: (!exclude.is_empty()).then_some(exclude),
+            };
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::WatchList { watches } => {
+                    println!("✓ Updated watch:");
+                    for watch in &watches {
+                        print_watch(watch);
                     }
-                    _ => {
-                        eprintln!("  ✗ [{}] Unexpected response: {:?}", rule_name, response);
-                        std::process::exit(1);
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("✗ Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::ListWatches {
+            server,
+            port,
+            timeout,
+            agent_socket,
+        } => {
+            log::debug!("Listing active watches");
+
+            let server = server.unwrap_or_else(|| format!("{}@localhost", get_default_user().unwrap()));
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+            let command = LocalCommand::ListWatches;
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::WatchList { watches } => {
+                    if watches.is_empty() {
+                        println!("No active watches");
+                    } else {
+                        println!("Active watches ({}):", watches.len());
+                        for watch in &watches {
+                            print_watch(watch);
+                        }
                     }
                 }
+                LocalResponse::Error { message } => {
+                    eprintln!("Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
             }
+        }
 
+        Commands::ConfigSync {
+            server,
+            port,
+            config,
+            timeout,
+            agent_socket,
+            all_or_nothing,
+            no_verify_events,
+            destination_base,
+        } => {
+            // Load config from specified path or search for it
+            let (config_path, mut config) = if let Some(path) = config {
+                let cfg = config::Config::from_file(&path)?;
+                (path, cfg)
+            } else {
+                config::Config::find_and_load()?
+            };
+
+            if let Some(base) = &destination_base {
+                config
+                    .apply_destination_base(base)
+                    .context(format!("Invalid --destination-base {}", base))?;
+            }
+
+            // The server only watches a repository for commits in a config it loads itself
+            let commit_rules = config
+                .sync_rules
+                .iter()
+                .filter(|rule| rule.trigger == config::Trigger::GitCommit)
+                .count();
+            if commit_rules > 0 {
+                eprintln!(
+                    "⚠️  Skipping {} rules with trigger = \"git-commit\"; start the server in the project to use them",
+                    commit_rules
+                );
+                config.sync_rules.retain(|rule| rule.trigger != config::Trigger::GitCommit);
+                if config.sync_rules.is_empty() {
+                    anyhow::bail!("No rules left to set up: every rule in {} syncs on commit", config_path.display());
+                }
+            }
+
+            log::info!("Loaded config from: {}", config_path.display());
+            log::info!("Project: {}", config.project.name);
+            log::info!("Sync rules: {}", config.sync_rules.len());
+
+            println!("✓ Loaded config: {}", config.project.name);
+            println!("  Config file: {}", config_path.display());
+            println!("  Sync rules: {}", config.sync_rules.len());
             println!();
-            println!("✓ All watches configured successfully!");
+
+            // Get config file's parent directory (the project root)
+            let project_root = config_path
+                .parent()
+                .context("Config file has no parent directory")?
+                .to_path_buf();
+
+            log::info!("Project root: {}", project_root.display());
+
+            let servers = if server.is_empty() {
+                vec![format!("{}@localhost", get_default_user()?)]
+            } else {
+                server
+            };
+
+            let mut targets = Vec::new();
+            for server in &servers {
+                let (user, host, conn_port) = parse_connection_string(server)?;
+                targets.push((user, host, conn_port.unwrap_or(port)));
+            }
+
+            // Set up every server concurrently; one unreachable server doesn't hold up the rest
+            let mut setups = tokio::task::JoinSet::new();
+            for (idx, (user, host, port)) in targets.into_iter().enumerate() {
+                let project_root = project_root.clone();
+                let rules = config.sync_rules.clone();
+                let agent_socket = agent_socket.clone();
+                setups.spawn(async move {
+                    let result = setup_config_watches(
+                        &user,
+                        &host,
+                        port,
+                        &project_root,
+                        &rules,
+                        agent_socket.as_deref(),
+                        !no_verify_events,
+                        control_timeout(timeout),
+                    )
+                    .await;
+                    (idx, result)
+                });
+            }
+
+            let mut results: Vec<Option<ServerWatches>> = (0..servers.len()).map(|_| None).collect();
+            while let Some(joined) = setups.join_next().await {
+                let (idx, result) = joined.context("Watch setup task failed")?;
+                results[idx] = Some(result);
+            }
+            let results: Vec<ServerWatches> = results.into_iter().flatten().collect();
+
+            for result in &results {
+                println!("Server {}@{}:{}:", result.user, result.host, result.port);
+                for watch in &result.watches {
+                    let rule = &config.sync_rules[watch.rule_idx];
+                    println!("  ✓ [{}] {}", watch.rule_name, watch.message);
+                    println!("      Include: {:?}", rule.include);
+                    if !rule.exclude.is_empty() {
+                        println!("      Exclude: {:?}", rule.exclude);
+                    }
+                    println!("      Destination: {}", rule.destination);
+                }
+                for error in &result.errors {
+                    eprintln!("  ✗ {}", error);
+                }
+                println!();
+            }
+
+            let failed = results.iter().filter(|r| !r.errors.is_empty()).count();
+
+            if failed > 0 && all_or_nothing {
+                eprintln!("✗ {} of {} servers failed, removing watches (--all-or-nothing)", failed, results.len());
+                for result in &results {
+                    remove_config_watches(result, agent_socket.as_deref(), control_timeout(timeout)).await;
+                }
+                std::process::exit(1);
+            }
+
+            if failed == results.len() {
+                eprintln!("✗ Watches failed on every server");
+                std::process::exit(1);
+            }
+
+            if failed == 0 {
+                println!("✓ All watches configured successfully!");
+            } else {
+                eprintln!("✗ Watches failed on {} of {} servers", failed, results.len());
+            }
             println!();
             println!("The server is now watching for file changes and will automatically");
             println!("sync them to connected clients. File changes will be logged on the server.");
             println!();
-            println!("To view active watches, run:");
-            println!("  halfremembered-launcher list-watches --server {}@{}", user, host);
+            for result in results.iter().filter(|r| r.errors.is_empty()) {
+                println!("To view or stop watches on {}, run:", result.host);
+                println!("  halfremembered-launcher list-watches --server {}@{}", result.user, result.host);
+                println!("  halfremembered-launcher unwatch <directory> --server {}@{}", result.user, result.host);
+            }
+
             println!();
-            println!("To stop a watch, run:");
-            println!("  halfremembered-launcher unwatch <directory> --server {}@{}", user, host);
+            for result in results.iter().filter(|r| r.errors.is_empty()) {
+                print_sync_targets(result, agent_socket.as_deref(), control_timeout(timeout)).await;
+            }
+
+            if failed > 0 {
+                std::process::exit(2);
+            }
+        }
+
+        Commands::ConfigLint { config } => {
+            let (config_path, config) = match config {
+                Some(path) => {
+                    let cfg = config::Config::from_file(&path)?;
+                    (path, cfg)
+                }
+                None => config::Config::find_and_load()?,
+            };
+            let project_root = config_path
+                .parent()
+                .context("Config file has no parent directory")?;
+            // A bare file name's parent is empty, meaning the current directory
+            let project_root = if project_root.as_os_str().is_empty() { Path::new(".") } else { project_root };
+
+            let findings = config_lint::lint(&config, project_root)?;
+            if findings.is_empty() {
+                println!("✓ No problems found in {}", config_path.display());
+                return Ok(());
+            }
+
+            for finding in &findings {
+                let icon = match finding.severity {
+                    config_lint::Severity::Danger => "🛑",
+                    config_lint::Severity::Warning => "⚠️ ",
+                };
+                println!("{} {}: [{}] {}", icon, finding.severity, finding.rule, finding.message);
+            }
+            let dangers = findings
+                .iter()
+                .filter(|finding| finding.severity == config_lint::Severity::Danger)
+                .count();
+            println!();
+            println!("{} findings in {} ({} dangerous)", findings.len(), config_path.display(), dangers);
+            if dangers > 0 {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::SelfUpdate {
+            server,
+            binary,
+            destination,
+            staging,
+            port,
+            ssh_port,
+            timeout,
+            agent_socket,
+        } => {
+            log::info!("Rolling out {} via {}", binary.display(), server);
+
+            let (user, host, conn_port) = parse_connection_string(&server)?;
+            let final_port = conn_port.unwrap_or(port);
+
+            // Upload binary via SFTP (uses host sshd)
+            let progress = UploadProgress::new(1);
+            let started = Instant::now();
+            let uploaded = ssh_client::SshClientConnection::upload_file_via_sftp(
+                &host,
+                ssh_port,
+                &user,
+                &binary,
+                &destination,
+                agent_socket.as_deref(),
+                ssh_client::UploadOptions::default(),
+                |uploaded, total| progress.update(0, uploaded, total),
+            )
+            .await;
+            progress.finish();
+            uploaded?;
+
+            println!(
+                "✓ Uploaded {} to {}@{}:{} ({})",
+                binary.display(),
+                user,
+                host,
+                destination,
+                describe_upload(&binary, started.elapsed())
+            );
+
+            // The synced file keeps the server-side mode, so clients can only execute
+            // the staged binary if it is executable here
+            let chmod_cmd = format!("chmod +x {}", ssh_client::shell_quote_path(&destination));
+            let (chmod_success, _, chmod_stderr) =
+                ssh_client::SshClientConnection::execute_remote_command(
+                    &host,
+                    ssh_port,
+                    &user,
+                    &chmod_cmd,
+                    agent_socket.as_deref(),
+                )
+                .await
+                .context("Failed to set executable permission")?;
+
+            if !chmod_success && !chmod_stderr.is_empty() {
+                log::warn!("chmod failed: {}", chmod_stderr);
+            }
+
+            let command = LocalCommand::SelfUpdate {
+                file: destination,
+                staging_path: staging,
+            };
+
+            let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+                &host,
+                final_port,
+                &user,
+                command,
+                agent_socket.as_deref(),
+                control_timeout(timeout),
+            )
+            .await?;
+
+            match response {
+                LocalResponse::SyncReport {
+                    file,
+                    delivered,
+                    failed,
+                    accepted,
+                } => {
+                    print_sync_report(&file, &delivered, &failed, accepted);
+                    println!("  Clients restart on the new binary once it has been applied");
+                }
+                LocalResponse::Error { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => {
+                    eprintln!("✗ Unexpected response: {:?}", response);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::ApplyUpdate => {
+            let staged = std::env::current_exe().context("Failed to locate staged binary")?;
+            let target = std::env::var(client_daemon::CLIENT_EXE_ENV).context(format!(
+                "{} is not set; apply-update must be run by the client daemon",
+                client_daemon::CLIENT_EXE_ENV
+            ))?;
+            let target = PathBuf::from(target);
+
+            client_daemon::apply_staged_update(&staged, &target)?;
+
+            println!("✓ Installed {} over {}", staged.display(), target.display());
         }
     }
 
     Ok(())
 }
 
+/// Push settings shared by every host of one `push` invocation
+struct PushOptions {
+    binary: PathBuf,
+    destination: String,
+    start: bool,
+    port: u16,
+    ssh_port: u16,
+    compress: bool,
+    upload: ssh_client::UploadOptions,
+    server_args: Vec<String>,
+    server_env: Vec<(String, String)>,
+    agent_socket: Option<String>,
+}
+
+/// Outcome of a push to one host: the steps that succeeded, in order, and the error
+/// that stopped it, if any
+struct HostPush {
+    server: String,
+    steps: Vec<String>,
+    error: Option<String>,
+}
+
+/// Upload the binary to one host and optionally start a server there, stopping at the
+/// first failure; `progress` is called as the upload proceeds
+async fn push_to_host(server: String, options: &PushOptions, progress: impl FnMut(u64, u64)) -> HostPush {
+    let mut result = HostPush {
+        server,
+        steps: Vec::new(),
+        error: None,
+    };
+    if let Err(e) = push_steps(&result.server, options, progress, &mut result.steps).await {
+        result.error = Some(format!("{:#}", e));
+    }
+    result
+}
+
+async fn push_steps(
+    server: &str,
+    options: &PushOptions,
+    progress: impl FnMut(u64, u64),
+    steps: &mut Vec<String>,
+) -> Result<()> {
+    log::info!("Pushing {} to {}", options.binary.display(), server);
+
+    let (user, host, conn_port) = parse_connection_string(server)?;
+    let ssh_port = conn_port.unwrap_or(options.ssh_port);
+    let agent_socket = options.agent_socket.as_deref();
+    let destination = &options.destination;
+
+    // Upload binary via SFTP (uses host sshd)
+    let started = Instant::now();
+    if options.compress {
+        ssh_client::SshClientConnection::upload_file_via_sftp_compressed(
+            &host,
+            ssh_port,
+            &user,
+            &options.binary,
+            destination,
+            agent_socket,
+        )
+        .await?;
+    } else {
+        ssh_client::SshClientConnection::upload_file_via_sftp(
+            &host,
+            ssh_port,
+            &user,
+            &options.binary,
+            destination,
+            agent_socket,
+            options.upload,
+            progress,
+        )
+        .await?;
+    }
+
+    steps.push(format!(
+        "Uploaded {} to {}@{}:{} ({})",
+        options.binary.display(),
+        user,
+        host,
+        destination,
+        describe_upload(&options.binary, started.elapsed())
+    ));
+
+    if !options.start {
+        return Ok(());
+    }
+
+    log::info!("Starting server on {}", host);
+
+    // Make the binary executable using russh
+    let chmod_cmd = format!("chmod +x {}", ssh_client::shell_quote_path(destination));
+    let (chmod_success, _, chmod_stderr) =
+        ssh_client::SshClientConnection::execute_remote_command(&host, ssh_port, &user, &chmod_cmd, agent_socket)
+            .await
+            .context("Failed to set executable permission")?;
+
+    if !chmod_success && !chmod_stderr.is_empty() {
+        log::warn!("chmod failed on {}: {}", host, chmod_stderr);
+    }
+
+    // Start the server in the background using russh; extra args and env
+    // values are shell-quoted since they end up in a remote shell string
+    let mut start_args = vec![
+        "server".to_string(),
+        "--port".to_string(),
+        options.port.to_string(),
+        "--daemonize".to_string(),
+    ];
+    start_args.extend(options.server_args.iter().cloned());
+    let start_cmd = ssh_client::remote_command_line(&options.server_env, destination, &start_args);
+    log::debug!("Remote start command: {}", start_cmd);
+    let (start_success, start_stdout, start_stderr) =
+        ssh_client::SshClientConnection::execute_remote_command(&host, ssh_port, &user, &start_cmd, agent_socket)
+            .await
+            .context("Failed to start server")?;
+
+    if start_success || start_stdout.is_empty() && start_stderr.is_empty() {
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        steps.push(format!("Started server on {}:{}", host, options.port));
+    } else if !start_stderr.is_empty() {
+        anyhow::bail!("Failed to start server: {}", start_stderr);
+    } else {
+        steps.push(format!("Server start command issued on {}:{}", host, options.port));
+    }
+
+    Ok(())
+}
+
+/// A watch config-sync set up on a server for one sync rule
+struct RuleWatch {
+    /// Index of the rule in the config's sync rules
+    rule_idx: usize,
+    rule_name: String,
+    watch_dir: String,
+    destination: String,
+    /// The directory was already watched (by an earlier rule, another config-sync or the
+    /// server's own config) and the rule was added as another route of that watch
+    joined: bool,
+    message: String,
+}
+
+/// Outcome of config-sync against one server: the watches set up, in rule order, and
+/// every rule's failure
+struct ServerWatches {
+    user: String,
+    host: String,
+    port: u16,
+    watches: Vec<RuleWatch>,
+    errors: Vec<String>,
+}
+
+/// Set up a watch for every sync rule on one server, carrying on past rules that fail
+#[allow(clippy::too_many_arguments)]
+async fn setup_config_watches(
+    user: &str,
+    host: &str,
+    port: u16,
+    project_root: &std::path::Path,
+    rules: &[config::SyncRule],
+    agent_socket: Option<&str>,
+    verify_events: bool,
+    timeout: Option<Duration>,
+) -> ServerWatches {
+    let mut result = ServerWatches {
+        user: user.to_string(),
+        host: host.to_string(),
+        port,
+        watches: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    // Every rule's watch goes out on one connection without waiting for the others
+    let session = match ssh_client::ControlSession::connect(host, port, user, agent_socket).await {
+        Ok(session) => session.with_timeout(timeout),
+        Err(e) => {
+            result.errors.push(format!("Failed to reach server: {:#}", e));
+            return result;
+        }
+    };
+
+    let mut requests = Vec::new();
+    for (idx, rule) in rules.iter().enumerate() {
+        let rule_name = rule.name.clone().unwrap_or_else(|| format!("rule-{}", idx + 1));
+
+        // Watch only the tightest directory covering the include patterns; the
+        // patterns and synced paths stay relative to the project root
+        let mut watch_dir = project_root.join(rule.watch_base());
+        while !watch_dir.is_dir() && watch_dir != project_root {
+            watch_dir.pop();
+        }
+
+        log::info!(
+            "Setting up watch for [{}] on {}: {} -> {}",
+            rule_name,
+            host,
+            watch_dir.display(),
+            rule.destination
+        );
+
+        // Send WatchDirectory command to server
+        let command = LocalCommand::WatchDirectory {
+            path: watch_dir.to_string_lossy().to_string(),
+            recursive: rule.recursive,
+            include_patterns: rule.include.clone(),
+            exclude_patterns: rule.exclude.clone(),
+            relative_to: Some(project_root.to_string_lossy().to_string()),
+            case_insensitive: rule.case_insensitive,
+            verify_events,
+            destination: Some(rule.destination.clone()),
+            base: None,
+            settle_ms: rule.settle_ms,
+            priority: rule.priority,
+        };
+        requests.push((idx, rule_name, watch_dir, session.send(command).await));
+    }
+
+    // Keep every watch that was set up, even after a failure, so they can be removed
+    for (rule_idx, rule_name, watch_dir, responses) in requests {
+        let response = match responses {
+            Ok(mut responses) => session.wait(&mut responses).await,
+            Err(e) => Err(e),
+        };
+
+        let error = match response {
+            Ok(LocalResponse::Watching { message, joined }) => {
+                result.watches.push(RuleWatch {
+                    rule_idx,
+                    rule_name,
+                    watch_dir: watch_dir.to_string_lossy().to_string(),
+                    destination: rules[rule_idx].destination.clone(),
+                    joined,
+                    message,
+                });
+                continue;
+            }
+            Ok(LocalResponse::Error { message }) => format!("[{}] Error: {}", rule_name, message),
+            Ok(response) => format!("[{}] Unexpected response: {:?}", rule_name, response),
+            Err(e) => format!("[{}] Failed to reach server: {:#}", rule_name, e),
+        };
+        result.errors.push(error);
+    }
+
+    result
+}
+
+/// Report which clients a configured server will currently sync to
+async fn print_sync_targets(result: &ServerWatches, agent_socket: Option<&str>, timeout: Option<Duration>) {
+    let response = ssh_client::SshClientConnection::send_control_command_with_timeout(
+        &result.host,
+        result.port,
+        &result.user,
+        LocalCommand::ListClients,
+        agent_socket,
+        timeout,
+    )
+    .await;
+
+    match response {
+        Ok(LocalResponse::ClientList { clients }) if clients.is_empty() => {
+            println!(
+                "→ {} will sync to: no one yet (0 clients connected — syncs will apply when clients join)",
+                result.host
+            );
+        }
+        Ok(LocalResponse::ClientList { clients }) => {
+            let hostnames: Vec<&str> = clients.iter().map(|c| c.hostname.as_str()).collect();
+            println!(
+                "→ {} will sync to: {} ({} client{} connected)",
+                result.host,
+                hostnames.join(", "),
+                clients.len(),
+                if clients.len() == 1 { "" } else { "s" }
+            );
+        }
+        Ok(LocalResponse::Error { message }) => {
+            eprintln!("✗ Couldn't list clients on {}: {}", result.host, message);
+        }
+        Ok(response) => {
+            eprintln!("✗ Unexpected response listing clients on {}: {:?}", result.host, response);
+        }
+        Err(e) => {
+            eprintln!("✗ Couldn't list clients on {}: {:#}", result.host, e);
+        }
+    }
+}
+
+/// Remove what config-sync set up on one server, logging (not failing on) errors
+///
+/// Watches it created go entirely, along with any routes its rules added to them. On
+/// watches it only joined, just its rules' routes are removed.
+async fn remove_config_watches(result: &ServerWatches, agent_socket: Option<&str>, timeout: Option<Duration>) {
+    let created: std::collections::BTreeSet<&str> = result
+        .watches
+        .iter()
+        .filter(|watch| !watch.joined)
+        .map(|watch| watch.watch_dir.as_str())
+        .collect();
+    let joined_routes = result
+        .watches
+        .iter()
+        .filter(|watch| watch.joined && !created.contains(watch.watch_dir.as_str()))
+        .map(|watch| (watch.watch_dir.as_str(), Some(watch.destination.as_str())));
+    let removals: Vec<(&str, Option<&str>)> =
+        joined_routes.chain(created.iter().map(|watch_dir| (*watch_dir, None))).collect();
+
+    for (watch_dir, destination) in removals {
+        let command = LocalCommand::UnwatchDirectory {
+            path: watch_dir.to_string(),
+            destination: destination.map(str::to_string),
+        };
+        let described = match destination {
+            Some(destination) => format!("{} -> {}", watch_dir, destination),
+            None => watch_dir.to_string(),
+        };
+        match ssh_client::SshClientConnection::send_control_command_with_timeout(
+            &result.host,
+            result.port,
+            &result.user,
+            command,
+            agent_socket,
+            timeout,
+        )
+        .await
+        {
+            Ok(LocalResponse::Success { .. }) => {
+                println!("  ↺ Removed watch on {}: {}", result.host, described);
+            }
+            Ok(response) => {
+                eprintln!("  ✗ Failed to remove watch on {}: {} ({:?})", result.host, described, response);
+            }
+            Err(e) => {
+                eprintln!("  ✗ Failed to remove watch on {}: {} ({:#})", result.host, described, e);
+            }
+        }
+    }
+}
+
+/// Print per-recipient sync results and exit non-zero if the server rejected the outcome:
+/// 1 when no client received the file, 2 when only some did
+fn print_sync_report(
+    file: &str,
+    delivered: &[String],
+    failed: &[halfremembered_protocol::RecipientFailure],
+    accepted: bool,
+) {
+    if accepted {
+        println!("✓ Synced {} to {} clients", file, delivered.len());
+    } else {
+        eprintln!(
+            "✗ Sync of {} failed for {} of {} clients",
+            file,
+            failed.len(),
+            delivered.len() + failed.len()
+        );
+    }
+
+    for failure in failed {
+        eprintln!("  ✗ {}: {}", failure.hostname, failure.error);
+    }
+
+    if !accepted {
+        std::process::exit(if delivered.is_empty() { 1 } else { 2 });
+    }
+}
+
+/// Print each client's verdict from a `sync --checksum-only`, exiting like
+/// print_sync_report: 1 when no client holds a matching copy, 2 when only some do
+fn print_verify_report(path: &str, results: &[halfremembered_protocol::ClientVerifyResult]) {
+    let matching = results.iter().filter(|result| result.matches).count();
+    if matching == results.len() {
+        println!("✓ {} matches on {} clients", path, matching);
+    } else {
+        eprintln!("✗ {} differs on {} of {} clients", path, results.len() - matching, results.len());
+    }
+
+    for result in results {
+        match (&result.error, &result.actual_checksum) {
+            _ if result.matches => println!("  ✓ {}", result.hostname),
+            (Some(error), _) => eprintln!("  ✗ {}: {}", result.hostname, error),
+            (None, Some(actual)) => eprintln!("  ✗ {}: has {}", result.hostname, actual),
+            (None, None) => eprintln!("  ✗ {}", result.hostname),
+        }
+    }
+
+    if matching < results.len() {
+        std::process::exit(if matching == 0 { 1 } else { 2 });
+    }
+}
+
+/// Print per-file failures and per-client totals of a tree sync, exiting like
+/// print_sync_report: 1 when no file reached any client, 2 when only some did
+fn print_sync_tree_report(root: &str, files: &[halfremembered_protocol::FileSyncResult], accepted: bool) {
+    let mut per_client: std::collections::BTreeMap<&str, usize> = std::collections::BTreeMap::new();
+    for result in files {
+        for hostname in &result.delivered {
+            *per_client.entry(hostname).or_default() += 1;
+        }
+    }
+    let incomplete = files
+        .iter()
+        .filter(|result| result.error.is_some() || !result.failed.is_empty())
+        .count();
+
+    if accepted {
+        println!("✓ Synced {} files from {} to {} clients", files.len(), root, per_client.len());
+    } else {
+        eprintln!("✗ Sync of {} failed for {} of {} files", root, incomplete, files.len());
+    }
+
+    for (hostname, count) in &per_client {
+        println!("  {}: {} files", hostname, count);
+    }
+    for result in files {
+        if let Some(ref error) = result.error {
+            eprintln!("  ✗ {}: {}", result.file, error);
+        }
+        for failure in &result.failed {
+            eprintln!("  ✗ {} on {}: {}", result.file, failure.hostname, failure.error);
+        }
+    }
+
+    if !accepted {
+        std::process::exit(if per_client.is_empty() { 1 } else { 2 });
+    }
+}
+
+/// Copy a streamed Execute's output to this terminal as it arrives; returns the
+/// exit code to leave with
+async fn stream_exec(
+    host: &str,
+    port: u16,
+    user: &str,
+    command: LocalCommand,
+    agent_socket: Option<&str>,
+) -> Result<i32> {
+    use futures::StreamExt;
+    use std::io::Write;
+
+    let responses =
+        ssh_client::SshClientConnection::send_control_command_streaming(host, port, user, command, agent_socket, None)
+            .await?;
+    let mut responses = std::pin::pin!(responses);
+
+    while let Some(response) = responses.next().await {
+        match response? {
+            LocalResponse::ExecOutput { stderr: false, data } => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            LocalResponse::ExecOutput { stderr: true, data } => {
+                let mut stderr = std::io::stderr().lock();
+                stderr.write_all(&data)?;
+                stderr.flush()?;
+            }
+            LocalResponse::ExecExit { exit_code, error } => {
+                if let Some(error) = error {
+                    eprintln!("✗ Error: {}", error);
+                    // A command that never ran still fails here
+                    if exit_code == 0 {
+                        return Ok(1);
+                    }
+                }
+                return Ok(exit_code);
+            }
+            LocalResponse::Error { message } => {
+                eprintln!("✗ Error: {}", message);
+                return Ok(1);
+            }
+            response => {
+                eprintln!("✗ Unexpected response: {:?}", response);
+                return Ok(1);
+            }
+        }
+    }
+
+    eprintln!("✗ Error: server closed the connection before the command finished");
+    Ok(1)
+}
+
 fn get_default_user() -> Result<String> {
     // Try USER first (Unix/Linux/WSL)
     if let Ok(user) = std::env::var("USER")
@@ -910,6 +2925,11 @@ fn get_default_user() -> Result<String> {
 }
 
 fn parse_connection_string(connection: &str) -> Result<(String, String, Option<u16>)> {
+    // A control socket stands in for the host; there's no user or port to parse
+    if connection.starts_with(ssh_client::CONTROL_SOCKET_PREFIX) {
+        return Ok((get_default_user().unwrap_or_default(), connection.to_string(), None));
+    }
+
     let parts: Vec<&str> = connection.split('@').collect();
 
     let (user, host, port) = match parts.len() {
@@ -952,6 +2972,219 @@ fn parse_host_port(host_str: &str) -> Result<(String, Option<u16>)> {
     }
 }
 
+/// Parse a `KEY=VALUE` environment assignment, rejecting keys that aren't shell variable names
+fn parse_env_var(assignment: &str) -> Result<(String, String), String> {
+    let (key, value) = assignment
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", assignment))?;
+
+    let mut chars = key.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("'{}' is not a valid environment variable name", key));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_client_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("the client name must not be empty".to_string());
+    }
+    Ok(name.to_string())
+}
+
+fn describe_transfer(transfer: &TransferInfo) -> String {
+    let described = match (transfer.file_size, transfer.wire_ratio()) {
+        (Some(size), Some(ratio)) => format!(
+            "{} ({} of {} bytes on the wire, {:.1}%)",
+            transfer.path,
+            transfer.bytes_transferred,
+            size,
+            ratio * 100.0
+        ),
+        _ => format!("{} ({} bytes)", transfer.path, transfer.bytes_transferred),
+    };
+    match transfer.rate() {
+        Some(rate) => format!("{} in {} ms, {}", described, transfer.duration_ms, rsync_utils::format_rate(rate)),
+        None => described,
+    }
+}
+
+/// Size, time taken and rate of an upload of `file`, for the line reporting it
+fn describe_upload(file: &Path, elapsed: Duration) -> String {
+    let size = std::fs::metadata(file).map(|metadata| metadata.len()).unwrap_or(0);
+    let seconds = elapsed.as_secs_f64();
+    let rate = if seconds > 0.0 { size as f64 / seconds } else { 0.0 };
+    format!("{}, {:.1}s at {}", format_megabytes(size), seconds, rsync_utils::format_rate(rate))
+}
+
+fn format_megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// Braille spinner frames for UploadProgress
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Shortest time between redraws of UploadProgress
+const PROGRESS_REDRAW: Duration = Duration::from_millis(100);
+
+/// Spinner line showing uploads as they run, redrawn in place on stderr
+///
+/// Hosts pushed to concurrently share the one line. Nothing is drawn unless stderr is
+/// a terminal, so redirected output doesn't fill with carriage returns.
+struct UploadProgress {
+    enabled: bool,
+    started: Instant,
+    state: Mutex<UploadProgressState>,
+}
+
+#[derive(Default)]
+struct UploadProgressState {
+    /// Per host, in push order: bytes already there when it started (a resume's
+    /// offset), bytes uploaded so far, and the file's size
+    hosts: Vec<Option<(u64, u64, u64)>>,
+    frame: usize,
+    last_draw: Option<Instant>,
+}
+
+impl UploadProgress {
+    fn new(hosts: usize) -> Self {
+        Self {
+            enabled: std::io::stderr().is_terminal(),
+            started: Instant::now(),
+            state: Mutex::new(UploadProgressState {
+                hosts: vec![None; hosts],
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Record how far host `idx`'s upload has got, redrawing at most every PROGRESS_REDRAW
+    fn update(&self, idx: usize, uploaded: u64, total: u64) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let host = state.hosts[idx].get_or_insert((uploaded, uploaded, total));
+        host.1 = uploaded;
+        host.2 = total;
+
+        let now = Instant::now();
+        if state.last_draw.is_some_and(|last| now - last < PROGRESS_REDRAW) {
+            return;
+        }
+        state.last_draw = Some(now);
+        state.frame = (state.frame + 1) % SPINNER.len();
+
+        let started: Vec<_> = state.hosts.iter().flatten().collect();
+        let sent: u64 = started.iter().map(|(offset, uploaded, _)| uploaded - offset).sum();
+        let uploaded: u64 = started.iter().map(|(_, uploaded, _)| uploaded).sum();
+        let total: u64 = started.iter().map(|(_, _, total)| total).sum();
+        let seconds = self.started.elapsed().as_secs_f64();
+        let rate = if seconds > 0.0 { sent as f64 / seconds } else { 0.0 };
+        let hosts = if state.hosts.len() > 1 {
+            format!(" to {} of {} hosts", started.len(), state.hosts.len())
+        } else {
+            String::new()
+        };
+        eprint!(
+            "\r\x1b[2K{} Uploading{}: {} of {} ({})",
+            SPINNER[state.frame],
+            hosts,
+            format_megabytes(uploaded),
+            format_megabytes(total),
+            rsync_utils::format_rate(rate)
+        );
+    }
+
+    /// Clear the line, so the summary that follows starts clean
+    fn finish(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// Average and peak rates, once any transfer has been timed
+fn describe_rates(rates: &TransferRates) -> Option<String> {
+    let average = rates.average()?;
+    Some(format!(
+        "{} average, {} peak over {} transfers",
+        rsync_utils::format_rate(average),
+        rsync_utils::format_rate(rates.peak),
+        rates.transfers
+    ))
+}
+
+fn print_watch(watch: &WatchInfo) {
+    let paused = if watch.paused { ", paused" } else { "" };
+    let broken = if watch.broken { ", broken: directory removed" } else { "" };
+    println!("  {} (recursive: {}{}{})", watch.path, watch.recursive, paused, broken);
+    if !watch.include_patterns.is_empty() {
+        println!("    Include: {:?}", watch.include_patterns);
+    }
+    if !watch.exclude_patterns.is_empty() {
+        println!("    Exclude: {:?}", watch.exclude_patterns);
+    }
+    if let Some(ref destination) = watch.destination {
+        println!("    Destination: {}", destination);
+    }
+    let last_triggered = match watch.last_triggered {
+        Some(seconds) => format!("{} ago", format_duration(seconds)),
+        None => "never".to_string(),
+    };
+    println!(
+        "    Events: {} seen, {} after dedup, {} syncs triggered (last: {})",
+        watch.events_seen, watch.events_passed, watch.syncs_triggered, last_triggered
+    );
+}
+
+/// Patterns from an --include-from/--exclude-from file: one per line, skipping blank
+/// lines and `#` comments
+fn read_pattern_file(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .context(format!("Failed to read pattern file: {}", path.display()))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        _ => Err(format!("'{}' is not an octal mode like 755", mode)),
+    }
+}
+
+/// Response timeout for a control command's `--timeout`; 0 waits indefinitely
+fn control_timeout(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// One line per exec process: request id, pid, running time and command line
+fn print_exec_processes(processes: &[ExecProcess], now: u64) {
+    for process in processes {
+        let pid = process.pid.map(|pid| pid.to_string()).unwrap_or_else(|| "-".to_string());
+        let command_line: Vec<&str> = std::iter::once(process.binary.as_str())
+            .chain(process.args.iter().map(String::as_str))
+            .collect();
+        println!(
+            "  {}  pid {}  {}  {}",
+            process.request_id,
+            pid,
+            format_duration(now.saturating_sub(process.started_at)),
+            command_line.join(" ")
+        );
+    }
+}
+
 fn format_duration(seconds: u64) -> String {
     let days = seconds / 86400;
     let hours = (seconds % 86400) / 3600;
@@ -1,7 +1,10 @@
 use anyhow::{Context, Result};
 use fast_rsync::{Signature, SignatureOptions};
+pub use halfremembered_protocol::ChecksumAlgo;
 use sha2::{Digest, Sha256};
-use std::path::Path;
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 /// Default block size for rsync algorithm (4KB)
 pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
@@ -9,20 +12,271 @@ pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
 /// Default crypto hash size (full MD4 hash)
 pub const DEFAULT_CRYPTO_HASH_SIZE: u32 = 16;
 
+/// Default in-memory budget for applying a delta (64 MB). Larger base files are
+/// memory-mapped and the result is streamed to disk instead of buffered.
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+/// Files smaller than this (4KB) are sent whole rather than as a delta, since the
+/// signature round trip costs more than the file
+pub const WHOLE_FILE_THRESHOLD: u64 = 4096;
+
+/// Suffix of the file a large delta is streamed into before it replaces the target
+pub const PARTIAL_SUFFIX: &str = ".hrl-partial";
+
+/// Contents of a base file: read onto the heap, or memory-mapped when over the budget
+enum BaseData {
+    Owned(Vec<u8>),
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for BaseData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BaseData::Owned(data) => data,
+            BaseData::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/// Load the base file at `path`, mapping it instead of reading it when it is larger
+/// than `memory_budget`. A missing file is an empty base.
+fn load_base(path: &Path, memory_budget: usize) -> Result<BaseData> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BaseData::Owned(Vec::new())),
+        Err(e) => return Err(e).context(format!("Failed to open base file: {}", path.display())),
+    };
+    let len = file
+        .metadata()
+        .context(format!("Failed to stat base file: {}", path.display()))?
+        .len();
+
+    if len > memory_budget as u64 {
+        log::debug!("Memory-mapping {} ({} bytes)", path.display(), len);
+        // The mapping is only read while the delta is applied; a concurrent writer is
+        // caught by the checksum comparison in fetch_and_apply_delta
+        let mmap = unsafe { memmap2::Mmap::map(&file) }
+            .context(format!("Failed to mmap base file: {}", path.display()))?;
+        Ok(BaseData::Mapped(mmap))
+    } else {
+        let mut data = Vec::with_capacity(len as usize);
+        std::io::Read::read_to_end(&mut &file, &mut data)
+            .context(format!("Failed to read file: {}", path.display()))?;
+        Ok(BaseData::Owned(data))
+    }
+}
+
 /// Generate signature from file
 pub async fn generate_signature(path: &Path, block_size: u32) -> Result<Vec<u8>> {
     let data = tokio::fs::read(path)
         .await
         .context(format!("Failed to read file: {}", path.display()))?;
 
+    Ok(signature_from_bytes(&data, block_size))
+}
+
+/// Generate signature from in-memory data
+fn signature_from_bytes(data: &[u8], block_size: u32) -> Vec<u8> {
     let sig = Signature::calculate(
-        &data,
+        data,
         SignatureOptions {
             block_size,
             crypto_hash_size: DEFAULT_CRYPTO_HASH_SIZE,
         },
     );
-    Ok(sig.into_serialized())
+    sig.into_serialized()
+}
+
+/// Where the result of an applied delta was written
+#[derive(Debug, PartialEq)]
+pub enum AppliedContent {
+    /// Result held in memory, for the caller to write out
+    Memory(Vec<u8>),
+    /// Result streamed to a partial file beside the target, for the caller to rename
+    /// into place (or remove on a checksum mismatch)
+    File(PathBuf),
+}
+
+/// A delta applied to the local base file
+pub struct AppliedDelta {
+    pub content: AppliedContent,
+    pub checksum: String,
+    /// Delta bytes received, summed over attempts
+    pub bytes_transferred: usize,
+}
+
+/// Signature of the base file at `base_path` plus a checksum of the bytes it was taken from
+/// (empty signature and no checksum when there is no base yet)
+fn snapshot_base(
+    base_path: &Path,
+    block_size: u32,
+    algo: ChecksumAlgo,
+    memory_budget: usize,
+) -> Result<(Vec<u8>, Option<String>)> {
+    if !base_path.exists() {
+        log::debug!("No existing file, sending empty signature");
+        return Ok((Vec::new(), None));
+    }
+
+    log::debug!("Generating signature for existing file: {}", base_path.display());
+    let data = load_base(base_path, memory_budget)?;
+
+    Ok((signature_from_bytes(&data, block_size), Some(compute_checksum(algo, &data))))
+}
+
+/// Checksum of the file at `path` as it is now, or None if it can't be read
+pub fn current_checksum(path: &Path, algo: ChecksumAlgo, memory_budget: usize) -> Option<String> {
+    if !path.exists() {
+        return None;
+    }
+    file_checksum(path, algo, memory_budget).ok()
+}
+
+/// Checksum of an existing file, with the reason it couldn't be read on failure
+pub fn file_checksum(path: &Path, algo: ChecksumAlgo, memory_budget: usize) -> Result<String> {
+    if !path.is_file() {
+        anyhow::bail!("No file at {}", path.display());
+    }
+    let data = load_base(path, memory_budget)?;
+    Ok(compute_checksum(algo, &data))
+}
+
+/// Path of the partial file a large result for `target` is streamed into
+pub fn partial_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push(PARTIAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Writer that hashes everything written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: ChecksumHasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Apply `delta_data` to the base at `base_path`, keeping the result in memory when
+/// base and delta fit within `memory_budget` and otherwise memory-mapping the base
+/// and streaming the result to `partial_path(base_path)`
+fn apply_delta_within_budget(
+    base_path: &Path,
+    delta_data: &[u8],
+    algo: ChecksumAlgo,
+    memory_budget: usize,
+) -> Result<(AppliedContent, String)> {
+    let base = load_base(base_path, memory_budget)?;
+
+    if base.len() + delta_data.len() <= memory_budget {
+        let mut output = Vec::new();
+        fast_rsync::apply(&base, delta_data, &mut output).context("Failed to apply delta")?;
+        let checksum = compute_checksum(algo, &output);
+        return Ok((AppliedContent::Memory(output), checksum));
+    }
+
+    let partial = partial_path(base_path);
+    log::debug!(
+        "Streaming {} byte base + {} byte delta to {}",
+        base.len(),
+        delta_data.len(),
+        partial.display()
+    );
+    let file = std::fs::File::create(&partial)
+        .context(format!("Failed to create {}", partial.display()))?;
+    let mut writer = HashingWriter {
+        inner: std::io::BufWriter::new(file),
+        hasher: ChecksumHasher::new(algo),
+    };
+
+    let result = fast_rsync::apply(&base, delta_data, &mut writer)
+        .context("Failed to apply delta")
+        .and_then(|()| writer.flush().context(format!("Failed to write {}", partial.display())));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    let checksum = writer.hasher.finalize();
+    Ok((AppliedContent::File(partial), checksum))
+}
+
+/// Sign the local base, fetch a delta for that signature, and apply it.
+///
+/// `fetch_delta` sends a signature to the server and returns the delta. If the result
+/// fails (apply error or checksum mismatch) because the base changed on disk after it
+/// was signed, the signature is regenerated from the current file and the delta
+/// re-requested once. A mismatch with an unchanged base is returned as-is for the
+/// caller to report.
+///
+/// Base files larger than `memory_budget` are memory-mapped, and results that would
+/// not fit are streamed to a partial file (see [`AppliedContent::File`]). Checksums
+/// are computed with `algo`, which must be the one `expected_checksum` came from.
+pub async fn fetch_and_apply_delta<F, Fut>(
+    base_path: &Path,
+    block_size: u32,
+    expected_checksum: &str,
+    algo: ChecksumAlgo,
+    memory_budget: usize,
+    mut fetch_delta: F,
+) -> Result<AppliedDelta>
+where
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>>>,
+{
+    let mut bytes_transferred = 0;
+    let mut retried = false;
+
+    loop {
+        let (signature, base_checksum) = snapshot_base(base_path, block_size, algo, memory_budget)?;
+        log::debug!("Signature size: {} bytes", signature.len());
+
+        let delta = fetch_delta(signature).await?;
+        bytes_transferred += delta.len();
+
+        let applied = apply_delta_within_budget(base_path, &delta, algo, memory_budget).map(
+            |(content, checksum)| AppliedDelta {
+                content,
+                checksum,
+                bytes_transferred,
+            },
+        );
+
+        if let Ok(ref result) = applied
+            && result.checksum == expected_checksum
+        {
+            return applied;
+        }
+
+        if !retried && current_checksum(base_path, algo, memory_budget) != base_checksum {
+            log::warn!(
+                "Base file {} changed during sync, retrying with a fresh signature",
+                base_path.display()
+            );
+            if let Ok(AppliedDelta {
+                content: AppliedContent::File(ref partial),
+                ..
+            }) = applied
+            {
+                let _ = std::fs::remove_file(partial);
+            }
+            retried = true;
+            continue;
+        }
+
+        return applied;
+    }
 }
 
 /// Generate delta from source file and signature
@@ -66,11 +320,48 @@ pub async fn apply_delta(base_path: Option<&Path>, delta_data: &[u8]) -> Result<
     Ok(output)
 }
 
-/// Compute SHA256 checksum of data
-pub fn compute_checksum(data: &[u8]) -> String {
-    let mut hasher = Sha256::new();
+/// Incremental hasher for the selected checksum algorithm
+pub enum ChecksumHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl ChecksumHasher {
+    pub fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Blake3 => ChecksumHasher::Blake3(Box::new(blake3::Hasher::new())),
+            ChecksumAlgo::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            ChecksumHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Hex digest of everything hashed so far
+    pub fn finalize(self) -> String {
+        match self {
+            ChecksumHasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            ChecksumHasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+        }
+    }
+}
+
+/// Compute the hex checksum of data with `algo`
+pub fn compute_checksum(algo: ChecksumAlgo, data: &[u8]) -> String {
+    let mut hasher = ChecksumHasher::new(algo);
     hasher.update(data);
-    hex::encode(hasher.finalize())
+    hasher.finalize()
+}
+
+/// A transfer rate in bytes per second, as MB/s for logs and status output
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{:.2} MB/s", bytes_per_sec / 1_000_000.0)
 }
 
 /// Choose appropriate block size based on file size
@@ -192,7 +483,7 @@ mod tests {
     #[test]
     fn test_compute_checksum() {
         let data = b"Hello, World!";
-        let checksum = compute_checksum(data);
+        let checksum = compute_checksum(ChecksumAlgo::Sha256, data);
 
         // SHA256 of "Hello, World!" should be consistent
         assert_eq!(checksum.len(), 64); // SHA256 is 32 bytes = 64 hex chars
@@ -200,6 +491,13 @@ mod tests {
             checksum,
             "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
         );
+
+        // BLAKE3 digests are the same length, so only the value tells them apart
+        let checksum = compute_checksum(ChecksumAlgo::Blake3, data);
+        assert_eq!(
+            checksum,
+            "288a86a79f20a3d6dccdca7713beaed178798296bdfa7913fa2a62d9727bf8f8"
+        );
     }
 
     #[test]
@@ -207,10 +505,21 @@ mod tests {
         let data1 = b"Hello, World!";
         let data2 = b"Hello, Rust!";
 
-        let checksum1 = compute_checksum(data1);
-        let checksum2 = compute_checksum(data2);
+        for algo in [ChecksumAlgo::Blake3, ChecksumAlgo::Sha256] {
+            assert_ne!(compute_checksum(algo, data1), compute_checksum(algo, data2));
+        }
+    }
 
-        assert_ne!(checksum1, checksum2);
+    #[test]
+    fn test_checksum_hasher_matches_one_shot() {
+        let data: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        for algo in [ChecksumAlgo::Blake3, ChecksumAlgo::Sha256] {
+            let mut hasher = ChecksumHasher::new(algo);
+            for chunk in data.chunks(4096) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), compute_checksum(algo, &data));
+        }
     }
 
     #[test]
@@ -249,4 +558,81 @@ mod tests {
 
         assert_eq!(result, modified);
     }
+
+    #[tokio::test]
+    async fn test_fetch_and_apply_delta_retries_when_base_changes() {
+        let base: Vec<u8> = (0..64u32).flat_map(|i| format!("block {:08}\n", i).into_bytes()).collect();
+        let mut source = base.clone();
+        source.extend_from_slice(b"appended on the server");
+        let expected_checksum = compute_checksum(ChecksumAlgo::Blake3, &source);
+
+        let mut temp_base = NamedTempFile::new().unwrap();
+        temp_base.write_all(&base).unwrap();
+        temp_base.flush().unwrap();
+        let base_path = temp_base.path().to_path_buf();
+
+        let mut attempts = 0;
+        let result = fetch_and_apply_delta(&base_path, 16, &expected_checksum, ChecksumAlgo::Blake3, DEFAULT_MEMORY_BUDGET, |signature| {
+            attempts += 1;
+            if attempts == 1 {
+                // Another process rewrites the base after it was signed
+                std::fs::write(&base_path, base.iter().rev().copied().collect::<Vec<u8>>()).unwrap();
+            }
+            let delta = generate_delta(&source, &signature);
+            async move { delta }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts, 2);
+        assert_eq!(result.content, AppliedContent::Memory(source));
+        assert_eq!(result.checksum, expected_checksum);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_apply_delta_does_not_retry_unchanged_base() {
+        let mut temp_base = NamedTempFile::new().unwrap();
+        temp_base.write_all(b"Hello, World!").unwrap();
+        temp_base.flush().unwrap();
+
+        let mut attempts = 0;
+        let result = fetch_and_apply_delta(temp_base.path(), DEFAULT_BLOCK_SIZE, "not-the-checksum", ChecksumAlgo::Blake3, DEFAULT_MEMORY_BUDGET, |signature| {
+            attempts += 1;
+            let delta = generate_delta(b"Hello, Rust!", &signature);
+            async move { delta }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts, 1);
+        assert_eq!(result.content, AppliedContent::Memory(b"Hello, Rust!".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_apply_delta_streams_over_budget() {
+        let base: Vec<u8> = (0..4096u32).flat_map(|i| format!("line {:08}\n", i).into_bytes()).collect();
+        let mut source = base.clone();
+        source[1000..1010].copy_from_slice(b"CHANGED!!!");
+        let expected_checksum = compute_checksum(ChecksumAlgo::Blake3, &source);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let base_path = temp_dir.path().join("large.bin");
+        std::fs::write(&base_path, &base).unwrap();
+
+        // Budget well under the file size forces the mmap + streaming path
+        let result = fetch_and_apply_delta(&base_path, DEFAULT_BLOCK_SIZE, &expected_checksum, ChecksumAlgo::Blake3, 4096, |signature| {
+            let delta = generate_delta(&source, &signature);
+            async move { delta }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(result.checksum, expected_checksum);
+        assert!(result.bytes_transferred < source.len());
+        let partial = partial_path(&base_path);
+        assert_eq!(result.content, AppliedContent::File(partial.clone()));
+        assert_eq!(std::fs::read(&partial).unwrap(), source);
+        // The base itself is left for the caller to replace
+        assert_eq!(std::fs::read(&base_path).unwrap(), base);
+    }
 }
@@ -1,17 +1,34 @@
 use anyhow::{Context, Result};
+use futures::{Stream, StreamExt, stream};
 use halfremembered_protocol::{
-    ClientMessage, Frame, LocalCommand, LocalResponse, MessageBuffer, ServerMessage,
-    FRAME_HEADER_SIZE,
+    capabilities, ClientMessage, Frame, LocalCommand, LocalResponse, MessageBuffer, ServerMessage, WireFormat,
+    FRAME_HEADER_SIZE, MSG_EXEC_HANDSHAKE,
 };
 use russh::client::{self, Handle};
 use russh::keys;
 use russh::*;
 use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::OpenFlags;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 
+/// ssh-agent socket (or named pipe on Windows) used when a command gets no --agent-socket
+pub const AGENT_SOCKET_ENV: &str = "HRL_AGENT_SOCKET";
+
+/// A control command host of `unix:PATH` is the server's --control-socket, reached
+/// without SSH
+pub const CONTROL_SOCKET_PREFIX: &str = "unix:";
+
+/// Limits authentication to one ssh-agent key, named by its public key file or its
+/// SHA256 fingerprint; unset offers every key the agent holds
+pub const IDENTITY_ENV: &str = "HRL_IDENTITY";
+
 #[cfg(unix)]
 type PlatformAgentClient = keys::agent::client::AgentClient<tokio::net::UnixStream>;
 
@@ -19,10 +36,370 @@ type PlatformAgentClient = keys::agent::client::AgentClient<tokio::net::UnixStre
 type PlatformAgentClient =
     keys::agent::client::AgentClient<tokio::net::windows::named_pipe::NamedPipeClient>;
 
+/// Connect and start an SFTP session on the host's sshd
+async fn open_sftp_session(
+    host: &str,
+    port: u16,
+    user: &str,
+    agent_socket: Option<&str>,
+) -> Result<(Handle<ClientHandler>, SftpSession)> {
+    let session = connect_and_authenticate(host, port, user, agent_socket, 30).await?;
+
+    // Open SFTP channel
+    let sftp_channel = session
+        .channel_open_session()
+        .await
+        .context("Failed to open SFTP channel")?;
+
+    sftp_channel
+        .request_subsystem(true, "sftp")
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to request SFTP subsystem: {:?}", e))?;
+
+    let sftp = SftpSession::new(sftp_channel.into_stream())
+        .await
+        .context("Failed to create SFTP session")?;
+
+    Ok((session, sftp))
+}
+
+/// Where to resume an upload of a `local_size` file given the remote file's size: its end,
+/// unless it can't be a prefix of the local file
+fn resume_offset(remote_size: Option<u64>, local_size: u64) -> u64 {
+    match remote_size {
+        Some(remote_size) if remote_size <= local_size => remote_size,
+        Some(remote_size) => {
+            log::warn!(
+                "Remote file is larger than the local one ({} > {} bytes), uploading from the start",
+                remote_size,
+                local_size
+            );
+            0
+        }
+        None => 0,
+    }
+}
+
+/// How long control commands wait for a response unless told otherwise
+pub const DEFAULT_CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default bytes read from disk per write by `upload_file_via_sftp`
+pub const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How `upload_file_via_sftp` streams a file to the remote host
+#[derive(Debug, Clone, Copy)]
+pub struct UploadOptions {
+    /// Bytes read from disk and written per step
+    pub chunk_size: usize,
+    /// Continue a partial upload from the remote file's size instead of starting over
+    pub resume: bool,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_UPLOAD_CHUNK_SIZE,
+            resume: false,
+        }
+    }
+}
+
+/// Cloned to report from background tasks; the clones share one session
+#[derive(Clone)]
 pub struct SshClientConnection {
-    session: Handle<ClientHandler>,
-    channel: Arc<Mutex<Option<Channel<client::Msg>>>>,
+    session: Arc<Handle<ClientHandler>>,
+    channel: Arc<Mutex<Option<ChannelWriteHalf<client::Msg>>>>,
+    /// What arrives on the channel, read off it as soon as it comes in
+    incoming: Arc<Mutex<tokio::sync::mpsc::UnboundedReceiver<ChannelMsg>>>,
     message_buffer: Arc<Mutex<MessageBuffer>>,
+    wire_format: WireFormat,
+}
+
+/// Open control channel read by `send_control_command_streaming`
+struct ControlConnection {
+    transport: ControlTransport,
+    buffer: MessageBuffer,
+}
+
+/// What a control command's responses arrive on
+enum ControlTransport {
+    Ssh {
+        /// Taken by Drop to disconnect
+        session: Option<Handle<ClientHandler>>,
+        channel: Channel<client::Msg>,
+    },
+    #[cfg(unix)]
+    Socket(tokio::net::UnixStream),
+}
+
+impl ControlConnection {
+    /// Next framed response, or None once the server closes the channel
+    async fn next_response(&mut self) -> Result<Option<LocalResponse>> {
+        loop {
+            if let Some(response) = self.buffer.try_parse_local_response()? {
+                return Ok(Some(response));
+            }
+            match &mut self.transport {
+                ControlTransport::Ssh { channel, .. } => match channel.wait().await {
+                    Some(ChannelMsg::Data { data }) => {
+                        self.buffer.append(&data);
+                    }
+                    Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => {
+                        return Ok(None);
+                    }
+                    Some(msg) => {
+                        log::debug!("Received other channel message: {:?}", msg);
+                    }
+                },
+                #[cfg(unix)]
+                ControlTransport::Socket(stream) => {
+                    let mut chunk = [0u8; 8192];
+                    let n = stream.read(&mut chunk).await.context("Failed to read control socket")?;
+                    if n == 0 {
+                        return Ok(None);
+                    }
+                    self.buffer.append(&chunk[..n]);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ControlConnection {
+    fn drop(&mut self) {
+        // Clean disconnect, off the dropping task since it has to await
+        if let ControlTransport::Ssh { session, .. } = &mut self.transport
+            && let Some(session) = session.take()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn(async move {
+                let _ = session
+                    .disconnect(Disconnect::ByApplication, "", "English")
+                    .await;
+            });
+        }
+    }
+}
+
+/// Where each pipelined request's responses go, by request_id; None once the
+/// connection has closed
+type PendingResponses =
+    Arc<std::sync::Mutex<Option<HashMap<String, tokio::sync::mpsc::UnboundedSender<LocalResponse>>>>>;
+
+/// What a ControlSession writes its commands to
+enum ControlWriter {
+    Ssh(ChannelWriteHalf<client::Msg>),
+    #[cfg(unix)]
+    Socket(tokio::net::unix::OwnedWriteHalf),
+}
+
+/// One control connection carrying many commands at once
+///
+/// Each command goes out Tagged with a new request_id and a reader task routes the
+/// server's Tagged responses back to it, so commands don't wait on each other's round
+/// trips. Dropping the session disconnects.
+pub struct ControlSession {
+    writer: Mutex<ControlWriter>,
+    pending: PendingResponses,
+    reader: tokio::task::JoinHandle<()>,
+    timeout: Option<Duration>,
+    /// Taken by Drop to disconnect
+    session: Option<Handle<ClientHandler>>,
+}
+
+impl ControlSession {
+    /// Connect to the server, or its control socket for a `unix:PATH` host
+    pub async fn connect(host: &str, port: u16, user: &str, agent_socket: Option<&str>) -> Result<Self> {
+        let pending: PendingResponses = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+        if let Some(path) = host.strip_prefix(CONTROL_SOCKET_PREFIX) {
+            return Self::connect_socket(path, pending).await;
+        }
+
+        log::debug!("Opening control session to {}:{}", host, port);
+        let session = connect_and_authenticate(host, port, user, agent_socket, 30).await?;
+        let channel = session
+            .channel_open_session()
+            .await
+            .context("Failed to open session channel")?;
+        let (mut read_half, write_half) = channel.split();
+
+        let routes = pending.clone();
+        let reader = tokio::spawn(async move {
+            let mut buffer = MessageBuffer::new();
+            while let Some(msg) = read_half.wait().await {
+                match msg {
+                    ChannelMsg::Data { data } => {
+                        buffer.append(&data);
+                        if !Self::route_responses(&mut buffer, &routes) {
+                            break;
+                        }
+                    }
+                    ChannelMsg::Eof | ChannelMsg::Close => break,
+                    msg => log::debug!("Received other channel message: {:?}", msg),
+                }
+            }
+            routes.lock().unwrap().take();
+        });
+
+        Ok(Self {
+            writer: Mutex::new(ControlWriter::Ssh(write_half)),
+            pending,
+            reader,
+            timeout: Some(DEFAULT_CONTROL_TIMEOUT),
+            session: Some(session),
+        })
+    }
+
+    /// How long `wait` and `request` wait for a response (None waits indefinitely);
+    /// DEFAULT_CONTROL_TIMEOUT unless set
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[cfg(unix)]
+    async fn connect_socket(path: &str, pending: PendingResponses) -> Result<Self> {
+        log::debug!("Opening control session to socket {}", path);
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .context(format!("Failed to connect to control socket {}", path))?;
+        let (mut read_half, write_half) = stream.into_split();
+
+        let routes = pending.clone();
+        let reader = tokio::spawn(async move {
+            let mut buffer = MessageBuffer::new();
+            let mut chunk = [0u8; 8192];
+            loop {
+                match read_half.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buffer.append(&chunk[..n]);
+                        if !Self::route_responses(&mut buffer, &routes) {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to read control socket: {:#}", e);
+                        break;
+                    }
+                }
+            }
+            routes.lock().unwrap().take();
+        });
+
+        Ok(Self {
+            writer: Mutex::new(ControlWriter::Socket(write_half)),
+            pending,
+            reader,
+            timeout: Some(DEFAULT_CONTROL_TIMEOUT),
+            session: None,
+        })
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_socket(_path: &str, _pending: PendingResponses) -> Result<Self> {
+        anyhow::bail!("Control sockets are only supported on Unix")
+    }
+
+    /// Hand each whole response in `buffer` to the request it answers; false once the
+    /// stream can't be parsed any further
+    fn route_responses(buffer: &mut MessageBuffer, routes: &PendingResponses) -> bool {
+        loop {
+            match buffer.try_parse_local_response() {
+                Ok(Some(LocalResponse::Tagged { request_id, response })) => {
+                    let mut routes = routes.lock().unwrap();
+                    let Some(routes) = routes.as_mut() else {
+                        return false;
+                    };
+                    // Streaming exec output is followed by more; anything else is the answer
+                    let sender = if matches!(*response, LocalResponse::ExecOutput { .. }) {
+                        routes.get(&request_id).cloned()
+                    } else {
+                        routes.remove(&request_id)
+                    };
+                    match sender {
+                        Some(sender) => {
+                            let _ = sender.send(*response);
+                        }
+                        None => log::debug!("Dropping response to unknown request {}", request_id),
+                    }
+                }
+                Ok(Some(response)) => log::warn!("Ignoring untagged control response: {:?}", response),
+                Ok(None) => return true,
+                Err(e) => {
+                    log::warn!("Failed to parse control response: {:#}", e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Send `command` without waiting for earlier ones, returning its responses as they
+    /// arrive; the receiver ends after the final one, or if the connection closes
+    pub async fn send(&self, command: LocalCommand) -> Result<tokio::sync::mpsc::UnboundedReceiver<LocalResponse>> {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(routes) => routes.insert(request_id.clone(), sender),
+            None => anyhow::bail!("Control session closed"),
+        };
+
+        let command = LocalCommand::Tagged {
+            request_id,
+            command: Box::new(command),
+        };
+        let mut full_message = Vec::new();
+        command
+            .write_framed(&mut full_message)
+            .context("Failed to serialize command")?;
+        match &mut *self.writer.lock().await {
+            ControlWriter::Ssh(channel) => channel
+                .data(&full_message[..])
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to send command: {:?}", e))?,
+            #[cfg(unix)]
+            ControlWriter::Socket(stream) => stream
+                .write_all(&full_message)
+                .await
+                .context("Failed to send command")?,
+        }
+        Ok(receiver)
+    }
+
+    /// Wait for the first response on a receiver from `send`
+    pub async fn wait(
+        &self,
+        responses: &mut tokio::sync::mpsc::UnboundedReceiver<LocalResponse>,
+    ) -> Result<LocalResponse> {
+        let response = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, responses.recv())
+                .await
+                .map_err(|_| anyhow::anyhow!("Timeout waiting for response"))?,
+            None => responses.recv().await,
+        };
+        response.context("Connection closed before receiving response")
+    }
+
+    /// Send `command` and wait for its single response
+    pub async fn request(&self, command: LocalCommand) -> Result<LocalResponse> {
+        let mut responses = self.send(command).await?;
+        self.wait(&mut responses).await
+    }
+}
+
+impl Drop for ControlSession {
+    fn drop(&mut self) {
+        self.reader.abort();
+        if let Some(session) = self.session.take()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn(async move {
+                let _ = session
+                    .disconnect(Disconnect::ByApplication, "", "English")
+                    .await;
+            });
+        }
+    }
 }
 
 pub struct ClientHandler;
@@ -39,6 +416,43 @@ impl client::Handler for ClientHandler {
     }
 }
 
+/// A non-empty environment variable, if set
+fn env_setting(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// The agent keys to offer for `identity` (an HRL_IDENTITY value): the one whose
+/// fingerprint matches, read from a public key file unless given as `SHA256:...`
+fn select_identity(identities: Vec<keys::PublicKey>, identity: &str) -> Result<Vec<keys::PublicKey>> {
+    let fingerprint = if identity.starts_with("SHA256:") {
+        identity.to_string()
+    } else {
+        // Like ssh -i, a private key path stands for the .pub beside it
+        let mut path = crate::client_daemon::expand_tilde(identity);
+        let mut public = path.clone().into_os_string();
+        public.push(".pub");
+        let public = std::path::PathBuf::from(public);
+        if path.extension().is_none_or(|extension| extension != "pub") && public.is_file() {
+            path = public;
+        }
+        let content = std::fs::read_to_string(&path)
+            .context(format!("Failed to read {} public key {}", IDENTITY_ENV, path.display()))?;
+        keys::PublicKey::from_openssh(content.trim())
+            .context(format!("Failed to parse {} public key {}", IDENTITY_ENV, path.display()))?
+            .fingerprint(keys::HashAlg::Sha256)
+            .to_string()
+    };
+
+    let selected: Vec<_> = identities
+        .into_iter()
+        .filter(|key| key.fingerprint(keys::HashAlg::Sha256).to_string() == fingerprint)
+        .collect();
+    if selected.is_empty() {
+        anyhow::bail!("{}={} ({}) is not loaded in ssh-agent", IDENTITY_ENV, identity, fingerprint);
+    }
+    Ok(selected)
+}
+
 /// Connect to ssh-agent with platform-specific handling
 #[cfg(unix)]
 async fn connect_agent(agent_socket: Option<&str>) -> Result<PlatformAgentClient> {
@@ -87,7 +501,9 @@ async fn connect_and_authenticate(
         .await
         .context(format!("Failed to connect to {}:{}", host, port))?;
 
-    let mut agent = connect_agent(agent_socket).await?;
+    // --agent-socket, then HRL_AGENT_SOCKET, then the platform default
+    let env_socket = env_setting(AGENT_SOCKET_ENV);
+    let mut agent = connect_agent(agent_socket.or(env_socket.as_deref())).await?;
 
     let identities = agent
         .request_identities()
@@ -97,6 +513,10 @@ async fn connect_and_authenticate(
     if identities.is_empty() {
         anyhow::bail!("No identities found in ssh-agent. Add a key with: ssh-add");
     }
+    let identities = match env_setting(IDENTITY_ENV) {
+        Some(identity) => select_identity(identities, &identity)?,
+        None => identities,
+    };
 
     let mut authenticated = false;
     for public_key in identities {
@@ -141,16 +561,38 @@ impl SshClientConnection {
             .await
             .context("Failed to open session channel")?;
 
+        // russh holds only a few unread messages per channel and stalls the whole session
+        // once they fill up, so a daemon busy opening an rsync channel while the server
+        // queues a large initial sync would never see that channel open; read from a
+        // task of its own instead
+        let (mut read_half, write_half) = channel.split();
+        let (sender, incoming) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(msg) = read_half.wait().await {
+                if sender.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+
         Ok(Self {
-            session,
-            channel: Arc::new(Mutex::new(Some(channel))),
+            session: Arc::new(session),
+            channel: Arc::new(Mutex::new(Some(write_half))),
+            incoming: Arc::new(Mutex::new(incoming)),
             message_buffer: Arc::new(Mutex::new(MessageBuffer::new())),
+            wire_format: WireFormat::default(),
         })
     }
 
+    /// Encode messages to the server in `format`; the server answers in the same one
+    pub fn with_wire_format(mut self, format: WireFormat) -> Self {
+        self.wire_format = format;
+        self
+    }
+
     pub async fn send_message(&self, msg: &ClientMessage) -> Result<()> {
         let mut full_message = Vec::new();
-        msg.write_framed(&mut full_message)?;
+        msg.write_framed_as(&mut full_message, self.wire_format)?;
 
         let mut channel_guard = self.channel.lock().await;
         let channel = channel_guard.as_mut().context("Channel not available")?;
@@ -164,7 +606,19 @@ impl SshClientConnection {
         Ok(())
     }
 
-    pub async fn send_register(&self, hostname: &str, initial_sync: bool) -> Result<()> {
+    /// Cleanly close the SSH session, flushing anything already queued on it
+    pub async fn disconnect(&self) {
+        if let Err(e) = self
+            .session
+            .disconnect(Disconnect::ByApplication, "", "English")
+            .await
+        {
+            log::debug!("Disconnect failed: {:?}", e);
+        }
+    }
+
+    /// Register as `hostname`, resuming the session that was given `resume_token` if any
+    pub async fn send_register(&self, hostname: &str, initial_sync: bool, resume_token: Option<&str>) -> Result<()> {
         let platform = if cfg!(target_os = "windows") {
             "windows"
         } else if cfg!(target_os = "linux") {
@@ -179,6 +633,8 @@ impl SshClientConnection {
             hostname: hostname.to_string(),
             platform: platform.to_string(),
             initial_sync,
+            resume_token: resume_token.map(str::to_string),
+            capabilities: capabilities::supported(),
         };
 
         self.send_message(&msg).await
@@ -199,14 +655,18 @@ impl SshClientConnection {
     }
 
     pub async fn try_receive_message(&self) -> Result<Option<ServerMessage>> {
-        let mut channel_guard = self.channel.lock().await;
-        let channel = channel_guard.as_mut().context("Channel not available")?;
+        let mut buffer = self.message_buffer.lock().await;
+        // One chunk of data can hold several messages
+        if let Some(msg) = buffer.try_parse_server_message()? {
+            log::debug!("Received message: {}", msg.message_type());
+            return Ok(Some(msg));
+        }
+        let mut incoming = self.incoming.lock().await;
 
         // Try to receive with a short timeout
         let timeout = tokio::time::Duration::from_millis(10);
-        match tokio::time::timeout(timeout, channel.wait()).await {
+        match tokio::time::timeout(timeout, incoming.recv()).await {
             Ok(Some(ChannelMsg::Data { data })) => {
-                let mut buffer = self.message_buffer.lock().await;
                 buffer.append(&data);
 
                 match buffer.try_parse_server_message() {
@@ -215,7 +675,7 @@ impl SshClientConnection {
                         Ok(Some(msg))
                     }
                     Ok(None) => Ok(None),
-                    Err(e) => Err(e),
+                    Err(e) => Err(e.into()),
                 }
             }
             Ok(Some(ChannelMsg::Eof)) => {
@@ -233,6 +693,11 @@ impl SshClientConnection {
         }
     }
 
+    /// Stream `local_path` to `remote_path` over SFTP and verify the result
+    ///
+    /// `progress` is called with the bytes uploaded so far and the file's size, once
+    /// before the first chunk (a resumed upload starts past 0) and after each chunk.
+    #[allow(clippy::too_many_arguments)]
     pub async fn upload_file_via_sftp(
         host: &str,
         port: u16,
@@ -240,6 +705,8 @@ impl SshClientConnection {
         local_path: &Path,
         remote_path: &str,
         agent_socket: Option<&str>,
+        options: UploadOptions,
+        mut progress: impl FnMut(u64, u64),
     ) -> Result<()> {
         log::info!(
             "Uploading {} to {}@{}:{}",
@@ -249,28 +716,266 @@ impl SshClientConnection {
             remote_path
         );
 
-        let session = connect_and_authenticate(host, port, user, agent_socket, 30).await?;
+        if options.chunk_size == 0 {
+            anyhow::bail!("Upload chunk size must be at least 1 byte");
+        }
 
-        // Open SFTP channel
-        let sftp_channel = session
-            .channel_open_session()
+        let mut local = tokio::fs::File::open(local_path).await.context(format!(
+            "Failed to open local file: {}",
+            local_path.display()
+        ))?;
+        let size = local
+            .metadata()
             .await
-            .context("Failed to open SFTP channel")?;
+            .context(format!("Failed to stat local file: {}", local_path.display()))?
+            .len();
+
+        let (session, sftp) = open_sftp_session(host, port, user, agent_socket).await?;
+
+        // Continue from what an earlier attempt left behind
+        let mut offset = if options.resume {
+            match sftp.metadata(remote_path).await {
+                Ok(metadata) => resume_offset(metadata.size, size),
+                Err(e) => {
+                    log::info!("Nothing to resume at {} ({}), uploading from the start", remote_path, e);
+                    0
+                }
+            }
+        } else {
+            0
+        };
+
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; options.chunk_size];
+
+        if offset > 0 {
+            // The skipped prefix still counts towards the checksum verified below
+            let mut remaining = offset;
+            while remaining > 0 {
+                let want = buffer.len().min(remaining as usize);
+                local
+                    .read_exact(&mut buffer[..want])
+                    .await
+                    .context(format!("Failed to read local file: {}", local_path.display()))?;
+                hasher.update(&buffer[..want]);
+                remaining -= want as u64;
+            }
+
+            // The upload writes onto the destination in place, so a different file there
+            // (an older binary, say) would end up with this one's tail appended
+            let prefix_checksum = hex::encode(hasher.clone().finalize());
+            let remote_checksum =
+                Self::remote_prefix_checksum(host, port, user, remote_path, offset, agent_socket).await?;
+            if remote_checksum != prefix_checksum {
+                log::warn!(
+                    "The first {} bytes of remote {} differ from {}, uploading from the start",
+                    offset,
+                    remote_path,
+                    local_path.display()
+                );
+                local
+                    .seek(SeekFrom::Start(0))
+                    .await
+                    .context(format!("Failed to rewind local file: {}", local_path.display()))?;
+                hasher = Sha256::new();
+                offset = 0;
+            }
+        }
+
+        let mut file = if offset > 0 {
+            log::info!("Resuming upload of {} at byte {} of {}", local_path.display(), offset, size);
 
-        sftp_channel
-            .request_subsystem(true, "sftp")
+            let mut file = sftp
+                .open_with_flags(remote_path, OpenFlags::WRITE)
+                .await
+                .context(format!("Failed to open remote file: {}", remote_path))?;
+            file.seek(SeekFrom::Start(offset))
+                .await
+                .context("Failed to seek remote file")?;
+            file
+        } else {
+            sftp.create(remote_path)
+                .await
+                .context(format!("Failed to create remote file: {}", remote_path))?
+        };
+
+        // Stream from disk a chunk at a time, so the file never has to fit in memory
+        let mut uploaded = offset;
+        progress(uploaded, size);
+        loop {
+            let read = local
+                .read(&mut buffer)
+                .await
+                .context(format!("Failed to read local file: {}", local_path.display()))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            file.write_all(&buffer[..read])
+                .await
+                .context("Failed to write to remote file")?;
+            uploaded += read as u64;
+            log::debug!("Uploaded {}/{} bytes to {}", uploaded, size, remote_path);
+            progress(uploaded, size);
+        }
+        file.shutdown().await.context("Failed to close remote file")?;
+
+        let remote_size = sftp
+            .metadata(remote_path)
+            .await
+            .context(format!("Failed to stat remote file: {}", remote_path))?
+            .size;
+        if remote_size != Some(size) {
+            anyhow::bail!(
+                "Remote {} is {} bytes after upload, expected {}",
+                remote_path,
+                remote_size.map_or("an unknown number of".to_string(), |size| size.to_string()),
+                size
+            );
+        }
+
+        log::info!("Uploaded {} bytes to {} ({} already there)", size - offset, remote_path, offset);
+
+        // Close SFTP session
+        sftp.close().await.context("Failed to close SFTP session")?;
+
+        // Clean disconnect
+        let _ = session
+            .disconnect(Disconnect::ByApplication, "", "English")
+            .await;
+
+        let checksum = hex::encode(hasher.finalize());
+        Self::verify_remote_checksum(host, port, user, remote_path, &checksum, agent_socket).await
+    }
+
+    /// SHA-256 of the first `length` bytes of `remote_path`, computed by the remote host's
+    /// sha256sum; a host without it can't have uploads resumed
+    async fn remote_prefix_checksum(
+        host: &str,
+        port: u16,
+        user: &str,
+        remote_path: &str,
+        length: u64,
+        agent_socket: Option<&str>,
+    ) -> Result<String> {
+        let command = format!("head -c {} {} | sha256sum", length, shell_quote_path(remote_path));
+        let (success, stdout, stderr) = Self::execute_remote_command(host, port, user, &command, agent_socket)
             .await
-            .map_err(|e| anyhow::anyhow!("Failed to request SFTP subsystem: {:?}", e))?;
+            .context("Failed to checksum remote file")?;
+
+        let checksum = stdout.split_whitespace().next().unwrap_or_default();
+        if !success || checksum.len() != 64 {
+            anyhow::bail!(
+                "Can't resume {}: couldn't checksum what is already on {} ({}); upload again without --resume",
+                remote_path,
+                host,
+                stderr.trim()
+            );
+        }
+        Ok(checksum.to_string())
+    }
 
-        let sftp = SftpSession::new(sftp_channel.into_stream())
+    /// Compare `remote_path`'s SHA-256 with `expected`, using the remote host's sha256sum;
+    /// hosts without it are only checked by size
+    async fn verify_remote_checksum(
+        host: &str,
+        port: u16,
+        user: &str,
+        remote_path: &str,
+        expected: &str,
+        agent_socket: Option<&str>,
+    ) -> Result<()> {
+        let command = format!("sha256sum {}", shell_quote_path(remote_path));
+        let (success, stdout, stderr) = Self::execute_remote_command(host, port, user, &command, agent_socket)
             .await
-            .context("Failed to create SFTP session")?;
+            .context("Failed to checksum remote file")?;
+
+        if !success {
+            log::warn!(
+                "Couldn't checksum {} on {} ({}); verified its size only",
+                remote_path,
+                host,
+                stderr.trim()
+            );
+            return Ok(());
+        }
+
+        let actual = stdout.split_whitespace().next().unwrap_or_default();
+        if actual != expected {
+            anyhow::bail!(
+                "Checksum mismatch for {} after upload: expected {}, got {} (upload again without --resume)",
+                remote_path,
+                expected,
+                actual
+            );
+        }
+
+        log::info!("✓ Verified checksum of {} ({})", remote_path, &expected[..8]);
+        Ok(())
+    }
+
+    /// Upload a file gzip-compressed and decompress it on the remote host, which saves
+    /// time for large binaries on slow links. Needs `gzip` on the remote host.
+    pub async fn upload_file_via_sftp_compressed(
+        host: &str,
+        port: u16,
+        user: &str,
+        local_path: &Path,
+        remote_path: &str,
+        agent_socket: Option<&str>,
+    ) -> Result<()> {
+        let (has_gzip, _, _) =
+            Self::execute_remote_command(host, port, user, "command -v gzip", agent_socket)
+                .await
+                .context("Failed to check for gzip on remote host")?;
+        if !has_gzip {
+            anyhow::bail!("{} has no gzip to decompress with; push without --compress", host);
+        }
 
-        // Read local file
         let contents = tokio::fs::read(local_path).await.context(format!(
             "Failed to read local file: {}",
             local_path.display()
         ))?;
+        let compressed = gzip(&contents).context("Failed to compress file")?;
+
+        log::info!(
+            "Compressed {} from {} to {} bytes",
+            local_path.display(),
+            contents.len(),
+            compressed.len()
+        );
+
+        let remote_gz = format!("{}.gz", remote_path);
+        Self::upload_bytes_via_sftp(host, port, user, &compressed, &remote_gz, agent_socket).await?;
+
+        // Decompress beside the destination and rename into place, so a failure never
+        // leaves a truncated binary behind
+        let decompress_cmd = format!(
+            "gzip -dc {gz} > {tmp} && mv {tmp} {dest} && rm -f {gz}",
+            gz = shell_quote_path(&remote_gz),
+            tmp = shell_quote_path(&format!("{}.tmp", remote_path)),
+            dest = shell_quote_path(remote_path)
+        );
+        let (success, _, stderr) =
+            Self::execute_remote_command(host, port, user, &decompress_cmd, agent_socket)
+                .await
+                .context("Failed to decompress on remote host")?;
+        if !success {
+            anyhow::bail!("Remote decompress of {} failed: {}", remote_gz, stderr.trim());
+        }
+
+        Ok(())
+    }
+
+    async fn upload_bytes_via_sftp(
+        host: &str,
+        port: u16,
+        user: &str,
+        contents: &[u8],
+        remote_path: &str,
+        agent_socket: Option<&str>,
+    ) -> Result<()> {
+        let (session, sftp) = open_sftp_session(host, port, user, agent_socket).await?;
 
         // Create remote file
         let mut file = sftp
@@ -279,7 +984,7 @@ impl SshClientConnection {
             .context(format!("Failed to create remote file: {}", remote_path))?;
 
         // Write contents
-        file.write_all(&contents)
+        file.write_all(contents)
             .await
             .context("Failed to write to remote file")?;
 
@@ -328,10 +1033,8 @@ impl SshClientConnection {
                 Some(ChannelMsg::Data { data }) => {
                     stdout.extend_from_slice(&data);
                 }
-                Some(ChannelMsg::ExtendedData { data, ext }) => {
-                    if ext == 1 {
-                        stderr.extend_from_slice(&data);
-                    }
+                Some(ChannelMsg::ExtendedData { data, ext: 1 }) => {
+                    stderr.extend_from_slice(&data);
                 }
                 Some(ChannelMsg::ExitStatus { exit_status }) => {
                     let success = exit_status == 0;
@@ -364,6 +1067,7 @@ impl SshClientConnection {
         Ok((false, stdout_str, stderr_str))
     }
 
+    /// Send a control command and wait up to DEFAULT_CONTROL_TIMEOUT for its single response
     pub async fn send_control_command(
         host: &str,
         port: u16,
@@ -371,63 +1075,121 @@ impl SshClientConnection {
         command: LocalCommand,
         agent_socket: Option<&str>,
     ) -> Result<LocalResponse> {
-        log::debug!("Sending control command to {}:{}", host, port);
+        Self::send_control_command_with_timeout(
+            host,
+            port,
+            user,
+            command,
+            agent_socket,
+            Some(DEFAULT_CONTROL_TIMEOUT),
+        )
+        .await
+    }
 
-        let session = connect_and_authenticate(host, port, user, agent_socket, 30).await?;
+    /// Send a control command and wait for its single response, at most `timeout`
+    /// (None waits indefinitely)
+    pub async fn send_control_command_with_timeout(
+        host: &str,
+        port: u16,
+        user: &str,
+        command: LocalCommand,
+        agent_socket: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<LocalResponse> {
+        let responses =
+            Self::send_control_command_streaming(host, port, user, command, agent_socket, timeout)
+                .await?;
+        let mut responses = std::pin::pin!(responses);
 
-        // Open a session channel
-        let mut channel = session
-            .channel_open_session()
+        log::debug!("Command sent, waiting for response");
+
+        responses
+            .next()
             .await
-            .context("Failed to open session channel")?;
+            .context("Channel closed before receiving response")?
+    }
 
+    /// Send a control command and read every response the server sends for it
+    ///
+    /// The stream ends when the server closes the channel; dropping it disconnects.
+    /// Connection and send errors are returned up front, read errors end the stream,
+    /// as does waiting longer than `timeout` for a response (None waits indefinitely).
+    pub async fn send_control_command_streaming(
+        host: &str,
+        port: u16,
+        user: &str,
+        command: LocalCommand,
+        agent_socket: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<impl Stream<Item = Result<LocalResponse>> + Send> {
         // Send command
         let mut full_message = Vec::new();
         command
             .write_framed(&mut full_message)
             .context("Failed to serialize command")?;
 
-        channel
-            .data(&full_message[..])
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to send command: {:?}", e))?;
+        let transport = match host.strip_prefix(CONTROL_SOCKET_PREFIX) {
+            Some(path) => Self::send_to_control_socket(path, &full_message).await?,
+            None => {
+                log::debug!("Sending control command to {}:{}", host, port);
 
-        log::debug!("Command sent, waiting for response");
+                let session = connect_and_authenticate(host, port, user, agent_socket, 30).await?;
 
-        // Wait for response with timeout
-        let timeout = tokio::time::Duration::from_secs(30);
-        let response = tokio::time::timeout(timeout, async {
-            let mut buffer = MessageBuffer::new();
-            loop {
-                match channel.wait().await {
-                    Some(ChannelMsg::Data { data }) => {
-                        buffer.append(&data);
-                        if let Some(resp) = buffer.try_parse_local_response()? {
-                            return Ok(resp);
-                        }
-                    }
-                    Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) => {
-                        anyhow::bail!("Channel closed before receiving response")
-                    }
-                    Some(msg) => {
-                        log::debug!("Received other channel message: {:?}", msg);
-                    }
-                    None => {
-                        anyhow::bail!("Channel ended without response")
-                    }
+                // Open a session channel
+                let channel = session
+                    .channel_open_session()
+                    .await
+                    .context("Failed to open session channel")?;
+
+                channel
+                    .data(&full_message[..])
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to send command: {:?}", e))?;
+
+                ControlTransport::Ssh {
+                    session: Some(session),
+                    channel,
                 }
             }
-        })
-        .await
-        .context("Timeout waiting for response")??;
+        };
 
-        // Clean disconnect
-        let _ = channel.eof().await;
-        let _ = session
-            .disconnect(Disconnect::ByApplication, "", "English")
-            .await;
+        let connection = ControlConnection {
+            transport,
+            buffer: MessageBuffer::new(),
+        };
 
-        Ok(response)
+        Ok(stream::unfold(Some(connection), move |connection| async move {
+            let mut connection = connection?;
+            let next = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connection.next_response())
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Timeout waiting for response"))),
+                None => connection.next_response().await,
+            };
+            match next {
+                Ok(Some(response)) => Some((Ok(response), Some(connection))),
+                Ok(None) => None,
+                // Nothing more can be read from a broken stream
+                Err(e) => Some((Err(e), None)),
+            }
+        }))
+    }
+
+    /// Send a framed control command to the server's control socket at `path`
+    #[cfg(unix)]
+    async fn send_to_control_socket(path: &str, message: &[u8]) -> Result<ControlTransport> {
+        log::debug!("Sending control command to socket {}", path);
+
+        let mut stream = tokio::net::UnixStream::connect(path)
+            .await
+            .context(format!("Failed to connect to control socket {}", path))?;
+        stream.write_all(message).await.context("Failed to send command")?;
+        Ok(ControlTransport::Socket(stream))
+    }
+
+    #[cfg(not(unix))]
+    async fn send_to_control_socket(_path: &str, _message: &[u8]) -> Result<ControlTransport> {
+        anyhow::bail!("Control sockets are only supported on Unix")
     }
 
     /// Open a dedicated rsync channel
@@ -446,6 +1208,24 @@ impl SshClientConnection {
         }
     }
 
+    /// Open a dedicated exec channel and send the `request_id` handshake
+    /// Output and exit frames for that request follow on the returned channel
+    pub async fn open_exec_channel(&self, request_id: &str) -> Result<Channel<client::Msg>> {
+        let mut channel = self
+            .session
+            .channel_open_session()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open exec channel: {:#}", e))?;
+
+        let handshake = Frame::new(MSG_EXEC_HANDSHAKE, request_id.as_bytes().to_vec());
+        Self::write_frame_to_channel(&mut channel, &handshake)
+            .await
+            .context("Failed to send exec handshake")?;
+
+        log::debug!("Opened exec channel for request {}", request_id);
+        Ok(channel)
+    }
+
     /// Read a frame from a channel
     /// Blocks until a complete frame is received
     pub async fn read_frame_from_channel(
@@ -527,3 +1307,160 @@ impl SshClientConnection {
         Ok(())
     }
 }
+
+/// Quote a string as a single POSIX shell word, so it reaches the remote program verbatim
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote a remote path as a shell word, leaving a leading `~/` outside the quotes so it
+/// still expands to the remote user's home directory
+pub fn shell_quote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None if path == "~" => path.to_string(),
+        None => shell_quote(path),
+    }
+}
+
+/// Build a remote shell command line: `KEY='value' ... ~/'program' 'arg' ...`
+///
+/// Everything interpolated is quoted; env keys must already be valid variable names.
+pub fn remote_command_line(env: &[(String, String)], program: &str, args: &[String]) -> String {
+    let mut words: Vec<String> = env
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, shell_quote(value)))
+        .collect();
+    words.push(shell_quote_path(program));
+    words.extend(args.iter().map(|arg| shell_quote(arg)));
+    words.join(" ")
+}
+
+/// Gzip-compress data at the best compression level; uploads are one-shot, so it's worth it
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("plain"), "'plain'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$(rm -rf ~); `id` \"x\""), "'$(rm -rf ~); `id` \"x\"'");
+    }
+
+    #[test]
+    fn test_remote_command_line() {
+        let env = vec![
+            ("RUST_LOG".to_string(), "debug".to_string()),
+            ("NOTE".to_string(), "a b; c".to_string()),
+        ];
+        let args = vec!["--port".to_string(), "20222".to_string(), "--daemonize".to_string()];
+        assert_eq!(
+            remote_command_line(&env, "~/halfremembered-launcher", &args),
+            "RUST_LOG='debug' NOTE='a b; c' ~/'halfremembered-launcher' '--port' '20222' '--daemonize'"
+        );
+    }
+
+    #[test]
+    fn test_shell_quote_path() {
+        assert_eq!(shell_quote_path("~/bin/launcher"), "~/'bin/launcher'");
+        assert_eq!(shell_quote_path("~"), "~");
+        assert_eq!(shell_quote_path("/opt/my tools/launcher"), "'/opt/my tools/launcher'");
+        assert_eq!(shell_quote_path("~/it's here"), r"~/'it'\''s here'");
+        assert_eq!(shell_quote_path("/tmp/$(reboot)"), "'/tmp/$(reboot)'");
+        // A tilde that isn't a home-directory prefix is just a character
+        assert_eq!(shell_quote_path("~other/x"), "'~other/x'");
+    }
+
+    // Run the quoted words through a real shell and check each arrives verbatim
+    #[cfg(unix)]
+    #[test]
+    fn test_quoted_words_survive_the_shell() {
+        let run = |command: String| {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let tricky = [
+            "with space",
+            "it's",
+            "\"double\"",
+            "$(echo injected)",
+            "`echo injected`",
+            "a; echo injected",
+            "$HOME",
+        ];
+        let mut args = vec!["%s\\n".to_string()];
+        args.extend(tricky.iter().map(|s| s.to_string()));
+        let stdout = run(remote_command_line(&[], "printf", &args));
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), tricky);
+
+        let value = "x'; echo injected; '$(id)";
+        let env = vec![("HRL_TEST".to_string(), value.to_string())];
+        let args = vec!["-c".to_string(), "printf %s \"$HRL_TEST\"".to_string()];
+        assert_eq!(run(remote_command_line(&env, "sh", &args)), value);
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let compressed = gzip(&data).unwrap();
+        assert!(compressed.len() < data.len() / 10);
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        assert_eq!(resume_offset(Some(4096), 10_000), 4096);
+        assert_eq!(resume_offset(Some(10_000), 10_000), 10_000);
+        // A bigger remote file isn't a partial upload of this one
+        assert_eq!(resume_offset(Some(20_000), 10_000), 0);
+        assert_eq!(resume_offset(None, 10_000), 0);
+    }
+
+    #[test]
+    fn test_select_identity() {
+        let key = || {
+            keys::PrivateKey::random(&mut rand_core::OsRng, keys::Algorithm::Ed25519)
+                .unwrap()
+                .public_key()
+                .clone()
+        };
+        let (work, ci) = (key(), key());
+        let agent = || vec![work.clone(), ci.clone()];
+
+        let fingerprint = ci.fingerprint(keys::HashAlg::Sha256).to_string();
+        assert_eq!(select_identity(agent(), &fingerprint).unwrap(), vec![ci.clone()]);
+
+        // A public key file, or the private key path beside it
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("id_ci.pub"), ci.to_openssh().unwrap()).unwrap();
+        let private = dir.path().join("id_ci");
+        assert_eq!(select_identity(agent(), &private.to_string_lossy()).unwrap(), vec![ci.clone()]);
+        let public = dir.path().join("id_ci.pub");
+        assert_eq!(select_identity(agent(), &public.to_string_lossy()).unwrap(), vec![ci.clone()]);
+
+        // A key the agent doesn't hold, or a file that isn't there
+        assert!(select_identity(vec![work.clone()], &fingerprint).is_err());
+        assert!(select_identity(agent(), &dir.path().join("missing").to_string_lossy()).is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use anyhow::{Context, Result};
 use halfremembered_protocol::{
-    ClientMessage, Frame, FrameBuffer, LocalCommand, LocalResponse, MessageBuffer, ServerMessage,
-    MSG_RSYNC_DELTA, MSG_RSYNC_SIGNATURE,
+    capabilities, ChecksumAlgo, ClientMessage, ClientState, ClientVerifyResult, ExecExit, FileSyncResult, Frame, FrameBuffer, LocalCommand, LocalResponse, ManifestEntry,
+    MessageBuffer, ProtocolError, RecipientFailure, ServerMessage, WireFormat, MSG_EXEC_EXIT, MSG_EXEC_HANDSHAKE,
+    MSG_EXEC_STDERR, MSG_EXEC_STDOUT, MSG_RSYNC_DELTA, MSG_RSYNC_SIGNATURE,
 };
 use rand_core::OsRng;
 use russh::keys::*;
@@ -10,34 +11,396 @@ use russh::*;
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-use crate::client_registry::{ClientRegistry, ConnectedClient};
-use crate::config::Config;
-use crate::file_watcher::FileWatcher;
+use crate::authorized_keys::AuthorizedKey;
+use crate::client_registry::{
+    ClientRegistry, ConnectedClient, ControlWriter, Delivery, ErrorCause, ErrorCounters, DEFAULT_SEND_QUEUE_DEPTH,
+};
+use crate::config::{Config, FileModes, Trigger};
+use crate::file_watcher::{FileWatcher, RelativePatterns, WatchConfig, WatchMode, WatchOptions};
 use crate::rsync_utils;
+use crate::sync_queue::SyncQueue;
 
 /// Shared storage for rsync file data: maps request_id to (file_path, file_contents, pending_clients)
 type RsyncFileStorage =
     Arc<Mutex<HashMap<String, (PathBuf, Arc<memmap2::Mmap>, HashSet<String>)>>>;
 type FileWatcherRef = Arc<Mutex<Option<FileWatcher>>>;
 
+/// Sync rules loaded from config: (project_root, rules)
+type SyncRulesRef = Arc<Mutex<Option<(PathBuf, Vec<CompiledRule>)>>>;
+
+/// A sync rule with its patterns compiled once as the config loads, not on every event
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    rule: crate::config::SyncRule,
+    include_set: RelativePatterns,
+    exclude_set: RelativePatterns,
+}
+
+impl CompiledRule {
+    fn compile(rule: crate::config::SyncRule) -> Result<Self> {
+        let include_set = RelativePatterns::compile(&rule.include, "include", rule.case_insensitive)?;
+        let exclude_set = RelativePatterns::compile(&rule.exclude, "exclude", rule.case_insensitive)?;
+        Ok(Self {
+            rule,
+            include_set,
+            exclude_set,
+        })
+    }
+
+    fn compile_all(rules: Vec<crate::config::SyncRule>) -> Result<Vec<Self>> {
+        rules.into_iter().map(Self::compile).collect()
+    }
+}
+
+impl std::ops::Deref for CompiledRule {
+    type Target = crate::config::SyncRule;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rule
+    }
+}
+
+/// How long a new watch's self-test waits for its probe file event
+const WATCH_PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How long a `client-status` request waits for the client to report its state
+const CLIENT_STATUS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long a `verify` request waits for clients to hash their copies; kept under the
+/// control client's default timeout so slow clients are reported rather than lost
+const VERIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// Pid file of a daemonized server, removed when a shutdown request exits the process
+static PID_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Register the daemon's pid file so `shutdown` cleans it up before exiting
+pub fn set_pid_file(path: PathBuf) {
+    let _ = PID_FILE.set(path);
+}
+
+/// Mode sent for files whose source has no Unix permissions, unless a rule overrides it
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// How long a new control channel has to send its first message before the server
+/// gives up on it as not speaking this protocol
+const IDENTIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Most bytes buffered while waiting for a control channel's first message; far more
+/// than any real first message needs
+const IDENTIFY_LIMIT: usize = 1024 * 1024;
+
+/// Pending connections the server's TCP socket queues before refusing more, unless
+/// `ServerOptions::listen_backlog` says otherwise
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// How often a draining server checks whether the last transfer finished
+const DRAIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
 // Shared storage for execute metadata: maps request_id to (relative_path, execute_config)
 type ExecuteMetadataStorage = Arc<Mutex<HashMap<String, (String, crate::config::ExecuteConfig)>>>;
 
+type ManifestCacheRef = Arc<std::sync::Mutex<ManifestCache>>;
+
+/// A file queued for initial sync: (absolute source, client destination, execute config, modes)
+type InitialSyncTarget = (PathBuf, String, Option<crate::config::ExecuteConfig>, FileModes);
+
+/// How a file is sent to one client, picked from the capabilities it registered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Encoding {
+    /// Answer the rsync handshake with the whole file instead of waiting for a signature
+    whole_file: bool,
+    checksum_algo: ChecksumAlgo,
+}
+
+/// Checksum of a watched file, trusted while its size and mtime are unchanged
+struct CachedChecksum {
+    size: u64,
+    modified: SystemTime,
+    algo: ChecksumAlgo,
+    checksum: String,
+}
+
+/// Checksums of watched files for initial-sync manifests
+///
+/// Every connecting client gets a manifest of the whole tree; caching means only files
+/// that changed since the last connection are read again. Change callbacks drop entries
+/// so edits within the filesystem's mtime granularity are still picked up.
+#[derive(Default)]
+struct ManifestCache {
+    entries: HashMap<PathBuf, CachedChecksum>,
+}
+
+impl ManifestCache {
+    /// Size, mtime (seconds) and `algo` checksum of `path`, reading it only on a cache miss
+    fn lookup(&mut self, path: &Path, algo: ChecksumAlgo) -> Result<(u64, u64, String)> {
+        let metadata = std::fs::metadata(path)
+            .context(format!("Failed to read metadata: {}", path.display()))?;
+        let size = metadata.len();
+        let modified = metadata
+            .modified()
+            .context(format!("Failed to get mtime: {}", path.display()))?;
+        let mtime = modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if let Some(cached) = self.entries.get(path)
+            && cached.size == size
+            && cached.modified == modified
+            && cached.algo == algo
+        {
+            return Ok((size, mtime, cached.checksum.clone()));
+        }
+
+        let checksum = {
+            let file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+            let mmap = unsafe { memmap2::Mmap::map(&file)? };
+            rsync_utils::compute_checksum(algo, &mmap)
+        };
+        self.entries.insert(
+            path.to_path_buf(),
+            CachedChecksum {
+                size,
+                modified,
+                algo,
+                checksum: checksum.clone(),
+            },
+        );
+        Ok((size, mtime, checksum))
+    }
+
+    fn invalidate(&mut self, path: &Path) {
+        self.entries.remove(path);
+    }
+
+    /// Build manifest entries for `targets` with `algo` checksums, dropping cached files
+    /// no longer watched
+    ///
+    /// Targets that can't be read are left out of the manifest and logged.
+    fn manifest(&mut self, targets: &[InitialSyncTarget], algo: ChecksumAlgo) -> Vec<ManifestEntry> {
+        let mut entries = Vec::with_capacity(targets.len());
+        let mut watched = HashSet::new();
+        for (absolute, destination, _, _) in targets {
+            watched.insert(absolute.clone());
+            match self.lookup(absolute, algo) {
+                Ok((size, mtime, checksum)) => entries.push(ManifestEntry {
+                    path: destination.clone(),
+                    size,
+                    checksum,
+                    mtime,
+                }),
+                Err(e) => log::warn!("Leaving {} out of manifest: {:#}", absolute.display(), e),
+            }
+        }
+        self.entries.retain(|path, _| watched.contains(path));
+        entries
+    }
+}
+
+/// Startup options for `SshServer::run_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Write the address actually bound here once listening; with port 0 this is how
+    /// callers learn the OS-assigned port
+    pub port_file: Option<PathBuf>,
+    /// How file watches detect changes
+    pub watch_mode: WatchMode,
+    /// Sync a file matched by several watches or sync rules only for the first, rather
+    /// than once for each
+    pub dedup: bool,
+    /// Unix mode for synced files when this platform has none to send; overrides the
+    /// config's `default_mode`
+    pub default_mode: Option<u32>,
+    /// Send files smaller than this many bytes whole instead of as a delta (0 disables;
+    /// default `rsync_utils::WHOLE_FILE_THRESHOLD`)
+    pub whole_file_threshold: Option<u64>,
+    /// Hash for file checksums; overrides the config's `checksum_algo` (default BLAKE3)
+    pub checksum_algo: Option<ChecksumAlgo>,
+    /// Also create empty source directories on clients during tree and initial syncs
+    pub sync_empty_dirs: bool,
+    /// Send every client a Keepalive this often; its Pong counts as a heartbeat
+    pub keepalive_interval: Option<std::time::Duration>,
+    /// Messages each client's send queue holds before a client that stopped reading is
+    /// evicted (default `client_registry::DEFAULT_SEND_QUEUE_DEPTH`)
+    pub send_queue_depth: Option<usize>,
+    /// Also serve control commands, without SSH, on a Unix socket at this path that
+    /// only this user can connect to
+    pub control_socket: Option<PathBuf>,
+    /// Client paths syncs may write under; overrides the config's
+    /// `allowed_destination_roots` (empty leaves it to the config)
+    pub allowed_destination_roots: Vec<String>,
+    /// Pending connections the listening socket queues (default `DEFAULT_LISTEN_BACKLOG`)
+    pub listen_backlog: Option<u32>,
+    /// Threads file watches check changed files on, off notify's event thread (default:
+    /// none, checking on the event thread)
+    pub watch_workers: Option<usize>,
+}
+
+/// Server-wide settings from startup options and the config, fixed once the server
+/// starts and shared with every session
+#[derive(Debug, Clone)]
+struct ServerSettings {
+    /// Unix mode for synced files when this platform has none to send
+    #[cfg_attr(unix, allow(dead_code))]
+    default_mode: Option<u32>,
+    /// Client paths syncs may write under; empty allows any
+    allowed_destination_roots: Vec<String>,
+    /// Hash the server computes (and clients verify) file checksums with
+    checksum_algo: ChecksumAlgo,
+    /// Files smaller than this many bytes skip the signature/delta exchange
+    whole_file_threshold: u64,
+    /// Also create empty source directories on clients during tree and initial syncs
+    sync_empty_dirs: bool,
+    /// Messages a client's send queue holds before the client is evicted
+    send_queue_depth: usize,
+    /// Worker threads file watches check changes on; 0 checks on notify's event thread
+    watch_workers: usize,
+    /// Unix socket serving control commands, removed when a shutdown request exits the process
+    control_socket: Option<PathBuf>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self::new(&ServerOptions::default(), None)
+    }
+}
+
+impl ServerSettings {
+    /// Settings from startup options, each falling back to the config's value where it
+    /// has one
+    fn new(options: &ServerOptions, config: Option<&Config>) -> Self {
+        let project = config.map(|config| &config.project);
+        if let (Some(mode), Some(configured)) = (options.default_mode, project.and_then(|project| project.default_mode))
+            && mode != configured
+        {
+            log::info!("--default-mode {:o} overrides the config's default_mode {:o}", mode, configured);
+        }
+        if let (Some(algo), Some(configured)) = (options.checksum_algo, project.and_then(|project| project.checksum_algo))
+            && algo != configured
+        {
+            log::info!("--checksum-algo {:?} overrides the config's checksum_algo {:?}", algo, configured);
+        }
+        let configured_roots = project.map_or(&[][..], |project| &project.allowed_destination_roots[..]);
+        let allowed_destination_roots = if options.allowed_destination_roots.is_empty() {
+            configured_roots.to_vec()
+        } else {
+            if !configured_roots.is_empty() && configured_roots != options.allowed_destination_roots {
+                log::info!(
+                    "--allowed-destination-root {} overrides the config's allowed_destination_roots {}",
+                    options.allowed_destination_roots.join(", "),
+                    configured_roots.join(", ")
+                );
+            }
+            options.allowed_destination_roots.clone()
+        };
+        Self {
+            default_mode: options.default_mode.or(project.and_then(|project| project.default_mode)),
+            allowed_destination_roots,
+            checksum_algo: options
+                .checksum_algo
+                .or(project.and_then(|project| project.checksum_algo))
+                .unwrap_or_default(),
+            whole_file_threshold: options.whole_file_threshold.unwrap_or(rsync_utils::WHOLE_FILE_THRESHOLD),
+            sync_empty_dirs: options.sync_empty_dirs,
+            send_queue_depth: options.send_queue_depth.unwrap_or(DEFAULT_SEND_QUEUE_DEPTH),
+            watch_workers: options.watch_workers.unwrap_or(0),
+            control_socket: options.control_socket.clone(),
+        }
+    }
+
+    /// The server's hash, unless it's BLAKE3 and the client can't verify that, in which
+    /// case SHA-256, which every client can
+    fn checksum_algo_for(&self, client_capabilities: &[String]) -> ChecksumAlgo {
+        match self.checksum_algo {
+            ChecksumAlgo::Blake3 if !capabilities::has(client_capabilities, capabilities::BLAKE3) => ChecksumAlgo::Sha256,
+            algo => algo,
+        }
+    }
+
+    /// How to send a file of `size` bytes to a client that registered with
+    /// `client_capabilities`
+    fn encoding(&self, client_capabilities: &[String], size: u64) -> Encoding {
+        Encoding {
+            whole_file: size < self.whole_file_threshold && capabilities::has(client_capabilities, capabilities::WHOLE_FILE),
+            checksum_algo: self.checksum_algo_for(client_capabilities),
+        }
+    }
+
+    /// Refuse a client path outside the allowed destination roots, if any are set
+    fn check_destination(&self, destination: &str) -> Result<()> {
+        if !self.allowed_destination_roots.is_empty()
+            && !within_destination_roots(destination, &self.allowed_destination_roots)
+        {
+            anyhow::bail!(
+                "Destination {} is outside allowed_destination_roots ({})",
+                destination,
+                self.allowed_destination_roots.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Refuse a config whose rules sync outside the allowed destination roots, naming
+    /// every rule that does
+    fn check_rules(&self, rules: &[crate::config::SyncRule]) -> Result<()> {
+        let violations: Vec<String> = rules
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, rule)| {
+                let error = self.check_destination(&rule.destination).err()?;
+                let default_name = format!("sync rule {}", idx + 1);
+                Some(format!("{}: {:#}", rule.name.as_deref().unwrap_or(&default_name), error))
+            })
+            .collect();
+        if !violations.is_empty() {
+            anyhow::bail!("{}", violations.join("; "));
+        }
+        Ok(())
+    }
+
+    /// Mode bits sent with a synced file: the rule's `file_mode`, else the source's own
+    /// permissions, else the default for server platforms without Unix modes
+    fn sync_mode(&self, metadata: &std::fs::Metadata, modes: FileModes) -> u32 {
+        if let Some(mode) = modes.file {
+            return mode;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.permissions().mode()
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = metadata;
+            self.default_mode.unwrap_or(DEFAULT_FILE_MODE)
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SshServer {
     client_registry: Arc<Mutex<ClientRegistry>>,
-    authorized_keys: Arc<Vec<ssh_key::PublicKey>>,
+    authorized_keys: Arc<Vec<AuthorizedKey>>,
     rsync_file_storage: RsyncFileStorage,
     execute_metadata: ExecuteMetadataStorage,
     file_watcher: FileWatcherRef,
-    sync_rules: Arc<Mutex<Option<(PathBuf, Vec<crate::config::SyncRule>)>>>, // (project_root, rules)
+    sync_rules: SyncRulesRef,
     start_time: Arc<Instant>,
-    rsync_semaphore: Arc<tokio::sync::Semaphore>,
+    sync_queue: Arc<SyncQueue>,
+    watch_mode: WatchMode,
+    dedup: bool,
+    settings: Arc<ServerSettings>,
+    manifest_cache: ManifestCacheRef,
+    /// Set by `drain`: new sessions and new sync work are refused
+    draining: Arc<AtomicBool>,
+    /// The registry's drop and rejection counters, for sessions to count into
+    error_counters: Arc<ErrorCounters>,
 }
 
 impl SshServer {
@@ -47,52 +410,250 @@ impl SshServer {
 
         log::info!("Loaded {} authorized keys", authorized_keys.len());
 
+        let client_registry = ClientRegistry::new();
+        let error_counters = client_registry.error_counters().clone();
         Ok(Self {
-            client_registry: Arc::new(Mutex::new(ClientRegistry::new())),
+            client_registry: Arc::new(Mutex::new(client_registry)),
             authorized_keys: Arc::new(authorized_keys),
             rsync_file_storage: Arc::new(Mutex::new(HashMap::new())),
             execute_metadata: Arc::new(Mutex::new(HashMap::new())),
             file_watcher: Arc::new(Mutex::new(None)),
             sync_rules: Arc::new(Mutex::new(None)),
             start_time: Arc::new(Instant::now()),
-            rsync_semaphore: Arc::new(tokio::sync::Semaphore::new(5)), // Limit to 5 concurrent rsyncs
+            sync_queue: SyncQueue::new(5), // Limit to 5 concurrent rsyncs
+            watch_mode: WatchMode::default(),
+            dedup: false,
+            settings: Arc::new(ServerSettings::default()),
+            manifest_cache: Arc::new(std::sync::Mutex::new(ManifestCache::default())),
+            draining: Arc::new(AtomicBool::new(false)),
+            error_counters,
         })
     }
 
-    /// Strip the pattern's base directory from the relative path to avoid duplication.
-    ///
-    /// For example:
-    /// - Pattern: "assets/**/*", Path: "assets/data/file.json" -> "data/file.json"
-    /// - Pattern: "target/release/binary", Path: "target/release/binary" -> "binary"
-    fn strip_pattern_base(pattern: &str, relative_path: &Path) -> PathBuf {
-        // Find the first glob character in the pattern
-        let glob_pos = pattern.find(&['*', '?', '[', '{'][..]);
+    /// First sync rule whose include patterns match a file under the project root, at a
+    /// depth the rule watches
+    fn matching_rule<'a>(
+        rules: &'a [CompiledRule],
+        project_root: &Path,
+        absolute: &Path,
+    ) -> Option<&'a CompiledRule> {
+        Self::matching_rules(rules, project_root, absolute).into_iter().next()
+    }
 
-        if let Some(pos) = glob_pos {
-            // Extract the base path before the glob
-            let base = &pattern[..pos];
-            // Remove trailing slashes and wildcards
-            let base = base.trim_end_matches('/');
+    /// Every sync rule that matches a file under the project root, in config order, each
+    /// by its own include and exclude patterns and case setting
+    fn matching_rules<'a>(
+        rules: &'a [CompiledRule],
+        project_root: &Path,
+        absolute: &Path,
+    ) -> Vec<&'a CompiledRule> {
+        let Ok(relative) = absolute.strip_prefix(project_root) else {
+            return Vec::new();
+        };
+        rules
+            .iter()
+            .filter(|rule| rule.reaches(relative) && rule.include_set.is_match(relative) && !rule.exclude_set.is_match(relative))
+            .collect()
+    }
+
+    /// Client destinations, execute configs and modes for a file: one per matching rule, or
+    /// only the first rule's with `dedup`. Files matched by several rules are logged.
+    fn rule_targets(
+        rules: &[CompiledRule],
+        project_root: &Path,
+        absolute: &Path,
+        relative: &Path,
+        dedup: bool,
+    ) -> Vec<(String, Option<crate::config::ExecuteConfig>, FileModes)> {
+        let mut matched = Self::matching_rules(rules, project_root, absolute);
+
+        if matched.len() > 1 {
+            let destinations: Vec<&str> = matched.iter().map(|rule| rule.destination.as_str()).collect();
+            if dedup {
+                log::warn!(
+                    "📎 {} matches {} sync rules (destinations: {}); dedup is on, syncing only to {}",
+                    relative.display(),
+                    matched.len(),
+                    destinations.join(", "),
+                    destinations[0]
+                );
+                matched.truncate(1);
+            } else {
+                log::info!(
+                    "📎 {} matches {} sync rules, syncing to each destination: {}",
+                    relative.display(),
+                    matched.len(),
+                    destinations.join(", ")
+                );
+            }
+        }
+
+        matched
+            .into_iter()
+            .map(|rule| {
+                let destination = Self::rule_destination(rule, relative).to_string_lossy().to_string();
+                (destination, rule.execute.clone(), rule.modes())
+            })
+            .collect()
+    }
 
-            if !base.is_empty() {
-                // Try to strip this base from the relative path
-                if let Ok(stripped) = relative_path.strip_prefix(base) {
-                    return stripped.to_path_buf();
+    /// Split `VAR=value` arguments out of an Execute's args into its environment
+    ///
+    /// This allows CLI usage like: exec client game.exe RUST_LOG=debug --windowed
+    fn split_env_args(args: Vec<String>) -> (HashMap<String, String>, Vec<String>) {
+        let mut env = HashMap::new();
+        let mut clean_args = Vec::new();
+        for arg in args {
+            if let Some((key, value)) = arg.split_once('=') {
+                // Check if this looks like an env var (uppercase letters/underscore)
+                if key.chars().all(|c| c.is_uppercase() || c.is_numeric() || c == '_')
+                    && key.chars().next().is_some_and(|c| c.is_alphabetic()) {
+                    env.insert(key.to_string(), value.to_string());
+                    log::debug!("Parsed env var: {}={}", key, value);
+                    continue;
                 }
             }
+            clean_args.push(arg);
+        }
+        (env, clean_args)
+    }
+
+    /// Whether a file is matched only by non-recursive rules, from below their single
+    /// level. The consolidated watch covers every level, so such files are skipped.
+    fn below_rule_depth(rules: &[CompiledRule], project_root: &Path, absolute: &Path) -> bool {
+        let Ok(relative) = absolute.strip_prefix(project_root) else {
+            return false;
+        };
+        Self::matching_rule(rules, project_root, absolute).is_none()
+            && rules.iter().any(|rule| rule.include_set.is_match(relative))
+    }
+
+    /// Client-side path of a file synced by `rule`
+    ///
+    /// The first include pattern's base is stripped to avoid duplication (e.g. "assets/"
+    /// from "assets/data/file.json") before joining the rule's destination.
+    fn rule_destination(rule: &crate::config::SyncRule, relative: &Path) -> PathBuf {
+        let pattern = rule.include.first().map(|s| s.as_str()).unwrap_or("");
+        PathBuf::from(&rule.destination).join(crate::config::strip_pattern_base(pattern, relative))
+    }
+
+    /// Client directories and modes for an empty source directory: one per rule whose
+    /// include pattern's literal base contains it, or only the first with `dedup`.
+    /// Rules naming an exact file cover no directories.
+    fn rule_dir_targets(rules: &[CompiledRule], relative: &Path, dedup: bool) -> Vec<(String, FileModes)> {
+        let mut targets: Vec<_> = rules
+            .iter()
+            .filter(|rule| {
+                let pattern = rule.include.first().map(|s| s.as_str()).unwrap_or("");
+                pattern.contains(['*', '?', '[', '{']) && relative.starts_with(crate::config::glob_base(pattern))
+            })
+            .map(|rule| {
+                let destination = Self::rule_destination(rule, relative).to_string_lossy().to_string();
+                (destination, rule.modes())
+            })
+            .collect();
+        if dedup {
+            targets.truncate(1);
+        }
+        targets
+    }
+
+    /// Client-side path to delete when a watched file is removed, if `rule` propagates
+    /// deletions and the path stays strictly inside the rule's destination
+    fn scoped_delete_path(rule: &crate::config::SyncRule, relative: &Path) -> Option<PathBuf> {
+        if rule.mirror_scope != crate::config::MirrorScope::Subtree {
+            return None;
+        }
+
+        let path = Self::rule_destination(rule, relative);
+        if crate::config::within_scope(Path::new(&rule.destination), &path) {
+            Some(path)
         } else {
-            // No globs in pattern - it's an exact file match
-            // Return just the filename
-            if let Some(filename) = relative_path.file_name() {
-                return PathBuf::from(filename);
-            }
+            log::warn!(
+                "Not deleting {}: outside destination {}",
+                path.display(),
+                rule.destination
+            );
+            None
         }
+    }
 
-        // Fallback: return the path as-is
-        relative_path.to_path_buf()
+    /// DeleteFile for a removed source file, if the rule matching it mirrors deletions
+    fn delete_message(
+        settings: &ServerSettings,
+        rules: &[CompiledRule],
+        project_root: &Path,
+        absolute: &Path,
+        relative: &Path,
+    ) -> Option<ServerMessage> {
+        let rule = Self::matching_rule(rules, project_root, absolute)?;
+        let path = Self::scoped_delete_path(rule, relative)?.to_string_lossy().to_string();
+        if let Err(e) = settings.check_destination(&path) {
+            log::warn!("Not deleting {} on clients: {:#}", path, e);
+            return None;
+        }
+        log::info!("🗑️  Deleting {} on clients (scope: {})", path, rule.destination);
+        Some(ServerMessage::DeleteFile {
+            request_id: format!("delete-{}", Uuid::new_v4()),
+            path,
+            scope: rule.destination.clone(),
+        })
     }
 
-    fn load_authorized_keys() -> Result<Vec<ssh_key::PublicKey>> {
+    /// Sync `absolute` to each of its rule targets; queued rather than started, so
+    /// higher-priority changes go first
+    fn queue_syncs(
+        sync_queue: &SyncQueue,
+        settings: &Arc<ServerSettings>,
+        registry: &Arc<Mutex<ClientRegistry>>,
+        storage: &RsyncFileStorage,
+        exec_metadata: &ExecuteMetadataStorage,
+        absolute: &Path,
+        targets: Vec<(String, Option<crate::config::ExecuteConfig>, FileModes)>,
+    ) {
+        for (destination_path, exec_config, modes) in targets {
+            log::debug!("Source: {}, Destination: {}", absolute.display(), destination_path);
+            let settings = settings.clone();
+            let registry = registry.clone();
+            let storage = storage.clone();
+            let exec_metadata = exec_metadata.clone();
+            let absolute = absolute.to_path_buf();
+
+            sync_queue.push(modes.priority, destination_path.clone(), async move {
+                log::info!("🔄 Syncing {} to clients", absolute.display());
+
+                // Sync with or without execute config
+                let result = if let Some(config) = exec_config {
+                    log::debug!("File has execute config: {}", config.command);
+                    Self::sync_file_to_clients_with_exec(
+                        &absolute.to_string_lossy(),
+                        &destination_path,
+                        modes,
+                        &settings,
+                        registry,
+                        storage,
+                        exec_metadata,
+                        Some(config),
+                    ).await
+                } else {
+                    Self::sync_file_to_clients(
+                        &absolute.to_string_lossy(),
+                        &destination_path,
+                        modes,
+                        &settings,
+                        registry,
+                        storage,
+                    ).await
+                };
+
+                if let Err(e) = result {
+                    log::error!("Failed to sync changed file: {:#}", e);
+                }
+            });
+        }
+    }
+
+    fn load_authorized_keys() -> Result<Vec<AuthorizedKey>> {
         let home = std::env::var("HOME").context("HOME not set")?;
         let authorized_keys_path = PathBuf::from(home).join(".ssh/authorized_keys");
 
@@ -116,18 +677,19 @@ impl SshServer {
                 continue;
             }
 
-            match ssh_key::PublicKey::from_openssh(line) {
+            match AuthorizedKey::parse(line) {
                 Ok(key) => {
                     log::debug!(
-                        "Loaded key from authorized_keys line {}: {}",
+                        "Loaded key from authorized_keys line {}: {}{}",
                         line_number,
-                        key.fingerprint(ssh_key::HashAlg::Sha256)
+                        key.key.fingerprint(ssh_key::HashAlg::Sha256),
+                        if key.from.is_some() { " (from= restricted)" } else { "" }
                     );
                     keys.push(key);
                 }
                 Err(e) => {
                     log::warn!(
-                        "Failed to parse key at authorized_keys line {}: {}",
+                        "Skipping key at authorized_keys line {}: {:#}",
                         line_number,
                         e
                     );
@@ -139,10 +701,24 @@ impl SshServer {
     }
 
     pub async fn run(port: u16) -> Result<()> {
+        Self::run_with_options(port, ServerOptions::default()).await
+    }
+
+    /// Run the server with non-default startup options
+    pub async fn run_with_options(port: u16, options: ServerOptions) -> Result<()> {
         let mut server = Self::new().await?;
+        server.watch_mode = options.watch_mode;
+        server.dedup = options.dedup;
+
+        if let Some(interval) = options.keepalive_interval {
+            Self::spawn_keepalive(server.client_registry.clone(), interval);
+        }
 
         // Try to auto-load config file from current directory or ancestors
-        match Config::find_and_load() {
+        let found = Config::find_and_load();
+        server.settings = Arc::new(ServerSettings::new(&options, found.as_ref().ok().map(|(_, config)| config)));
+
+        match found {
             Ok((config_path, config)) => {
                 log::info!("📄 Found config: {}", config_path.display());
                 log::info!("🚀 Project: {}", config.project.name);
@@ -155,124 +731,125 @@ impl SshServer {
 
                 log::info!("📁 Project root: {}", project_root.display());
 
+                server
+                    .settings
+                    .check_rules(&config.sync_rules)
+                    .context(format!("Invalid config {}", config_path.display()))?;
+
+                // Rules triggered by commits are kept out of the file watcher entirely
+                let (commit_rules, change_rules): (Vec<_>, Vec<_>) = CompiledRule::compile_all(config.sync_rules.clone())
+                    .context(format!("Invalid config {}", config_path.display()))?
+                    .into_iter()
+                    .partition(|rule| rule.trigger == Trigger::GitCommit);
+                if !commit_rules.is_empty() {
+                    server.spawn_commit_watch(&project_root, commit_rules)?;
+                }
+
                 // Store sync rules for later lookup in callback
-                *server.sync_rules.lock().await = Some((project_root.clone(), config.sync_rules.clone()));
+                *server.sync_rules.lock().await = Some((project_root.clone(), change_rules.clone()));
 
                 // Set up watches for each sync rule
                 let registry = server.client_registry.clone();
                 let storage = server.rsync_file_storage.clone();
                 let exec_metadata = server.execute_metadata.clone();
                 let sync_rules = server.sync_rules.clone();
-                let semaphore = server.rsync_semaphore.clone();
+                let sync_queue = server.sync_queue.clone();
+                let settings = server.settings.clone();
+                let dedup = server.dedup;
+                let file_watcher = server.file_watcher.clone();
+                let manifest_cache = server.manifest_cache.clone();
                 let runtime_handle = tokio::runtime::Handle::current();
 
                 // Create callback for file changes
                 // FileWatcher already verified the file actually changed (checksum-based)
-                let callback = move |_watch_root: PathBuf, relative: PathBuf, absolute: PathBuf| {
+                let callback = move |watch_root: PathBuf, relative: PathBuf, absolute: PathBuf| {
+                    manifest_cache.lock().unwrap().invalidate(&absolute);
                     let registry = registry.clone();
                     let storage = storage.clone();
                     let exec_metadata = exec_metadata.clone();
                     let sync_rules = sync_rules.clone();
-                    let semaphore = semaphore.clone();
+                    let sync_queue = sync_queue.clone();
+                    let settings = settings.clone();
+                    let file_watcher = file_watcher.clone();
                     let relative_str = relative.to_string_lossy().to_string();
 
                     runtime_handle.spawn(async move {
-                        let available = semaphore.available_permits();
-                        log::info!("🔄 Syncing {} to clients (semaphore: {} available)", absolute.display(), available);
-
-                        // Acquire semaphore to limit concurrent syncs
-                        let _permit = semaphore.acquire().await.unwrap();
-                        log::debug!("Acquired semaphore permit for {}", absolute.display());
-
-                        // Find which sync rule matches this file to get destination and execute config
-                        let (destination_path, exec_config) = {
+                        // Find which sync rules match this file to get destinations and execute configs
+                        let mut targets = {
                             let rules_lock = sync_rules.lock().await;
-                            if let Some((project_root, rules)) = rules_lock.as_ref() {
-                                // Find the first rule that matches this file
-                                let matched_rule = rules.iter().find(|rule| {
-                                    // Check if file matches this rule's include patterns
-                                    use globset::{Glob, GlobSetBuilder};
-                                    let mut builder = GlobSetBuilder::new();
-                                    for pattern in &rule.include {
-                                        if let Ok(glob) = Glob::new(pattern) {
-                                            builder.add(glob);
-                                        }
+                            match rules_lock.as_ref() {
+                                Some((project_root, rules)) => {
+                                    if Self::below_rule_depth(rules, project_root, &absolute) {
+                                        log::debug!("Skipping {}: below its non-recursive rule", absolute.display());
+                                        return;
                                     }
-                                    if let Ok(set) = builder.build() {
-                                        if let Ok(rel) = absolute.strip_prefix(project_root) {
-                                            set.is_match(rel)
-                                        } else {
-                                            false
-                                        }
-                                    } else {
-                                        false
-                                    }
-                                });
-
-                                if let Some(rule) = matched_rule {
-                                    // Find which pattern matched (use first for simplicity)
-                                    let pattern = rule.include.first().map(|s| s.as_str()).unwrap_or("");
-
-                                    // Strip pattern base to avoid duplication (e.g., "assets/" from "assets/data/file.json")
-                                    let stripped_path = Self::strip_pattern_base(pattern, &relative);
-
-                                    // Construct destination by joining rule's destination with the stripped path
-                                    let dest = std::path::PathBuf::from(&rule.destination);
-                                    let full_dest = dest.join(&stripped_path);
-                                    let dest_str = full_dest.to_string_lossy().to_string();
-
-                                    log::debug!("Pattern: {}, Original: {}, Stripped: {}, Destination: {}",
-                                        pattern, relative_str, stripped_path.display(), dest_str);
-
-                                    (dest_str, rule.execute.clone())
-                                } else {
-                                    // No matching rule, use relative path as-is
-                                    (relative_str.clone(), None)
+                                    Self::rule_targets(rules, project_root, &absolute, &relative, dedup)
                                 }
-                            } else {
-                                // No sync rules configured, use relative path as-is
-                                (relative_str.clone(), None)
+                                None => Vec::new(),
                             }
                         };
+                        // No matching rule: a watch added later (e.g. by config-sync) may carry
+                        // its own destination, otherwise use the relative path as-is
+                        if targets.is_empty() {
+                            let watch_targets = file_watcher
+                                .lock()
+                                .await
+                                .as_ref()
+                                .map(|watcher| watcher.targets_for(&watch_root, &relative))
+                                .unwrap_or_default();
+                            for (destination, priority) in watch_targets {
+                                let destination = destination
+                                    .map(|path| path.to_string_lossy().to_string())
+                                    .unwrap_or_else(|| relative_str.clone());
+                                targets.push((destination, None, FileModes { priority, ..FileModes::default() }));
+                            }
+                        }
 
-                        // Sync with or without execute config
-                        let result = if let Some(config) = exec_config {
-                            log::debug!("File has execute config: {}", config.command);
-                            Self::sync_file_to_clients_with_exec(
-                                &absolute.to_string_lossy(),
-                                &destination_path,
-                                registry,
-                                storage,
-                                exec_metadata,
-                                Some(config),
-                            ).await
-                        } else {
-                            Self::sync_file_to_clients(
-                                &absolute.to_string_lossy(),
-                                &destination_path,
-                                registry,
-                                storage,
-                            ).await
-                        };
+                        Self::queue_syncs(&sync_queue, &settings, &registry, &storage, &exec_metadata, &absolute, targets);
+                    });
+                };
 
-                        if let Err(e) = result {
-                            log::error!("Failed to sync changed file: {:#}", e);
-                        }
+                // Propagate deletions for rules with mirror_scope = "subtree"
+                let registry = server.client_registry.clone();
+                let sync_rules = server.sync_rules.clone();
+                let settings = server.settings.clone();
+                let manifest_cache = server.manifest_cache.clone();
+                let runtime_handle = tokio::runtime::Handle::current();
+                let on_remove = move |_watch_root: PathBuf, relative: PathBuf, absolute: PathBuf| {
+                    manifest_cache.lock().unwrap().invalidate(&absolute);
+                    let registry = registry.clone();
+                    let sync_rules = sync_rules.clone();
+                    let settings = settings.clone();
 
-                        log::debug!("Released semaphore permit for {}", absolute.display());
+                    runtime_handle.spawn(async move {
+                        let msg = {
+                            let rules_lock = sync_rules.lock().await;
+                            let Some(msg) = rules_lock.as_ref().and_then(|(project_root, rules)| {
+                                Self::delete_message(&settings, rules, project_root, &absolute, &relative)
+                            }) else {
+                                return;
+                            };
+                            msg
+                        };
+                        if let Err(e) = registry.lock().await.broadcast(&msg) {
+                            log::error!("Failed to send delete: {:#}", e);
+                        }
                     });
                 };
 
-                let mut watcher = FileWatcher::new(callback)
-                    .context("Failed to create file watcher")?;
+                let mut watcher = FileWatcher::new(server.watch_mode, server.settings.watch_workers, callback)
+                    .context("Failed to create file watcher")?
+                    .with_on_remove(on_remove)
+                    .with_dedup(server.dedup)
+                    .with_checksum_algo(server.settings.checksum_algo);
 
-                log::info!("👁️  Setting up {} watch rules", config.sync_rules.len());
+                log::info!("👁️  Setting up {} watch rules", change_rules.len());
 
                 // Consolidate all sync rules into a single watch with merged patterns
                 let mut all_includes: Vec<String> = Vec::new();
                 let mut all_excludes: Vec<String> = Vec::new();
 
-                for (idx, rule) in config.sync_rules.iter().enumerate() {
+                for (idx, rule) in change_rules.iter().enumerate() {
                     let default_name = format!("rule-{}", idx + 1);
                     let rule_name = rule.name.as_deref().unwrap_or(&default_name);
 
@@ -288,20 +865,41 @@ impl SshServer {
                 }
 
                 // Add consolidated watch
-                log::info!("  ⚙️  Watching {} with {} include patterns, {} exclude patterns",
-                    project_root.display(),
-                    all_includes.len(),
-                    all_excludes.len()
-                );
+                if !change_rules.is_empty() {
+                    log::info!("  ⚙️  Watching {} with {} include patterns, {} exclude patterns",
+                        project_root.display(),
+                        all_includes.len(),
+                        all_excludes.len()
+                    );
+                }
 
-                if let Err(e) = watcher.add_watch(
+                if change_rules.is_empty() {
+                    log::info!("  ⚙️  No rules sync on change, not watching {}", project_root.display());
+                } else if let Err(e) = watcher.add_watch_with(
                     project_root.clone(),
                     true, // Always recursive for directory watches
                     all_includes,
                     all_excludes,
+                    None,
+                    // One watch serves every rule, so its includes have to be as permissive
+                    // as the loosest rule's, and its excludes keep their case so no rule loses
+                    // files to another's case setting; matching_rules then applies each
+                    // rule's own patterns and case setting
+                    change_rules.iter().any(|rule| rule.case_insensitive),
+                    WatchOptions {
+                        exclude_case_sensitive: true,
+                        ..WatchOptions::default()
+                    },
                 ) {
                     log::error!("  ❌ Failed to add consolidated watch: {:#}", e);
                 } else {
+                    // Likewise the longest settle period covers every rule
+                    let settle_ms = change_rules.iter().map(|rule| rule.settle_ms).max().unwrap_or(0);
+                    if settle_ms > 0
+                        && let Err(e) = watcher.set_settle(&project_root, std::time::Duration::from_millis(settle_ms))
+                    {
+                        log::error!("  ❌ Failed to set settle period: {:#}", e);
+                    }
                     log::info!("  ✅ Consolidated watch configured");
                 }
 
@@ -315,38 +913,630 @@ impl SshServer {
             }
         }
 
-        let host_key = russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)
-            .context("Failed to generate ephemeral host key")?;
+        let host_key = russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)
+            .context("Failed to generate ephemeral host key")?;
+
+        log::info!("Generated ephemeral Ed25519 host key");
+
+        let config = russh::server::Config {
+            inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+            auth_rejection_time: std::time::Duration::from_secs(3),
+            auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+            keys: vec![host_key],
+            ..Default::default()
+        };
+
+        let listener = bind_listener(port, options.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))
+            .context(format!("Failed to bind 0.0.0.0:{}", port))?;
+        let local_addr = listener
+            .local_addr()
+            .context("Failed to read bound address")?;
+
+        log::info!("Starting SSH server on {}", local_addr);
+
+        if let Some(path) = options.control_socket {
+            server.spawn_control_socket(&path)?;
+        }
+
+        // Last, so whoever waits for the port file finds the control socket up as well
+        if let Some(port_file) = options.port_file {
+            std::fs::write(&port_file, format!("{}\n", local_addr))
+                .context(format!("Failed to write port file {}", port_file.display()))?;
+            log::info!("📝 Wrote bound address to {}", port_file.display());
+        }
+
+        server.run_on_socket(Arc::new(config), &listener).await?;
+
+        Ok(())
+    }
+
+    /// Serve control commands on a Unix socket at `path`, speaking the same framing as
+    /// a control channel but without SSH: the socket is created owner-only, and
+    /// connections from other users are refused
+    #[cfg(unix)]
+    fn spawn_control_socket(&self, path: &Path) -> Result<()> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+        // A socket left by a server that didn't shut down cleanly would fail the bind,
+        // but one that still accepts connections belongs to a running server
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                anyhow::bail!("{} exists and is not a socket", path.display());
+            }
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => anyhow::bail!("Control socket {} is already in use by another server", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                Err(e) => {
+                    return Err(e).context(format!("Failed to check control socket {}", path.display()));
+                }
+            }
+            std::fs::remove_file(path)
+                .context(format!("Failed to remove stale control socket {}", path.display()))?;
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)
+            .context(format!("Failed to bind control socket {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .context(format!("Failed to restrict control socket {}", path.display()))?;
+        // Connections made before the chmod are still checked against the owner
+        let owner = std::fs::metadata(path)
+            .context(format!("Failed to read control socket {}", path.display()))?
+            .uid();
+        log::info!("🔌 Serving control commands on {}", path.display());
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("Failed to accept control socket connection: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                match stream.peer_cred() {
+                    Ok(cred) if cred.uid() == owner => {}
+                    Ok(cred) => {
+                        log::warn!("Refused control socket connection from uid {}", cred.uid());
+                        continue;
+                    }
+                    Err(e) => {
+                        log::warn!("Refused control socket connection with unknown peer: {}", e);
+                        continue;
+                    }
+                }
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.serve_control_connection(stream).await {
+                        log::warn!("Control socket connection failed: {:#}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn spawn_control_socket(&self, _path: &Path) -> Result<()> {
+        anyhow::bail!("--control-socket is only supported on Unix")
+    }
+
+    /// Sync the files of trigger = "git-commit" rules each time a commit changes them
+    ///
+    /// Polled from a thread of its own, which ends along with the server.
+    #[cfg(feature = "git")]
+    fn spawn_commit_watch(&self, project_root: &Path, rules: Vec<CompiledRule>) -> Result<()> {
+        use crate::git_trigger::{CommitWatch, POLL_INTERVAL};
+
+        let mut watch = CommitWatch::open(project_root)
+            .context("Rules with trigger = \"git-commit\" need the project in a git working tree")?;
+        log::info!(
+            "🔖 Syncing {} rules on commits to {}",
+            rules.len(),
+            watch.workdir().display()
+        );
+
+        // Commit paths come from the working tree's real location
+        let project_root = project_root.canonicalize().unwrap_or_else(|_| project_root.to_path_buf());
+        let registry = Arc::downgrade(&self.client_registry);
+        let storage = self.rsync_file_storage.clone();
+        let exec_metadata = self.execute_metadata.clone();
+        let sync_queue = self.sync_queue.clone();
+        let settings = self.settings.clone();
+        let manifest_cache = self.manifest_cache.clone();
+        let dedup = self.dedup;
+        let runtime_handle = tokio::runtime::Handle::current();
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let Some(registry) = registry.upgrade() else {
+                return;
+            };
+            let changes = match watch.poll() {
+                Ok(Some(changes)) => changes,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("Failed to check {} for commits: {:#}", watch.workdir().display(), e);
+                    continue;
+                }
+            };
+            log::info!("🔖 New commit {}: {} files changed", changes.commit, changes.changed.len());
+
+            for absolute in &changes.dirty {
+                log::warn!("⏭️  Not syncing {}: it has changed again since the commit", absolute.display());
+            }
+            for absolute in &changes.changed {
+                manifest_cache.lock().unwrap().invalidate(absolute);
+                let Ok(relative) = absolute.strip_prefix(&project_root) else {
+                    continue;
+                };
+                if Self::below_rule_depth(&rules, &project_root, absolute) {
+                    continue;
+                }
+                let targets = Self::rule_targets(&rules, &project_root, absolute, relative, dedup);
+                Self::queue_syncs(&sync_queue, &settings, &registry, &storage, &exec_metadata, absolute, targets);
+            }
+            for absolute in &changes.removed {
+                manifest_cache.lock().unwrap().invalidate(absolute);
+                let Some(msg) = absolute
+                    .strip_prefix(&project_root)
+                    .ok()
+                    .and_then(|relative| Self::delete_message(&settings, &rules, &project_root, absolute, relative))
+                else {
+                    continue;
+                };
+                let registry = registry.clone();
+                runtime_handle.spawn(async move {
+                    if let Err(e) = registry.lock().await.broadcast(&msg) {
+                        log::error!("Failed to send delete: {:#}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(feature = "git"))]
+    fn spawn_commit_watch(&self, _project_root: &Path, _rules: Vec<CompiledRule>) -> Result<()> {
+        anyhow::bail!("Rules with trigger = \"git-commit\" need a launcher built with the `git` feature")
+    }
+
+    /// Answer the control commands a control socket connection sends
+    ///
+    /// An untagged command is answered and the connection closed, as before. Tagged
+    /// commands are answered concurrently, each response tagged with its request_id,
+    /// and the connection is read until the caller closes it.
+    #[cfg(unix)]
+    async fn serve_control_connection(&self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        let mut buffer = MessageBuffer::new();
+        let mut chunk = [0u8; 8192];
+        loop {
+            let command = loop {
+                if let Some(command) = buffer.try_parse_local_command()? {
+                    break command;
+                }
+                let n = reader.read(&mut chunk).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                buffer.append(&chunk[..n]);
+            };
+            log::debug!("Handling control socket command: {:?}", command);
+            // Answer in the format the command was sent in
+            let format = buffer.last_format();
+
+            let (tag, command) = Self::untag(command);
+            if tag.is_none() {
+                self.answer_socket_command(command, None, &writer, format).await?;
+                writer.lock().await.shutdown().await?;
+                return Ok(());
+            }
+            let server = self.clone();
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.answer_socket_command(command, tag, &writer, format).await {
+                    log::debug!("Control socket caller went away: {:#}", e);
+                }
+            });
+        }
+    }
+
+    /// Answer one control socket command, tagging each response with `tag` if set
+    #[cfg(unix)]
+    async fn answer_socket_command(
+        &self,
+        command: LocalCommand,
+        tag: Option<String>,
+        writer: &Mutex<tokio::net::unix::OwnedWriteHalf>,
+        format: WireFormat,
+    ) -> Result<()> {
+        if let LocalCommand::Execute {
+            target,
+            binary,
+            args,
+            session_id,
+            stream: true,
+            working_dir,
+            env,
+        } = command
+        {
+            log::info!("Streaming execute request: {} on {}", binary, target);
+            let (request_id, exec_msg) = Self::execute_message(binary, args, working_dir, env);
+            let started =
+                Self::start_streaming_exec(&self.client_registry, &target, session_id, &request_id, &exec_msg).await;
+            let mut relay = match started {
+                Ok(relay) => relay,
+                Err(response) => {
+                    let response = Self::tagged(&tag, response);
+                    return Self::write_response(&mut *writer.lock().await, &response, format).await;
+                }
+            };
+            loop {
+                let (response, last) = Self::next_exec_response(&mut relay, &target).await;
+                // Dropping the relay on a failed write tells the registry to stop forwarding
+                let response = Self::tagged(&tag, response);
+                Self::write_response(&mut *writer.lock().await, &response, format).await?;
+                if last {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+
+        let response = Self::handle_local_command(
+            command,
+            self.client_registry.clone(),
+            self.rsync_file_storage.clone(),
+            self.execute_metadata.clone(),
+            self.file_watcher.clone(),
+            self.watch_mode,
+            self.dedup,
+            self.settings.clone(),
+            self.manifest_cache.clone(),
+            self.start_time.clone(),
+            self.sync_queue.clone(),
+            self.draining.clone(),
+        )
+        .await;
+        let response = Self::tagged(&tag, response);
+        Self::write_response(&mut *writer.lock().await, &response, format).await
+    }
+
+    #[cfg(unix)]
+    async fn write_response(
+        writer: &mut tokio::net::unix::OwnedWriteHalf,
+        response: &LocalResponse,
+        format: WireFormat,
+    ) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut full_message = Vec::new();
+        response.write_framed_as(&mut full_message, format)?;
+        writer.write_all(&full_message).await?;
+        Ok(())
+    }
+
+    /// Split a Tagged command into its request_id and the command it carries
+    fn untag(command: LocalCommand) -> (Option<String>, LocalCommand) {
+        match command {
+            LocalCommand::Tagged { request_id, command } => (Some(request_id), *command),
+            command => (None, command),
+        }
+    }
+
+    /// Wrap `response` as Tagged when it answers a tagged command
+    fn tagged(tag: &Option<String>, response: LocalResponse) -> LocalResponse {
+        match tag {
+            Some(request_id) => LocalResponse::Tagged {
+                request_id: request_id.clone(),
+                response: Box::new(response),
+            },
+            None => response,
+        }
+    }
+
+    /// The Execute for an exec control command, with a new request_id
+    ///
+    /// `VAR=value` args become environment too, but `env` wins over them.
+    fn execute_message(
+        binary: String,
+        args: Vec<String>,
+        working_dir: Option<String>,
+        env: HashMap<String, String>,
+    ) -> (String, ServerMessage) {
+        let (mut merged_env, args) = Self::split_env_args(args);
+        merged_env.extend(env);
+        let request_id = format!("exec-{}", uuid::Uuid::new_v4());
+        let exec_msg = ServerMessage::Execute {
+            request_id: request_id.clone(),
+            binary,
+            args,
+            working_dir,
+            env: merged_env,
+            self_update: false,
+        };
+        (request_id, exec_msg)
+    }
+
+    /// Send an Execute whose output the client streams back through the returned relay
+    async fn start_streaming_exec(
+        registry: &Arc<Mutex<ClientRegistry>>,
+        target: &str,
+        session_id: Option<String>,
+        request_id: &str,
+        exec_msg: &ServerMessage,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<LocalResponse>, LocalResponse> {
+        registry
+            .lock()
+            .await
+            .send_streaming_exec(target, session_id.as_deref(), request_id, exec_msg)
+            .map_err(|e| LocalResponse::Error {
+                message: format!("Failed to send execute command: {:#}", e),
+            })
+    }
+
+    /// The next response relayed for a streaming Execute, or an Error once the client
+    /// has disconnected, and whether it is the last
+    async fn next_exec_response(
+        relay: &mut tokio::sync::mpsc::UnboundedReceiver<LocalResponse>,
+        target: &str,
+    ) -> (LocalResponse, bool) {
+        let response = relay.recv().await.unwrap_or_else(|| LocalResponse::Error {
+            message: format!("{} disconnected before the command finished", target),
+        });
+        let last = !matches!(response, LocalResponse::ExecOutput { .. });
+        (response, last)
+    }
+
+    /// Broadcast a Keepalive every `interval`; clients answer with a Pong, which
+    /// refreshes their last heartbeat without the full Status a Ping asks for
+    fn spawn_keepalive(registry: Arc<Mutex<ClientRegistry>>, interval: std::time::Duration) {
+        log::info!("💓 Sending keepalives every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            let mut nonce: u64 = 0;
+            loop {
+                ticker.tick().await;
+                nonce = nonce.wrapping_add(1);
+                let mut registry = registry.lock().await;
+                if registry.client_count() == 0 {
+                    continue;
+                }
+                if let Err(e) = registry.broadcast(&ServerMessage::Keepalive { nonce }) {
+                    log::warn!("Failed to send keepalive: {:#}", e);
+                }
+            }
+        });
+    }
+
+    /// Tell connected clients the server is going away and give them a moment to hear it
+    async fn notify_shutdown(registry: &Arc<Mutex<ClientRegistry>>) {
+        let client_count = registry.lock().await.client_count();
+        if client_count > 0 {
+            log::info!("Sending shutdown notification to {} clients", client_count);
+            let shutdown_msg = ServerMessage::Shutdown {
+                message: Some("Server is shutting down".to_string()),
+            };
+            let _ = registry.lock().await.broadcast(&shutdown_msg);
+
+            // Give clients a moment to receive the message
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Remove the pid file and control socket, if any, and exit the process
+    fn exit_server(settings: &ServerSettings) -> ! {
+        log::info!("Server shutting down");
+        if let Some(pid_file) = PID_FILE.get()
+            && let Err(e) = std::fs::remove_file(pid_file)
+        {
+            log::warn!("Failed to remove pid file {}: {}", pid_file.display(), e);
+        }
+        if let Some(socket) = &settings.control_socket
+            && let Err(e) = std::fs::remove_file(socket)
+        {
+            log::warn!("Failed to remove control socket {}: {}", socket.display(), e);
+        }
+        std::process::exit(0);
+    }
 
-        log::info!("Generated ephemeral Ed25519 host key");
+    /// What `list` and `status` report about each connected client
+    fn client_infos(reg: &ClientRegistry) -> Vec<halfremembered_protocol::ClientInfo> {
+        reg.list_clients()
+            .iter()
+            .map(|c| halfremembered_protocol::ClientInfo {
+                hostname: c.hostname.clone(),
+                platform: c.platform.clone(),
+                session_id: c.session_id.clone(),
+                connected_at: c.connected_at.elapsed().as_secs(),
+                last_heartbeat: c.last_heartbeat.elapsed().as_secs(),
+                auth_key_label: c.auth_key_label.clone(),
+                last_error: reg.last_error(&c.hostname),
+                last_transfer: c.last_transfer.clone(),
+                heartbeat_gaps: c.heartbeat_gaps,
+                send_queue_depth: c.control_writer.queued() as u64,
+                send_queue_peak: c.control_writer.peak_queued() as u64,
+                send_queue_capacity: c.control_writer.depth() as u64,
+                transfer_rates: c.transfer_rates.clone(),
+            })
+            .collect()
+    }
 
-        let config = russh::server::Config {
-            inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
-            auth_rejection_time: std::time::Duration::from_secs(3),
-            auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-            keys: vec![host_key],
-            ..Default::default()
+    /// Ping a client and wait for the state it replies with
+    async fn query_client_state(
+        registry: &Arc<Mutex<ClientRegistry>>,
+        hostname: &str,
+        session_id: Option<&str>,
+    ) -> Result<ClientState, LocalResponse> {
+        // The client answers a Ping with its full state under the same request_id
+        let request_id = format!("status-{}", uuid::Uuid::new_v4());
+        let reply = {
+            let mut reg = registry.lock().await;
+            let reply = reg.expect_status(&request_id);
+            let ping_msg = ServerMessage::Ping {
+                request_id: request_id.clone(),
+            };
+            if let Err(e) = reg.send_to_client(hostname, session_id, &ping_msg) {
+                reg.cancel_status(&request_id);
+                return Err(LocalResponse::Error {
+                    message: format!("Failed to query {}: {:#}", hostname, e),
+                });
+            }
+            reply
         };
 
-        log::info!("Starting SSH server on 0.0.0.0:{}", port);
+        match tokio::time::timeout(CLIENT_STATUS_TIMEOUT, reply).await {
+            Ok(Ok(state)) => Ok(state),
+            Ok(Err(_)) => Err(LocalResponse::Error {
+                message: format!("{} disconnected before replying", hostname),
+            }),
+            Err(_) => {
+                registry.lock().await.cancel_status(&request_id);
+                Err(LocalResponse::Error {
+                    message: format!(
+                        "{} did not reply within {}s",
+                        hostname,
+                        CLIENT_STATUS_TIMEOUT.as_secs()
+                    ),
+                })
+            }
+        }
+    }
 
-        server
-            .run_on_address(Arc::new(config), ("0.0.0.0", port))
-            .await?;
+    /// Ask each targeted client to hash its copy of `relative_path` and collect the answers
+    async fn verify_on_clients(
+        registry: &Arc<Mutex<ClientRegistry>>,
+        target: Option<&str>,
+        relative_path: &str,
+        expected_checksum: &str,
+        checksum_algo: ChecksumAlgo,
+    ) -> LocalResponse {
+        let mut results = Vec::new();
+        let mut replies = Vec::new();
+        {
+            let mut reg = registry.lock().await;
+            let clients: Vec<_> = reg
+                .list_clients()
+                .into_iter()
+                .filter(|c| target.is_none_or(|target| c.hostname == target))
+                .collect();
+            if clients.is_empty() {
+                return LocalResponse::Error {
+                    message: match target {
+                        Some(target) => format!("Client not found: {}", target),
+                        None => "No clients connected".to_string(),
+                    },
+                };
+            }
 
-        Ok(())
+            for client in clients {
+                let request_id = format!("verify-{}", uuid::Uuid::new_v4());
+                let reply = reg.expect_verify(&request_id);
+                let verify_msg = ServerMessage::VerifyFile {
+                    request_id: request_id.clone(),
+                    relative_path: relative_path.to_string(),
+                    expected_checksum: expected_checksum.to_string(),
+                    checksum_algo,
+                };
+                match reg.send_to_client(&client.hostname, Some(&client.session_id), &verify_msg) {
+                    Ok(()) => replies.push((client.hostname, request_id, reply)),
+                    Err(e) => {
+                        reg.cancel_verify(&request_id);
+                        results.push(ClientVerifyResult {
+                            hostname: client.hostname,
+                            matches: false,
+                            actual_checksum: None,
+                            error: Some(format!("{:#}", e)),
+                        });
+                    }
+                }
+            }
+        }
+
+        // Clients hash in parallel, so one deadline covers them all
+        let deadline = tokio::time::Instant::now() + VERIFY_TIMEOUT;
+        for (hostname, request_id, reply) in replies {
+            let error = match tokio::time::timeout_at(deadline, reply).await {
+                Ok(Ok(result)) => {
+                    results.push(result);
+                    continue;
+                }
+                Ok(Err(_)) => "Disconnected before replying".to_string(),
+                Err(_) => {
+                    registry.lock().await.cancel_verify(&request_id);
+                    format!("No reply within {}s", VERIFY_TIMEOUT.as_secs())
+                }
+            };
+            results.push(ClientVerifyResult {
+                hostname,
+                matches: false,
+                actual_checksum: None,
+                error: Some(error),
+            });
+        }
+
+        LocalResponse::VerifyReport {
+            path: relative_path.to_string(),
+            results,
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_local_command(
         command: LocalCommand,
         registry: Arc<Mutex<ClientRegistry>>,
         rsync_storage: RsyncFileStorage,
+        exec_metadata: ExecuteMetadataStorage,
         file_watcher: FileWatcherRef,
+        watch_mode: WatchMode,
+        dedup: bool,
+        settings: Arc<ServerSettings>,
+        manifest_cache: ManifestCacheRef,
         start_time: Arc<Instant>,
-        rsync_semaphore: Arc<tokio::sync::Semaphore>,
+        sync_queue: Arc<SyncQueue>,
+        draining: Arc<AtomicBool>,
     ) -> LocalResponse {
+        if draining.load(Ordering::SeqCst)
+            && matches!(
+                command,
+                LocalCommand::SyncFile { .. }
+                    | LocalCommand::SyncTree { .. }
+                    | LocalCommand::WatchDirectory { .. }
+                    | LocalCommand::ResumeWatch { .. }
+                    | LocalCommand::SelfUpdate { .. }
+            )
+        {
+            return LocalResponse::Error {
+                message: "Server is draining and not accepting new work".to_string(),
+            };
+        }
+
         match command {
-            LocalCommand::Ping { target } => {
+            LocalCommand::Tagged { request_id, command } => {
+                let response = Box::pin(Self::handle_local_command(
+                    *command,
+                    registry,
+                    rsync_storage,
+                    exec_metadata,
+                    file_watcher,
+                    watch_mode,
+                    dedup,
+                    settings,
+                    manifest_cache,
+                    start_time,
+                    sync_queue,
+                    draining,
+                ))
+                .await;
+                Self::tagged(&Some(request_id), response)
+            }
+            LocalCommand::Ping { target, session_id } => {
                 log::info!("Ping request for client: {}", target);
 
                 let request_id = format!("ping-{}", uuid::Uuid::new_v4());
@@ -357,8 +1547,7 @@ impl SshServer {
                 let result = registry
                     .lock()
                     .await
-                    .send_to_client(&target, &ping_msg)
-                    .await;
+                    .send_to_client(&target, session_id.as_deref(), &ping_msg);
 
                 match result {
                     Ok(_) => LocalResponse::Success {
@@ -370,84 +1559,264 @@ impl SshServer {
                 }
             }
 
+            LocalCommand::ClientStatus { hostname, session_id } => {
+                log::info!("Client status request for: {}", hostname);
+
+                match Self::query_client_state(&registry, &hostname, session_id.as_deref()).await {
+                    Ok(state) => LocalResponse::ClientState { hostname, state },
+                    Err(response) => response,
+                }
+            }
+
+            LocalCommand::ListProcesses { target, session_id } => {
+                log::info!("List processes request for: {}", target);
+
+                match Self::query_client_state(&registry, &target, session_id.as_deref()).await {
+                    Ok(state) => LocalResponse::ProcessList {
+                        hostname: target,
+                        processes: state.exec_processes,
+                    },
+                    Err(response) => response,
+                }
+            }
+
+            LocalCommand::KillProcess {
+                target,
+                session_id,
+                request_id,
+            } => {
+                log::info!("Kill request for {} on {}", request_id, target);
+
+                // Checking first gives a useful error for a finished or mistyped request_id
+                let state = match Self::query_client_state(&registry, &target, session_id.as_deref()).await {
+                    Ok(state) => state,
+                    Err(response) => return response,
+                };
+                let Some(process) = state.exec_processes.into_iter().find(|p| p.request_id == request_id) else {
+                    return LocalResponse::Error {
+                        message: format!("{} is not running a process for {}", target, request_id),
+                    };
+                };
+
+                let kill_msg = ServerMessage::Kill {
+                    request_id: request_id.clone(),
+                };
+                let result = registry
+                    .lock()
+                    .await
+                    .send_to_client(&target, session_id.as_deref(), &kill_msg);
+
+                match result {
+                    Ok(_) => LocalResponse::Success {
+                        message: match process.pid {
+                            Some(pid) => format!("Kill sent to {} for {} (pid {})", target, process.binary, pid),
+                            None => format!("Kill sent to {} for {}", target, process.binary),
+                        },
+                    },
+                    Err(e) => LocalResponse::Error {
+                        message: format!("Failed to send kill: {:#}", e),
+                    },
+                }
+            }
+
+            LocalCommand::VerifyFile {
+                target,
+                relative_path,
+                expected_checksum,
+                checksum_algo,
+            } => {
+                log::info!(
+                    "Verify request for {} on {}",
+                    relative_path,
+                    target.as_deref().unwrap_or("all clients")
+                );
+                Self::verify_on_clients(
+                    &registry,
+                    target.as_deref(),
+                    &relative_path,
+                    &expected_checksum,
+                    checksum_algo,
+                )
+                .await
+            }
+
             LocalCommand::ListClients => {
                 log::info!("List clients request");
 
-                let client_infos: Vec<halfremembered_protocol::ClientInfo> = {
-                    let reg = registry.lock().await;
-                    let clients = reg.list_clients();
-                    clients
-                        .iter()
-                        .map(|c| halfremembered_protocol::ClientInfo {
-                            hostname: c.hostname.clone(),
-                            platform: c.platform.clone(),
-                            session_id: c.session_id.clone(),
-                            connected_at: c.connected_at.elapsed().as_secs(),
-                            last_heartbeat: c.last_heartbeat.elapsed().as_secs(),
-                        })
-                        .collect()
-                };
+                let client_infos = Self::client_infos(&*registry.lock().await);
 
                 LocalResponse::ClientList {
                     clients: client_infos,
                 }
             }
 
-            LocalCommand::SyncFile { file, destination } => {
+            LocalCommand::SyncFile {
+                file,
+                destination,
+                allow_partial,
+                force,
+            } => {
                 log::info!("Sync file request: {} -> {}", file, destination);
 
-                match Self::sync_file_to_clients(&file, &destination, registry, rsync_storage).await {
-                    Ok(count) => LocalResponse::Success {
-                        message: format!("Synced {} to {} clients", file, count),
-                    },
+                let modes = FileModes { force, ..FileModes::default() };
+                match Self::sync_file_to_clients(&file, &destination, modes, &settings, registry, rsync_storage).await {
+                    Ok(deliveries) => {
+                        let (delivered, failed) = Self::split_deliveries(deliveries);
+                        // Strict mode keeps the old zero-client behaviour: nothing failed, so it succeeds
+                        let accepted = if allow_partial {
+                            !delivered.is_empty()
+                        } else {
+                            failed.is_empty()
+                        };
+                        LocalResponse::SyncReport {
+                            file,
+                            delivered,
+                            failed,
+                            accepted,
+                        }
+                    }
                     Err(e) => LocalResponse::Error {
                         message: format!("Failed to sync file: {:#}", e),
                     },
                 }
             }
 
+            LocalCommand::SyncTree {
+                root,
+                destination,
+                include_patterns,
+                exclude_patterns,
+                allow_partial,
+                force,
+            } => {
+                log::info!("Sync tree request: {} -> {}", root, destination);
+
+                if let Err(e) = settings.check_destination(&destination) {
+                    return LocalResponse::Error {
+                        message: format!("{:#}", e),
+                    };
+                }
+
+                let root_path = match std::fs::canonicalize(&root) {
+                    Ok(path) if path.is_dir() => path,
+                    Ok(_) => {
+                        return LocalResponse::Error {
+                            message: format!("Not a directory: {}", root),
+                        };
+                    }
+                    Err(e) => {
+                        return LocalResponse::Error {
+                            message: format!("Failed to resolve {}: {}", root, e),
+                        };
+                    }
+                };
+                let config = match WatchConfig::new(
+                    root_path.clone(),
+                    true,
+                    include_patterns,
+                    exclude_patterns,
+                    false,
+                ) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        return LocalResponse::Error {
+                            message: format!("{:#}", e),
+                        };
+                    }
+                };
+
+                let matched = config.matching_files(&root_path);
+                let empty_dirs = if settings.sync_empty_dirs {
+                    config.empty_dirs(&root_path)
+                } else {
+                    Vec::new()
+                };
+                if matched.is_empty() && empty_dirs.is_empty() {
+                    return LocalResponse::Error {
+                        message: format!("No files to sync under {}", root),
+                    };
+                }
+                log::info!("Syncing {} files under {}", matched.len(), root_path.display());
+
+                if !empty_dirs.is_empty() {
+                    log::info!("Creating {} empty directories under {}", empty_dirs.len(), destination);
+                    let dirs = empty_dirs
+                        .into_iter()
+                        .map(|(relative, absolute)| {
+                            let dir_destination = Path::new(&destination).join(&relative).to_string_lossy().to_string();
+                            (dir_destination, Self::dir_sync_mode(&absolute, FileModes::default()))
+                        })
+                        .collect();
+                    Self::create_dirs_on_clients(&settings, &registry, dirs).await;
+                }
+
+                let mut files = Vec::new();
+                let mut accepted = true;
+                for (relative, absolute) in matched {
+                    let file_destination = Path::new(&destination).join(&relative).to_string_lossy().to_string();
+                    let result = match Self::sync_file_to_clients(
+                        &absolute.to_string_lossy(),
+                        &file_destination,
+                        FileModes { force, ..FileModes::default() },
+                        &settings,
+                        registry.clone(),
+                        rsync_storage.clone(),
+                    )
+                    .await
+                    {
+                        Ok(deliveries) => {
+                            let (delivered, failed) = Self::split_deliveries(deliveries);
+                            FileSyncResult {
+                                file: file_destination,
+                                delivered,
+                                failed,
+                                error: None,
+                            }
+                        }
+                        Err(e) => FileSyncResult {
+                            file: file_destination,
+                            delivered: Vec::new(),
+                            failed: Vec::new(),
+                            error: Some(format!("{:#}", e)),
+                        },
+                    };
+                    accepted &= result.error.is_none()
+                        && if allow_partial {
+                            !result.delivered.is_empty()
+                        } else {
+                            result.failed.is_empty()
+                        };
+                    files.push(result);
+                }
+
+                LocalResponse::SyncTreeReport {
+                    root,
+                    files,
+                    accepted,
+                }
+            }
+
             LocalCommand::Execute {
                 target,
                 binary,
                 args,
+                session_id,
+                working_dir,
+                env,
+                ..
             } => {
                 log::info!("Execute request: {} on {}", binary, target);
 
-                // Parse optional env vars from args (format: VAR=value)
-                // This allows CLI usage like: execute client game.exe RUST_LOG=debug --windowed
-                let mut env = std::collections::HashMap::new();
-                let mut clean_args = Vec::new();
-                for arg in args {
-                    if let Some((key, value)) = arg.split_once('=') {
-                        // Check if this looks like an env var (uppercase letters/underscore)
-                        if key.chars().all(|c| c.is_uppercase() || c.is_numeric() || c == '_')
-                            && key.chars().next().map_or(false, |c| c.is_alphabetic()) {
-                            env.insert(key.to_string(), value.to_string());
-                            log::debug!("Parsed env var: {}={}", key, value);
-                            continue;
-                        }
-                    }
-                    clean_args.push(arg);
-                }
-
-                let request_id = format!("exec-{}", uuid::Uuid::new_v4());
-                let exec_msg = ServerMessage::Execute {
-                    request_id: request_id.clone(),
-                    binary,
-                    args: clean_args,
-                    working_dir: None,
-                    env,
-                };
+                let (request_id, exec_msg) = Self::execute_message(binary, args, working_dir, env);
 
                 let result = registry
                     .lock()
                     .await
-                    .send_to_client(&target, &exec_msg)
-                    .await;
+                    .send_to_client(&target, session_id.as_deref(), &exec_msg);
 
                 match result {
                     Ok(_) => LocalResponse::Success {
-                        message: format!("Execute command sent to {}", target),
+                        message: format!("Execute command sent to {} (request: {})", target, request_id),
                     },
                     Err(e) => LocalResponse::Error {
                         message: format!("Failed to send execute command: {:#}", e),
@@ -457,27 +1826,50 @@ impl SshServer {
 
             LocalCommand::Shutdown => {
                 log::info!("Shutdown request received");
+                Self::notify_shutdown(&registry).await;
+                Self::exit_server(&settings)
+            }
 
-                // Send shutdown message to all connected clients
-                let client_count = {
-                    let reg = registry.lock().await;
-                    reg.client_count()
-                };
-
-                if client_count > 0 {
-                    log::info!("Sending shutdown notification to {} clients", client_count);
-                    let shutdown_msg = ServerMessage::Shutdown {
-                        message: Some("Server is shutting down".to_string()),
+            LocalCommand::Drain => {
+                if draining.swap(true, Ordering::SeqCst) {
+                    return LocalResponse::Success {
+                        message: "Server is already draining".to_string(),
                     };
-                    let _ = registry.lock().await.broadcast(&shutdown_msg).await;
+                }
 
-                    // Give clients a moment to receive the message
-                    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                // Watches would keep starting syncs; the server is going away anyway
+                if file_watcher.lock().await.take().is_some() {
+                    log::info!("🛑 Stopped file watches for drain");
                 }
 
-                // Exit the process
-                log::info!("Server shutting down");
-                std::process::exit(0);
+                let in_flight = rsync_storage.lock().await.len();
+                let queued = sync_queue.waiting() + sync_queue.running();
+                log::info!(
+                    "🚰 Draining: {} transfers in flight and {} syncs queued, refusing new work",
+                    in_flight,
+                    queued
+                );
+
+                // Check after a pause, so this response goes out before an idle server exits.
+                // Queued syncs haven't stored their transfer yet, so wait for the queue too
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+                        if sync_queue.is_idle() && rsync_storage.lock().await.is_empty() {
+                            break;
+                        }
+                    }
+                    log::info!("✅ Drain complete, no transfers in flight");
+                    Self::notify_shutdown(&registry).await;
+                    Self::exit_server(&settings);
+                });
+
+                LocalResponse::Success {
+                    message: format!(
+                        "Draining: {} transfers in flight and {} syncs queued; the server shuts down when they finish",
+                        in_flight, queued
+                    ),
+                }
             }
 
             LocalCommand::Status => {
@@ -490,19 +1882,13 @@ impl SshServer {
 
                 let uptime = start_time.elapsed().as_secs();
 
-                let client_infos: Vec<halfremembered_protocol::ClientInfo> = {
+                let (client_infos, transfer_rates, error_counts) = {
                     let reg = registry.lock().await;
-                    let clients = reg.list_clients();
-                    clients
-                        .iter()
-                        .map(|c| halfremembered_protocol::ClientInfo {
-                            hostname: c.hostname.clone(),
-                            platform: c.platform.clone(),
-                            session_id: c.session_id.clone(),
-                            connected_at: c.connected_at.elapsed().as_secs(),
-                            last_heartbeat: c.last_heartbeat.elapsed().as_secs(),
-                        })
-                        .collect()
+                    (
+                        Self::client_infos(&reg),
+                        reg.transfer_rates().clone(),
+                        reg.error_counters().snapshot(),
+                    )
                 };
 
                 LocalResponse::Status {
@@ -510,6 +1896,8 @@ impl SshServer {
                     version: env!("CARGO_PKG_VERSION").to_string(),
                     uptime,
                     clients: client_infos,
+                    transfer_rates,
+                    error_counts,
                 }
             }
 
@@ -518,18 +1906,36 @@ impl SshServer {
                 recursive,
                 include_patterns,
                 exclude_patterns,
+                relative_to,
+                case_insensitive,
+                verify_events,
+                destination,
+                base,
+                settle_ms,
+                priority,
             } => {
                 log::info!("Watch directory request: {} (recursive: {})", path, recursive);
                 log::debug!("Include patterns: {:?}", include_patterns);
                 log::debug!("Exclude patterns: {:?}", exclude_patterns);
 
+                if let Some(destination) = &destination
+                    && let Err(e) = settings.check_destination(destination)
+                {
+                    return LocalResponse::Error {
+                        message: format!("{:#}", e),
+                    };
+                }
+
                 let mut watcher_lock = file_watcher.lock().await;
 
                 // Create FileWatcher lazily on first watch
                 if watcher_lock.is_none() {
                     let registry_clone = registry.clone();
                     let storage_clone = rsync_storage.clone();
-                    let semaphore_clone = rsync_semaphore.clone();
+                    let queue_clone = sync_queue.clone();
+                    let watcher_clone = file_watcher.clone();
+                    let settings_clone = settings.clone();
+                    let manifest_cache = manifest_cache.clone();
 
                     // Get a handle to the current tokio runtime
                     let runtime_handle = tokio::runtime::Handle::current();
@@ -537,38 +1943,59 @@ impl SshServer {
                     // Create callback that syncs files when they change
                     // FileWatcher already verified the file actually changed (checksum-based)
                     let callback =
-                        move |_watch_root: PathBuf, relative: PathBuf, absolute: PathBuf| {
+                        move |watch_root: PathBuf, relative: PathBuf, absolute: PathBuf| {
+                            manifest_cache.lock().unwrap().invalidate(&absolute);
                             let registry = registry_clone.clone();
                             let storage = storage_clone.clone();
-                            let semaphore = semaphore_clone.clone();
+                            let sync_queue = queue_clone.clone();
+                            let watcher = watcher_clone.clone();
+                            let settings = settings_clone.clone();
                             let relative_str = relative.to_string_lossy().to_string();
 
                             // Spawn on the tokio runtime from the std::thread callback
                             runtime_handle.spawn(async move {
-                                let available = semaphore.available_permits();
-                                log::info!("🔄 Syncing {} to clients (semaphore: {} available)", absolute.display(), available);
-
-                                // Acquire semaphore to limit concurrent syncs
-                                let _permit = semaphore.acquire().await.unwrap();
-                                log::debug!("Acquired semaphore permit for {}", absolute.display());
-
-                                if let Err(e) = Self::sync_file_to_clients(
-                                    &absolute.to_string_lossy(),
-                                    &relative_str,
-                                    registry,
-                                    storage,
-                                )
-                                .await
-                                {
-                                    log::error!("Failed to sync changed file: {:#}", e);
+                                // Watches added with a destination sync under it, once per
+                                // sibling watch on the directory that matches
+                                let targets = watcher
+                                    .lock()
+                                    .await
+                                    .as_ref()
+                                    .map(|watcher| watcher.targets_for(&watch_root, &relative))
+                                    .unwrap_or_default();
+
+                                for (destination, priority) in targets {
+                                    let destination_path = destination
+                                        .map(|path| path.to_string_lossy().to_string())
+                                        .unwrap_or_else(|| relative_str.clone());
+                                    let absolute = absolute.clone();
+                                    let registry = registry.clone();
+                                    let storage = storage.clone();
+                                    let settings = settings.clone();
+                                    sync_queue.push(priority, destination_path.clone(), async move {
+                                        log::info!("🔄 Syncing {} to clients", absolute.display());
+                                        let modes = FileModes { priority, ..FileModes::default() };
+                                        if let Err(e) = Self::sync_file_to_clients(
+                                            &absolute.to_string_lossy(),
+                                            &destination_path,
+                                            modes,
+                                            &settings,
+                                            registry,
+                                            storage,
+                                        )
+                                        .await
+                                        {
+                                            log::error!("Failed to sync changed file: {:#}", e);
+                                        }
+                                    });
                                 }
-
-                                log::debug!("Released semaphore permit for {}", absolute.display());
                             });
                         };
 
-                    match FileWatcher::new(callback) {
+                    match FileWatcher::new(watch_mode, settings.watch_workers, callback) {
                         Ok(watcher) => {
+                            let watcher = watcher
+                                .with_dedup(dedup)
+                                .with_checksum_algo(settings.checksum_algo);
                             log::info!("Created FileWatcher");
                             *watcher_lock = Some(watcher);
                         }
@@ -581,15 +2008,60 @@ impl SshServer {
                 }
 
                 let path_buf = PathBuf::from(&path);
-                let result = watcher_lock.as_mut().unwrap().add_watch(
+                // Joining a watched directory as a sibling leaves its events as they were
+                let joined = watcher_lock.as_ref().unwrap().is_watched(&path_buf);
+                let options = WatchOptions {
+                    destination,
+                    base: base.map(PathBuf::from),
+                    settle: std::time::Duration::from_millis(settle_ms),
+                    priority,
+                    ..WatchOptions::default()
+                };
+                let result = watcher_lock.as_mut().unwrap().add_watch_with(
                     path_buf.clone(),
                     recursive,
                     include_patterns,
                     exclude_patterns,
+                    relative_to.map(PathBuf::from),
+                    case_insensitive,
+                    options,
                 );
 
                 match result {
                     Ok(_) => {
+                        // A poller always sees changes eventually, just not within the probe window
+                        let probe = if verify_events && !joined && watch_mode == WatchMode::Native {
+                            match watcher_lock.as_ref().unwrap().probe_events(&path_buf) {
+                                Ok(probe) => Some(probe),
+                                Err(e) => {
+                                    log::warn!("⚠️  Couldn't verify filesystem events for {}, keeping the watch: {:#}", path, e);
+                                    None
+                                }
+                            }
+                        } else {
+                            None
+                        };
+                        if let Some(probe) = probe {
+                            // Other commands and syncs needn't wait out the probe window
+                            drop(watcher_lock);
+                            let verified = probe.wait(WATCH_PROBE_TIMEOUT).await;
+                            watcher_lock = file_watcher.lock().await;
+                            if let Err(e) = verified {
+                                log::warn!("⚠️  Filesystem events not delivered for {}: {:#}", path, e);
+                                if let Err(e) = watcher_lock.as_mut().unwrap().remove_watch(&path_buf) {
+                                    log::warn!("Failed to remove unverified watch {}: {:#}", path, e);
+                                }
+                                return LocalResponse::Error {
+                                    message: format!(
+                                        "Filesystem events don't seem to work for {} ({:#}); this is common on \
+                                         network mounts and container overlay filesystems. The watch was removed. \
+                                         Restart the server with --watch-mode poll, or retry with --no-verify-events to keep it anyway",
+                                        path, e
+                                    ),
+                                };
+                            }
+                        }
+
                         // After adding a watch, trigger a sync for the new files to all clients
                         // This is crucial for interactive watch commands after clients are connected
                         if let Ok(canonical_path) = path_buf.canonicalize() {
@@ -607,22 +2079,35 @@ impl SshServer {
                                         clients.len()
                                     );
 
+                                    let targets: Vec<(&PathBuf, PathBuf)> = files_to_sync
+                                        .iter()
+                                        .flat_map(|(watch_root, relative_path, absolute_path)| {
+                                            watcher_lock
+                                                .as_ref()
+                                                .unwrap()
+                                                .targets_for(watch_root, relative_path)
+                                                .into_iter()
+                                                .map(move |(destination, _)| {
+                                                    (absolute_path, destination.unwrap_or_else(|| relative_path.clone()))
+                                                })
+                                        })
+                                        .collect();
                                     for client in &clients {
-                                        for (_watch_root, relative_path, absolute_path) in
-                                            &files_to_sync
-                                        {
+                                        for (absolute_path, destination) in &targets {
                                             let registry_clone = registry.clone();
                                             let storage_clone = rsync_storage.clone();
+                                            let settings_clone = settings.clone();
                                             let client_clone = client.clone();
                                             let abs_path_str =
                                                 absolute_path.to_string_lossy().to_string();
-                                            let rel_path_str =
-                                                relative_path.to_string_lossy().to_string();
+                                            let rel_path_str = destination.to_string_lossy().to_string();
 
                                             tokio::spawn(async move {
                                                 if let Err(e) = SshServer::sync_file_to_client(
                                                     &abs_path_str,
                                                     &rel_path_str,
+                                                    FileModes::default(),
+                                                    &settings_clone,
                                                     &client_clone.hostname,
                                                     &client_clone.session_id,
                                                     registry_clone,
@@ -644,8 +2129,9 @@ impl SshServer {
                             }
                         }
 
-                        LocalResponse::Success {
+                        LocalResponse::Watching {
                             message: format!("Watching {}", path),
+                            joined,
                         }
                     }
                     Err(e) => LocalResponse::Error {
@@ -654,15 +2140,22 @@ impl SshServer {
                 }
             }
 
-            LocalCommand::UnwatchDirectory { path } => {
-                log::info!("Unwatch directory request: {}", path);
+            LocalCommand::UnwatchDirectory { path, destination } => {
+                log::info!("Unwatch directory request: {} (route: {:?})", path, destination);
 
                 let mut watcher_lock = file_watcher.lock().await;
 
                 if let Some(watcher) = watcher_lock.as_mut() {
-                    match watcher.remove_watch(Path::new(&path)) {
+                    let result = match &destination {
+                        Some(destination) => watcher.remove_route(Path::new(&path), destination),
+                        None => watcher.remove_watch(Path::new(&path)),
+                    };
+                    match result {
                         Ok(_) => LocalResponse::Success {
-                            message: format!("Stopped watching {}", path),
+                            message: match destination {
+                                Some(destination) => format!("Stopped syncing {} to {}", path, destination),
+                                None => format!("Stopped watching {}", path),
+                            },
                         },
                         Err(e) => LocalResponse::Error {
                             message: format!("Failed to remove watch: {:#}", e),
@@ -675,6 +2168,154 @@ impl SshServer {
                 }
             }
 
+            LocalCommand::PreviewUnwatch { path } => {
+                log::info!("Preview unwatch request: {}", path);
+
+                let watcher_lock = file_watcher.lock().await;
+
+                if let Some(watcher) = watcher_lock.as_ref() {
+                    let to_strings =
+                        |files: Vec<PathBuf>| files.iter().map(|f| f.to_string_lossy().to_string()).collect();
+                    match watcher.preview_remove(Path::new(&path)) {
+                        Ok((stops_syncing, still_covered)) => LocalResponse::UnwatchPreview {
+                            path,
+                            stops_syncing: to_strings(stops_syncing),
+                            still_covered: to_strings(still_covered),
+                        },
+                        Err(e) => LocalResponse::Error {
+                            message: format!("Failed to preview unwatch: {:#}", e),
+                        },
+                    }
+                } else {
+                    LocalResponse::Error {
+                        message: "No file watcher active".to_string(),
+                    }
+                }
+            }
+
+            LocalCommand::PauseWatch { path } => {
+                log::info!("Pause watch request: {}", path);
+
+                let mut watcher_lock = file_watcher.lock().await;
+
+                if let Some(watcher) = watcher_lock.as_mut() {
+                    match watcher.pause_watch(Path::new(&path)) {
+                        Ok(()) => LocalResponse::Success {
+                            message: format!("Paused watch on {}", path),
+                        },
+                        Err(e) => LocalResponse::Error {
+                            message: format!("Failed to pause watch: {:#}", e),
+                        },
+                    }
+                } else {
+                    LocalResponse::Error {
+                        message: "No file watcher active".to_string(),
+                    }
+                }
+            }
+
+            LocalCommand::ResumeWatch { path } => {
+                log::info!("Resume watch request: {}", path);
+
+                let mut watcher_lock = file_watcher.lock().await;
+
+                if let Some(watcher) = watcher_lock.as_mut() {
+                    match watcher.resume_watch(Path::new(&path)) {
+                        Ok(synced) => LocalResponse::Success {
+                            message: format!("Resumed watch on {} ({} file(s) changed while paused)", path, synced),
+                        },
+                        Err(e) => LocalResponse::Error {
+                            message: format!("Failed to resume watch: {:#}", e),
+                        },
+                    }
+                } else {
+                    LocalResponse::Error {
+                        message: "No file watcher active".to_string(),
+                    }
+                }
+            }
+
+            LocalCommand::UpdateWatch {
+                path,
+                destination,
+                include_patterns,
+                exclude_patterns,
+            } => {
+                log::info!("Update watch request: {}", path);
+
+                if let Some(destination) = &destination
+                    && let Err(e) = settings.check_destination(destination)
+                {
+                    return LocalResponse::Error {
+                        message: format!("{:#}", e),
+                    };
+                }
+
+                let mut watcher_lock = file_watcher.lock().await;
+
+                if let Some(watcher) = watcher_lock.as_mut() {
+                    match watcher.update_watch(Path::new(&path), destination, include_patterns, exclude_patterns) {
+                        Ok(watch) => LocalResponse::WatchList {
+                            watches: vec![watch],
+                        },
+                        Err(e) => LocalResponse::Error {
+                            message: format!("Failed to update watch: {:#}", e),
+                        },
+                    }
+                } else {
+                    LocalResponse::Error {
+                        message: "No file watcher active".to_string(),
+                    }
+                }
+            }
+
+            LocalCommand::Diagnostics => {
+                log::info!("Diagnostics request");
+
+                let (clients, transfer_rates) = {
+                    let reg = registry.lock().await;
+                    (Self::client_infos(&reg), reg.transfer_rates().clone())
+                };
+                let watches = file_watcher
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map(|watcher| watcher.list_watches())
+                    .unwrap_or_default();
+                let mut transfers: Vec<halfremembered_protocol::InFlightTransfer> = rsync_storage
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|(request_id, (path, _data, pending))| {
+                        let mut pending_sessions: Vec<String> = pending.iter().cloned().collect();
+                        pending_sessions.sort();
+                        halfremembered_protocol::InFlightTransfer {
+                            request_id: request_id.clone(),
+                            path: path.display().to_string(),
+                            pending_sessions,
+                        }
+                    })
+                    .collect();
+                transfers.sort_by(|a, b| a.request_id.cmp(&b.request_id));
+
+                LocalResponse::Diagnostics {
+                    report: halfremembered_protocol::DiagnosticsReport {
+                        hostname: hostname::get()
+                            .unwrap_or_else(|_| "unknown".into())
+                            .to_string_lossy()
+                            .to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        uptime: start_time.elapsed().as_secs(),
+                        draining: draining.load(Ordering::SeqCst),
+                        clients,
+                        transfer_rates,
+                        watches,
+                        transfers,
+                        logs: crate::log_buffer::recent(),
+                    },
+                }
+            }
+
             LocalCommand::ListWatches => {
                 log::info!("List watches request");
 
@@ -688,37 +2329,109 @@ impl SshServer {
                     LocalResponse::WatchList { watches: vec![] }
                 }
             }
+
+            LocalCommand::SelfUpdate { file, staging_path } => {
+                log::info!("Self-update request: {} -> {} on all clients", file, staging_path);
+
+                // The launcher binary was just uploaded over SFTP, typically to a ~/ path
+                let source = crate::client_daemon::expand_tilde(&file);
+
+                // Clients run the staged binary's `apply-update` subcommand, which swaps it
+                // over the running daemon's executable. The daemon re-execs itself only after
+                // that exits successfully and its exit status has been sent.
+                let exec_config = crate::config::ExecuteConfig {
+                    command: staging_path.clone(),
+                    args: vec![crate::client_daemon::APPLY_UPDATE_SUBCOMMAND.to_string()],
+                    env: HashMap::new(),
+                    working_dir: None,
+                    // The staged copy may match from an earlier push that never applied
+                    run_on: crate::config::RunOn::Always,
+                    self_update: true,
+                };
+
+                // The staged binary must be executable even when this server's platform
+                // has no mode bits to send
+                // Nobody edits the staged copy, so it is always replaced
+                let modes = FileModes {
+                    file: Some(0o755),
+                    dir: None,
+                    atomic_replace: false,
+                    priority: 0,
+                    force: true,
+                };
+
+                match Self::sync_file_to_clients_with_exec(
+                    &source.to_string_lossy(),
+                    &staging_path,
+                    modes,
+                    &settings,
+                    registry,
+                    rsync_storage,
+                    exec_metadata,
+                    Some(exec_config),
+                )
+                .await
+                {
+                    Ok(deliveries) => {
+                        let (delivered, failed) = Self::split_deliveries(deliveries);
+                        if delivered.is_empty() && failed.is_empty() {
+                            LocalResponse::Error {
+                                message: "No clients connected to update".to_string(),
+                            }
+                        } else {
+                            LocalResponse::SyncReport {
+                                file: staging_path,
+                                accepted: !delivered.is_empty(),
+                                delivered,
+                                failed,
+                            }
+                        }
+                    }
+                    Err(e) => LocalResponse::Error {
+                        message: format!("Failed to stage update: {:#}", e),
+                    },
+                }
+            }
         }
     }
 
     async fn sync_file_to_clients(
         file_path: &str,
         destination: &str,
+        modes: FileModes,
+        settings: &ServerSettings,
         registry: Arc<Mutex<ClientRegistry>>,
         rsync_storage: RsyncFileStorage,
-    ) -> Result<usize> {
-        Self::sync_file_to_clients_impl(file_path, destination, registry, rsync_storage, None, None).await
+    ) -> Result<Vec<Delivery>> {
+        Self::sync_file_to_clients_impl(file_path, destination, modes, settings, registry, rsync_storage, None, None).await
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn sync_file_to_clients_with_exec(
         file_path: &str,
         destination: &str,
+        modes: FileModes,
+        settings: &ServerSettings,
         registry: Arc<Mutex<ClientRegistry>>,
         rsync_storage: RsyncFileStorage,
         exec_metadata: ExecuteMetadataStorage,
         exec_config: Option<crate::config::ExecuteConfig>,
-    ) -> Result<usize> {
-        Self::sync_file_to_clients_impl(file_path, destination, registry, rsync_storage, Some(exec_metadata), exec_config).await
+    ) -> Result<Vec<Delivery>> {
+        Self::sync_file_to_clients_impl(file_path, destination, modes, settings, registry, rsync_storage, Some(exec_metadata), exec_config).await
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn sync_file_to_clients_impl(
         file_path: &str,
         destination: &str,
+        modes: FileModes,
+        settings: &ServerSettings,
         registry: Arc<Mutex<ClientRegistry>>,
         rsync_storage: RsyncFileStorage,
         exec_metadata: Option<ExecuteMetadataStorage>,
         exec_config: Option<crate::config::ExecuteConfig>,
-    ) -> Result<usize> {
+    ) -> Result<Vec<Delivery>> {
+        settings.check_destination(destination)?;
         let path = Path::new(file_path);
 
         if !path.exists() {
@@ -728,7 +2441,7 @@ impl SshServer {
         // Open file, mmap it, and immediately close the file handle.
         // The mmap will remain valid until the Arc is dropped.
         let file_data = {
-            let file = std::fs::File::open(&path).context("Failed to open file")?;
+            let file = std::fs::File::open(path).context("Failed to open file")?;
             let mmap = unsafe { memmap2::Mmap::map(&file)? };
             Arc::new(mmap)
         };
@@ -746,21 +2459,11 @@ impl SshServer {
             .context("Invalid mtime")?
             .as_secs();
 
-        // Get file permissions (Unix mode)
-        let mode = {
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                metadata.permissions().mode()
-            }
-            #[cfg(not(unix))]
-            {
-                0o644_u32 // Default permissions for non-Unix platforms
-            }
-        };
+        let mode = settings.sync_mode(&metadata, modes);
 
         // Compute checksum
-        let checksum = rsync_utils::compute_checksum(&file_data);
+        let checksum_algo = settings.checksum_algo;
+        let checksum = rsync_utils::compute_checksum(checksum_algo, &file_data);
 
         // Choose block size
         let block_size = rsync_utils::choose_block_size(size);
@@ -775,28 +2478,54 @@ impl SshServer {
 
         // Broadcast to all clients
         let request_id = format!("rsync-{}", uuid::Uuid::new_v4());
-        let rsync_msg = ServerMessage::RsyncStart {
-            request_id: request_id.clone(),
-            relative_path: destination.to_string(),
-            size,
-            checksum,
-            mtime,
-            block_size,
-            mode,
-        };
-
-        let (client_count, client_ids) = {
+        let (client_count, client_ids, encodings) = {
             let reg = registry.lock().await;
             let clients = reg.list_clients();
             let ids: HashSet<String> = clients.iter().map(|c| c.session_id.clone()).collect();
-            (clients.len(), ids)
+            let mut encodings = Vec::new();
+            for client in &clients {
+                let encoding = settings.encoding(&client.capabilities, file_data.len() as u64);
+                if !encodings.contains(&encoding) {
+                    encodings.push(encoding);
+                }
+            }
+            (clients.len(), ids, encodings)
         };
 
         if client_count == 0 {
             log::warn!("No clients connected to sync to");
-            return Ok(0);
+            return Ok(Vec::new());
         }
 
+        // One RsyncStart per encoding in use, each sent only to the clients that take it
+        let rsync_msgs: Vec<(Encoding, ServerMessage)> = encodings
+            .into_iter()
+            .map(|encoding| {
+                let checksum = if encoding.checksum_algo == checksum_algo {
+                    checksum.clone()
+                } else {
+                    rsync_utils::compute_checksum(encoding.checksum_algo, &file_data)
+                };
+                let rsync_msg = ServerMessage::RsyncStart {
+                    request_id: request_id.clone(),
+                    relative_path: destination.to_string(),
+                    size,
+                    checksum,
+                    mtime,
+                    block_size,
+                    mode,
+                    dir_mode: modes.dir,
+                    whole_file: encoding.whole_file,
+                    checksum_algo: encoding.checksum_algo,
+                    atomic_replace: modes.atomic_replace,
+                    priority: modes.priority,
+                    force: modes.force,
+                };
+                (encoding, rsync_msg)
+            })
+            .collect();
+        let file_size = file_data.len() as u64;
+
         // Store file data for rsync operations
         rsync_storage.lock().await.insert(
             request_id.clone(),
@@ -804,6 +2533,7 @@ impl SshServer {
         );
 
         // Store execute metadata if provided
+        let exec_storage_for_cleanup = exec_metadata.clone();
         if let (Some(exec_storage), Some(config)) = (exec_metadata, exec_config) {
             exec_storage.lock().await.insert(
                 request_id.clone(),
@@ -812,22 +2542,126 @@ impl SshServer {
             log::debug!("Stored execute metadata for request: {}", request_id);
         }
 
-        registry.lock().await.broadcast(&rsync_msg).await?;
+        let deliveries = {
+            let mut reg = registry.lock().await;
+            let mut deliveries = Vec::new();
+            for (encoding, rsync_msg) in &rsync_msgs {
+                deliveries.extend(reg.broadcast_to(rsync_msg, |client| {
+                    settings.encoding(&client.capabilities, file_size) == *encoding
+                })?);
+            }
+            deliveries
+        };
+
+        // Recipients that never got RsyncStart will never send RsyncComplete, so stop
+        // waiting on them or the file data would be held forever
+        let failed_sessions: Vec<&str> = deliveries
+            .iter()
+            .filter(|delivery| delivery.error.is_some())
+            .map(|delivery| delivery.session_id.as_str())
+            .collect();
+        if !failed_sessions.is_empty() {
+            let mut storage = rsync_storage.lock().await;
+            if let Some((_path, _data, pending_clients)) = storage.get_mut(&request_id) {
+                for session_id in &failed_sessions {
+                    pending_clients.remove(*session_id);
+                }
+                if pending_clients.is_empty() {
+                    storage.remove(&request_id);
+                    drop(storage);
+                    if let Some(exec_storage) = exec_storage_for_cleanup {
+                        exec_storage.lock().await.remove(&request_id);
+                    }
+                }
+            }
+        }
+
+        log::info!(
+            "Broadcast rsync start to {} of {} clients",
+            client_count - failed_sessions.len(),
+            client_count
+        );
+        Ok(deliveries)
+    }
+
+    /// Mode bits sent with an empty directory: the rule's `dir_mode`, else the source's
+    /// own permissions; None leaves the client's default
+    fn dir_sync_mode(dir: &Path, modes: FileModes) -> Option<u32> {
+        if modes.dir.is_some() {
+            return modes.dir;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(dir).ok().map(|metadata| metadata.permissions().mode() & 0o7777)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = dir;
+            None
+        }
+    }
+
+    /// Ask every client to create each (destination, mode) directory; a client that
+    /// misses one is already reported by the files synced alongside it
+    async fn create_dirs_on_clients(
+        settings: &ServerSettings,
+        registry: &Arc<Mutex<ClientRegistry>>,
+        dirs: Vec<(String, Option<u32>)>,
+    ) {
+        for (relative_path, mode) in dirs {
+            if let Err(e) = settings.check_destination(&relative_path) {
+                log::warn!("Not creating {} on clients: {:#}", relative_path, e);
+                continue;
+            }
+            let msg = ServerMessage::CreateDir {
+                request_id: format!("mkdir-{}", Uuid::new_v4()),
+                relative_path,
+                mode,
+            };
+            match registry.lock().await.broadcast(&msg) {
+                Ok(deliveries) => {
+                    for delivery in deliveries {
+                        if let Some(error) = delivery.error {
+                            log::warn!("Failed to send {} to {}: {}", msg.message_type(), delivery.hostname, error);
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Failed to broadcast {}: {:#}", msg.message_type(), e),
+            }
+        }
+    }
 
-        log::info!("Broadcast rsync start to {} clients", client_count);
-        Ok(client_count)
+    /// Split broadcast deliveries into delivered hostnames and per-recipient failures
+    fn split_deliveries(deliveries: Vec<Delivery>) -> (Vec<String>, Vec<RecipientFailure>) {
+        let mut delivered = Vec::new();
+        let mut failed = Vec::new();
+        for delivery in deliveries {
+            match delivery.error {
+                None => delivered.push(delivery.hostname),
+                Some(error) => failed.push(RecipientFailure {
+                    hostname: delivery.hostname,
+                    error,
+                }),
+            }
+        }
+        (delivered, failed)
     }
 
     /// Sync a file to a specific client by hostname
+    #[allow(clippy::too_many_arguments)]
     async fn sync_file_to_client(
         file_path: &str,
         destination: &str,
+        modes: FileModes,
+        settings: &ServerSettings,
         hostname: &str,
         session_id: &str,
         registry: Arc<Mutex<ClientRegistry>>,
         rsync_storage: RsyncFileStorage,
     ) -> Result<()> {
         log::debug!("sync_file_to_client called: {} -> {} (client: {})", file_path, destination, hostname);
+        settings.check_destination(destination)?;
 
         let path = Path::new(file_path);
 
@@ -840,7 +2674,7 @@ impl SshServer {
 
         // Open file, mmap it, and immediately close the file handle
         let file_data = {
-            let file = std::fs::File::open(&path).context("Failed to open file")?;
+            let file = std::fs::File::open(path).context("Failed to open file")?;
             let mmap = unsafe { memmap2::Mmap::map(&file)? };
             Arc::new(mmap)
         };
@@ -858,21 +2692,15 @@ impl SshServer {
             .context("Invalid mtime")?
             .as_secs();
 
-        // Get file permissions (Unix mode)
-        let mode = {
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                metadata.permissions().mode()
-            }
-            #[cfg(not(unix))]
-            {
-                0o644_u32 // Default permissions for non-Unix platforms
-            }
-        };
+        let mode = settings.sync_mode(&metadata, modes);
 
-        // Compute checksum
-        let checksum = rsync_utils::compute_checksum(&file_data);
+        // Compute checksum, with a hash this client can verify
+        let encoding = {
+            let reg = registry.lock().await;
+            settings.encoding(&reg.resolve(hostname, Some(session_id))?.capabilities, file_data.len() as u64)
+        };
+        let checksum_algo = encoding.checksum_algo;
+        let checksum = rsync_utils::compute_checksum(checksum_algo, &file_data);
 
         // Choose block size
         let block_size = rsync_utils::choose_block_size(size);
@@ -894,6 +2722,12 @@ impl SshServer {
             mtime,
             block_size,
             mode,
+            dir_mode: modes.dir,
+            whole_file: encoding.whole_file,
+            checksum_algo,
+            atomic_replace: modes.atomic_replace,
+            priority: modes.priority,
+            force: modes.force,
         };
 
         // Store file data for rsync operations with just this client
@@ -905,16 +2739,22 @@ impl SshServer {
             (path.to_path_buf(), file_data, client_ids),
         );
 
-        registry.lock().await.send_to_client(hostname, &rsync_msg).await?;
+        registry
+            .lock()
+            .await
+            .send_to_client(hostname, Some(session_id), &rsync_msg)?;
 
         log::trace!("Sent rsync start for {} to {}", file_path, hostname);
         Ok(())
     }
 
     /// Sync a file to a specific client with execute config
+    #[allow(clippy::too_many_arguments)]
     async fn sync_file_to_client_with_exec(
         file_path: &str,
         destination: &str,
+        modes: FileModes,
+        settings: &ServerSettings,
         hostname: &str,
         session_id: &str,
         registry: Arc<Mutex<ClientRegistry>>,
@@ -923,6 +2763,7 @@ impl SshServer {
         exec_config: Option<crate::config::ExecuteConfig>,
     ) -> Result<()> {
         log::debug!("sync_file_to_client_with_exec called: {} -> {} (client: {})", file_path, destination, hostname);
+        settings.check_destination(destination)?;
 
         let path = Path::new(file_path);
 
@@ -935,7 +2776,7 @@ impl SshServer {
 
         // Open file, mmap it, and immediately close the file handle
         let file_data = {
-            let file = std::fs::File::open(&path).context("Failed to open file")?;
+            let file = std::fs::File::open(path).context("Failed to open file")?;
             let mmap = unsafe { memmap2::Mmap::map(&file)? };
             Arc::new(mmap)
         };
@@ -953,21 +2794,15 @@ impl SshServer {
             .context("Invalid mtime")?
             .as_secs();
 
-        // Get file permissions (Unix mode)
-        let mode = {
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                metadata.permissions().mode()
-            }
-            #[cfg(not(unix))]
-            {
-                0o644_u32 // Default permissions for non-Unix platforms
-            }
-        };
+        let mode = settings.sync_mode(&metadata, modes);
 
-        // Compute checksum
-        let checksum = rsync_utils::compute_checksum(&file_data);
+        // Compute checksum, with a hash this client can verify
+        let encoding = {
+            let reg = registry.lock().await;
+            settings.encoding(&reg.resolve(hostname, Some(session_id))?.capabilities, file_data.len() as u64)
+        };
+        let checksum_algo = encoding.checksum_algo;
+        let checksum = rsync_utils::compute_checksum(checksum_algo, &file_data);
 
         // Choose block size
         let block_size = rsync_utils::choose_block_size(size);
@@ -989,6 +2824,12 @@ impl SshServer {
             mtime,
             block_size,
             mode,
+            dir_mode: modes.dir,
+            whole_file: encoding.whole_file,
+            checksum_algo,
+            atomic_replace: modes.atomic_replace,
+            priority: modes.priority,
+            force: modes.force,
         };
 
         // Store file data for rsync operations with just this client
@@ -1009,7 +2850,10 @@ impl SshServer {
             log::debug!("Stored execute metadata for request: {}", request_id);
         }
 
-        registry.lock().await.send_to_client(hostname, &rsync_msg).await?;
+        registry
+            .lock()
+            .await
+            .send_to_client(hostname, Some(session_id), &rsync_msg)?;
 
         log::trace!("Sent rsync start for {} to {}", file_path, hostname);
         Ok(())
@@ -1035,26 +2879,98 @@ impl russh::server::Server for SshServer {
         SshSession {
             client_registry: self.client_registry.clone(),
             authorized_keys: self.authorized_keys.clone(),
+            peer_addr: addr,
             session_id,
             hostname: None,
+            auth_key_label: None,
+            capabilities: Vec::new(),
             control_channel_id: None,
+            control_writer: None,
             message_buffer: MessageBuffer::new(),
             session_type: SessionType::Unknown,
             rsync_channels: HashMap::new(),
+            exec_channels: HashMap::new(),
             rsync_file_storage: self.rsync_file_storage.clone(),
             execute_metadata: self.execute_metadata.clone(),
             file_watcher: self.file_watcher.clone(),
             sync_rules: self.sync_rules.clone(),
+            watch_mode: self.watch_mode,
+            dedup: self.dedup,
+            settings: self.settings.clone(),
             start_time: self.start_time.clone(),
-            rsync_semaphore: self.rsync_semaphore.clone(),
+            sync_queue: self.sync_queue.clone(),
+            manifest_cache: self.manifest_cache.clone(),
+            pending_manifests: HashMap::new(),
+            draining: self.draining.clone(),
+            error_counters: self.error_counters.clone(),
+            identify_timer: None,
         }
     }
 }
 
+#[derive(Debug)]
 enum SessionType {
     Unknown,
     ClientDaemon,
     ControlCommand,
+    /// Told it isn't speaking this protocol; anything more it sends is dropped
+    Rejected,
+}
+
+/// Listen on 0.0.0.0:`port` with SO_REUSEADDR, so a restarted server can rebind while
+/// the last one's connections sit in TIME_WAIT. Windows is left alone: there the option
+/// would let another process take over a port in use.
+fn bind_listener(port: u16, backlog: u32) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = tokio::net::TcpSocket::new_v4()?;
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind((std::net::Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.listen(backlog)
+}
+
+/// What a plain `ssh` client (or anything else that isn't a launcher) is told before
+/// its channel is closed
+fn wrong_protocol_notice() -> String {
+    format!(
+        "This is a halfremembered-launcher {} server, which doesn't offer a shell or run \
+         commands for plain ssh. Connect with halfremembered-launcher (client, exec, \
+         status, ...) at the same version.\r\n",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// A client path's components with `.` dropped and `..` applied, with "/" first for
+/// an absolute path; None if `..` climbs above its start
+fn destination_components(path: &str) -> Option<Vec<&str>> {
+    let mut components = Vec::new();
+    if path.starts_with(['/', '\\']) {
+        components.push("/");
+    }
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                if components.last().is_none_or(|last| *last == "/") {
+                    return None;
+                }
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components)
+}
+
+/// Whether `destination` lies under one of `roots`; a root of "." allows any relative path
+fn within_destination_roots(destination: &str, roots: &[String]) -> bool {
+    let Some(destination) = destination_components(destination) else {
+        return false;
+    };
+    roots.iter().any(|root| match destination_components(root) {
+        Some(root) if root.is_empty() => destination.first() != Some(&"/"),
+        Some(root) => destination.starts_with(&root),
+        None => false,
+    })
 }
 
 struct RsyncChannelState {
@@ -1075,21 +2991,46 @@ impl RsyncChannelState {
     }
 }
 
+/// A client's exec channel: output frames for one Execute request, then its exit
+struct ExecChannelState {
+    request_id: String,
+    frame_buffer: FrameBuffer,
+    stdout_bytes: usize,
+    stderr_bytes: usize,
+}
+
 pub struct SshSession {
     client_registry: Arc<Mutex<ClientRegistry>>,
-    authorized_keys: Arc<Vec<ssh_key::PublicKey>>,
+    authorized_keys: Arc<Vec<AuthorizedKey>>,
+    /// Checked against authorized_keys `from=` restrictions
+    peer_addr: Option<SocketAddr>,
     session_id: String,
     hostname: Option<String>,
+    auth_key_label: Option<String>,
+    /// What the client advertised in Register; empty until then
+    capabilities: Vec<String>,
     control_channel_id: Option<ChannelId>,
+    control_writer: Option<ControlWriter>,
     message_buffer: MessageBuffer,
     session_type: SessionType,
     rsync_channels: HashMap<ChannelId, RsyncChannelState>,
+    exec_channels: HashMap<ChannelId, ExecChannelState>,
     rsync_file_storage: RsyncFileStorage,
     execute_metadata: ExecuteMetadataStorage,
     file_watcher: FileWatcherRef,
-    sync_rules: Arc<Mutex<Option<(PathBuf, Vec<crate::config::SyncRule>)>>>,
+    sync_rules: SyncRulesRef,
+    watch_mode: WatchMode,
+    dedup: bool,
+    settings: Arc<ServerSettings>,
     start_time: Arc<Instant>,
-    rsync_semaphore: Arc<tokio::sync::Semaphore>,
+    sync_queue: Arc<SyncQueue>,
+    manifest_cache: ManifestCacheRef,
+    /// Initial-sync targets offered in a Manifest, keyed by its request_id
+    pending_manifests: HashMap<String, Vec<InitialSyncTarget>>,
+    draining: Arc<AtomicBool>,
+    error_counters: Arc<ErrorCounters>,
+    /// Closes the control channel if no message identifies it within IDENTIFY_TIMEOUT
+    identify_timer: Option<tokio::task::AbortHandle>,
 }
 
 impl russh::server::Handler for SshSession {
@@ -1113,10 +3054,35 @@ impl russh::server::Handler for SshSession {
         // Fingerprints are the correct way to compare SSH keys - they hash only the
         // algorithm and public key bytes, not metadata like comments.
         for authorized_key in self.authorized_keys.iter() {
-            let auth_fingerprint = authorized_key.fingerprint(ssh_key::HashAlg::Sha256);
+            let auth_fingerprint = authorized_key.key.fingerprint(ssh_key::HashAlg::Sha256);
 
             if client_fingerprint == auth_fingerprint {
-                log::info!("✓ Public key authentication successful for {}", user);
+                if authorized_key.expired() {
+                    log::warn!("Key {} for {} is past its expiry-time", auth_fingerprint, user);
+                    continue;
+                }
+
+                // The same key may appear again with a from= that does allow this address
+                if !authorized_key.allows(self.peer_addr.map(|addr| addr.ip())) {
+                    log::warn!(
+                        "Key {} for {} is not allowed from {:?} by its from= option",
+                        auth_fingerprint,
+                        user,
+                        self.peer_addr
+                    );
+                    continue;
+                }
+
+                // The agent's key carries no comment; take the label from authorized_keys
+                let comment = authorized_key.key.comment();
+                self.auth_key_label = (!comment.is_empty()).then(|| comment.to_string());
+
+                log::info!(
+                    "✓ Public key authentication successful for {} (key: {}, {})",
+                    user,
+                    self.auth_key_label.as_deref().unwrap_or("<no comment>"),
+                    auth_fingerprint
+                );
                 return Ok(Auth::Accept);
             }
         }
@@ -1126,6 +3092,7 @@ impl russh::server::Handler for SshSession {
             user
         );
         log::debug!("Client fingerprint: {}", client_fingerprint);
+        self.error_counters.record(ErrorCause::AuthReject);
         Ok(Auth::Reject {
             proceed_with_methods: None,
             partial_success: false,
@@ -1135,17 +3102,45 @@ impl russh::server::Handler for SshSession {
     async fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<bool, Self::Error> {
         let channel_id = channel.id();
         log::debug!("Session channel opened: {:?}", channel_id);
 
         // First channel is the control channel
         if self.control_channel_id.is_none() {
+            if self.draining.load(Ordering::SeqCst) {
+                log::info!("Refusing new session {} while draining", self.session_id);
+                return Ok(false);
+            }
+
+
             log::debug!("Setting control channel: {:?}", channel_id);
             self.control_channel_id = Some(channel_id);
+
+            // Incoming data is handled by Handler::data; with the read half dropped
+            // russh discards its copy instead of queueing it
+            let (_read_half, write_half) = channel.split();
+            self.control_writer = Some(ControlWriter::spawn(write_half, self.settings.send_queue_depth));
+
+            // Launchers send their first message at once; anything else gets a clear
+            // answer instead of an open channel that never responds
+            let handle = session.handle();
+            let session_id = self.session_id.clone();
+            let timer = tokio::spawn(async move {
+                tokio::time::sleep(IDENTIFY_TIMEOUT).await;
+                log::warn!(
+                    "Closing session {}: no launcher message within {}s",
+                    session_id,
+                    IDENTIFY_TIMEOUT.as_secs()
+                );
+                let _ = handle.data(channel_id, CryptoVec::from(wrong_protocol_notice())).await;
+                let _ = handle.eof(channel_id).await;
+                let _ = handle.close(channel_id).await;
+            });
+            self.identify_timer = Some(timer.abort_handle());
         } else {
-            // Additional channels are rsync channels
+            // Additional channels are rsync channels until an exec handshake says otherwise
             log::debug!("Detected rsync channel: {:?}", channel_id);
             self.rsync_channels.insert(channel_id, RsyncChannelState::new());
         }
@@ -1163,36 +3158,48 @@ impl russh::server::Handler for SshSession {
         if self.rsync_channels.contains_key(&channel) {
             return self.handle_rsync_data(channel, data, session).await;
         }
+        if self.exec_channels.contains_key(&channel) {
+            return self.handle_exec_data(channel, data).await;
+        }
 
         // Control channel data
         self.message_buffer.append(data);
 
         match self.session_type {
             SessionType::Unknown => {
-                // Try to determine session type from first message
-                if let Some(msg) = self
-                    .message_buffer
-                    .try_parse_client_message()
-                    .map_err(|e| russh::Error::from(std::io::Error::other(e)))?
-                {
-                    log::debug!("Detected client daemon session");
-                    self.session_type = SessionType::ClientDaemon;
-                    self.handle_client_message(msg, channel, session).await?;
-                } else if let Some(cmd) = self
-                    .message_buffer
-                    .try_parse_local_command()
-                    .map_err(|e| russh::Error::from(std::io::Error::other(e)))?
-                {
-                    log::debug!("Detected control command session");
-                    self.session_type = SessionType::ControlCommand;
-                    self.handle_control_command(cmd, channel, session).await?;
+                // The first message's type byte says which kind of session this is
+                match self.message_buffer.try_parse_client_message() {
+                    Ok(Some(msg)) => {
+                        log::debug!("Detected client daemon session");
+                        self.identified(SessionType::ClientDaemon);
+                        self.handle_client_message(msg, channel, session).await?;
+                    }
+                    Ok(None) if self.message_buffer.remaining() > IDENTIFY_LIMIT => {
+                        let error = ProtocolError::TooLarge {
+                            kind: "First message",
+                            len: self.message_buffer.remaining(),
+                            max: IDENTIFY_LIMIT,
+                        };
+                        self.reject_first_message(channel, session, error)?;
+                    }
+                    Ok(None) => {}
+                    Err(ProtocolError::WrongType { .. }) => match self.message_buffer.try_parse_local_command() {
+                        Ok(Some(cmd)) => {
+                            log::debug!("Detected control command session");
+                            self.identified(SessionType::ControlCommand);
+                            self.handle_control_command(cmd, channel, session).await?;
+                        }
+                        Ok(None) => {}
+                        Err(e) => self.reject_first_message(channel, session, e)?,
+                    },
+                    Err(e) => self.reject_first_message(channel, session, e)?,
                 }
             }
             SessionType::ClientDaemon => {
                 while let Some(msg) = self
                     .message_buffer
                     .try_parse_client_message()
-                    .map_err(|e| russh::Error::from(std::io::Error::other(e)))?
+                    .map_err(|e| self.reject_stream(e))?
                 {
                     self.handle_client_message(msg, channel, session).await?;
                 }
@@ -1201,16 +3208,55 @@ impl russh::server::Handler for SshSession {
                 while let Some(cmd) = self
                     .message_buffer
                     .try_parse_local_command()
-                    .map_err(|e| russh::Error::from(std::io::Error::other(e)))?
+                    .map_err(|e| self.reject_stream(e))?
                 {
                     self.handle_control_command(cmd, channel, session).await?;
                 }
             }
+            SessionType::Rejected => {
+                self.message_buffer = MessageBuffer::new();
+            }
         }
 
         Ok(())
     }
 
+    /// Accepted only so that interactive `ssh` goes on to its shell request and shows
+    /// the notice sent there
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _term: &str,
+        _col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(russh::Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel)?;
+        Ok(())
+    }
+
+    /// Launchers never ask for a shell; this is someone running plain `ssh` at the port.
+    /// The request succeeds because `ssh` exits on a failure without showing the notice.
+    async fn shell_request(&mut self, channel: ChannelId, session: &mut Session) -> Result<(), Self::Error> {
+        session.channel_success(channel)?;
+        self.reject_unknown(channel, session, "asked for a shell", wrong_protocol_notice().into_bytes())
+    }
+
+    /// Launchers never run commands this way; this is `ssh host command`
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        _data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel)?;
+        self.reject_unknown(channel, session, "asked to run a command", wrong_protocol_notice().into_bytes())
+    }
+
     async fn channel_eof(
         &mut self,
         channel: ChannelId,
@@ -1222,6 +3268,243 @@ impl russh::server::Handler for SshSession {
 }
 
 impl SshSession {
+    /// Record which kind of session the first message showed this to be
+    fn identified(&mut self, session_type: SessionType) {
+        self.session_type = session_type;
+        if let Some(timer) = self.identify_timer.take() {
+            timer.abort();
+        }
+    }
+
+    /// Answer a control channel whose first message isn't from a launcher, then close it
+    ///
+    /// A launcher of another version gets an error response it can print; anything
+    /// else gets a line of text, which plain `ssh` shows.
+    fn reject_first_message(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+        error: ProtocolError,
+    ) -> Result<(), russh::Error> {
+        let reply = match error {
+            ProtocolError::Decode { kind: "LocalCommand", .. } => {
+                let response = LocalResponse::Error {
+                    message: format!(
+                        "The server (halfremembered-launcher {}) didn't understand this command; \
+                         is this launcher the same version?",
+                        env!("CARGO_PKG_VERSION")
+                    ),
+                };
+                let mut framed = Vec::new();
+                response
+                    .write_framed_as(&mut framed, self.message_buffer.last_format())
+                    .map_err(std::io::Error::other)?;
+                framed
+            }
+            _ => wrong_protocol_notice().into_bytes(),
+        };
+        self.reject_unknown(channel, session, &error.to_string(), reply)
+    }
+
+    /// Send `reply` on the control channel and close it, ignoring anything more it sends
+    fn reject_unknown(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+        reason: &str,
+        reply: Vec<u8>,
+    ) -> Result<(), russh::Error> {
+        log::warn!("Closing session {}: not a launcher of this version ({})", self.session_id, reason);
+        self.identified(SessionType::Rejected);
+        self.message_buffer = MessageBuffer::new();
+
+        session.data(channel, CryptoVec::from(reply))?;
+        session.eof(channel)?;
+        session.close(channel)?;
+        Ok(())
+    }
+
+    /// Drop a session whose control channel can't be parsed any more
+    ///
+    /// Nothing after a bad length or an undecodable message can be trusted to start
+    /// on a message boundary, so the whole connection goes.
+    fn reject_stream(&self, error: ProtocolError) -> russh::Error {
+        match &error {
+            ProtocolError::WrongType { .. } | ProtocolError::TooLarge { .. } | ProtocolError::TooSmall { .. } => {
+                log::warn!("Dropping {:?} session: not speaking this protocol ({})", self.session_type, error)
+            }
+            _ => log::warn!("Dropping {:?} session: {}", self.session_type, error),
+        }
+        self.error_counters.record(ErrorCause::ParseError);
+        russh::Error::from(std::io::Error::other(error))
+    }
+
+    /// Every watched file a newly registered client should have, with its destination
+    async fn initial_sync_targets(&self) -> Vec<InitialSyncTarget> {
+        let watched_files: Vec<_> = {
+            let watcher_lock = self.file_watcher.lock().await;
+            let Some(watcher) = watcher_lock.as_ref() else {
+                return Vec::new();
+            };
+            watcher
+                .get_all_watched_files()
+                .into_iter()
+                .map(|(watch_root, relative, absolute)| {
+                    let watch_targets = watcher.targets_for(&watch_root, &relative);
+                    (relative, absolute, watch_targets)
+                })
+                .collect()
+        };
+
+        // Get sync rules for destination path construction
+        let sync_rules = self.sync_rules.lock().await.clone();
+
+        let mut targets = Vec::new();
+        for (relative_path, absolute_path, watch_targets) in watched_files {
+            if let Some((project_root, rules)) = sync_rules.as_ref()
+                && SshServer::below_rule_depth(rules, project_root, &absolute_path)
+            {
+                continue;
+            }
+
+            // Find matching sync rules to get destinations and execute configs
+            let mut rule_targets = match sync_rules.as_ref() {
+                Some((project_root, rules)) => SshServer::rule_targets(
+                    rules,
+                    project_root,
+                    &absolute_path,
+                    &relative_path,
+                    self.dedup,
+                ),
+                None => Vec::new(),
+            };
+            if rule_targets.is_empty() {
+                for (destination, priority) in watch_targets {
+                    let destination = destination.unwrap_or_else(|| relative_path.clone());
+                    let modes = FileModes {
+                        priority,
+                        ..FileModes::default()
+                    };
+                    rule_targets.push((destination.to_string_lossy().to_string(), None, modes));
+                }
+            }
+
+            for (destination_path, exec_config, modes) in rule_targets {
+                targets.push((absolute_path.clone(), destination_path, exec_config, modes));
+            }
+        }
+        targets
+    }
+
+    /// Empty directories under every watch, with the client directory and mode each
+    /// is created with, mapped through sync rules like the files beside them
+    async fn initial_empty_dirs(&self) -> Vec<(String, Option<u32>)> {
+        let empty_dirs: Vec<_> = {
+            let watcher_lock = self.file_watcher.lock().await;
+            let Some(watcher) = watcher_lock.as_ref() else {
+                return Vec::new();
+            };
+            watcher
+                .get_all_empty_dirs()
+                .into_iter()
+                .map(|(watch_root, relative, absolute)| {
+                    let watch_destination = watcher.destination_for(&watch_root, &relative);
+                    (relative, absolute, watch_destination)
+                })
+                .collect()
+        };
+
+        let sync_rules = self.sync_rules.lock().await.clone();
+
+        let mut dirs = Vec::new();
+        for (relative_path, absolute_path, watch_destination) in empty_dirs {
+            let mut rule_targets = match sync_rules.as_ref() {
+                Some((project_root, rules)) => match absolute_path.strip_prefix(project_root) {
+                    Ok(relative) => SshServer::rule_dir_targets(rules, relative, self.dedup),
+                    Err(_) => Vec::new(),
+                },
+                None => Vec::new(),
+            };
+            if rule_targets.is_empty() {
+                let destination = watch_destination.unwrap_or(relative_path);
+                rule_targets.push((destination.to_string_lossy().to_string(), FileModes::default()));
+            }
+
+            for (destination_path, modes) in rule_targets {
+                if let Err(e) = self.settings.check_destination(&destination_path) {
+                    log::warn!("Not creating {} on clients: {:#}", destination_path, e);
+                    continue;
+                }
+                dirs.push((destination_path, SshServer::dir_sync_mode(&absolute_path, modes)));
+            }
+        }
+        dirs
+    }
+
+    /// Sync `targets` to this session's client in the background
+    fn queue_initial_sync(&self, hostname: &str, targets: Vec<InitialSyncTarget>) {
+        let file_count = targets.len();
+        log::info!("Starting initial sync of {} files to {}", file_count, hostname);
+
+        for (idx, (absolute_path, destination_path, exec_config, modes)) in targets.into_iter().enumerate() {
+            let file_path_str = absolute_path.to_string_lossy().to_string();
+            log::info!("Queueing file {}/{}: {} -> {}", idx + 1, file_count, file_path_str, destination_path);
+
+            let registry_clone = self.client_registry.clone();
+            let storage_clone = self.rsync_file_storage.clone();
+            let exec_metadata_clone = self.execute_metadata.clone();
+            let settings = self.settings.clone();
+            let hostname_clone = hostname.to_string();
+            let session_id_clone = self.session_id.clone();
+
+            // Runs in the background, highest-priority files first, so the session's
+            // event loop isn't blocked
+            self.sync_queue.push(modes.priority, destination_path.clone(), async move {
+                log::debug!("Initial sync starting: {}", file_path_str);
+
+                let result = if exec_config.is_some() {
+                    log::debug!("Initial sync with execute config: {}", file_path_str);
+                    SshServer::sync_file_to_client_with_exec(
+                        &file_path_str,
+                        &destination_path,
+                        modes,
+                        &settings,
+                        &hostname_clone,
+                        &session_id_clone,
+                        registry_clone,
+                        storage_clone,
+                        exec_metadata_clone,
+                        exec_config,
+                    ).await
+                } else {
+                    SshServer::sync_file_to_client(
+                        &file_path_str,
+                        &destination_path,
+                        modes,
+                        &settings,
+                        &hostname_clone,
+                        &session_id_clone,
+                        registry_clone,
+                        storage_clone,
+                    ).await
+                };
+
+                if let Err(e) = result {
+                    log::error!(
+                        "Failed to sync {} to {}: {:#}",
+                        file_path_str,
+                        hostname_clone,
+                        e
+                    );
+                }
+
+                log::debug!("Initial sync completed: {}", file_path_str);
+            });
+        }
+
+        log::info!("Queued initial sync of {} files to {}", file_count, hostname);
+    }
+
     async fn handle_client_message(
         &mut self,
         msg: ClientMessage,
@@ -1231,30 +3514,53 @@ impl SshSession {
         log::debug!("Received {}", msg.message_type());
 
         match msg {
-            ClientMessage::Register { hostname, platform, initial_sync } => {
-                log::info!("Client registered: {} ({}, initial_sync: {})", hostname, platform, initial_sync);
+            ClientMessage::Register { hostname, platform, initial_sync, resume_token, capabilities } => {
+                log::info!(
+                    "Client registered: {} ({}, initial_sync: {}, capabilities: [{}])",
+                    hostname,
+                    platform,
+                    initial_sync,
+                    capabilities.join(", ")
+                );
 
                 self.hostname = Some(hostname.clone());
+                self.capabilities = capabilities.clone();
 
-                let client = ConnectedClient {
+                let mut client = ConnectedClient {
                     hostname: hostname.clone(),
                     session_id: self.session_id.clone(),
                     platform,
                     connected_at: Instant::now(),
                     last_heartbeat: Instant::now(),
-                    session_handle: session.handle(),
-                    channel_id: channel,
+                    control_writer: self
+                        .control_writer
+                        .clone()
+                        .ok_or_else(|| russh::Error::from(std::io::Error::other("No control channel")))?,
+                    auth_key_label: self.auth_key_label.clone(),
+                    last_transfer: None,
+                    transfer_rates: Default::default(),
+                    last_heartbeat_sequence: None,
+                    heartbeat_gaps: 0,
+                    wire_format: self.message_buffer.last_format(),
+                    resume_token: Uuid::new_v4().to_string(),
+                    capabilities,
+                };
+                let next_token = client.resume_token.clone();
+
+                let resumed = {
+                    let mut registry = self.client_registry.lock().await;
+                    let resumed = resume_token.is_some_and(|token| registry.resume(&mut client, &token));
+                    registry
+                        .register(client)
+                        .map_err(|e| russh::Error::from(std::io::Error::other(e)))?;
+                    resumed
                 };
-
-                self.client_registry
-                    .lock()
-                    .await
-                    .register(client)
-                    .map_err(|e| russh::Error::from(std::io::Error::other(e)))?;
 
                 let welcome = ServerMessage::Welcome {
                     server_version: env!("CARGO_PKG_VERSION").to_string(),
                     session_id: self.session_id.clone(),
+                    resume_token: next_token,
+                    resumed,
                 };
 
                 self.send_message(&welcome, channel, session).await?;
@@ -1264,133 +3570,86 @@ impl SshSession {
                     request_id: format!("test-ping-{}", self.session_id),
                 };
 
-                log::info!("Sending test ping to {}", hostname);
-                self.send_message(&ping, channel, session).await?;
-
-                // Perform initial sync of all watched files (if requested)
-                if initial_sync {
-                    let watcher_lock = self.file_watcher.lock().await;
-                    if let Some(watcher) = watcher_lock.as_ref() {
-                    let watched_files = watcher.get_all_watched_files();
-                    drop(watcher_lock); // Release lock before async operations
-
-                    if !watched_files.is_empty() {
-                        let file_count = watched_files.len();
-                        log::info!(
-                            "Starting initial sync of {} files to {}",
-                            file_count,
-                            hostname
-                        );
-
-                        // Get sync rules for destination path construction
-                        let sync_rules = self.sync_rules.lock().await.clone();
-
-                        for (idx, (watch_root, relative_path, absolute_path)) in watched_files.iter().enumerate() {
-                            let file_path_str = absolute_path.to_string_lossy().to_string();
-                            let relative_str = relative_path.to_string_lossy().to_string();
-
-                            // Find matching sync rule to get destination and execute config
-                            let (destination_path, exec_config) = if let Some((project_root, rules)) = &sync_rules {
-                                let matched_rule = rules.iter().find(|rule| {
-                                    use globset::{Glob, GlobSetBuilder};
-                                    let mut builder = GlobSetBuilder::new();
-                                    for pattern in &rule.include {
-                                        if let Ok(glob) = Glob::new(pattern) {
-                                            builder.add(glob);
-                                        }
-                                    }
-                                    if let Ok(set) = builder.build() {
-                                        if let Ok(rel) = absolute_path.strip_prefix(project_root) {
-                                            set.is_match(rel)
-                                        } else {
-                                            false
-                                        }
-                                    } else {
-                                        false
-                                    }
-                                });
-
-                                if let Some(rule) = matched_rule {
-                                    // Find which pattern matched (use first for simplicity)
-                                    let pattern = rule.include.first().map(|s| s.as_str()).unwrap_or("");
-
-                                    // Strip pattern base to avoid duplication (e.g., "assets/" from "assets/data/file.json")
-                                    let stripped_path = SshServer::strip_pattern_base(pattern, relative_path);
-
-                                    // Construct destination by joining rule's destination with the stripped path
-                                    let dest = std::path::PathBuf::from(&rule.destination);
-                                    let full_dest = dest.join(&stripped_path);
-                                    let dest_str = full_dest.to_string_lossy().to_string();
-
-                                    log::debug!("Initial sync - Pattern: {}, Original: {}, Stripped: {}, Destination: {}",
-                                        pattern, relative_str, stripped_path.display(), dest_str);
-
-                                    (dest_str, rule.execute.clone())
-                                } else {
-                                    (relative_str.clone(), None)
-                                }
-                            } else {
-                                (relative_str.clone(), None)
-                            };
-
-                            log::info!("Queueing file {}/{}: {} -> {}", idx + 1, file_count, file_path_str, destination_path);
-
-                            let registry_clone = self.client_registry.clone();
-                            let storage_clone = self.rsync_file_storage.clone();
-                            let exec_metadata_clone = self.execute_metadata.clone();
-                            let semaphore_clone = self.rsync_semaphore.clone();
-                            let hostname_clone = hostname.clone();
-                            let session_id_clone = self.session_id.clone();
-
-                            // Spawn sync task to avoid blocking registration
-                            tokio::spawn(async move {
-                                let available = semaphore_clone.available_permits();
-                                log::debug!("Initial sync queued: {} (semaphore: {} available)", file_path_str, available);
-
-                                // Acquire semaphore to limit concurrent syncs
-                                let _permit = semaphore_clone.acquire().await.unwrap();
-                                log::debug!("Initial sync starting: {}", file_path_str);
-
-                                let result = if exec_config.is_some() {
-                                    log::debug!("Initial sync with execute config: {}", file_path_str);
-                                    SshServer::sync_file_to_client_with_exec(
-                                        &file_path_str,
-                                        &destination_path,
-                                        &hostname_clone,
-                                        &session_id_clone,
-                                        registry_clone,
-                                        storage_clone,
-                                        exec_metadata_clone,
-                                        exec_config,
-                                    ).await
-                                } else {
-                                    SshServer::sync_file_to_client(
-                                        &file_path_str,
-                                        &destination_path,
-                                        &hostname_clone,
-                                        &session_id_clone,
-                                        registry_clone,
-                                        storage_clone,
-                                    ).await
-                                };
-
-                                if let Err(e) = result {
-                                    log::error!(
-                                        "Failed to sync {} to {}: {:#}",
-                                        file_path_str,
-                                        hostname_clone,
-                                        e
-                                    );
-                                }
+                log::info!("Sending test ping to {}", hostname);
+                self.send_message(&ping, channel, session).await?;
 
-                                log::debug!("Initial sync completed: {}", file_path_str);
-                            });
+                // Offer the client a manifest of all watched files (if requested); it
+                // answers with a ManifestDiff naming the ones it actually needs
+                // Sent from a task that waits for room in the send queue, which a
+                // large layout would overflow
+                if initial_sync
+                    && self.settings.sync_empty_dirs
+                    && let Some(writer) = self.control_writer.clone()
+                {
+                    let dirs = self.initial_empty_dirs().await;
+                    let format = self.message_buffer.last_format();
+                    if !dirs.is_empty() {
+                        log::info!("Creating {} empty directories on {}", dirs.len(), hostname);
+                    }
+                    tokio::spawn(async move {
+                        for (relative_path, mode) in dirs {
+                            let create = ServerMessage::CreateDir {
+                                request_id: format!("mkdir-{}", Uuid::new_v4()),
+                                relative_path,
+                                mode,
+                            };
+                            let mut full_message = Vec::new();
+                            if let Err(e) = create.write_framed_as(&mut full_message, format) {
+                                log::error!("Failed to serialize {}: {:#}", create.message_type(), e);
+                                return;
+                            }
+                            if let Err(e) = writer.send(full_message).await {
+                                log::warn!("Failed to send {}: {:#}", create.message_type(), e);
+                                return;
+                            }
                         }
+                    });
+                }
 
-                        log::info!("Queued initial sync of {} files to {}", file_count, hostname);
-                    } else {
+                if initial_sync {
+                    let targets = self.initial_sync_targets().await;
+                    if targets.is_empty() {
                         log::debug!("No watched files to sync to {}", hostname);
-                    }
+                    } else if let Some(writer) = self.control_writer.clone() {
+                        // Recorded now, since the client can't answer before the
+                        // manifest reaches it
+                        let request_id = format!("manifest-{}", Uuid::new_v4());
+                        self.pending_manifests.insert(request_id.clone(), targets.clone());
+
+                        // Checksums come from the cache; only files changed since they
+                        // were last offered are read again. That can take a while, so
+                        // it's built and sent from a task and this session carries on
+                        let cache = self.manifest_cache.clone();
+                        let checksum_algo = self.settings.checksum_algo_for(&self.capabilities);
+                        let format = self.message_buffer.last_format();
+                        tokio::spawn(async move {
+                            let entries = match tokio::task::spawn_blocking(move || {
+                                cache.lock().unwrap().manifest(&targets, checksum_algo)
+                            })
+                            .await
+                            {
+                                Ok(entries) => entries,
+                                Err(e) => {
+                                    log::error!("Manifest task for {} failed: {}", hostname, e);
+                                    return;
+                                }
+                            };
+                            log::info!("Offering manifest of {} files to {}", entries.len(), hostname);
+
+                            let manifest = ServerMessage::Manifest {
+                                request_id,
+                                entries,
+                                checksum_algo,
+                            };
+                            let mut full_message = Vec::new();
+                            if let Err(e) = manifest.write_framed_as(&mut full_message, format) {
+                                log::error!("Failed to serialize {}: {:#}", manifest.message_type(), e);
+                                return;
+                            }
+                            if let Err(e) = writer.send(full_message).await {
+                                log::warn!("Failed to send {}: {:#}", manifest.message_type(), e);
+                            }
+                        });
                     }
                 } else {
                     log::info!("Skipping initial sync for {} (disabled by client)", hostname);
@@ -1403,9 +3662,27 @@ impl SshSession {
             } => {
                 log::trace!("Heartbeat from {:?}: seq={}", self.hostname, sequence);
 
-                if let Some(ref hostname) = self.hostname {
-                    self.client_registry.lock().await.update_heartbeat(hostname);
-                }
+                self.client_registry
+                    .lock()
+                    .await
+                    .record_heartbeat(&self.session_id, sequence);
+            }
+
+            // The source stays stored until the client applies the file and reports again
+            ClientMessage::RsyncComplete {
+                request_id,
+                path,
+                error,
+                queued: true,
+                ..
+            } => {
+                log::info!(
+                    "⏸️  {} queued on {} (request: {}): {}",
+                    path,
+                    self.hostname.as_deref().unwrap_or("client"),
+                    request_id,
+                    error.as_deref().unwrap_or("client busy")
+                );
             }
 
             ClientMessage::RsyncComplete {
@@ -1415,19 +3692,62 @@ impl SshSession {
                 checksum,
                 bytes_transferred,
                 error,
+                duration_ms,
+                queued: false,
             } => {
                 if success {
+                    let file_size = self
+                        .rsync_file_storage
+                        .lock()
+                        .await
+                        .get(&request_id)
+                        .map(|(_path, data, _pending)| data.len() as u64);
+                    let transfer = halfremembered_protocol::TransferInfo {
+                        path: path.clone(),
+                        bytes_transferred,
+                        file_size,
+                        duration_ms,
+                    };
+                    let ratio = transfer
+                        .wire_ratio()
+                        .map(|ratio| format!("{:.1}%", ratio * 100.0))
+                        .unwrap_or_else(|| "n/a".to_string());
+                    let rate = transfer
+                        .rate()
+                        .map(rsync_utils::format_rate)
+                        .unwrap_or_else(|| "n/a".to_string());
                     log::info!(
-                        "Rsync complete: {} ({} bytes transferred, checksum: {}, request: {})",
+                        "Rsync complete: {} ({} of {} bytes transferred, {} of file size, {} ms at {}, checksum: {}, request: {})",
                         path,
                         bytes_transferred,
+                        file_size.map_or_else(|| "?".to_string(), |size| size.to_string()),
+                        ratio,
+                        duration_ms,
+                        rate,
                         &checksum[..8],
                         request_id
                     );
 
+                    self.client_registry
+                        .lock()
+                        .await
+                        .record_transfer(&self.session_id, transfer);
+
                     // Check if this sync has execute config
                     let exec_metadata = self.execute_metadata.lock().await;
-                    if let Some((relative_path, exec_config)) = exec_metadata.get(&request_id) {
+                    // A client that already had the file transfers nothing
+                    let exec_config = exec_metadata.get(&request_id).map(|(_relative_path, config)| config);
+                    if let Some(exec_config) = exec_config
+                        && !exec_config.run_on.runs_after(bytes_transferred)
+                    {
+                        log::info!(
+                            "Not running {} after syncing {} (run_on = {:?}, {} bytes transferred)",
+                            exec_config.command,
+                            path,
+                            exec_config.run_on,
+                            bytes_transferred
+                        );
+                    } else if let Some(exec_config) = exec_config {
                         log::info!("Triggering execute after sync: {}", exec_config.command);
 
                         // Create execute message
@@ -1438,6 +3758,7 @@ impl SshSession {
                             args: exec_config.args.clone(),
                             working_dir: exec_config.working_dir.clone(),
                             env: exec_config.env.clone(),
+                            self_update: exec_config.self_update,
                         };
 
                         // Send to this client
@@ -1453,6 +3774,10 @@ impl SshSession {
                         request_id,
                         error
                     );
+                    // Refusals report no checksum; a failure with one is a mismatch
+                    if !checksum.is_empty() {
+                        self.error_counters.record(ErrorCause::ChecksumFailure);
+                    }
                 }
 
                 // Clean up storage
@@ -1474,22 +3799,64 @@ impl SshSession {
                 exit_code,
                 stdout,
                 stderr,
+                error,
             } => {
-                log::info!(
-                    "Execution complete (request: {}, exit: {})",
-                    request_id,
-                    exit_code
-                );
                 if !stdout.is_empty() {
                     log::debug!("stdout: {}", stdout);
                 }
                 if !stderr.is_empty() {
                     log::debug!("stderr: {}", stderr);
                 }
+                let exit = ExecExit { exit_code, error };
+                Self::log_exec_exit(&request_id, &exit);
+
+                // Without an exec channel, a streaming caller gets all output at once
+                let mut registry = self.client_registry.lock().await;
+                for (is_stderr, text) in [(false, stdout), (true, stderr)] {
+                    if !text.is_empty() {
+                        registry.relay_exec(
+                            &request_id,
+                            LocalResponse::ExecOutput {
+                                stderr: is_stderr,
+                                data: text.into_bytes(),
+                            },
+                        );
+                    }
+                }
+                registry.relay_exec(
+                    &request_id,
+                    LocalResponse::ExecExit {
+                        exit_code: exit.exit_code,
+                        error: exit.error,
+                    },
+                );
             }
 
             ClientMessage::Status { request_id, state } => {
                 log::info!("Status (request: {}): {:?}", request_id, state);
+                self.client_registry
+                    .lock()
+                    .await
+                    .complete_status(&request_id, state);
+            }
+
+            ClientMessage::VerifyResult {
+                request_id,
+                matches,
+                actual_checksum,
+                error,
+            } => {
+                let hostname = self.hostname.clone().unwrap_or_default();
+                log::info!("Verify (request: {}) on {}: matches={}", request_id, hostname, matches);
+                self.client_registry.lock().await.complete_verify(
+                    &request_id,
+                    ClientVerifyResult {
+                        hostname,
+                        matches,
+                        actual_checksum,
+                        error,
+                    },
+                );
             }
 
             ClientMessage::Error {
@@ -1498,6 +3865,62 @@ impl SshSession {
             } => {
                 log::error!("Client error (request: {:?}): {}", request_id, message);
             }
+
+            ClientMessage::Pong { nonce } => {
+                log::trace!("Pong from {:?}: nonce={}", self.hostname, nonce);
+
+                self.client_registry.lock().await.record_pong(&self.session_id);
+            }
+
+            ClientMessage::ManifestDiff { request_id, needed } => {
+                let Some(targets) = self.pending_manifests.remove(&request_id) else {
+                    log::warn!("ManifestDiff for unknown manifest: {}", request_id);
+                    return Ok(());
+                };
+                let hostname = self.hostname.clone().unwrap_or_default();
+                let offered = targets.len();
+
+                let needed: HashSet<String> = needed.into_iter().collect();
+                let targets: Vec<_> = targets
+                    .into_iter()
+                    .filter(|(_, destination, _, _)| needed.contains(destination))
+                    .collect();
+                log::info!(
+                    "📋 {} is missing or stale on {} of {} files",
+                    hostname,
+                    targets.len(),
+                    offered
+                );
+
+                if !targets.is_empty() {
+                    self.queue_initial_sync(&hostname, targets);
+                }
+            }
+
+            ClientMessage::RequestResync { relative_path } => {
+                let hostname = self.hostname.clone().unwrap_or_default();
+                if self.draining.load(Ordering::SeqCst) {
+                    log::info!("Ignoring resync of {} for {}: server is draining", relative_path, hostname);
+                    return Ok(());
+                }
+
+                // Only the file is sent again; a repair shouldn't rerun the rule's execute
+                let requested = Path::new(&relative_path);
+                let targets: Vec<InitialSyncTarget> = self
+                    .initial_sync_targets()
+                    .await
+                    .into_iter()
+                    .filter(|(_, destination, _, _)| Path::new(destination) == requested)
+                    .map(|(source, destination, _exec, modes)| (source, destination, None, modes))
+                    .collect();
+
+                if targets.is_empty() {
+                    log::warn!("🩹 {} asked to resync {}, which no watched file syncs to", hostname, relative_path);
+                } else {
+                    log::info!("🩹 Resyncing {} to {} on request", relative_path, hostname);
+                    self.queue_initial_sync(&hostname, targets);
+                }
+            }
         }
 
         Ok(())
@@ -1509,11 +3932,28 @@ impl SshSession {
         channel: ChannelId,
         session: &mut Session,
     ) -> Result<(), russh::Error> {
+        // Reply in the format the peer last wrote in
         let mut full_message = Vec::new();
-        msg.write_framed(&mut full_message)
+        msg.write_framed_as(&mut full_message, self.message_buffer.last_format())
             .map_err(|e| russh::Error::from(std::io::Error::other(e)))?;
 
-        let _ = session.data(channel, full_message.into());
+        // Client daemons share the control channel with registry sends, so go through
+        // the same ordered writer; never wait here, the event loop must keep running
+        match (&self.session_type, &self.control_writer) {
+            (SessionType::ClientDaemon, Some(writer)) => {
+                if let Err(e) = writer.try_send(full_message) {
+                    log::warn!("Failed to queue {}: {:#}", msg.message_type(), e);
+                    self.client_registry
+                        .lock()
+                        .await
+                        .evict(&self.session_id, format!("{:#}", e));
+                    return Ok(());
+                }
+            }
+            _ => {
+                let _ = session.data(channel, full_message.into());
+            }
+        }
 
         log::debug!("Sent {}", msg.message_type());
         Ok(())
@@ -1527,19 +3967,60 @@ impl SshSession {
     ) -> Result<(), russh::Error> {
         log::debug!("Handling control command: {:?}", command);
 
-        let response = SshServer::handle_local_command(
+        let (tag, command) = SshServer::untag(command);
+        if let LocalCommand::Execute {
+            target,
+            binary,
+            args,
+            session_id,
+            stream: true,
+            working_dir,
+            env,
+        } = command
+        {
+            log::info!("Streaming execute request: {} on {}", binary, target);
+            let (request_id, exec_msg) = SshServer::execute_message(binary, args, working_dir, env);
+            return self
+                .handle_streaming_exec(target, session_id, request_id, exec_msg, tag, channel, session)
+                .await;
+        }
+
+        let answer = SshServer::handle_local_command(
             command,
             self.client_registry.clone(),
             self.rsync_file_storage.clone(),
+            self.execute_metadata.clone(),
             self.file_watcher.clone(),
+            self.watch_mode,
+            self.dedup,
+            self.settings.clone(),
+            self.manifest_cache.clone(),
             self.start_time.clone(),
-            self.rsync_semaphore.clone(),
-        )
-        .await;
+            self.sync_queue.clone(),
+            self.draining.clone(),
+        );
+        let format = self.message_buffer.last_format();
+
+        // Tagged commands may be pipelined, so answer them from a task and let this
+        // session read the next one meanwhile
+        if tag.is_some() {
+            let handle = session.handle();
+            tokio::spawn(async move {
+                let response = SshServer::tagged(&tag, answer.await);
+                let mut full_message = Vec::new();
+                if let Err(e) = response.write_framed_as(&mut full_message, format) {
+                    log::error!("Failed to frame control response: {:#}", e);
+                    return;
+                }
+                let _ = handle.data(channel, full_message.into()).await;
+            });
+            return Ok(());
+        }
 
+        let response = answer.await;
         let mut full_message = Vec::new();
         response
-            .write_framed(&mut full_message)
+            .write_framed_as(&mut full_message, format)
             .map_err(|e| russh::Error::from(std::io::Error::other(e)))?;
 
         let _ = session.data(channel, full_message.into());
@@ -1548,6 +4029,101 @@ impl SshSession {
         Ok(())
     }
 
+    /// Start an Execute whose output is relayed back on this control channel as the
+    /// client reads it; the channel closes after ExecExit, or an Error if the client
+    /// disconnects first. A tagged Execute tags each response and leaves the channel
+    /// open for the other commands pipelined on it
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_streaming_exec(
+        &self,
+        target: String,
+        session_id: Option<String>,
+        request_id: String,
+        exec_msg: ServerMessage,
+        tag: Option<String>,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), russh::Error> {
+        let format = self.message_buffer.last_format();
+        let started =
+            SshServer::start_streaming_exec(&self.client_registry, &target, session_id, &request_id, &exec_msg).await;
+        let mut relay = match started {
+            Ok(relay) => relay,
+            Err(response) => {
+                let mut full_message = Vec::new();
+                SshServer::tagged(&tag, response)
+                    .write_framed_as(&mut full_message, format)
+                    .map_err(|e| russh::Error::from(std::io::Error::other(e)))?;
+                let _ = session.data(channel, full_message.into());
+                return Ok(());
+            }
+        };
+
+        // Relay from a task, so this session keeps handling its channel meanwhile
+        let handle = session.handle();
+        tokio::spawn(async move {
+            loop {
+                let (response, last) = SshServer::next_exec_response(&mut relay, &target).await;
+                let response = SshServer::tagged(&tag, response);
+
+                let mut full_message = Vec::new();
+                if let Err(e) = response.write_framed_as(&mut full_message, format) {
+                    log::error!("Failed to frame exec output (request: {}): {:#}", request_id, e);
+                    break;
+                }
+                if handle.data(channel, full_message.into()).await.is_err() {
+                    // Dropping the relay tells the registry to stop forwarding
+                    log::debug!("Exec caller went away (request: {})", request_id);
+                    return;
+                }
+                if last {
+                    break;
+                }
+            }
+            if tag.is_none() {
+                let _ = handle.close(channel).await;
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Send `payload` on an rsync channel as MSG_RSYNC_DELTA frames, chunked to stay
+    /// within the SSH window, followed by the zero-length end marker
+    fn send_rsync_payload(session: &mut Session, channel: ChannelId, payload: &[u8]) -> Result<(), russh::Error> {
+        const CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
+
+        let num_chunks = payload.len().div_ceil(CHUNK_SIZE);
+        for (chunk_idx, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+            let frame = Frame::new(MSG_RSYNC_DELTA, chunk.to_vec());
+            let mut buffer = Vec::new();
+            frame.write(&mut buffer).map_err(|e| {
+                russh::Error::from(std::io::Error::other(format!(
+                    "Failed to write frame chunk {}: {:#}",
+                    chunk_idx,
+                    e
+                )))
+            })?;
+
+            let _ = session.data(channel, buffer.into());
+            log::trace!("Sent delta chunk {}/{} ({} bytes)", chunk_idx + 1, num_chunks, chunk.len());
+        }
+        log::debug!("Sent {} bytes in {} chunks on channel {:?}", payload.len(), num_chunks, channel);
+
+        // Send zero-length frame to signal end of delta stream
+        let end_frame = Frame::new(MSG_RSYNC_DELTA, Vec::new());
+        let mut end_buffer = Vec::new();
+        end_frame.write(&mut end_buffer).map_err(|e| {
+            russh::Error::from(std::io::Error::other(format!(
+                "Failed to write end frame: {:#}",
+                e
+            )))
+        })?;
+        let _ = session.data(channel, end_buffer.into());
+        log::debug!("Sent end-of-delta marker on channel {:?}", channel);
+        Ok(())
+    }
+
     async fn handle_rsync_data(
         &mut self,
         channel: ChannelId,
@@ -1570,6 +4146,7 @@ impl SshSession {
 
         // Try to parse frames
         let mut should_remove_channel = false;
+        let mut exec_request_id = None;
         loop {
             let frame = {
                 let state = self.rsync_channels.get_mut(&channel).unwrap();
@@ -1578,6 +4155,7 @@ impl SshSession {
                     Ok(None) => break, // Need more data
                     Err(e) => {
                         log::error!("Frame parse error on rsync channel: {:#}", e);
+                        self.error_counters.record(ErrorCause::ParseError);
                         return Err(russh::Error::from(std::io::Error::other(e)));
                     }
                 }
@@ -1614,6 +4192,14 @@ impl SshSession {
                             state.file_path = Some(file_path.clone());
                             state.file_data = Some(file_data.clone());
                             log::debug!("Found file for request: {} bytes", file_data.len());
+
+                            // Small files were announced as whole_file to clients that take
+                            // them: no signature follows
+                            if self.settings.encoding(&self.capabilities, file_data.len() as u64).whole_file {
+                                Self::send_rsync_payload(session, channel, file_data)?;
+                                log::debug!("Sent whole file ({} bytes) for {}", file_data.len(), request_id);
+                                should_remove_channel = true;
+                            }
                         } else {
                             log::error!("No file found for request_id: {}", request_id);
                             return Err(russh::Error::from(std::io::Error::other(format!(
@@ -1644,86 +4230,44 @@ impl SshSession {
 
                         log::debug!("Generated delta: {} bytes", delta.len());
 
-                        // Send delta frame in chunks to avoid exceeding SSH window size
-                        const CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
-
-                        let delta_len = delta.len();
-
-                        if delta_len <= CHUNK_SIZE {
-                            // Small delta - send as single frame
-                            let delta_frame = Frame::new(MSG_RSYNC_DELTA, delta);
-                            let mut buffer = Vec::new();
-                            delta_frame.write(&mut buffer).map_err(|e| {
-                                russh::Error::from(std::io::Error::other(format!(
-                                    "Failed to write frame: {:#}",
-                                    e
-                                )))
-                            })?;
-
-                            let _ = session.data(channel, buffer.into());
-                            log::debug!("Sent delta frame on channel {:?} ({} bytes)", channel, delta_len);
-
-                            // Send zero-length frame to signal end of delta stream
-                            let end_frame = Frame::new(MSG_RSYNC_DELTA, Vec::new());
-                            let mut end_buffer = Vec::new();
-                            end_frame.write(&mut end_buffer).map_err(|e| {
-                                russh::Error::from(std::io::Error::other(format!(
-                                    "Failed to write end frame: {:#}",
-                                    e
-                                )))
-                            })?;
-                            let _ = session.data(channel, end_buffer.into());
-                            log::debug!("Sent end-of-delta marker on channel {:?}", channel);
-                        } else {
-                            // Large delta - chunk it
-                            let num_chunks = (delta_len + CHUNK_SIZE - 1) / CHUNK_SIZE;
-                            log::debug!("Chunking large delta: {} bytes into {} byte chunks ({} chunks total)",
-                                delta_len, CHUNK_SIZE, num_chunks);
-
-                            for (chunk_idx, chunk) in delta.chunks(CHUNK_SIZE).enumerate() {
-                                let delta_frame = Frame::new(MSG_RSYNC_DELTA, chunk.to_vec());
-                                let mut buffer = Vec::new();
-                                delta_frame.write(&mut buffer).map_err(|e| {
-                                    russh::Error::from(std::io::Error::other(format!(
-                                        "Failed to write frame chunk {}: {:#}",
-                                        chunk_idx,
-                                        e
-                                    )))
-                                })?;
-
-                                let _ = session.data(channel, buffer.into());
-                                log::trace!("Sent delta chunk {}/{} ({} bytes)",
-                                    chunk_idx + 1,
-                                    num_chunks,
-                                    chunk.len());
-                            }
-
-                            log::debug!("Sent chunked delta on channel {:?} ({} bytes in {} chunks)",
-                                channel, delta_len, num_chunks);
-
-                            // Send zero-length frame to signal end of delta stream
-                            let end_frame = Frame::new(MSG_RSYNC_DELTA, Vec::new());
-                            let mut end_buffer = Vec::new();
-                            end_frame.write(&mut end_buffer).map_err(|e| {
-                                russh::Error::from(std::io::Error::other(format!(
-                                    "Failed to write end frame: {:#}",
-                                    e
-                                )))
-                            })?;
-                            let _ = session.data(channel, end_buffer.into());
-                            log::debug!("Sent end-of-delta marker on channel {:?}", channel);
-                        }
+                        Self::send_rsync_payload(session, channel, &delta)?;
 
                         // Mark for removal - client will close channel
                         should_remove_channel = true;
                     }
                 }
+                MSG_EXEC_HANDSHAKE if self.rsync_channels[&channel].request_id.is_none() => {
+                    let request_id = String::from_utf8(frame.payload).map_err(|e| {
+                        russh::Error::from(std::io::Error::other(format!(
+                            "Invalid exec request_id: {}",
+                            e
+                        )))
+                    })?;
+                    exec_request_id = Some(request_id);
+                    break;
+                }
                 _ => {
                     log::warn!("Unexpected frame type on rsync channel: {}", frame.message_type);
                 }
             }
         }
 
+        // An exec channel takes over the channel, along with any frames already buffered
+        if let Some(request_id) = exec_request_id {
+            log::debug!("Exec handshake on channel {:?}: request_id={}", channel, request_id);
+            let state = self.rsync_channels.remove(&channel).unwrap();
+            self.exec_channels.insert(
+                channel,
+                ExecChannelState {
+                    request_id,
+                    frame_buffer: state.frame_buffer,
+                    stdout_bytes: 0,
+                    stderr_bytes: 0,
+                },
+            );
+            return self.handle_exec_data(channel, &[]).await;
+        }
+
         // Clean up channel if needed
         if should_remove_channel {
             self.rsync_channels.remove(&channel);
@@ -1731,6 +4275,95 @@ impl SshSession {
 
         Ok(())
     }
+
+    async fn handle_exec_data(&mut self, channel: ChannelId, data: &[u8]) -> Result<(), russh::Error> {
+        let Some(state) = self.exec_channels.get_mut(&channel) else {
+            return Err(russh::Error::from(std::io::Error::other(
+                "Exec channel not found",
+            )));
+        };
+        state.frame_buffer.append(data);
+
+        let hostname = self.hostname.as_deref().unwrap_or("unknown");
+        loop {
+            let frame = match state.frame_buffer.try_parse() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    log::error!("Frame parse error on exec channel: {:#}", e);
+                    self.error_counters.record(ErrorCause::ParseError);
+                    return Err(russh::Error::from(std::io::Error::other(e)));
+                }
+            };
+
+            match frame.message_type {
+                MSG_EXEC_STDOUT => {
+                    state.stdout_bytes += frame.payload.len();
+                    log::info!(
+                        "[{}] {}",
+                        hostname,
+                        String::from_utf8_lossy(&frame.payload).trim_end()
+                    );
+                    self.client_registry.lock().await.relay_exec(
+                        &state.request_id,
+                        LocalResponse::ExecOutput {
+                            stderr: false,
+                            data: frame.payload,
+                        },
+                    );
+                }
+                MSG_EXEC_STDERR => {
+                    state.stderr_bytes += frame.payload.len();
+                    log::warn!(
+                        "[{}] {}",
+                        hostname,
+                        String::from_utf8_lossy(&frame.payload).trim_end()
+                    );
+                    self.client_registry.lock().await.relay_exec(
+                        &state.request_id,
+                        LocalResponse::ExecOutput {
+                            stderr: true,
+                            data: frame.payload,
+                        },
+                    );
+                }
+                MSG_EXEC_EXIT => {
+                    let exit = ExecExit::from_bytes(&frame.payload)
+                        .map_err(|e| russh::Error::from(std::io::Error::other(e)))?;
+                    log::debug!(
+                        "Exec channel output (request: {}): {} bytes stdout, {} bytes stderr",
+                        state.request_id,
+                        state.stdout_bytes,
+                        state.stderr_bytes
+                    );
+                    Self::log_exec_exit(&state.request_id, &exit);
+                    self.client_registry.lock().await.relay_exec(
+                        &state.request_id,
+                        LocalResponse::ExecExit {
+                            exit_code: exit.exit_code,
+                            error: exit.error,
+                        },
+                    );
+                    self.exec_channels.remove(&channel);
+                    return Ok(());
+                }
+                _ => {
+                    log::warn!("Unexpected frame type on exec channel: {}", frame.message_type);
+                }
+            }
+        }
+    }
+
+    fn log_exec_exit(request_id: &str, exit: &ExecExit) {
+        log::info!(
+            "Execution complete (request: {}, exit: {})",
+            request_id,
+            exit.exit_code
+        );
+        if let Some(ref error) = exit.error {
+            log::warn!("Execution error (request: {}): {}", request_id, error);
+        }
+    }
 }
 
 impl Drop for SshSession {
@@ -1765,3 +4398,442 @@ impl Drop for SshSession {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MirrorScope, SyncRule, Trigger};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_mode_prefers_rule_override() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("tool");
+        std::fs::write(&path, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+
+        assert_eq!(ServerSettings::default().sync_mode(&metadata, FileModes::default()) & 0o7777, 0o640);
+        let modes = FileModes {
+            file: Some(0o755),
+            ..FileModes::default()
+        };
+        assert_eq!(ServerSettings::default().sync_mode(&metadata, modes), 0o755);
+    }
+
+    #[test]
+    fn test_settings_prefer_options_over_config() {
+        let config: Config = toml::from_str(
+            r#"
+[project]
+name = "settings"
+default_mode = 0o600
+checksum_algo = "sha256"
+
+[[sync]]
+include = ["bin/*"]
+destination = "bin/"
+"#,
+        )
+        .unwrap();
+
+        let settings = ServerSettings::new(&ServerOptions::default(), Some(&config));
+        assert_eq!(settings.default_mode, Some(0o600));
+        assert_eq!(settings.checksum_algo, ChecksumAlgo::Sha256);
+        assert_eq!(ServerSettings::default().checksum_algo, ChecksumAlgo::Blake3);
+        assert_eq!(ServerSettings::default().whole_file_threshold, rsync_utils::WHOLE_FILE_THRESHOLD);
+
+        let options = ServerOptions {
+            default_mode: Some(0o755),
+            checksum_algo: Some(ChecksumAlgo::Blake3),
+            ..ServerOptions::default()
+        };
+        let settings = ServerSettings::new(&options, Some(&config));
+        assert_eq!(settings.default_mode, Some(0o755));
+        assert_eq!(settings.checksum_algo, ChecksumAlgo::Blake3);
+        assert_eq!(ServerSettings::new(&options, None).default_mode, Some(0o755));
+    }
+
+    #[test]
+    fn test_rules_outside_allowed_roots_are_refused() {
+        let config: Config = toml::from_str(
+            r#"
+[project]
+name = "settings"
+allowed_destination_roots = ["games/"]
+
+[[sync]]
+include = ["bin/*"]
+destination = "games/bin/"
+
+[[sync]]
+name = "tools"
+include = ["tools/*"]
+destination = "tools/"
+"#,
+        )
+        .unwrap();
+
+        let settings = ServerSettings::new(&ServerOptions::default(), Some(&config));
+        let err = settings.check_rules(&config.sync_rules).unwrap_err();
+        assert!(err.to_string().starts_with("tools: Destination tools/"), "{:#}", err);
+        assert!(settings.check_rules(&config.sync_rules[..1]).is_ok());
+
+        // The command line's roots replace the config's
+        let options = ServerOptions {
+            allowed_destination_roots: vec![".".to_string()],
+            ..ServerOptions::default()
+        };
+        assert!(ServerSettings::new(&options, Some(&config)).check_rules(&config.sync_rules).is_ok());
+    }
+
+    #[test]
+    fn test_manifest_cache_reuses_checksum_until_invalidated() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("app.bin");
+        std::fs::write(&path, b"version 1").unwrap();
+        let targets = vec![(path.clone(), "bin/app.bin".to_string(), None, FileModes::default())];
+
+        let mut cache = ManifestCache::default();
+        let first = cache.manifest(&targets, ChecksumAlgo::default());
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].path, "bin/app.bin");
+        assert_eq!(first[0].checksum, rsync_utils::compute_checksum(ChecksumAlgo::default(), b"version 1"));
+
+        // Same size and mtime: the cached checksum is trusted without reading the file
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, b"version 2").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        assert_eq!(cache.manifest(&targets, ChecksumAlgo::default()), first);
+
+        // A change callback drops the entry, so the next manifest reads the new content
+        cache.invalidate(&path);
+        assert_eq!(cache.manifest(&targets, ChecksumAlgo::default())[0].checksum, rsync_utils::compute_checksum(ChecksumAlgo::default(), b"version 2"));
+
+        // Files no longer watched fall out of the cache
+        cache.manifest(&[], ChecksumAlgo::default());
+        assert!(cache.entries.is_empty());
+    }
+
+    fn rule(include: &str, destination: &str, mirror_scope: MirrorScope) -> SyncRule {
+        SyncRule {
+            name: None,
+            include: vec![include.to_string()],
+            exclude: Vec::new(),
+            destination: destination.to_string(),
+            clients: Vec::new(),
+            mirror: false,
+            mirror_scope,
+            case_insensitive: false,
+            recursive: true,
+            settle_ms: 0,
+            file_mode: None,
+            dir_mode: None,
+            atomic_replace: false,
+            priority: 0,
+            trigger: Trigger::Change,
+            execute: None,
+        }
+    }
+
+    #[test]
+    fn test_scoped_delete_path_at_root() {
+        let rule = rule("*.exe", ".", MirrorScope::Subtree);
+        assert_eq!(
+            SshServer::scoped_delete_path(&rule, Path::new("game.exe")),
+            Some(PathBuf::from("./game.exe"))
+        );
+    }
+
+    #[test]
+    fn test_scoped_delete_path_in_nested_subdirs() {
+        let rule = rule("assets/**/*", "games/demo/assets/", MirrorScope::Subtree);
+        assert_eq!(
+            SshServer::scoped_delete_path(&rule, Path::new("assets/ui/icons/close.png")),
+            Some(PathBuf::from("games/demo/assets/ui/icons/close.png"))
+        );
+    }
+
+    #[test]
+    fn test_scoped_delete_path_off_by_default() {
+        let rule = rule("assets/**/*", "assets/", MirrorScope::Off);
+        assert_eq!(SshServer::scoped_delete_path(&rule, Path::new("assets/a.png")), None);
+    }
+
+    #[test]
+    fn test_destination_roots() {
+        let roots = vec!["games/".to_string(), "~/tools".to_string(), "/opt/demo".to_string()];
+        assert!(within_destination_roots("games/demo/game.exe", &roots));
+        assert!(within_destination_roots("./games/./game.exe", &roots));
+        assert!(within_destination_roots("~/tools/bin/tool", &roots));
+        assert!(within_destination_roots("/opt/demo/game", &roots));
+        assert!(within_destination_roots("games\\demo\\game.exe", &roots));
+
+        assert!(!within_destination_roots("gamesx/game.exe", &roots));
+        assert!(!within_destination_roots("games/../etc/passwd", &roots));
+        assert!(!within_destination_roots("../games/game.exe", &roots));
+        assert!(!within_destination_roots("/opt/demo/../other", &roots));
+        assert!(!within_destination_roots("opt/demo/game", &roots));
+
+        // "." allows the client's working directory, but not absolute paths
+        let roots = vec![".".to_string()];
+        assert!(within_destination_roots("bin/game.exe", &roots));
+        assert!(!within_destination_roots("/etc/passwd", &roots));
+        assert!(!within_destination_roots("../outside", &roots));
+    }
+
+    #[test]
+    fn test_matching_rule_picks_first_match() {
+        let rules = CompiledRule::compile_all(vec![
+            rule("assets/**/*.png", "textures/", MirrorScope::Off),
+            rule("assets/**/*", "assets/", MirrorScope::Subtree),
+        ])
+        .unwrap();
+        let root = Path::new("/project");
+
+        let matched = SshServer::matching_rule(&rules, root, Path::new("/project/assets/sfx/boom.ogg"));
+        assert_eq!(matched.map(|rule| rule.destination.as_str()), Some("assets/"));
+        assert!(SshServer::matching_rule(&rules, root, Path::new("/elsewhere/a.png")).is_none());
+    }
+
+    #[test]
+    fn test_rule_targets_for_overlapping_rules() {
+        let rules = CompiledRule::compile_all(vec![
+            rule("assets/**/*.png", "textures/", MirrorScope::Off),
+            rule("assets/**/*", "assets/", MirrorScope::Off),
+        ])
+        .unwrap();
+        let root = Path::new("/project");
+        let absolute = Path::new("/project/assets/ui/button.png");
+        let relative = Path::new("assets/ui/button.png");
+
+        let destinations = |dedup| -> Vec<String> {
+            SshServer::rule_targets(&rules, root, absolute, relative, dedup)
+                .into_iter()
+                .map(|(destination, _, _)| destination)
+                .collect()
+        };
+        assert_eq!(destinations(false), vec!["textures/ui/button.png", "assets/ui/button.png"]);
+        assert_eq!(destinations(true), vec!["textures/ui/button.png"]);
+        assert!(SshServer::rule_targets(&rules, root, Path::new("/project/README.md"), Path::new("README.md"), false).is_empty());
+    }
+
+    #[test]
+    fn test_rule_dir_targets_follow_pattern_base() {
+        let rules = CompiledRule::compile_all(vec![
+            rule("assets/**/*", "game/assets/", MirrorScope::Off),
+            rule("target/release/game.exe", "game/", MirrorScope::Off),
+        ])
+        .unwrap();
+
+        let targets = SshServer::rule_dir_targets(&rules, Path::new("assets/sounds"), false);
+        let destinations: Vec<&str> = targets.iter().map(|(destination, _)| destination.as_str()).collect();
+        assert_eq!(destinations, vec!["game/assets/sounds"]);
+
+        // An exact-file rule doesn't claim the directories beside its file
+        assert!(SshServer::rule_dir_targets(&rules, Path::new("target/release/logs"), false).is_empty());
+    }
+
+    #[test]
+    fn test_matching_rules_apply_each_rules_excludes() {
+        let mut sensitive = rule("**/*", "all/", MirrorScope::Off);
+        sensitive.exclude = vec!["build/**".to_string()];
+        let mut insensitive = rule("**/*.exe", "bin/", MirrorScope::Off);
+        insensitive.exclude = vec!["**/debug.exe".to_string()];
+        insensitive.case_insensitive = true;
+        let rules = CompiledRule::compile_all(vec![sensitive, insensitive]).unwrap();
+        let root = Path::new("/project");
+        let destinations = |absolute: &str| -> Vec<&str> {
+            SshServer::matching_rules(&rules, root, Path::new(absolute))
+                .into_iter()
+                .map(|rule| rule.destination.as_str())
+                .collect()
+        };
+
+        // Each rule excludes only its own files, with its own case setting
+        assert_eq!(destinations("/project/build/game.exe"), vec!["bin/"]);
+        assert_eq!(destinations("/project/Build/GAME.EXE"), vec!["all/", "bin/"]);
+        assert_eq!(destinations("/project/DEBUG.EXE"), vec!["all/"]);
+    }
+
+    #[test]
+    fn test_non_recursive_rule_skips_subdirectories() {
+        let mut top_level = rule("bin/*", "bin/", MirrorScope::Off);
+        top_level.recursive = false;
+        let rules = CompiledRule::compile_all(vec![top_level, rule("assets/**/*", "assets/", MirrorScope::Off)]).unwrap();
+        let root = Path::new("/project");
+
+        let matched = SshServer::matching_rule(&rules, root, Path::new("/project/bin/game.exe"));
+        assert_eq!(matched.map(|rule| rule.destination.as_str()), Some("bin/"));
+
+        let deep = Path::new("/project/bin/tools/editor.exe");
+        assert!(SshServer::matching_rule(&rules, root, deep).is_none());
+        assert!(SshServer::below_rule_depth(&rules, root, deep));
+        assert!(!SshServer::below_rule_depth(&rules, root, Path::new("/project/assets/a/b.png")));
+        assert!(!SshServer::below_rule_depth(&rules, root, Path::new("/project/README.md")));
+    }
+
+    #[tokio::test]
+    async fn test_auth_enforces_from_restriction() {
+        use russh::server::Handler as _;
+
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let line = key.public_key().to_openssh().unwrap();
+        let mut server = SshServer::new().await.unwrap();
+        server.authorized_keys = Arc::new(vec![
+            AuthorizedKey::parse(&format!(r#"from="10.0.0.0/8",no-pty {} office"#, line)).unwrap(),
+            AuthorizedKey::parse(&format!(r#"from="192.168.1.*" {} home"#, line)).unwrap(),
+        ]);
+
+        let mut auth_from = async |addr: &str| {
+            let mut session = server.new_client(Some(addr.parse().unwrap()));
+            let auth = session.auth_publickey("testuser", key.public_key()).await.unwrap();
+            (matches!(auth, Auth::Accept), session.auth_key_label.clone())
+        };
+        assert_eq!(auth_from("10.1.2.3:50000").await, (true, Some("office".to_string())));
+        assert_eq!(auth_from("192.168.1.20:50000").await, (true, Some("home".to_string())));
+        assert_eq!(auth_from("[::ffff:10.1.2.3]:50000").await, (true, Some("office".to_string())));
+        assert_eq!(auth_from("203.0.113.9:50000").await, (false, None));
+    }
+
+    #[tokio::test]
+    async fn test_draining_refuses_new_work() {
+        let run = |command| {
+            SshServer::handle_local_command(
+                command,
+                Arc::new(Mutex::new(ClientRegistry::new())),
+                Arc::new(Mutex::new(HashMap::new())),
+                Arc::new(Mutex::new(HashMap::new())),
+                Arc::new(Mutex::new(None)),
+                WatchMode::default(),
+                false,
+                Arc::new(ServerSettings::default()),
+                Arc::new(std::sync::Mutex::new(ManifestCache::default())),
+                Arc::new(Instant::now()),
+                SyncQueue::new(1),
+                Arc::new(AtomicBool::new(true)),
+            )
+        };
+
+        let refused = run(LocalCommand::SyncFile {
+            file: "/nonexistent".to_string(),
+            destination: "a".to_string(),
+            allow_partial: false,
+            force: false,
+        })
+        .await;
+        assert!(
+            matches!(refused, LocalResponse::Error { ref message } if message.contains("draining")),
+            "{:?}",
+            refused
+        );
+
+        // Read-only commands still answer
+        assert!(matches!(run(LocalCommand::ListWatches).await, LocalResponse::WatchList { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_reports_in_flight_transfers() {
+        let rsync_storage: RsyncFileStorage = Arc::new(Mutex::new(HashMap::new()));
+        let data = memmap2::MmapMut::map_anon(4).unwrap().make_read_only().unwrap();
+        rsync_storage.lock().await.insert(
+            "rsync-1".to_string(),
+            (
+                PathBuf::from("/project/game.exe"),
+                Arc::new(data),
+                HashSet::from(["session-b".to_string(), "session-a".to_string()]),
+            ),
+        );
+
+        let response = SshServer::handle_local_command(
+            LocalCommand::Diagnostics,
+            Arc::new(Mutex::new(ClientRegistry::new())),
+            rsync_storage,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(None)),
+            WatchMode::default(),
+            false,
+            Arc::new(ServerSettings::default()),
+            Arc::new(std::sync::Mutex::new(ManifestCache::default())),
+            Arc::new(Instant::now()),
+            SyncQueue::new(1),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .await;
+
+        let LocalResponse::Diagnostics { report } = response else {
+            panic!("Unexpected response: {:?}", response);
+        };
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+        assert!(report.draining);
+        assert!(report.clients.is_empty());
+        assert!(report.watches.is_empty());
+        assert_eq!(report.transfers.len(), 1);
+        assert_eq!(report.transfers[0].request_id, "rsync-1");
+        assert_eq!(report.transfers[0].path, PathBuf::from("/project/game.exe").display().to_string());
+        assert_eq!(report.transfers[0].pending_sessions, vec!["session-a", "session-b"]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_tree_reports_each_matching_file() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(temp.path().join("bin/tools")).unwrap();
+        std::fs::write(temp.path().join("bin/game.exe"), b"game").unwrap();
+        std::fs::write(temp.path().join("bin/tools/editor.exe"), b"editor").unwrap();
+        std::fs::write(temp.path().join("bin/game.pdb"), b"symbols").unwrap();
+
+        let response = SshServer::handle_local_command(
+            LocalCommand::SyncTree {
+                root: temp.path().join("bin").to_string_lossy().to_string(),
+                destination: "games/demo".to_string(),
+                include_patterns: vec!["**/*.exe".to_string()],
+                exclude_patterns: vec!["tools/**".to_string()],
+                allow_partial: false,
+                force: false,
+            },
+            Arc::new(Mutex::new(ClientRegistry::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(None)),
+            WatchMode::default(),
+            false,
+            Arc::new(ServerSettings::default()),
+            Arc::new(std::sync::Mutex::new(ManifestCache::default())),
+            Arc::new(Instant::now()),
+            SyncQueue::new(1),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .await;
+
+        match response {
+            LocalResponse::SyncTreeReport { files, accepted, .. } => {
+                let names: Vec<&str> = files.iter().map(|result| result.file.as_str()).collect();
+                assert_eq!(names, vec![Path::new("games/demo").join("game.exe").to_string_lossy()]);
+                // No clients: nothing failed, so strict mode accepts it
+                assert!(files[0].delivered.is_empty() && files[0].error.is_none());
+                assert!(accepted);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listener_rebinds_port_in_time_wait() {
+        let listener = bind_listener(0, 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let port = addr.port();
+
+        // Closing the accepted side first leaves the server's end in TIME_WAIT
+        let client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        drop(accepted);
+        drop(listener);
+        drop(client);
+
+        let rebound = bind_listener(port, 16).unwrap();
+        assert_eq!(rebound.local_addr().unwrap().port(), port);
+    }
+}
@@ -0,0 +1,95 @@
+// Durable record of the syncs a client received
+//
+// With --sync-log the daemon appends one JSON line per completed sync to a file of
+// its own, so what arrived when survives the main logger's rotation and can be
+// grepped or parsed later. The file is opened for each line, so it can be rotated
+// or removed while the daemon runs.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+/// One line of the sync log; new fields are only ever added at the end
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncLogEntry {
+    /// Unix time, in milliseconds, the sync finished
+    pub timestamp_ms: u64,
+    pub request_id: String,
+    /// The destination as the server sent it, before resolving it on this client
+    pub relative_path: String,
+    pub success: bool,
+    /// Bytes received, which for a delta is less than the file's size
+    pub bytes: u64,
+    /// Checksum of the content received; empty when nothing was applied
+    pub checksum: String,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+impl SyncLogEntry {
+    pub fn to_line(&self) -> Result<String> {
+        let mut line = serde_json::to_string(self).context("Failed to encode sync log entry")?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+/// Append `entry` to the log at `path`, creating the file if needed
+pub async fn append(path: &Path, entry: &SyncLogEntry) -> Result<()> {
+    let line = entry.to_line()?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open sync log {}", path.display()))?;
+    // One write per line, so lines from an append-mode file never interleave
+    file.write_all(line.as_bytes())
+        .await
+        .with_context(|| format!("Failed to write sync log {}", path.display()))?;
+    // tokio finishes writes in the background; dropping the file unflushed can lose one
+    file.flush()
+        .await
+        .with_context(|| format!("Failed to write sync log {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append_writes_json_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sync.log");
+
+        let first = SyncLogEntry {
+            timestamp_ms: 1_700_000_000_000,
+            request_id: "rsync-1".to_string(),
+            relative_path: "bin/game.exe".to_string(),
+            success: true,
+            bytes: 4096,
+            checksum: "abc123".to_string(),
+            duration_ms: 12,
+            error: None,
+        };
+        let second = SyncLogEntry {
+            request_id: "rsync-2".to_string(),
+            success: false,
+            bytes: 0,
+            checksum: String::new(),
+            error: Some("Checksum mismatch".to_string()),
+            ..first.clone()
+        };
+        append(&path, &first).await.unwrap();
+        append(&path, &second).await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<SyncLogEntry> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries, vec![first, second]);
+    }
+}
@@ -0,0 +1,191 @@
+// Ordered dispatch of the server's sync work
+//
+// When many watched files change at once (a build writing its output and assets),
+// their syncs wait for one of a few concurrency permits. Waiting on the semaphore
+// directly would start them in arrival order; queueing them here starts the highest
+// priority first, and same-priority syncs in the order they were queued.
+
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, Semaphore};
+
+type SyncJob = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Syncs waiting for a concurrency permit, started highest priority first
+pub struct SyncQueue {
+    pending: Mutex<BinaryHeap<QueuedSync>>,
+    ready: Notify,
+    permits: Arc<Semaphore>,
+    concurrency: usize,
+    next_seq: AtomicU64,
+}
+
+struct QueuedSync {
+    priority: i32,
+    seq: u64,
+    label: String,
+    job: SyncJob,
+}
+
+// The heap pops the greatest: higher priority, then the earlier sequence number
+impl Ord for QueuedSync {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedSync {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedSync {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for QueuedSync {}
+
+impl SyncQueue {
+    /// A queue running at most `concurrency` syncs at a time; must be called within a
+    /// tokio runtime, which runs its dispatcher
+    pub fn new(concurrency: usize) -> Arc<Self> {
+        let queue = Arc::new(Self {
+            pending: Mutex::new(BinaryHeap::new()),
+            ready: Notify::new(),
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+            next_seq: AtomicU64::new(0),
+        });
+        tokio::spawn(queue.clone().dispatch());
+        queue
+    }
+
+    /// Queue `job`, labelled for logging, to start once a permit is free and no
+    /// higher-priority sync is waiting
+    pub fn push(&self, priority: i32, label: String, job: impl Future<Output = ()> + Send + 'static) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let mut pending = self.pending.lock().unwrap();
+        log::debug!(
+            "Sync queued: {} (priority {}, {} waiting, {} permits available)",
+            label,
+            priority,
+            pending.len(),
+            self.permits.available_permits()
+        );
+        pending.push(QueuedSync {
+            priority,
+            seq,
+            label,
+            job: Box::pin(job),
+        });
+        self.ready.notify_one();
+    }
+
+    /// Syncs queued but not yet started
+    pub fn waiting(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Syncs started and not yet finished, each holding a permit
+    pub fn running(&self) -> usize {
+        self.concurrency - self.permits.available_permits()
+    }
+
+    /// Whether no sync is waiting or running
+    pub fn is_idle(&self) -> bool {
+        self.waiting() == 0 && self.running() == 0
+    }
+
+    async fn dispatch(self: Arc<Self>) {
+        loop {
+            if self.waiting() == 0 {
+                self.ready.notified().await;
+                continue;
+            }
+            // Choose only once a permit is free, so syncs queued meanwhile compete too
+            let Ok(permit) = self.permits.clone().acquire_owned().await else {
+                return;
+            };
+            let Some(sync) = self.pending.lock().unwrap().pop() else {
+                continue;
+            };
+            log::debug!("Sync starting: {} (priority {})", sync.label, sync.priority);
+            tokio::spawn(async move {
+                sync.job.await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_higher_priority_starts_first() {
+        let queue = SyncQueue::new(1);
+        let started = Arc::new(Mutex::new(Vec::new()));
+
+        // Hold the only permit so everything after queues up behind it
+        let (release, blocked) = oneshot::channel::<()>();
+        queue.push(0, "blocker".to_string(), async move {
+            let _ = blocked.await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        for (priority, name) in [(0, "assets-1"), (10, "game.exe"), (0, "assets-2"), (-5, "docs"), (10, "game.pdb")] {
+            let started = started.clone();
+            queue.push(priority, name.to_string(), async move {
+                started.lock().unwrap().push(name);
+            });
+        }
+        assert_eq!(queue.waiting(), 5);
+        release.send(()).unwrap();
+
+        for _ in 0..50 {
+            if started.lock().unwrap().len() == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec!["game.exe", "game.pdb", "assets-1", "assets-2", "docs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_once_nothing_waits_or_runs() {
+        let queue = SyncQueue::new(1);
+        assert!(queue.is_idle());
+
+        let (release, blocked) = oneshot::channel::<()>();
+        queue.push(0, "running".to_string(), async move {
+            let _ = blocked.await;
+        });
+        queue.push(0, "waiting".to_string(), async {});
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!((queue.running(), queue.waiting()), (1, 1));
+        assert!(!queue.is_idle());
+
+        release.send(()).unwrap();
+        for _ in 0..50 {
+            if queue.is_idle() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(queue.is_idle());
+    }
+}
@@ -0,0 +1,158 @@
+// Integration test for per-client encodings chosen from advertised capabilities
+//
+// A client that doesn't advertise a capability must not be sent what needs it, so this test:
+// 1. Starts a server and connects a daemon, which advertises everything it supports
+// 2. Connects a stand-in client whose Register names no capabilities
+// 3. Syncs a small file, which the daemon can take whole with a BLAKE3 checksum
+// 4. Checks the stand-in's RsyncStart asks for a delta with a SHA-256 checksum instead,
+//    while the daemon still gets the file
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::rsync_utils;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ChecksumAlgo, ClientMessage, LocalCommand, LocalResponse, MessageBuffer, ServerMessage};
+use russh::client;
+use russh::{Channel, ChannelMsg};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+use common::AcceptingHandler;
+
+const CONTENT: &[u8] = b"small enough to send whole";
+
+async fn list_hostnames(port: u16) -> Result<Vec<String>> {
+    match SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+        .await?
+    {
+        LocalResponse::ClientList { clients } => Ok(clients.into_iter().map(|c| c.hostname).collect()),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+// Register as `hostname` without naming any capabilities, the way a client from
+// before they existed would
+async fn connect_baseline_client(
+    port: u16,
+    hostname: &str,
+) -> Result<(client::Handle<AcceptingHandler>, Channel<client::Msg>)> {
+    let mut session =
+        client::connect(Arc::new(client::Config::default()), ("localhost", port), AcceptingHandler).await?;
+
+    let mut agent = russh::keys::agent::client::AgentClient::connect_env().await?;
+    let mut authenticated = false;
+    for key in agent.request_identities().await? {
+        if session
+            .authenticate_publickey_with("testuser", key, None, &mut agent)
+            .await?
+            .success()
+        {
+            authenticated = true;
+            break;
+        }
+    }
+    anyhow::ensure!(authenticated, "no agent identity was accepted");
+
+    let channel = session.channel_open_session().await?;
+    let register = ClientMessage::Register {
+        hostname: hostname.to_string(),
+        platform: "test".to_string(),
+        initial_sync: false,
+        resume_token: None,
+        capabilities: vec![],
+    };
+    let mut framed = Vec::new();
+    register.write_framed(&mut framed)?;
+    channel.data(framed.as_slice()).await?;
+    Ok((session, channel))
+}
+
+// Read the control channel until an RsyncStart arrives
+async fn next_rsync_start(channel: &mut Channel<client::Msg>) -> Result<ServerMessage> {
+    let mut buffer = MessageBuffer::new();
+    loop {
+        while let Some(msg) = buffer.try_parse_server_message()? {
+            if let ServerMessage::RsyncStart { .. } = msg {
+                return Ok(msg);
+            }
+        }
+        match tokio::time::timeout(Duration::from_secs(10), channel.wait()).await? {
+            Some(ChannelMsg::Data { data }) => buffer.append(&data),
+            Some(_) => {}
+            None => anyhow::bail!("Channel closed before an RsyncStart arrived"),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_baseline_client_gets_compatible_encoding() -> Result<()> {
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+    let source = source_dir.path().join("small.txt");
+    std::fs::write(&source, CONTENT)?;
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "current".to_string(),
+        )
+        .with_working_dir(client_dir.path().to_path_buf())
+        .with_initial_sync(false),
+    );
+    let (_session, mut channel) = connect_baseline_client(port, "baseline").await?;
+
+    let start = Instant::now();
+    loop {
+        let hostnames = list_hostnames(port).await?;
+        if hostnames.iter().any(|h| h == "current") && hostnames.iter().any(|h| h == "baseline") {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for both clients; have {:?}", hostnames);
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let sync = LocalCommand::SyncFile {
+        file: source.to_string_lossy().to_string(),
+        destination: "small.txt".to_string(),
+        allow_partial: true,
+        force: false,
+    };
+    SshClientConnection::send_control_command("localhost", port, "testuser", sync, None).await?;
+
+    match next_rsync_start(&mut channel).await? {
+        ServerMessage::RsyncStart {
+            whole_file,
+            checksum_algo,
+            checksum,
+            ..
+        } => {
+            assert!(!whole_file, "a baseline client was sent a file whole");
+            assert_eq!(checksum_algo, ChecksumAlgo::Sha256);
+            assert_eq!(checksum, rsync_utils::compute_checksum(ChecksumAlgo::Sha256, CONTENT));
+        }
+        other => anyhow::bail!("Unexpected message: {:?}", other),
+    }
+
+    let delivered = client_dir.path().join("small.txt");
+    let start = Instant::now();
+    while std::fs::read(&delivered).ok().as_deref() != Some(CONTENT) {
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for the daemon to receive the file");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
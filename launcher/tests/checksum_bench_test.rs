@@ -0,0 +1,65 @@
+// Benchmark of the file checksum algorithms on a large file
+//
+// BLAKE3 is the default because it's cheaper on the CPU than SHA-256, so this test:
+// 1. Writes a 256 MiB file of pseudo-random bytes
+// 2. Hashes it, memory-mapped as the server does, once per algorithm after a warm-up
+// 3. Prints the time and throughput of each
+//
+// Ignored by default; run it with optimizations for meaningful numbers:
+//     cargo test --release -p halfremembered-launcher --test checksum_bench_test -- --ignored --nocapture
+
+use anyhow::Result;
+use halfremembered_launcher::rsync_utils;
+use halfremembered_protocol::ChecksumAlgo;
+use std::io::Write;
+use std::time::Instant;
+use tempfile::TempDir;
+
+const FILE_SIZE: usize = 256 * 1024 * 1024;
+
+// Fill `size` bytes with xorshift output, which no hash can shortcut
+fn write_random_file(path: &std::path::Path, size: usize) -> Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut chunk = vec![0u8; 1024 * 1024];
+    for _ in 0..size / chunk.len() {
+        for word in chunk.chunks_exact_mut(8) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            word.copy_from_slice(&state.to_le_bytes());
+        }
+        file.write_all(&chunk)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn bench_checksum_algos_on_large_file() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("large.bin");
+    write_random_file(&path, FILE_SIZE)?;
+
+    let file = std::fs::File::open(&path)?;
+    let data = unsafe { memmap2::Mmap::map(&file)? };
+
+    for algo in [ChecksumAlgo::Blake3, ChecksumAlgo::Sha256] {
+        // Warm the page cache so both measure hashing rather than the disk
+        rsync_utils::compute_checksum(algo, &data);
+
+        let start = Instant::now();
+        let checksum = rsync_utils::compute_checksum(algo, &data);
+        let elapsed = start.elapsed();
+        println!(
+            "{:?}: {} MiB in {:.0} ms ({}), {}",
+            algo,
+            FILE_SIZE / (1024 * 1024),
+            elapsed.as_secs_f64() * 1000.0,
+            rsync_utils::format_rate(FILE_SIZE as f64 / elapsed.as_secs_f64()),
+            &checksum[..16]
+        );
+    }
+    Ok(())
+}
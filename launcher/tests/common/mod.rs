@@ -0,0 +1,71 @@
+// Helpers shared by the integration tests
+//
+// Each test file is its own crate and pulls this in with `mod common;`, using only
+// some of the helpers. It lives in common/mod.rs so cargo doesn't also build it as an
+// empty test target of its own.
+#![allow(dead_code)]
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_server::{ServerOptions, SshServer};
+use russh::client;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+// Polling helper: wait for the server to write a parseable address to the port file
+pub async fn wait_for_port_file(path: &Path, timeout: Duration) -> Result<SocketAddr> {
+    let start = Instant::now();
+    loop {
+        if let Ok(content) = std::fs::read_to_string(path)
+            && let Ok(addr) = content.trim().parse::<SocketAddr>()
+        {
+            return Ok(addr);
+        }
+        if start.elapsed() > timeout {
+            anyhow::bail!("Timeout waiting for port file: {}", path.display());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Run a server on an OS-assigned port, returning that port once it is listening.
+// The server writes its port file only when it is ready, so connections made after
+// this returns don't race its startup.
+pub async fn start_server(options: ServerOptions) -> Result<(u16, JoinHandle<()>)> {
+    let port_dir = TempDir::new()?;
+    let port_file = port_dir.path().join("server.port");
+    let options = ServerOptions {
+        port_file: Some(port_file.clone()),
+        ..options
+    };
+    let server_task = tokio::spawn(async move {
+        SshServer::run_with_options(0, options)
+            .await
+            .expect("Server failed to start");
+    });
+    let addr = wait_for_port_file(&port_file, Duration::from_secs(10)).await?;
+    Ok((addr.port(), server_task))
+}
+
+// Run `daemon` until the test aborts it. It reconnects on its own, so returning at all
+// means something went wrong, and the panic puts the reason in the test output.
+pub fn spawn_daemon(mut daemon: ClientDaemon) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        daemon.run().await.expect("Client daemon failed");
+    })
+}
+
+// Client handler for the tests' own connections, which trust the test server's key
+pub struct AcceptingHandler;
+
+impl client::Handler for AcceptingHandler {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _key: &russh::keys::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
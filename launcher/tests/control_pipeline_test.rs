@@ -0,0 +1,123 @@
+// Integration test for pipelining control commands on one connection
+//
+// A ControlSession shouldn't make quick commands wait behind slow ones, so this test:
+// 1. Starts a server with a control socket and connects a daemon
+// 2. Over SSH and over the socket, starts a streaming exec that takes a moment and then
+//    sends status, list and ping on the same connection
+// 3. Checks the quick commands are answered while the exec is still running, each
+//    with the response its command asks for
+// 4. Checks the exec's output and exit still reach its own receiver
+#![cfg(unix)]
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::{CONTROL_SOCKET_PREFIX, ControlSession, SshClientConnection};
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+// Pipeline quick commands behind a slow exec on one session to `host`
+async fn check_pipeline(host: &str, port: u16) -> Result<()> {
+    let session = ControlSession::connect(host, port, "testuser", None).await?;
+
+    let mut exec = session
+        .send(LocalCommand::Execute {
+            target: "runner".to_string(),
+            binary: "sh".to_string(),
+            args: vec!["-c".to_string(), "sleep 2; echo finished".to_string()],
+            session_id: None,
+            stream: true,
+            working_dir: None,
+            env: Default::default(),
+        })
+        .await?;
+    let mut status = session.send(LocalCommand::Status).await?;
+    let mut list = session.send(LocalCommand::ListClients).await?;
+    let mut ping = session
+        .send(LocalCommand::Ping {
+            target: "runner".to_string(),
+            session_id: None,
+        })
+        .await?;
+
+    let sent = Instant::now();
+    match session.wait(&mut ping).await? {
+        LocalResponse::Success { .. } => {}
+        other => anyhow::bail!("Unexpected ping response: {:?}", other),
+    }
+    match session.wait(&mut list).await? {
+        LocalResponse::ClientList { clients } => assert_eq!(clients[0].hostname, "runner"),
+        other => anyhow::bail!("Unexpected list response: {:?}", other),
+    }
+    match session.wait(&mut status).await? {
+        LocalResponse::Status { version, .. } => assert_eq!(version, env!("CARGO_PKG_VERSION")),
+        other => anyhow::bail!("Unexpected status response: {:?}", other),
+    }
+    assert!(sent.elapsed() < Duration::from_secs(2), "quick commands waited for the exec");
+
+    let mut output = Vec::new();
+    loop {
+        match session.wait(&mut exec).await? {
+            LocalResponse::ExecOutput { data, .. } => output.extend(data),
+            LocalResponse::ExecExit { exit_code, error } => {
+                assert_eq!(exit_code, 0, "{:?}", error);
+                break;
+            }
+            other => anyhow::bail!("Unexpected exec response: {:?}", other),
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(&output).trim(), "finished");
+    // The exec's receiver ends once it has its final response
+    assert!(exec.recv().await.is_none());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_commands_pipeline_on_one_connection() -> Result<()> {
+    let socket_dir = TempDir::new()?;
+    let socket_path = socket_dir.path().join("control.sock");
+    let socket_host = format!("{}{}", CONTROL_SOCKET_PREFIX, socket_path.display());
+
+    let options = ServerOptions {
+        control_socket: Some(socket_path),
+        ..Default::default()
+    };
+    let (port, server_task) = common::start_server(options).await?;
+
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "runner".to_string(),
+        )
+        .with_initial_sync(false),
+    );
+
+    let start = Instant::now();
+    loop {
+        let response =
+            SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+                .await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && !clients.is_empty()
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    check_pipeline("localhost", port).await?;
+    check_pipeline(&socket_host, 0).await?;
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
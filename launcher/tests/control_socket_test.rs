@@ -0,0 +1,119 @@
+// Integration test for the server's --control-socket
+//
+// Local control commands can skip SSH and the agent entirely, so this test:
+// 1. Starts a server that also listens on a control socket, and checks the socket
+//    is only accessible to its owner
+// 2. Sends status and list over `unix:PATH`
+// 3. Connects a daemon and streams an exec through the socket to its exit
+#![cfg(unix)]
+
+use anyhow::Result;
+use futures::StreamExt;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::{CONTROL_SOCKET_PREFIX, SshClientConnection};
+use halfremembered_launcher::ssh_server::{ServerOptions, SshServer};
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_control_commands_over_socket() -> Result<()> {
+    let socket_dir = TempDir::new()?;
+    let socket_path = socket_dir.path().join("control.sock");
+    let host = format!("{}{}", CONTROL_SOCKET_PREFIX, socket_path.display());
+
+    let options = ServerOptions {
+        control_socket: Some(socket_path.clone()),
+        ..Default::default()
+    };
+    let (port, server_task) = common::start_server(options).await?;
+
+    let mode = std::fs::metadata(&socket_path)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // No port, user or agent needed for the socket
+    match SshClientConnection::send_control_command(&host, 0, "", LocalCommand::Status, None).await? {
+        LocalResponse::Status { version, .. } => assert_eq!(version, env!("CARGO_PKG_VERSION")),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "local".to_string(),
+        )
+        .with_initial_sync(false),
+    );
+
+    let start = Instant::now();
+    loop {
+        let response = SshClientConnection::send_control_command(&host, 0, "", LocalCommand::ListClients, None).await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && !clients.is_empty()
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let exec = LocalCommand::Execute {
+        target: "local".to_string(),
+        binary: "sh".to_string(),
+        args: vec!["-c".to_string(), "echo over the socket; exit 3".to_string()],
+        session_id: None,
+        stream: true,
+        working_dir: None,
+        env: Default::default(),
+    };
+    let responses = SshClientConnection::send_control_command_streaming(&host, 0, "", exec, None, None).await?;
+    let mut responses = std::pin::pin!(responses);
+    let mut stdout = Vec::new();
+    let mut exit = None;
+    while let Some(response) = tokio::time::timeout(Duration::from_secs(10), responses.next()).await? {
+        match response? {
+            LocalResponse::ExecOutput { data, .. } => stdout.extend_from_slice(&data),
+            LocalResponse::ExecExit { exit_code, .. } => exit = Some(exit_code),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        }
+    }
+    assert_eq!(String::from_utf8(stdout)?, "over the socket\n");
+    assert_eq!(exit, Some(3));
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_second_server_leaves_a_live_control_socket_alone() -> Result<()> {
+    let socket_dir = TempDir::new()?;
+    let socket_path = socket_dir.path().join("control.sock");
+    let host = format!("{}{}", CONTROL_SOCKET_PREFIX, socket_path.display());
+
+    let options = ServerOptions {
+        control_socket: Some(socket_path.clone()),
+        ..Default::default()
+    };
+    let (_, server_task) = common::start_server(options.clone()).await?;
+
+    let second = tokio::time::timeout(Duration::from_secs(10), SshServer::run_with_options(0, options)).await?;
+    let error = second.expect_err("a second server took over a live control socket");
+    assert!(format!("{:#}", error).contains("already in use"), "{:#}", error);
+
+    match SshClientConnection::send_control_command(&host, 0, "", LocalCommand::Status, None).await? {
+        LocalResponse::Status { .. } => {}
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    server_task.abort();
+    Ok(())
+}
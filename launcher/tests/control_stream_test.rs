@@ -0,0 +1,57 @@
+// Integration test for reading control responses as a stream
+//
+// The one-shot send_control_command is the first item of the streaming variant, so this test:
+// 1. Starts a server on an OS-assigned port
+// 2. Sends Status through send_control_command_streaming and reads the first response
+// 3. Checks the one-shot call still answers on the same server
+
+use anyhow::Result;
+use futures::StreamExt;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::time::Duration;
+
+mod common;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_streaming_control_command_yields_response() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let responses = SshClientConnection::send_control_command_streaming(
+        "localhost",
+        port,
+        "testuser",
+        LocalCommand::Status,
+        None,
+        None,
+    )
+    .await?;
+    let mut responses = std::pin::pin!(responses);
+
+    let first = tokio::time::timeout(Duration::from_secs(10), responses.next())
+        .await?
+        .expect("stream ended without a response")?;
+    assert!(
+        matches!(first, LocalResponse::Status { .. }),
+        "unexpected response: {:?}",
+        first
+    );
+
+    let response = SshClientConnection::send_control_command(
+        "localhost",
+        port,
+        "testuser",
+        LocalCommand::Status,
+        None,
+    )
+    .await?;
+    assert!(
+        matches!(response, LocalResponse::Status { .. }),
+        "unexpected response: {:?}",
+        response
+    );
+
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,70 @@
+// Integration test for client daemon connection events
+//
+// Embedders watch these to alert on outages, so this test checks that:
+// 1. A daemon reports Connected once it registers with a running server
+// 2. Stopping the server produces Disconnected, then Reconnecting
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::{ClientDaemon, DaemonEvent};
+use halfremembered_launcher::ssh_server::ServerOptions;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+
+// Polling helper: wait until a recorded event satisfies `predicate`
+async fn wait_for_event(
+    events: &Mutex<Vec<DaemonEvent>>,
+    predicate: impl Fn(&DaemonEvent) -> bool,
+    timeout: Duration,
+) -> Result<()> {
+    let start = Instant::now();
+    while !events.lock().unwrap().iter().any(&predicate) {
+        if start.elapsed() > timeout {
+            anyhow::bail!("Timeout waiting for event, got: {:?}", events.lock().unwrap());
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_daemon_reports_connection_transitions() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "events-client".to_string(),
+        )
+        .with_reconnect_delay(Duration::from_millis(200))
+        .with_initial_sync(false)
+        .with_event_handler(move |event| recorded.lock().unwrap().push(event.clone())),
+    );
+
+    wait_for_event(&events, |event| *event == DaemonEvent::Connected, Duration::from_secs(10)).await?;
+
+    server_task.abort();
+    wait_for_event(
+        &events,
+        |event| matches!(event, DaemonEvent::Reconnecting { attempt: 1, .. }),
+        Duration::from_secs(10),
+    )
+    .await?;
+
+    let events = events.lock().unwrap().clone();
+    let connected = events.iter().position(|event| *event == DaemonEvent::Connected).unwrap();
+    assert!(
+        matches!(events.get(connected + 1), Some(DaemonEvent::Disconnected { .. })),
+        "expected Disconnected after Connected: {:?}",
+        events
+    );
+
+    client_task.abort();
+    Ok(())
+}
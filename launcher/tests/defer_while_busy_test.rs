@@ -0,0 +1,117 @@
+// Integration test for deferring syncs while the client is busy
+//
+// A sync must not swap files out from under a running workload, so this test:
+// 1. Starts a stand-in workload process and a daemon that defers syncs while it runs
+// 2. Syncs a file and checks the client queues it, reporting the process and the
+//    pending transfer in its state, without writing the file
+// 3. Stops the workload and checks the queued sync is applied
+#![cfg(unix)]
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ClientState, LocalCommand, LocalResponse};
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+// Short enough that Linux doesn't truncate it in the process list
+const WORKLOAD: &str = "hrlbusygame";
+
+async fn control(port: u16, command: LocalCommand) -> Result<LocalResponse> {
+    SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await
+}
+
+async fn client_state(port: u16) -> Result<ClientState> {
+    let command = LocalCommand::ClientStatus {
+        hostname: "busy".to_string(),
+        session_id: None,
+    };
+    match control(port, command).await? {
+        LocalResponse::ClientState { state, .. } => Ok(state),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+// Poll the client's state until `done` accepts it
+async fn wait_for_state(port: u16, done: impl Fn(&ClientState) -> bool) -> Result<ClientState> {
+    let start = Instant::now();
+    loop {
+        if let Ok(state) = client_state(port).await
+            && done(&state)
+        {
+            return Ok(state);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client state");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sync_waits_for_workload_to_exit() -> Result<()> {
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+
+    // A script is listed under its own name, unlike a copied multi-call binary
+    let workload_path = source_dir.path().join(WORKLOAD);
+    std::fs::write(&workload_path, "#!/bin/sh\nwhile :; do sleep 1; done\n")?;
+    std::fs::set_permissions(&workload_path, std::fs::Permissions::from_mode(0o755))?;
+    let mut workload = tokio::process::Command::new(&workload_path)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let working_dir = client_dir.path().to_path_buf();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "busy".to_string(),
+        )
+        .with_working_dir(working_dir)
+        .with_initial_sync(false)
+        .with_defer_while_busy(vec![WORKLOAD.to_string()]),
+    );
+    wait_for_state(port, |_| true).await?;
+
+    std::fs::write(source_dir.path().join("level.dat"), b"new level")?;
+    let sync = LocalCommand::SyncFile {
+        file: source_dir.path().join("level.dat").to_string_lossy().to_string(),
+        destination: "level.dat".to_string(),
+        allow_partial: false,
+        force: false,
+    };
+    match control(port, sync).await? {
+        LocalResponse::SyncReport { accepted: true, .. } => {}
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let state = wait_for_state(port, |state| state.pending_transfers == 1).await?;
+    assert_eq!(state.running_processes, vec![WORKLOAD.to_string()]);
+    let synced = client_dir.path().join("level.dat");
+    assert!(!synced.exists(), "synced while the workload was running");
+
+    workload.kill().await?;
+    let state = wait_for_state(port, |state| state.pending_transfers == 0).await?;
+    assert!(state.running_processes.is_empty());
+
+    let start = Instant::now();
+    while std::fs::read(&synced).ok().as_deref() != Some(b"new level".as_slice()) {
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for the deferred sync");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// Integration test for the server's --allowed-destination-root
+//
+// A server restricted to some client paths must refuse the rest before any client is
+// asked to write, so this test:
+// 1. Starts a server that only syncs under games/ and connects a daemon
+// 2. Syncs a file under games/, which lands on the client
+// 3. Syncs to a path outside it, and one that climbs out with .., and checks both are
+//    refused naming the policy, with nothing written
+// 4. Checks a watch with a destination outside games/ is refused too
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+async fn sync(port: u16, file: &Path, destination: &str) -> Result<LocalResponse> {
+    let command = LocalCommand::SyncFile {
+        file: file.to_string_lossy().to_string(),
+        destination: destination.to_string(),
+        allow_partial: false,
+        force: false,
+    };
+    SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await
+}
+
+// Polling helper: wait for a file to exist
+async fn wait_for_file(path: &Path) -> Result<()> {
+    let start = Instant::now();
+    while !path.exists() {
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for {}", path.display());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_destinations_outside_allowed_roots_are_refused() -> Result<()> {
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+    let source = source_dir.path().join("game.exe");
+    std::fs::write(&source, b"game")?;
+
+    // Away from the repo's own .hrlauncher.toml, whose destinations are outside games/
+    std::env::set_current_dir(source_dir.path())?;
+
+    let options = ServerOptions {
+        allowed_destination_roots: vec!["games/".to_string()],
+        ..Default::default()
+    };
+    let (port, server_task) = common::start_server(options).await?;
+
+    let working_dir = client_dir.path().to_path_buf();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "fenced".to_string(),
+        )
+        .with_working_dir(working_dir)
+        .with_initial_sync(false),
+    );
+
+    let start = Instant::now();
+    loop {
+        let response =
+            SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+                .await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && !clients.is_empty()
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    match sync(port, &source, "games/demo/game.exe").await? {
+        LocalResponse::SyncReport { accepted: true, .. } => {}
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+    wait_for_file(&client_dir.path().join("games/demo/game.exe")).await?;
+
+    for destination in ["tools/game.exe", "games/../game.exe"] {
+        match sync(port, &source, destination).await? {
+            LocalResponse::Error { message } => {
+                assert!(message.contains("allowed_destination_roots"), "{}", message);
+            }
+            other => anyhow::bail!("Unexpected response for {}: {:?}", destination, other),
+        }
+    }
+    sleep(Duration::from_millis(500)).await;
+    assert!(!client_dir.path().join("tools").exists());
+    assert!(!client_dir.path().join("game.exe").exists());
+
+    let watch = LocalCommand::WatchDirectory {
+        path: source_dir.path().to_string_lossy().to_string(),
+        recursive: true,
+        include_patterns: vec![],
+        exclude_patterns: vec![],
+        relative_to: None,
+        case_insensitive: false,
+        verify_events: false,
+        destination: Some("tools/".to_string()),
+        base: None,
+        settle_ms: 0,
+        priority: 0,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", watch, None).await? {
+        LocalResponse::Error { message } => assert!(message.contains("allowed_destination_roots"), "{}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
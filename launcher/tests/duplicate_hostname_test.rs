@@ -0,0 +1,105 @@
+// Integration test for clients that register with the same hostname
+//
+// Cloned VMs report identical hostnames, so this test:
+// 1. Connects two daemons that both call themselves "twin"
+// 2. Checks a ping by hostname alone is refused as ambiguous
+// 3. Checks a ping naming one of the sessions reaches it
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ClientInfo, LocalCommand, LocalResponse};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+
+async fn send(port: u16, command: LocalCommand) -> Result<LocalResponse> {
+    SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await
+}
+
+// Polling helper: wait until `count` clients are registered
+async fn wait_for_clients(port: u16, count: usize, timeout: Duration) -> Result<Vec<ClientInfo>> {
+    let start = Instant::now();
+    loop {
+        if let Ok(LocalResponse::ClientList { clients }) = send(port, LocalCommand::ListClients).await
+            && clients.len() == count
+        {
+            return Ok(clients);
+        }
+        if start.elapsed() > timeout {
+            anyhow::bail!("Timeout waiting for {} clients", count);
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_duplicate_hostnames_need_a_session_id() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let mut client_tasks = Vec::new();
+    for _ in 0..2 {
+        client_tasks.push(common::spawn_daemon(
+            ClientDaemon::new(
+                "localhost".to_string(),
+                port,
+                "testuser".to_string(),
+                "twin".to_string(),
+            )
+            .with_initial_sync(false),
+        ));
+    }
+
+    let clients = wait_for_clients(port, 2, Duration::from_secs(10)).await?;
+    assert!(clients.iter().all(|client| client.hostname == "twin"));
+
+    let response = send(
+        port,
+        LocalCommand::Ping {
+            target: "twin".to_string(),
+            session_id: None,
+        },
+    )
+    .await?;
+    match response {
+        LocalResponse::Error { message } => assert!(message.contains("ambiguous"), "{}", message),
+        other => panic!("expected ambiguity error, got {:?}", other),
+    }
+
+    let response = send(
+        port,
+        LocalCommand::Ping {
+            target: "twin".to_string(),
+            session_id: Some(clients[0].session_id.clone()),
+        },
+    )
+    .await?;
+    assert!(
+        matches!(response, LocalResponse::Success { .. }),
+        "unexpected response: {:?}",
+        response
+    );
+
+    // A session id has to belong to the named host
+    let response = send(
+        port,
+        LocalCommand::Ping {
+            target: "other".to_string(),
+            session_id: Some(clients[0].session_id.clone()),
+        },
+    )
+    .await?;
+    assert!(
+        matches!(response, LocalResponse::Error { .. }),
+        "unexpected response: {:?}",
+        response
+    );
+
+    for task in client_tasks {
+        task.abort();
+    }
+    server_task.abort();
+    Ok(())
+}
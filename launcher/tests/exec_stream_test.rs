@@ -0,0 +1,168 @@
+// Integration test for relaying a command's output back to the control caller
+//
+// `exec --stream` turns the launcher into a remote build runner, so this test:
+// 1. Connects a daemon and runs a command that writes to stdout and stderr
+// 2. Checks the output arrives as ExecOutput responses, ending with the exit code
+// 3. Checks a client that disconnects mid-command ends the stream with an error
+// 4. Runs a command with a working directory and environment, checking the explicit
+//    environment wins over a VAR=value argument
+#![cfg(unix)]
+
+use anyhow::Result;
+use futures::StreamExt;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+mod common;
+
+// Start a daemon named "runner" and wait until it has registered
+async fn start_client(port: u16) -> Result<JoinHandle<()>> {
+    let task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "runner".to_string(),
+        )
+        .with_initial_sync(false),
+    );
+
+    let start = Instant::now();
+    loop {
+        let response =
+            SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+                .await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && !clients.is_empty()
+        {
+            return Ok(task);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+fn shell(script: &str) -> LocalCommand {
+    LocalCommand::Execute {
+        target: "runner".to_string(),
+        binary: "sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+        session_id: None,
+        stream: true,
+        working_dir: None,
+        env: Default::default(),
+    }
+}
+
+// Read every response for a streamed Execute, in order
+async fn collect(port: u16, command: LocalCommand, on_first: impl FnOnce()) -> Result<Vec<LocalResponse>> {
+    let responses =
+        SshClientConnection::send_control_command_streaming("localhost", port, "testuser", command, None, None)
+            .await?;
+    let mut responses = std::pin::pin!(responses);
+
+    let mut collected = Vec::new();
+    let mut on_first = Some(on_first);
+    while let Some(response) = tokio::time::timeout(Duration::from_secs(20), responses.next()).await? {
+        collected.push(response?);
+        if let Some(on_first) = on_first.take() {
+            on_first();
+        }
+    }
+    Ok(collected)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_exec_stream_relays_output_and_exit_code() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+    let client_task = start_client(port).await?;
+
+    let responses = collect(port, shell("printf 'building\\n'; printf 'warning' >&2; exit 3"), || {}).await?;
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    for response in &responses[..responses.len() - 1] {
+        match response {
+            LocalResponse::ExecOutput { stderr: false, data } => stdout.extend_from_slice(data),
+            LocalResponse::ExecOutput { stderr: true, data } => stderr.extend_from_slice(data),
+            other => panic!("unexpected response before the exit: {:?}", other),
+        }
+    }
+    assert_eq!(stdout, b"building\n");
+    assert_eq!(stderr, b"warning");
+    match responses.last() {
+        Some(LocalResponse::ExecExit { exit_code, error }) => {
+            assert_eq!(*exit_code, 3);
+            assert_eq!(*error, None);
+        }
+        other => panic!("expected ExecExit, got {:?}", other),
+    }
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_exec_stream_reports_client_disconnect() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+    let client_task = start_client(port).await?;
+
+    // Drop the client as soon as the command has started producing output
+    let abort = client_task.abort_handle();
+    let responses = collect(port, shell("echo started; sleep 30"), move || abort.abort()).await?;
+
+    match responses.last() {
+        Some(LocalResponse::Error { message }) => {
+            assert!(message.contains("disconnected"), "{}", message)
+        }
+        other => panic!("expected a disconnect error, got {:?}", other),
+    }
+
+    server_task.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_exec_runs_in_working_dir_with_env() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+    let client_task = start_client(port).await?;
+
+    let dir = tempfile::TempDir::new()?;
+    let command = LocalCommand::Execute {
+        target: "runner".to_string(),
+        binary: "sh".to_string(),
+        args: vec![
+            "-c".to_string(),
+            "pwd -P; printf '%s %s' \"$GREETING\" \"$TARGET\"".to_string(),
+            "GREETING=inline".to_string(),
+            "TARGET=release".to_string(),
+        ],
+        session_id: None,
+        stream: true,
+        working_dir: Some(dir.path().to_string_lossy().to_string()),
+        env: [("GREETING".to_string(), "explicit".to_string())].into(),
+    };
+
+    let responses = collect(port, command, || {}).await?;
+    let mut stdout = Vec::new();
+    for response in &responses {
+        if let LocalResponse::ExecOutput { stderr: false, data } = response {
+            stdout.extend_from_slice(data);
+        }
+    }
+    let expected = format!("{}\nexplicit release", dir.path().canonicalize()?.display());
+    assert_eq!(String::from_utf8_lossy(&stdout), expected);
+    assert!(matches!(responses.last(), Some(LocalResponse::ExecExit { exit_code: 0, .. })));
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
@@ -1,19 +1,10 @@
 
 use anyhow::{Context, Result};
-use halfremembered_launcher::ssh_server::SshServer;
+use halfremembered_launcher::ssh_server::ServerOptions;
 use serial_test::serial;
-use std::path::PathBuf;
-use std::time::Duration;
 use tempfile::TempDir;
-use tokio::net::TcpStream;
-use tokio::time::sleep;
-
-// Helper function to find an available port
-async fn find_available_port() -> Result<u16> {
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
-    let addr = listener.local_addr()?;
-    Ok(addr.port())
-}
+
+mod common;
 
 struct TestFixture {
     _server_watch_dir: TempDir,
@@ -23,29 +14,9 @@ struct TestFixture {
 }
 
 async fn setup_test() -> Result<TestFixture> {
-    let port = find_available_port().await?;
+    let (port, _server_task) = common::start_server(ServerOptions::default()).await?;
     let user = std::env::var("USER").unwrap_or_else(|_| "testuser".to_string());
 
-    // Server setup
-    tokio::spawn(async move {
-        if let Err(e) = SshServer::run(port).await {
-            log::error!("Server failed: {:?}", e);
-        }
-    });
-
-    // Wait for server to start
-    let mut attempts = 0;
-    while attempts < 10 {
-        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
-            break;
-        }
-        sleep(Duration::from_millis(100)).await;
-        attempts += 1;
-    }
-    if attempts == 10 {
-        anyhow::bail!("Server did not start in time");
-    }
-
     Ok(TestFixture {
         _server_watch_dir: TempDir::new()?,
         _client_output_dir: TempDir::new()?,
@@ -120,6 +91,8 @@ async fn test_fd_leak_on_sync() -> Result<()> {
         let sync_command = halfremembered_protocol::LocalCommand::SyncFile {
             file: file_path.to_string_lossy().to_string(),
             destination: format!("file_{}.txt", i),
+            allow_partial: false,
+            force: false,
         };
 
         // This will fail if the server process crashes due to FD exhaustion
@@ -134,7 +107,7 @@ async fn test_fd_leak_on_sync() -> Result<()> {
         .context(format!("Failed to send sync command for file {}", i))?;
 
         match response {
-            halfremembered_protocol::LocalResponse::Success { .. } => {
+            halfremembered_protocol::LocalResponse::SyncReport { accepted: true, .. } => {
                 // Expected
             }
             _ => {
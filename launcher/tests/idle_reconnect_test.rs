@@ -0,0 +1,126 @@
+// Integration test for --idle-reconnect
+//
+// A connection can die without either end closing it, leaving the client waiting on
+// the hour-long SSH timeout. This test runs a stand-in server that:
+// 1. Accepts a daemon's registration and then never sends anything
+// 2. Checks the daemon gives up on the silent connection once --idle-reconnect
+//    passes, reporting why, and registers again
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::{ClientDaemon, DaemonEvent};
+use halfremembered_protocol::{ClientMessage, MessageBuffer};
+use rand_core::OsRng;
+use russh::server::{Auth, Msg, Server as _, Session};
+use russh::{Channel, ChannelId};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+mod common;
+
+const IDLE_RECONNECT: Duration = Duration::from_millis(800);
+
+// Accepts any key and forwards every control message it receives to the test
+struct SilentServer {
+    received: mpsc::UnboundedSender<ClientMessage>,
+}
+
+impl russh::server::Server for SilentServer {
+    type Handler = SilentSession;
+
+    fn new_client(&mut self, _addr: Option<std::net::SocketAddr>) -> SilentSession {
+        SilentSession {
+            received: self.received.clone(),
+            buffer: MessageBuffer::new(),
+        }
+    }
+}
+
+struct SilentSession {
+    received: mpsc::UnboundedSender<ClientMessage>,
+    buffer: MessageBuffer,
+}
+
+impl russh::server::Handler for SilentSession {
+    type Error = russh::Error;
+
+    async fn auth_publickey(&mut self, _user: &str, _key: &russh::keys::PublicKey) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn data(&mut self, _channel: ChannelId, data: &[u8], _session: &mut Session) -> Result<(), Self::Error> {
+        self.buffer.append(data);
+        while let Ok(Some(msg)) = self.buffer.try_parse_client_message() {
+            let _ = self.received.send(msg);
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_daemon_replaces_silent_connection() -> Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let (sender, mut received) = mpsc::unbounded_channel();
+
+    let config = russh::server::Config {
+        keys: vec![russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)?],
+        ..Default::default()
+    };
+    let server_task = tokio::spawn(async move {
+        let mut server = SilentServer { received: sender };
+        let _ = server.run_on_socket(Arc::new(config), &listener).await;
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "idle".to_string(),
+        )
+        .with_initial_sync(false)
+        .with_reconnect_delay(Duration::from_millis(100))
+        .with_idle_reconnect(Some(IDLE_RECONNECT))
+        .with_event_handler(move |event| recorded.lock().unwrap().push(event.clone())),
+    );
+
+    // The daemon's own heartbeats don't count: only the server's silence matters
+    let mut registrations = Vec::new();
+    while registrations.len() < 2 {
+        let msg = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await?
+            .expect("server stopped");
+        if let ClientMessage::Register { .. } = msg {
+            registrations.push(Instant::now());
+        }
+    }
+
+    let silence = registrations[1] - registrations[0];
+    assert!(silence >= IDLE_RECONNECT, "reconnected after only {:?}", silence);
+
+    let reason = events
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|event| match event {
+            DaemonEvent::Disconnected { reason } => Some(reason.clone()),
+            _ => None,
+        })
+        .expect("the silent connection should be reported disconnected");
+    assert!(reason.contains("Nothing received from the server"), "{}", reason);
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
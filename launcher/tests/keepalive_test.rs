@@ -0,0 +1,64 @@
+// Integration test for server keepalives
+//
+// Keepalives check the connection without asking for a client's full status, so this test:
+// 1. Starts a server that sends a keepalive every 200ms
+// 2. Connects a daemon whose own heartbeats are an hour apart
+// 3. Checks the daemon's pongs keep its last heartbeat fresh
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ClientInfo, LocalCommand, LocalResponse};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+
+async fn list_clients(port: u16) -> Result<Vec<ClientInfo>> {
+    match SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+        .await?
+    {
+        LocalResponse::ClientList { clients } => Ok(clients),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pongs_refresh_last_heartbeat() -> Result<()> {
+    let options = ServerOptions {
+        keepalive_interval: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (port, server_task) = common::start_server(options).await?;
+
+    // Only the heartbeat sent on connecting arrives during the test
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "quiet".to_string(),
+        )
+        .with_heartbeat_interval(Duration::from_secs(3600))
+        .with_initial_sync(false),
+    );
+
+    let start = Instant::now();
+    while list_clients(port).await?.is_empty() {
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // Long past the connecting heartbeat, only pongs can keep this under a second
+    sleep(Duration::from_millis(2500)).await;
+    let clients = list_clients(port).await?;
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].last_heartbeat, 0, "no pong for {}s", clients[0].last_heartbeat);
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// Integration test for listing and killing a client's exec processes
+//
+// A hung command would otherwise run until the client restarts, so this test:
+// 1. Connects a daemon and starts a streamed command that never finishes on its own
+// 2. Checks `processes` lists it, and that the daemon still answers while it runs
+// 3. Checks killing an unknown request_id fails, then kills the command and checks
+//    the stream ends with the kill's exit code and the process is no longer listed
+#![cfg(unix)]
+
+use anyhow::Result;
+use futures::StreamExt;
+use halfremembered_launcher::client_daemon::{ClientDaemon, EXEC_KILLED_EXIT_CODE};
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ExecProcess, LocalCommand, LocalResponse};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+
+async fn control(port: u16, command: LocalCommand) -> Result<LocalResponse> {
+    SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await
+}
+
+async fn list_processes(port: u16) -> Result<Vec<ExecProcess>> {
+    let command = LocalCommand::ListProcesses {
+        target: "worker".to_string(),
+        session_id: None,
+    };
+    match control(port, command).await? {
+        LocalResponse::ProcessList { processes, .. } => Ok(processes),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+// Poll the process list until `done` accepts it
+async fn wait_for_processes(port: u16, done: impl Fn(&[ExecProcess]) -> bool) -> Result<Vec<ExecProcess>> {
+    let start = Instant::now();
+    loop {
+        if let Ok(processes) = list_processes(port).await
+            && done(&processes)
+        {
+            return Ok(processes);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for the process list");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+fn kill(request_id: &str) -> LocalCommand {
+    LocalCommand::KillProcess {
+        target: "worker".to_string(),
+        session_id: None,
+        request_id: request_id.to_string(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_kill_stops_listed_process() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "worker".to_string(),
+        )
+        .with_initial_sync(false),
+    );
+    wait_for_processes(port, |_| true).await?;
+
+    let exec = LocalCommand::Execute {
+        target: "worker".to_string(),
+        binary: "sh".to_string(),
+        args: vec!["-c".to_string(), "sleep 30".to_string()],
+        session_id: None,
+        stream: true,
+        working_dir: None,
+        env: Default::default(),
+    };
+    let stream_task = tokio::spawn(async move {
+        let responses =
+            SshClientConnection::send_control_command_streaming("localhost", port, "testuser", exec, None, None)
+                .await?;
+        let mut responses = std::pin::pin!(responses);
+        let mut last = None;
+        while let Some(response) = tokio::time::timeout(Duration::from_secs(20), responses.next()).await? {
+            last = Some(response?);
+        }
+        anyhow::Ok(last)
+    });
+
+    // Listing at all shows the daemon isn't blocked on the running command
+    let processes = wait_for_processes(port, |processes| !processes.is_empty()).await?;
+    assert_eq!(processes.len(), 1);
+    let process = &processes[0];
+    assert_eq!(process.binary, "sh");
+    assert_eq!(process.args, vec!["-c".to_string(), "sleep 30".to_string()]);
+    assert!(process.pid.is_some());
+
+    match control(port, kill("exec-unknown")).await? {
+        LocalResponse::Error { message } => assert!(message.contains("not running"), "{}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let started = Instant::now();
+    match control(port, kill(&process.request_id)).await? {
+        LocalResponse::Success { .. } => {}
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    match stream_task.await?? {
+        Some(LocalResponse::ExecExit { exit_code, error }) => {
+            assert_eq!(exit_code, EXEC_KILLED_EXIT_CODE);
+            assert_eq!(error.as_deref(), Some("Killed on request"));
+        }
+        other => anyhow::bail!("Unexpected last response: {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(10));
+    wait_for_processes(port, |processes| processes.is_empty()).await?;
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,127 @@
+// Integration test for the client's --no-clobber-local
+//
+// A file edited on the client must survive the next sync unless the server forces it,
+// so this test:
+// 1. Connects a daemon with --no-clobber-local and syncs a file to it
+// 2. Edits the client's copy, syncs a new version, and checks the daemon refuses it
+//    as a conflict, keeping the edit
+// 3. Syncs again with force, which replaces the edited copy
+// 4. Syncs a further version, which applies normally since nothing was edited
+// 5. Checks the synced checksums were kept under the state directory
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_launcher::sync_log::SyncLogEntry;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+async fn sync(port: u16, file: &Path, force: bool) -> Result<()> {
+    let command = LocalCommand::SyncFile {
+        file: file.to_string_lossy().to_string(),
+        destination: "config.ini".to_string(),
+        allow_partial: false,
+        force,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await? {
+        LocalResponse::SyncReport { accepted: true, .. } => Ok(()),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+// Polling helper: wait for the sync log to hold `count` entries, returning the last
+async fn wait_for_entry(path: &Path, count: usize) -> Result<SyncLogEntry> {
+    let start = Instant::now();
+    loop {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        if content.lines().count() >= count {
+            let line = content.lines().nth(count - 1).unwrap();
+            return Ok(serde_json::from_str(line)?);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for {} entries in {}", count, path.display());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_local_edits_are_kept_unless_forced() -> Result<()> {
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+    let state_dir = TempDir::new()?;
+    let sync_log = state_dir.path().join("syncs.jsonl");
+    let source = source_dir.path().join("config.ini");
+    let local = client_dir.path().join("config.ini");
+    std::fs::write(&source, b"volume=5")?;
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let working_dir = client_dir.path().to_path_buf();
+    let daemon_state = state_dir.path().to_path_buf();
+    let daemon_log = sync_log.clone();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "careful".to_string(),
+        )
+        .with_working_dir(working_dir)
+        .with_initial_sync(false)
+        .with_state_dir(Some(daemon_state))
+        .with_sync_log(Some(daemon_log))
+        .with_no_clobber_local(true),
+    );
+
+    let start = Instant::now();
+    loop {
+        let response =
+            SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+                .await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && !clients.is_empty()
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    sync(port, &source, false).await?;
+    assert!(wait_for_entry(&sync_log, 1).await?.success);
+    assert_eq!(std::fs::read(&local)?, b"volume=5");
+
+    std::fs::write(&local, b"volume=11")?;
+    std::fs::write(&source, b"volume=6")?;
+    sync(port, &source, false).await?;
+    let refused = wait_for_entry(&sync_log, 2).await?;
+    assert!(!refused.success);
+    let error = refused.error.unwrap_or_default();
+    assert!(error.starts_with("Conflict:"), "{}", error);
+    assert_eq!(std::fs::read(&local)?, b"volume=11");
+
+    sync(port, &source, true).await?;
+    assert!(wait_for_entry(&sync_log, 3).await?.success);
+    assert_eq!(std::fs::read(&local)?, b"volume=6");
+
+    std::fs::write(&source, b"volume=7")?;
+    sync(port, &source, false).await?;
+    assert!(wait_for_entry(&sync_log, 4).await?.success);
+    assert_eq!(std::fs::read(&local)?, b"volume=7");
+
+    let synced = std::fs::read_to_string(state_dir.path().join(format!("synced-localhost-{}.toml", port)))?;
+    assert!(synced.contains("config.ini"), "{}", synced);
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,52 @@
+// Integration test for starting the server on an OS-assigned port
+//
+// With `--port 0` the only way to find the server is the port file, so this test:
+// 1. Starts the server on port 0 with a port file
+// 2. Reads the real address back from the file
+// 3. Sends a control command to that port
+
+use anyhow::Result;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::{ServerOptions, SshServer};
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_port_zero_writes_bound_address() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let port_file = temp_dir.path().join("server.port");
+
+    let server_port_file = port_file.clone();
+    let server_task = tokio::spawn(async move {
+        let options = ServerOptions {
+            port_file: Some(server_port_file),
+            ..Default::default()
+        };
+        SshServer::run_with_options(0, options)
+            .await
+            .expect("Server failed to start");
+    });
+
+    let addr = common::wait_for_port_file(&port_file, Duration::from_secs(5)).await?;
+    assert_ne!(addr.port(), 0, "port file should hold the OS-assigned port");
+
+    let response = SshClientConnection::send_control_command(
+        "localhost",
+        addr.port(),
+        "testuser",
+        LocalCommand::Status,
+        None,
+    )
+    .await?;
+    assert!(
+        matches!(response, LocalResponse::Status { .. }),
+        "unexpected response: {:?}",
+        response
+    );
+
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,145 @@
+// Integration test for resuming an interrupted initial sync
+//
+// A big initial sync cut off partway shouldn't start over, so this test:
+// 1. Watches a directory of more files than an SSH channel buffers unread, and connects
+//    a daemon with a state directory
+// 2. Stops the daemon once some of the files have been delivered
+// 3. Starts a new daemon on the same state and working directories
+// 4. Checks every file arrives, and none delivered before the interruption is sent again
+// 5. Checks the synced checksums were kept under the state directory
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_launcher::sync_log::SyncLogEntry;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+mod common;
+
+const FILES: usize = 150;
+
+fn content(idx: usize) -> Vec<u8> {
+    format!("file {} ", idx).repeat(2048).into_bytes()
+}
+
+fn start_client(port: u16, working_dir: PathBuf, state_dir: PathBuf, sync_log: PathBuf) -> JoinHandle<()> {
+    common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "resumer".to_string(),
+        )
+        .with_working_dir(working_dir)
+        .with_state_dir(Some(state_dir))
+        .with_sync_log(Some(sync_log)),
+    )
+}
+
+fn read_log(path: &Path) -> Result<Vec<SyncLogEntry>> {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interrupted_initial_sync_resumes() -> Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+    let state_dir = TempDir::new()?;
+    let sync_log = state_dir.path().join("syncs.jsonl");
+    for idx in 0..FILES {
+        std::fs::write(source_dir.path().join(format!("file-{:03}.bin", idx)), content(idx))?;
+    }
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let watch = LocalCommand::WatchDirectory {
+        path: source_dir.path().to_string_lossy().to_string(),
+        recursive: true,
+        include_patterns: vec!["*.bin".to_string()],
+        exclude_patterns: vec![],
+        relative_to: None,
+        case_insensitive: false,
+        verify_events: false,
+        destination: None,
+        base: None,
+        settle_ms: 0,
+        priority: 0,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", watch, None).await? {
+        LocalResponse::Watching { .. } => {}
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let client = start_client(
+        port,
+        client_dir.path().to_path_buf(),
+        state_dir.path().to_path_buf(),
+        sync_log.clone(),
+    );
+    let start = Instant::now();
+    while read_log(&sync_log)?.len() < 10 {
+        if start.elapsed() > Duration::from_secs(20) {
+            anyhow::bail!("Timeout waiting for the initial sync to start");
+        }
+        sleep(Duration::from_millis(5)).await;
+    }
+    client.abort();
+    sleep(Duration::from_millis(500)).await;
+
+    let first_run = read_log(&sync_log)?;
+    let delivered: HashSet<String> = first_run
+        .iter()
+        .filter(|entry| entry.success)
+        .map(|entry| entry.relative_path.clone())
+        .collect();
+    assert!(delivered.len() < FILES, "the sync finished before it could be interrupted");
+
+    let client = start_client(
+        port,
+        client_dir.path().to_path_buf(),
+        state_dir.path().to_path_buf(),
+        sync_log.clone(),
+    );
+    let start = Instant::now();
+    loop {
+        let complete = (0..FILES).all(|idx| {
+            std::fs::read(client_dir.path().join(format!("file-{:03}.bin", idx))).is_ok_and(|data| data == content(idx))
+        });
+        if complete {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(30) {
+            anyhow::bail!("Timeout waiting for the resumed sync to finish");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let resumed: Vec<SyncLogEntry> = read_log(&sync_log)?.into_iter().skip(first_run.len()).collect();
+    assert!(!resumed.is_empty());
+    for entry in &resumed {
+        assert!(entry.success, "{:?}", entry);
+        assert!(!delivered.contains(&entry.relative_path), "{} was sent again", entry.relative_path);
+    }
+
+    let synced = std::fs::read_to_string(state_dir.path().join(format!("synced-localhost-{}.toml", port)))?;
+    for entry in &resumed {
+        assert!(synced.contains(&entry.relative_path), "{} missing from {}", entry.relative_path, synced);
+    }
+
+    client.abort();
+    server_task.abort();
+    Ok(())
+}
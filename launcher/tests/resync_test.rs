@@ -0,0 +1,83 @@
+// Integration test for client-requested resyncs
+//
+// A client that finds its copy of a file damaged shouldn't have to wait for the source
+// to change again, so this test:
+// 1. Watches a source directory into `maps/` and lets a daemon's initial sync fetch it
+// 2. Corrupts the client's copy and asks for an unknown path, which is ignored
+// 3. Requests the file through the daemon's ResyncHandle and checks it is repaired
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+// Polling helper: wait for a file to hold `expected`
+async fn wait_for_content(path: &Path, expected: &[u8]) -> Result<()> {
+    let start = Instant::now();
+    while std::fs::read(path).ok().as_deref() != Some(expected) {
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for {}", path.display());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_requested_resync_repairs_local_copy() -> Result<()> {
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+    std::fs::write(source_dir.path().join("level.dat"), b"pristine level data")?;
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let watch = LocalCommand::WatchDirectory {
+        path: source_dir.path().to_string_lossy().to_string(),
+        recursive: true,
+        include_patterns: vec!["*.dat".to_string()],
+        exclude_patterns: Vec::new(),
+        relative_to: None,
+        case_insensitive: false,
+        verify_events: false,
+        destination: Some("maps".to_string()),
+        base: None,
+        settle_ms: 0,
+        priority: 0,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", watch, None).await? {
+        LocalResponse::Watching { .. } => {}
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let daemon = ClientDaemon::new(
+        "localhost".to_string(),
+        port,
+        "testuser".to_string(),
+        "healer".to_string(),
+    )
+    .with_working_dir(client_dir.path().to_path_buf());
+    let resync = daemon.resync_handle();
+    let client_task = common::spawn_daemon(daemon);
+
+    let synced = client_dir.path().join("maps").join("level.dat");
+    wait_for_content(&synced, b"pristine level data").await?;
+
+    std::fs::write(&synced, b"pristine lev\0\0\0\0 data, plus junk")?;
+    assert!(resync.request("maps/missing.dat"));
+    sleep(Duration::from_millis(500)).await;
+    assert!(!client_dir.path().join("maps").join("missing.dat").exists());
+
+    assert!(resync.request("maps/level.dat"));
+    wait_for_content(&synced, b"pristine level data").await?;
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
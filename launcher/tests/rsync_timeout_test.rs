@@ -0,0 +1,173 @@
+// Integration test for a server that stops answering mid-transfer
+//
+// A server that dies or wedges after RsyncStart must not stall the client for good,
+// so this test runs a stand-in server that:
+// 1. Answers a daemon's registration with an RsyncStart
+// 2. Never sends anything on the rsync channel the daemon opens for it
+// 3. Checks the daemon reports the transfer failed once --rsync-timeout passes,
+//    and still answers a ping afterwards
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_protocol::{ChecksumAlgo, ClientMessage, MessageBuffer, ServerMessage};
+use rand_core::OsRng;
+use russh::server::{Auth, Msg, Server as _, Session};
+use russh::{Channel, ChannelId};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+mod common;
+
+const RSYNC_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Accepts any key and forwards every control message it receives to the test
+struct StalledServer {
+    received: mpsc::UnboundedSender<ClientMessage>,
+}
+
+impl russh::server::Server for StalledServer {
+    type Handler = StalledSession;
+
+    fn new_client(&mut self, _addr: Option<std::net::SocketAddr>) -> StalledSession {
+        StalledSession {
+            received: self.received.clone(),
+            control: None,
+            buffer: MessageBuffer::new(),
+        }
+    }
+}
+
+struct StalledSession {
+    received: mpsc::UnboundedSender<ClientMessage>,
+    control: Option<ChannelId>,
+    buffer: MessageBuffer,
+}
+
+impl StalledSession {
+    fn send(session: &mut Session, channel: ChannelId, msg: &ServerMessage) {
+        let mut framed = Vec::new();
+        msg.write_framed(&mut framed).unwrap();
+        let _ = session.data(channel, framed.into());
+    }
+}
+
+impl russh::server::Handler for StalledSession {
+    type Error = russh::Error;
+
+    async fn auth_publickey(&mut self, _user: &str, _key: &russh::keys::PublicKey) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        // Later channels are rsync channels, left unanswered
+        if self.control.is_none() {
+            self.control = Some(channel.id());
+        }
+        Ok(true)
+    }
+
+    async fn data(&mut self, channel: ChannelId, data: &[u8], session: &mut Session) -> Result<(), Self::Error> {
+        if Some(channel) != self.control {
+            return Ok(());
+        }
+
+        self.buffer.append(data);
+        while let Ok(Some(msg)) = self.buffer.try_parse_client_message() {
+            match msg {
+                ClientMessage::Register { .. } => {
+                    let start = ServerMessage::RsyncStart {
+                        request_id: "stalled".to_string(),
+                        relative_path: "stalled.txt".to_string(),
+                        size: 5,
+                        checksum: "unused".to_string(),
+                        mtime: 0,
+                        block_size: 1024,
+                        mode: 0o644,
+                        dir_mode: None,
+                        whole_file: true,
+                        checksum_algo: ChecksumAlgo::Blake3,
+                        atomic_replace: false,
+                        priority: 0,
+                        force: false,
+                    };
+                    Self::send(session, channel, &start);
+                }
+                ClientMessage::RsyncComplete { .. } => {
+                    let ping = ServerMessage::Ping {
+                        request_id: "after-timeout".to_string(),
+                    };
+                    Self::send(session, channel, &ping);
+                }
+                _ => {}
+            }
+            let _ = self.received.send(msg);
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_abandons_transfer_with_no_delta() -> Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let (sender, mut received) = mpsc::unbounded_channel();
+
+    let config = russh::server::Config {
+        keys: vec![russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)?],
+        ..Default::default()
+    };
+    let server_task = tokio::spawn(async move {
+        let mut server = StalledServer { received: sender };
+        let _ = server.run_on_socket(Arc::new(config), &listener).await;
+    });
+
+    let dir = tempfile::tempdir()?;
+    let working_dir = dir.path().to_path_buf();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "stalled".to_string(),
+        )
+        .with_working_dir(working_dir)
+        .with_initial_sync(false)
+        .with_rsync_timeout(RSYNC_TIMEOUT),
+    );
+
+    let mut started = None;
+    let mut failure = None;
+    let mut answered_ping = false;
+    while !answered_ping {
+        let msg = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await?
+            .expect("server stopped");
+        match msg {
+            ClientMessage::Register { .. } => started = Some(Instant::now()),
+            ClientMessage::RsyncComplete { success, error, .. } => {
+                assert!(!success);
+                failure = error;
+                let elapsed = started.expect("registered first").elapsed();
+                assert!(elapsed >= RSYNC_TIMEOUT, "gave up after only {:?}", elapsed);
+            }
+            ClientMessage::Status { request_id, .. } => {
+                assert_eq!(request_id, "after-timeout");
+                answered_ping = true;
+            }
+            _ => {}
+        }
+    }
+
+    let failure = failure.expect("the transfer should be reported failed");
+    assert!(failure.contains("abandoning"), "{}", failure);
+    assert!(!dir.path().join("stalled.txt").exists());
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
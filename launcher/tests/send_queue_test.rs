@@ -0,0 +1,124 @@
+// Integration test for per-client send queues
+//
+// A client that stops reading must not hold up sends to the others, so this test:
+// 1. Starts a server with a small send queue that sends keepalives every 20ms
+// 2. Connects a daemon that reads normally and a stand-in client that never reads
+// 3. Checks the stand-in is evicted once its queue fills, while the daemon stays
+//    registered and reports its queue depth
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ClientInfo, ClientMessage, LocalCommand, LocalResponse};
+use russh::client;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+use common::AcceptingHandler;
+
+const SEND_QUEUE_DEPTH: usize = 4;
+
+async fn list_clients(port: u16) -> Result<Vec<ClientInfo>> {
+    match SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+        .await?
+    {
+        LocalResponse::ClientList { clients } => Ok(clients),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+// Poll the client list until `done` accepts it
+async fn wait_for_clients(port: u16, done: impl Fn(&[ClientInfo]) -> bool) -> Result<Vec<ClientInfo>> {
+    let start = Instant::now();
+    loop {
+        let clients = list_clients(port).await?;
+        if done(&clients) {
+            return Ok(clients);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for clients; have {:?}", clients);
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Register as `hostname`, then never read: with a tiny window and a one-message
+// channel buffer, the connection stops taking data almost at once
+async fn connect_wedged_client(port: u16, hostname: &str) -> Result<client::Handle<AcceptingHandler>> {
+    let config = client::Config {
+        window_size: 1024,
+        channel_buffer_size: 1,
+        ..Default::default()
+    };
+    let mut session = client::connect(Arc::new(config), ("localhost", port), AcceptingHandler).await?;
+
+    let mut agent = russh::keys::agent::client::AgentClient::connect_env().await?;
+    let mut authenticated = false;
+    for key in agent.request_identities().await? {
+        if session
+            .authenticate_publickey_with("testuser", key, None, &mut agent)
+            .await?
+            .success()
+        {
+            authenticated = true;
+            break;
+        }
+    }
+    anyhow::ensure!(authenticated, "no agent identity was accepted");
+
+    let channel = session.channel_open_session().await?;
+    let register = ClientMessage::Register {
+        hostname: hostname.to_string(),
+        platform: "test".to_string(),
+        initial_sync: false,
+        resume_token: None,
+        capabilities: vec![],
+    };
+    let mut framed = Vec::new();
+    register.write_framed(&mut framed)?;
+    channel.data(framed.as_slice()).await?;
+
+    // The channel stays open but unread for as long as the session is held
+    std::mem::forget(channel);
+    Ok(session)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wedged_client_is_evicted() -> Result<()> {
+    let options = ServerOptions {
+        keepalive_interval: Some(Duration::from_millis(20)),
+        send_queue_depth: Some(SEND_QUEUE_DEPTH),
+        ..Default::default()
+    };
+    let (port, server_task) = common::start_server(options).await?;
+
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "steady".to_string(),
+        )
+        .with_initial_sync(false),
+    );
+    wait_for_clients(port, |clients| clients.iter().any(|c| c.hostname == "steady")).await?;
+
+    let _wedged = connect_wedged_client(port, "wedged").await?;
+    wait_for_clients(port, |clients| clients.iter().any(|c| c.hostname == "wedged")).await?;
+
+    // Keepalives pile up behind the unread window until the queue overflows
+    let clients = wait_for_clients(port, |clients| clients.iter().all(|c| c.hostname != "wedged")).await?;
+    assert_eq!(clients.len(), 1);
+    let steady = &clients[0];
+    assert_eq!(steady.hostname, "steady");
+    assert_eq!(steady.send_queue_capacity, SEND_QUEUE_DEPTH as u64);
+    assert!(steady.send_queue_peak >= 1);
+    assert!(steady.send_queue_depth <= steady.send_queue_capacity);
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
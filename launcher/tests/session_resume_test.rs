@@ -0,0 +1,131 @@
+// Integration test for resuming a session with the token from its Welcome
+//
+// A client on a flapping link should stay the same registry entry across reconnects,
+// so this test:
+// 1. Registers a client and records a heartbeat gap, a stat a new session wouldn't have
+// 2. Reconnects with its resume token while the first connection is still registered,
+//    and checks the new session replaces it, keeping the stat
+// 3. Disconnects, then resumes again from the token the second Welcome gave
+// 4. Checks an already-used token registers a new client instead
+// 5. Separately, checks a live session's token presented by another hostname registers
+//    that client anew and leaves the live session connected
+
+use anyhow::Result;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ClientInfo, ClientMessage, LocalCommand, LocalResponse, ServerMessage};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+
+// Poll the client list until `done` accepts it
+async fn wait_for_clients(port: u16, done: impl Fn(&[ClientInfo]) -> bool) -> Result<Vec<ClientInfo>> {
+    let start = Instant::now();
+    loop {
+        if let Ok(LocalResponse::ClientList { clients }) =
+            SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+                .await
+            && done(&clients)
+        {
+            return Ok(clients);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for clients");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Register as `hostname`, returning the connection with its Welcome's session id,
+// resume token and whether it resumed
+async fn register(
+    port: u16,
+    hostname: &str,
+    resume_token: Option<&str>,
+) -> Result<(SshClientConnection, String, String, bool)> {
+    let connection = SshClientConnection::connect("localhost", port, "testuser", None).await?;
+    connection.send_register(hostname, false, resume_token).await?;
+
+    let start = Instant::now();
+    loop {
+        if let Some(ServerMessage::Welcome {
+            session_id,
+            resume_token,
+            resumed,
+            ..
+        }) = connection.try_receive_message().await?
+        {
+            return Ok((connection, session_id, resume_token, resumed));
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for Welcome");
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reconnect_resumes_session_with_token() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let (first, first_session, first_token, resumed) = register(port, "flappy", None).await?;
+    assert!(!resumed);
+    first
+        .send_message(&ClientMessage::Heartbeat { timestamp: 0, sequence: 5 })
+        .await?;
+    wait_for_clients(port, |clients| clients.len() == 1 && clients[0].heartbeat_gaps == 1).await?;
+
+    // The server hasn't seen the first connection drop; resuming replaces it
+    let (second, second_session, second_token, resumed) = register(port, "flappy", Some(&first_token)).await?;
+    assert!(resumed);
+    assert_ne!(second_token, first_token);
+    let clients = wait_for_clients(port, |clients| clients.len() == 1 && clients[0].session_id == second_session).await?;
+    assert_eq!(clients[0].heartbeat_gaps, 1);
+    assert_ne!(second_session, first_session);
+
+    second.disconnect().await;
+    wait_for_clients(port, |clients| clients.is_empty()).await?;
+    let (_third, third_session, _, resumed) = register(port, "flappy", Some(&second_token)).await?;
+    assert!(resumed);
+    let clients = wait_for_clients(port, |clients| clients.len() == 1).await?;
+    assert_eq!(clients[0].session_id, third_session);
+    assert_eq!(clients[0].heartbeat_gaps, 1);
+
+    // Each token resumes once
+    let (_fourth, fourth_session, _, resumed) = register(port, "flappy", Some(&first_token)).await?;
+    assert!(!resumed);
+    let clients = wait_for_clients(port, |clients| clients.len() == 2).await?;
+    let fourth = clients.iter().find(|client| client.session_id == fourth_session).unwrap();
+    assert_eq!(fourth.heartbeat_gaps, 0);
+
+    drop(first);
+    server_task.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_token_from_another_host_leaves_live_session() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let (live, live_session, live_token, _) = register(port, "flappy", None).await?;
+    wait_for_clients(port, |clients| clients.len() == 1).await?;
+
+    let (_intruder, intruder_session, _, resumed) = register(port, "intruder", Some(&live_token)).await?;
+    assert!(!resumed);
+    let clients = wait_for_clients(port, |clients| clients.len() == 2).await?;
+    assert!(clients.iter().any(|client| client.session_id == live_session));
+    assert!(clients.iter().any(|client| client.session_id == intruder_session));
+
+    // Still served: a heartbeat is recorded against the live session
+    live.send_message(&ClientMessage::Heartbeat { timestamp: 0, sequence: 5 })
+        .await?;
+    wait_for_clients(port, |clients| {
+        clients
+            .iter()
+            .any(|client| client.session_id == live_session && client.heartbeat_gaps == 1)
+    })
+    .await?;
+
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,130 @@
+// Integration test for the client's --sync-log
+//
+// The sync log is meant to be parsed, so this test:
+// 1. Connects a daemon that records its syncs and syncs a file to it
+// 2. Sends a sync to an absolute destination, which the daemon refuses
+// 3. Checks the log holds one parseable line per sync, with the outcome of each
+// 4. Removes the log and checks the next sync starts a new one
+// 5. Syncs the same content again, which the daemon already has and logs as 0 bytes
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_launcher::sync_log::SyncLogEntry;
+use halfremembered_protocol::{LocalCommand, LocalResponse};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+async fn sync(port: u16, file: &Path, destination: &str) -> Result<()> {
+    let command = LocalCommand::SyncFile {
+        file: file.to_string_lossy().to_string(),
+        destination: destination.to_string(),
+        allow_partial: false,
+        force: false,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await? {
+        LocalResponse::SyncReport { accepted: true, .. } => Ok(()),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+// Polling helper: wait for the log to hold `count` entries
+async fn wait_for_entries(path: &Path, count: usize) -> Result<Vec<SyncLogEntry>> {
+    let start = Instant::now();
+    loop {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        if content.lines().count() >= count {
+            return content
+                .lines()
+                .map(|line| Ok(serde_json::from_str(line)?))
+                .collect();
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for {} entries in {}", count, path.display());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sync_log_records_each_sync() -> Result<()> {
+    let source_dir = TempDir::new()?;
+    let client_dir = TempDir::new()?;
+    let log_dir = TempDir::new()?;
+    let sync_log = log_dir.path().join("syncs.jsonl");
+    let source = source_dir.path().join("level.dat");
+    std::fs::write(&source, b"level data")?;
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let working_dir = client_dir.path().to_path_buf();
+    let daemon_log = sync_log.clone();
+    let client_task = common::spawn_daemon(
+        ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            "recorder".to_string(),
+        )
+        .with_working_dir(working_dir)
+        .with_initial_sync(false)
+        .with_sync_log(Some(daemon_log)),
+    );
+
+    let start = Instant::now();
+    loop {
+        let response =
+            SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+                .await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && !clients.is_empty()
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for client to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    sync(port, &source, "level.dat").await?;
+    let entries = wait_for_entries(&sync_log, 1).await?;
+    let first = &entries[0];
+    assert_eq!(first.relative_path, "level.dat");
+    assert!(first.success);
+    assert_eq!(first.bytes, b"level data".len() as u64);
+    assert!(!first.checksum.is_empty());
+    assert_eq!(first.error, None);
+
+    let absolute = client_dir.path().join("elsewhere.dat").to_string_lossy().to_string();
+    sync(port, &source, &absolute).await.ok();
+    let entries = wait_for_entries(&sync_log, 2).await?;
+    let refused = entries.last().unwrap();
+    assert_eq!(refused.relative_path, absolute);
+    assert!(!refused.success);
+    assert_eq!(refused.bytes, 0);
+    assert!(refused.error.is_some());
+
+    // Rotating the log away doesn't stop the daemon recording
+    std::fs::remove_file(&sync_log)?;
+    std::fs::write(&source, b"level data, revised")?;
+    sync(port, &source, "level.dat").await?;
+    let entries = wait_for_entries(&sync_log, 1).await?;
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].success);
+
+    sync(port, &source, "level.dat").await?;
+    let entries = wait_for_entries(&sync_log, 2).await?;
+    assert!(entries[1].success);
+    assert_eq!(entries[1].bytes, 0);
+    assert_eq!(entries[1].checksum, entries[0].checksum);
+
+    client_task.abort();
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,99 @@
+// Integration test for checksum-only verification of clients' copies
+//
+// Auditing a deploy shouldn't move any data, so this test:
+// 1. Connects two daemons, one holding the expected file and one a stale copy
+// 2. Verifies against every client and checks each verdict and reported checksum
+// 3. Verifies a single target, and a path neither client has
+
+use anyhow::Result;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::rsync_utils::{self, ChecksumAlgo};
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{ClientVerifyResult, LocalCommand, LocalResponse};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+async fn verify(port: u16, target: Option<&str>, path: &str, expected: &str) -> Result<Vec<ClientVerifyResult>> {
+    let command = LocalCommand::VerifyFile {
+        target: target.map(str::to_string),
+        relative_path: path.to_string(),
+        expected_checksum: expected.to_string(),
+        checksum_algo: ChecksumAlgo::Blake3,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await? {
+        LocalResponse::VerifyReport { path: reported, mut results } => {
+            assert_eq!(reported, path);
+            results.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+            Ok(results)
+        }
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_verify_reports_each_clients_copy() -> Result<()> {
+    let alpha_dir = TempDir::new()?;
+    let beta_dir = TempDir::new()?;
+    for dir in [&alpha_dir, &beta_dir] {
+        std::fs::create_dir(dir.path().join("bin"))?;
+    }
+    std::fs::write(alpha_dir.path().join("bin").join("game.exe"), b"release 2")?;
+    std::fs::write(beta_dir.path().join("bin").join("game.exe"), b"release 1")?;
+    let expected = rsync_utils::compute_checksum(ChecksumAlgo::Blake3, b"release 2");
+    let stale = rsync_utils::compute_checksum(ChecksumAlgo::Blake3, b"release 1");
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let mut client_tasks = Vec::new();
+    for (hostname, dir) in [("alpha", &alpha_dir), ("beta", &beta_dir)] {
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            hostname.to_string(),
+        )
+        .with_working_dir(dir.path().to_path_buf())
+        .with_initial_sync(false);
+        client_tasks.push(common::spawn_daemon(daemon));
+    }
+
+    let start = Instant::now();
+    loop {
+        let response = SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None).await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && clients.len() == 2
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for clients to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let results = verify(port, None, "bin/game.exe", &expected).await?;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].hostname, "alpha");
+    assert!(results[0].matches);
+    assert_eq!(results[0].actual_checksum.as_deref(), Some(expected.as_str()));
+    assert_eq!(results[1].hostname, "beta");
+    assert!(!results[1].matches);
+    assert_eq!(results[1].actual_checksum.as_deref(), Some(stale.as_str()));
+
+    let results = verify(port, Some("beta"), "bin/game.exe", &stale).await?;
+    assert_eq!(results.len(), 1);
+    assert!(results[0].matches);
+
+    let results = verify(port, None, "bin/missing.exe", &expected).await?;
+    assert!(results.iter().all(|result| !result.matches && result.actual_checksum.is_none() && result.error.is_some()));
+
+    for task in client_tasks {
+        task.abort();
+    }
+    server_task.abort();
+    Ok(())
+}
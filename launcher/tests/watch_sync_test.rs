@@ -8,13 +8,15 @@
 // 5. Client receives and writes the synced file
 
 use anyhow::Result;
-use std::net::TcpListener;
+use halfremembered_launcher::ssh_server::ServerOptions;
 use std::path::Path;
 use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
+mod common;
+
 // Test fixture that automatically cleans up server and client tasks
 struct TestFixture {
     server_task: JoinHandle<()>,
@@ -32,25 +34,6 @@ impl Drop for TestFixture {
     }
 }
 
-// Get an unused TCP port from the OS
-fn find_free_port() -> Result<u16> {
-    let listener = TcpListener::bind("127.0.0.1:0")?;
-    let addr = listener.local_addr()?;
-    Ok(addr.port())
-}
-
-// Polling helper: wait for a file to exist with timeout
-async fn wait_for_file(path: &Path, timeout: Duration) -> Result<()> {
-    let start = Instant::now();
-    while !path.exists() {
-        if start.elapsed() > timeout {
-            anyhow::bail!("Timeout waiting for file: {}", path.display());
-        }
-        sleep(Duration::from_millis(100)).await;
-    }
-    Ok(())
-}
-
 // Polling helper: wait for file to exist and have expected content
 async fn wait_for_file_content(path: &Path, expected: &str, timeout: Duration) -> Result<()> {
     let start = Instant::now();
@@ -98,42 +81,24 @@ async fn wait_for_file_absence(path: &Path, duration: Duration) -> Result<()> {
     Ok(())
 }
 
-// Polling helper: wait for server to be ready to accept connections
-async fn wait_for_server_ready(port: u16, timeout: Duration) -> Result<()> {
-    let start = Instant::now();
-    loop {
-        if tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
-            log::debug!("Server is accepting connections on port {}", port);
-            return Ok(());
-        }
-
-        if start.elapsed() > timeout {
-            anyhow::bail!("Timeout waiting for server to start");
-        }
-        sleep(Duration::from_millis(100)).await;
-    }
-}
-
 // Polling helper: wait for at least one client to be connected
 async fn wait_for_client_connected(port: u16, user: &str, timeout: Duration) -> Result<()> {
     let start = Instant::now();
     loop {
         let command = halfremembered_protocol::LocalCommand::ListClients;
-        if let Ok(response) = halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
-            "localhost",
-            port,
-            user,
-            command,
-            None,
-        )
-        .await
+        if let Ok(halfremembered_protocol::LocalResponse::ClientList { clients }) =
+            halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
+                "localhost",
+                port,
+                user,
+                command,
+                None,
+            )
+            .await
+            && !clients.is_empty()
         {
-            if let halfremembered_protocol::LocalResponse::ClientList { clients } = response {
-                if !clients.is_empty() {
-                    log::info!("Client connected: {:?}", clients[0].hostname);
-                    return Ok(());
-                }
-            }
+            log::info!("Client connected: {:?}", clients[0].hostname);
+            return Ok(());
         }
 
         if start.elapsed() > timeout {
@@ -181,20 +146,10 @@ include = ["**/*"]
     std::fs::write(server_watch_dir.path().join(".hrlauncher.toml"), config_content)?;
     log::info!("Created temporary .hrlauncher.toml in server watch dir");
 
-    // Use a random high port to avoid conflicts
-    let test_port = find_free_port()?;
+    // Start the server in a background task on an OS-assigned port
+    let (test_port, server_task) = common::start_server(ServerOptions::default()).await?;
     log::info!("Using test port: {}", test_port);
 
-    // Start the server in a background task
-    let server_task = tokio::spawn(async move {
-        halfremembered_launcher::ssh_server::SshServer::run(test_port)
-            .await
-            .expect("Server failed to start");
-    });
-
-    // Wait for server to be ready (poll, not sleep)
-    wait_for_server_ready(test_port, Duration::from_secs(2)).await?;
-
     // Start client daemon in background
     let client_output_path = client_output_dir.path().to_path_buf();
     let hostname = hostname::get()?.to_string_lossy().to_string();
@@ -243,6 +198,13 @@ async fn setup_watch(
         recursive: true,
         include_patterns,
         exclude_patterns,
+        relative_to: None,
+        case_insensitive: false,
+        verify_events: true,
+        destination: None,
+        base: None,
+        settle_ms: 0,
+        priority: 0,
     };
 
     let response = halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
@@ -255,7 +217,7 @@ async fn setup_watch(
     .await?;
 
     match response {
-        halfremembered_protocol::LocalResponse::Success { message } => {
+        halfremembered_protocol::LocalResponse::Watching { message, .. } => {
             log::info!("Watch set up successfully: {}", message);
             Ok(())
         }
@@ -365,6 +327,13 @@ async fn test_watch_single_file() -> Result<()> {
         recursive: false,
         include_patterns: vec![],
         exclude_patterns: vec![],
+        relative_to: None,
+        case_insensitive: false,
+        verify_events: true,
+        destination: None,
+        base: None,
+        settle_ms: 0,
+        priority: 0,
     };
 
     let response = halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
@@ -377,7 +346,7 @@ async fn test_watch_single_file() -> Result<()> {
     .await?;
 
     match response {
-        halfremembered_protocol::LocalResponse::Success { message } => {
+        halfremembered_protocol::LocalResponse::Watching { message, .. } => {
             log::info!("Watch set up successfully: {}", message);
         }
         halfremembered_protocol::LocalResponse::Error { message } => {
@@ -434,6 +403,13 @@ async fn test_remove_single_file_watch() -> Result<()> {
         recursive: false,
         include_patterns: vec![],
         exclude_patterns: vec![],
+        relative_to: None,
+        case_insensitive: false,
+        verify_events: true,
+        destination: None,
+        base: None,
+        settle_ms: 0,
+        priority: 0,
     };
     halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
         "localhost",
@@ -453,6 +429,7 @@ async fn test_remove_single_file_watch() -> Result<()> {
     // Remove the watch
     let unwatch_command = halfremembered_protocol::LocalCommand::UnwatchDirectory {
         path: test_file.to_string_lossy().to_string(),
+        destination: None,
     };
     halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
         "localhost",
@@ -482,3 +459,110 @@ async fn test_remove_single_file_watch() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_overlapping_watches_sync_to_each_destination() -> Result<()> {
+    let fixture = setup_test().await?;
+
+    let subdir = fixture.server_watch_dir.path().join("sub");
+    std::fs::create_dir(&subdir)?;
+
+    // The outer watch covers the whole tree, the inner one only "sub",
+    // each with its own destination on the client
+    for (path, pattern, destination) in [
+        (fixture.server_watch_dir.path(), "**/*.dll", "first"),
+        (subdir.as_path(), "*.dll", "second"),
+    ] {
+        let watch_command = halfremembered_protocol::LocalCommand::WatchDirectory {
+            path: path.to_string_lossy().to_string(),
+            recursive: true,
+            include_patterns: vec![pattern.to_string()],
+            exclude_patterns: vec![],
+            relative_to: None,
+            case_insensitive: false,
+            verify_events: true,
+            destination: Some(destination.to_string()),
+            base: None,
+            settle_ms: 0,
+            priority: 0,
+        };
+
+        let response = halfremembered_launcher::ssh_client::SshClientConnection::send_control_command(
+            "localhost",
+            fixture.port,
+            &fixture.user,
+            watch_command,
+            None,
+        )
+        .await?;
+
+        if let halfremembered_protocol::LocalResponse::Error { message } = response {
+            anyhow::bail!("Failed to set up watch: {}", message);
+        }
+    }
+
+    // Hard-link a finished file into place (as cargo does) so no event can catch it
+    // half-written
+    let content = "shared library";
+    std::fs::write(subdir.join("lib.partial"), content)?;
+    std::fs::hard_link(subdir.join("lib.partial"), subdir.join("lib.dll"))?;
+    log::info!("Created file under both watches");
+
+    let first = fixture.client_output_dir.path().join("first/sub/lib.dll");
+    let second = fixture.client_output_dir.path().join("second/lib.dll");
+    wait_for_file_content(&first, content, Duration::from_secs(3)).await?;
+    wait_for_file_content(&second, content, Duration::from_secs(3)).await?;
+
+    log::info!("✓ File synced to both watch destinations!");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_initial_sync_sends_only_stale_files() -> Result<()> {
+    let fixture = setup_test().await?;
+
+    std::fs::write(fixture.server_watch_dir.path().join("current.txt"), "same on both")?;
+    std::fs::write(fixture.server_watch_dir.path().join("stale.txt"), "server version")?;
+    setup_watch(&fixture, vec!["*.txt".to_string()], vec![]).await?;
+
+    // A second client that already has one file up to date and an old copy of the other
+    let late_output_dir = TempDir::new()?;
+    let current = late_output_dir.path().join("current.txt");
+    std::fs::write(&current, "same on both")?;
+    std::fs::write(late_output_dir.path().join("stale.txt"), "old")?;
+    let old_mtime = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    std::fs::File::options().write(true).open(&current)?.set_modified(old_mtime)?;
+
+    let late_output_path = late_output_dir.path().to_path_buf();
+    let port = fixture.port;
+    let user = fixture.user.clone();
+    let late_client = tokio::spawn(async move {
+        let mut daemon = halfremembered_launcher::client_daemon::ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            user,
+            "late-client".to_string(),
+        )
+        .with_heartbeat_interval(Duration::from_secs(5))
+        .with_reconnect_delay(Duration::from_secs(1))
+        .with_working_dir(late_output_path);
+
+        daemon.run().await.expect("Client daemon failed");
+    });
+
+    let stale = late_output_dir.path().join("stale.txt");
+    let result = wait_for_file_content(&stale, "server version", Duration::from_secs(3)).await;
+    sleep(Duration::from_millis(500)).await;
+    let current_mtime = std::fs::metadata(&current)?.modified()?;
+    late_client.abort();
+    result?;
+
+    // The up-to-date file was listed in the manifest but never rewritten
+    assert_eq!(current_mtime, old_mtime);
+    assert_eq!(std::fs::read_to_string(&current)?, "same on both");
+
+    log::info!("✓ Initial sync skipped the file the client already had!");
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+// Integration test for daemons speaking different wire formats to one server
+//
+// Non-Rust clients send MessagePack, and must be served alongside bincode daemons, so this test:
+// 1. Connects one daemon using bincode and one using MessagePack
+// 2. Verifies a file on both, which needs a request and a reply in each client's format
+// 3. Streams a command's output from the MessagePack daemon to its exit
+#![cfg(unix)]
+
+use anyhow::Result;
+use futures::StreamExt;
+use halfremembered_launcher::client_daemon::ClientDaemon;
+use halfremembered_launcher::rsync_utils::{self, ChecksumAlgo};
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse, WireFormat};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+mod common;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bincode_and_msgpack_clients_share_a_server() -> Result<()> {
+    let bincode_dir = TempDir::new()?;
+    let msgpack_dir = TempDir::new()?;
+    for dir in [&bincode_dir, &msgpack_dir] {
+        std::fs::write(dir.path().join("data.bin"), b"same everywhere")?;
+    }
+    let expected = rsync_utils::compute_checksum(ChecksumAlgo::Blake3, b"same everywhere");
+
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let mut client_tasks = Vec::new();
+    for (hostname, dir, format) in [
+        ("rusty", &bincode_dir, WireFormat::Bincode),
+        ("polyglot", &msgpack_dir, WireFormat::MessagePack),
+    ] {
+        let daemon = ClientDaemon::new(
+            "localhost".to_string(),
+            port,
+            "testuser".to_string(),
+            hostname.to_string(),
+        )
+        .with_working_dir(dir.path().to_path_buf())
+        .with_initial_sync(false)
+        .with_wire_format(format);
+        client_tasks.push(common::spawn_daemon(daemon));
+    }
+
+    let start = Instant::now();
+    loop {
+        let response = SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None).await;
+        if let Ok(LocalResponse::ClientList { clients }) = response
+            && clients.len() == 2
+        {
+            break;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("Timeout waiting for clients to register");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let command = LocalCommand::VerifyFile {
+        target: None,
+        relative_path: "data.bin".to_string(),
+        expected_checksum: expected.clone(),
+        checksum_algo: ChecksumAlgo::Blake3,
+    };
+    match SshClientConnection::send_control_command("localhost", port, "testuser", command, None).await? {
+        LocalResponse::VerifyReport { results, .. } => {
+            assert_eq!(results.len(), 2);
+            assert!(results.iter().all(|result| result.matches), "{:?}", results);
+        }
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let exec = LocalCommand::Execute {
+        target: "polyglot".to_string(),
+        binary: "sh".to_string(),
+        args: vec!["-c".to_string(), "echo packed".to_string()],
+        session_id: None,
+        stream: true,
+        working_dir: None,
+        env: Default::default(),
+    };
+    let responses =
+        SshClientConnection::send_control_command_streaming("localhost", port, "testuser", exec, None, None).await?;
+    let mut responses = std::pin::pin!(responses);
+    let mut stdout = Vec::new();
+    let mut exit = None;
+    while let Some(response) = tokio::time::timeout(Duration::from_secs(10), responses.next()).await? {
+        match response? {
+            LocalResponse::ExecOutput { data, .. } => stdout.extend_from_slice(&data),
+            LocalResponse::ExecExit { exit_code, .. } => exit = Some(exit_code),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        }
+    }
+    assert_eq!(String::from_utf8(stdout)?, "packed\n");
+    assert_eq!(exit, Some(0));
+
+    for task in client_tasks {
+        task.abort();
+    }
+    server_task.abort();
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// Integration test for connections that don't speak the launcher protocol
+//
+// Pointing plain `ssh` or a launcher of another version at the server used to leave
+// a channel open that never answered. This test connects raw SSH sessions that:
+// 1. Send text instead of a message, and get a notice before the channel closes
+// 2. Ask for a shell, as interactive `ssh` does, and get the same notice
+// 3. Send a well-framed command the server can't decode, as a launcher of another
+//    version would, and get an error response it can print
+// 4. Checks a real control command still works afterwards
+
+use anyhow::Result;
+use halfremembered_launcher::ssh_client::SshClientConnection;
+use halfremembered_launcher::ssh_server::ServerOptions;
+use halfremembered_protocol::{LocalCommand, LocalResponse, MessageBuffer};
+use russh::{ChannelMsg, client};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+use common::AcceptingHandler;
+
+// An authenticated session with one open channel, as the first thing a launcher opens
+async fn open_channel(port: u16) -> Result<(client::Handle<AcceptingHandler>, russh::Channel<client::Msg>)> {
+    let mut session = client::connect(Arc::new(client::Config::default()), ("localhost", port), AcceptingHandler).await?;
+
+    let mut agent = russh::keys::agent::client::AgentClient::connect_env().await?;
+    let mut authenticated = false;
+    for key in agent.request_identities().await? {
+        if session
+            .authenticate_publickey_with("testuser", key, None, &mut agent)
+            .await?
+            .success()
+        {
+            authenticated = true;
+            break;
+        }
+    }
+    anyhow::ensure!(authenticated, "no agent identity was accepted");
+
+    let channel = session.channel_open_session().await?;
+    Ok((session, channel))
+}
+
+// Everything the server sends before closing the channel
+async fn read_until_close(channel: &mut russh::Channel<client::Msg>) -> Result<Vec<u8>> {
+    let mut received = Vec::new();
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), channel.wait())
+            .await
+            .map_err(|_| anyhow::anyhow!("server left the channel open"))?;
+        match msg {
+            Some(ChannelMsg::Data { data }) => received.extend_from_slice(&data),
+            Some(ChannelMsg::Close) | None => return Ok(received),
+            Some(_) => {}
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_foreign_clients_are_told_and_closed() -> Result<()> {
+    let (port, server_task) = common::start_server(ServerOptions::default()).await?;
+
+    let (_session, mut channel) = open_channel(port).await?;
+    channel.data(&b"hello, is this a shell?\n"[..]).await?;
+    let notice = String::from_utf8(read_until_close(&mut channel).await?)?;
+    assert!(notice.contains("halfremembered-launcher"), "{}", notice);
+    assert!(notice.contains("plain ssh"), "{}", notice);
+
+    let (_session, mut channel) = open_channel(port).await?;
+    channel.request_shell(false).await?;
+    let notice = String::from_utf8(read_until_close(&mut channel).await?)?;
+    assert!(notice.contains("plain ssh"), "{}", notice);
+
+    // A control command's framing around a body no version of LocalCommand decodes to
+    let (_session, mut channel) = open_channel(port).await?;
+    let body = [0xff; 8];
+    let mut framed = ((body.len() + 1) as u32).to_be_bytes().to_vec();
+    framed.push(0x03);
+    framed.extend_from_slice(&body);
+    channel.data(framed.as_slice()).await?;
+
+    let mut buffer = MessageBuffer::new();
+    buffer.append(&read_until_close(&mut channel).await?);
+    match buffer.try_parse_local_response()? {
+        Some(LocalResponse::Error { message }) => assert!(message.contains("same version"), "{}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+
+    let response =
+        SshClientConnection::send_control_command("localhost", port, "testuser", LocalCommand::ListClients, None)
+            .await?;
+    assert!(matches!(response, LocalResponse::ClientList { .. }), "{:?}", response);
+
+    server_task.abort();
+    Ok(())
+}
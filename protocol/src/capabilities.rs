@@ -0,0 +1,25 @@
+// Capabilities a client advertises in its Register
+//
+// The server can't assume every client understands every way it can send a file, so
+// clients name what they handle and the server picks, per client, an encoding it can
+// take. Names the server doesn't know are ignored, and a client that names none (or
+// predates the field) gets the baseline: rsync deltas with SHA-256 checksums.
+
+/// Small files answered with their whole content at the rsync handshake, no signature
+pub const WHOLE_FILE: &str = "whole-file";
+
+/// Checksums computed with BLAKE3
+pub const BLAKE3: &str = "blake3";
+
+/// Every capability this version's client supports
+pub const SUPPORTED: &[&str] = &[WHOLE_FILE, BLAKE3];
+
+/// This version's capabilities, as sent in Register
+pub fn supported() -> Vec<String> {
+    SUPPORTED.iter().map(|capability| capability.to_string()).collect()
+}
+
+/// Whether advertised `capabilities` include `capability`
+pub fn has(capabilities: &[String], capability: &str) -> bool {
+    capabilities.iter().any(|advertised| advertised == capability)
+}
@@ -0,0 +1,65 @@
+use thiserror::Error;
+
+/// Why a frame or message couldn't be written or read
+///
+/// Lets callers tell a stream that is merely cut short from one that can't be
+/// trusted any more: `TooLarge`, `TooSmall`, `WrongType` and `Decode` mean the peer
+/// sent something this side doesn't understand, while `Incomplete` means the input
+/// ended before a whole message arrived. Buffers that parse incrementally report an
+/// incomplete message as `Ok(None)` instead, since more data may still come.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    /// A length prefix over the limit; usually a desynchronized or foreign stream
+    #[error("{kind} too large: {len} bytes (max: {max})")]
+    TooLarge {
+        kind: &'static str,
+        len: usize,
+        max: usize,
+    },
+
+    /// A length prefix too short to hold the type it must start with
+    #[error("{kind} too small: {len} bytes (min: {min})")]
+    TooSmall {
+        kind: &'static str,
+        len: usize,
+        min: usize,
+    },
+
+    /// The input ended partway through a message
+    #[error("Stream ended partway through a {kind}")]
+    Incomplete { kind: &'static str },
+
+    /// A message of another kind than the one being read
+    #[error("Invalid message type: expected {expected}, got {actual}")]
+    WrongType { expected: u8, actual: u8 },
+
+    /// The body didn't deserialize as the expected message
+    #[error("Failed to deserialize {kind}")]
+    Decode {
+        kind: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Failed to serialize {kind}")]
+    Encode {
+        kind: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl ProtocolError {
+    /// An error from reading part of a `kind`, where running out of input means the
+    /// message is incomplete
+    pub(crate) fn reading(kind: &'static str, error: std::io::Error) -> Self {
+        if error.kind() == std::io::ErrorKind::UnexpectedEof {
+            ProtocolError::Incomplete { kind }
+        } else {
+            ProtocolError::Io(error)
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use crate::ProtocolError;
 use bytes::{Buf, BufMut, BytesMut};
 use std::io::{Read, Write};
 
@@ -33,31 +33,25 @@ impl Frame {
     }
 
     /// Write frame to writer
-    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
         // Length = type (2) + payload
         let length = (2 + self.payload.len()) as u32;
 
         if length as usize > MAX_FRAME_SIZE {
-            anyhow::bail!(
-                "Frame too large: {} bytes (max: {})",
-                length,
-                MAX_FRAME_SIZE
-            );
+            return Err(ProtocolError::TooLarge {
+                kind: "Frame",
+                len: length as usize,
+                max: MAX_FRAME_SIZE,
+            });
         }
 
         // Write header
-        writer
-            .write_all(&length.to_be_bytes())
-            .context("Failed to write frame length")?;
-        writer
-            .write_all(&self.message_type.to_be_bytes())
-            .context("Failed to write message type")?;
+        writer.write_all(&length.to_be_bytes())?;
+        writer.write_all(&self.message_type.to_be_bytes())?;
 
         // Write payload
-        writer
-            .write_all(&self.payload)
-            .context("Failed to write frame payload")?;
-        writer.flush().context("Failed to flush writer")?;
+        writer.write_all(&self.payload)?;
+        writer.flush()?;
 
         log::trace!(
             "Sent frame: type=0x{:04x}, size={} bytes",
@@ -69,39 +63,23 @@ impl Frame {
     }
 
     /// Read frame from reader
-    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
         // Read length
+        let reading = |e| ProtocolError::reading("frame", e);
         let mut len_bytes = [0u8; 4];
-        reader
-            .read_exact(&mut len_bytes)
-            .context("Failed to read frame length")?;
+        reader.read_exact(&mut len_bytes).map_err(reading)?;
         let length = u32::from_be_bytes(len_bytes) as usize;
-
-        if length > MAX_FRAME_SIZE {
-            anyhow::bail!(
-                "Frame too large: {} bytes (max: {})",
-                length,
-                MAX_FRAME_SIZE
-            );
-        }
-
-        if length < 2 {
-            anyhow::bail!("Frame too small: {} bytes (min: 2)", length);
-        }
+        check_frame_len(length)?;
 
         // Read message type
         let mut type_bytes = [0u8; 2];
-        reader
-            .read_exact(&mut type_bytes)
-            .context("Failed to read message type")?;
+        reader.read_exact(&mut type_bytes).map_err(reading)?;
         let message_type = u16::from_be_bytes(type_bytes);
 
         // Read payload
         let payload_len = length - 2;
         let mut payload = vec![0u8; payload_len];
-        reader
-            .read_exact(&mut payload)
-            .context("Failed to read frame payload")?;
+        reader.read_exact(&mut payload).map_err(reading)?;
 
         log::trace!(
             "Received frame: type=0x{:04x}, size={} bytes",
@@ -116,6 +94,25 @@ impl Frame {
     }
 }
 
+/// A frame's length prefix covers its 2-byte message type and payload
+fn check_frame_len(length: usize) -> Result<(), ProtocolError> {
+    if length > MAX_FRAME_SIZE {
+        return Err(ProtocolError::TooLarge {
+            kind: "Frame",
+            len: length,
+            max: MAX_FRAME_SIZE,
+        });
+    }
+    if length < 2 {
+        return Err(ProtocolError::TooSmall {
+            kind: "Frame",
+            len: length,
+            min: 2,
+        });
+    }
+    Ok(())
+}
+
 /// Async frame buffer for parsing frames from byte stream
 pub struct FrameBuffer {
     buffer: BytesMut,
@@ -134,7 +131,7 @@ impl FrameBuffer {
     }
 
     /// Try to parse a complete frame from buffer
-    pub fn try_parse(&mut self) -> Result<Option<Frame>> {
+    pub fn try_parse(&mut self) -> Result<Option<Frame>, ProtocolError> {
         // Need at least header
         if self.buffer.len() < FRAME_HEADER_SIZE {
             return Ok(None);
@@ -148,13 +145,7 @@ impl FrameBuffer {
             self.buffer[3],
         ]) as usize;
 
-        if length > MAX_FRAME_SIZE {
-            anyhow::bail!("Frame too large: {} bytes", length);
-        }
-
-        if length < 2 {
-            anyhow::bail!("Frame too small: {} bytes", length);
-        }
+        check_frame_len(length)?;
 
         // Check if we have complete frame
         let total_size = 4 + length; // 4-byte length prefix + frame
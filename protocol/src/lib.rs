@@ -1,5 +1,5 @@
-use anyhow::{Context, Result};
 use bytes::{Buf, BufMut, BytesMut};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::{Read, Write};
@@ -9,13 +9,18 @@ fn default_initial_sync() -> bool {
     true
 }
 
+pub mod capabilities;
+pub mod error;
 // Unified frame protocol
 pub mod frame;
 pub mod message_types;
+pub mod wire_format;
 
 // Re-export commonly used types
+pub use error::ProtocolError;
 pub use frame::{Frame, FrameBuffer, FRAME_HEADER_SIZE, MAX_FRAME_SIZE};
 pub use message_types::*;
+pub use wire_format::WireFormat;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientMessage {
@@ -24,6 +29,13 @@ pub enum ClientMessage {
         platform: String,
         #[serde(default = "default_initial_sync")]
         initial_sync: bool,
+        /// Token from the Welcome of a session this one continues, to keep its
+        /// registry entry instead of registering as a new client
+        #[serde(default)]
+        resume_token: Option<String>,
+        /// What the client can handle, from `capabilities`; empty means the baseline
+        #[serde(default)]
+        capabilities: Vec<String>,
     },
     Heartbeat {
         timestamp: u64,
@@ -36,12 +48,18 @@ pub enum ClientMessage {
         checksum: String,
         bytes_transferred: u64,
         error: Option<String>,
+        /// Time from receiving RsyncStart to finishing (or giving up on) the file
+        duration_ms: u64,
+        /// The client is busy and queued the file instead of applying it; another
+        /// RsyncComplete for the same request follows once it has
+        queued: bool,
     },
     ExecComplete {
         request_id: String,
         exit_code: i32,
         stdout: String,
         stderr: String,
+        error: Option<String>,
     },
     Status {
         request_id: String,
@@ -51,6 +69,28 @@ pub enum ClientMessage {
         request_id: Option<String>,
         message: String,
     },
+    /// Reply to a Manifest: the entry paths that are missing or stale locally
+    ManifestDiff {
+        request_id: String,
+        needed: Vec<String>,
+    },
+    /// Reply to a Keepalive, echoing its nonce
+    Pong {
+        nonce: u64,
+    },
+    /// Ask for a fresh copy of a file the client suspects is corrupt or was changed
+    /// locally; `relative_path` is the path an RsyncStart for it would carry
+    RequestResync {
+        relative_path: String,
+    },
+    /// Reply to a VerifyFile; `actual_checksum` is None when the file couldn't be
+    /// hashed, with the reason in `error`
+    VerifyResult {
+        request_id: String,
+        matches: bool,
+        actual_checksum: Option<String>,
+        error: Option<String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,6 +98,11 @@ pub enum ServerMessage {
     Welcome {
         server_version: String,
         session_id: String,
+        /// Present in the Register of the next connection to resume this session
+        resume_token: String,
+        /// The Register's resume_token was accepted, and the previous session's
+        /// uptime and stats carried over
+        resumed: bool,
     },
     RsyncStart {
         request_id: String,
@@ -67,6 +112,12 @@ pub enum ServerMessage {
         mtime: u64,
         block_size: u32,
         mode: u32, // Unix file permissions (ignored on non-Unix platforms)
+        dir_mode: Option<u32>, // Unix permissions for parent directories the client creates
+        whole_file: bool, // Small file: the server answers the handshake with its content, no signature
+        checksum_algo: ChecksumAlgo, // Hash `checksum` was computed with; the client verifies with the same
+        atomic_replace: bool, // Stage beside the destination and rename over it, so a running binary can be replaced
+        priority: i32, // The sending rule's priority; higher-priority syncs were dispatched first
+        force: bool, // Replace the file even if a --no-clobber-local client sees it was edited there
     },
     Execute {
         request_id: String,
@@ -74,6 +125,7 @@ pub enum ServerMessage {
         args: Vec<String>,
         working_dir: Option<String>,
         env: HashMap<String, String>,
+        self_update: bool, // Sent by self-update: once the command succeeds, the client restarts on the new binary
     },
     Ping {
         request_id: String,
@@ -81,6 +133,65 @@ pub enum ServerMessage {
     Shutdown {
         message: Option<String>,
     },
+    /// Delete a file whose source was removed; `path` must lie strictly under `scope`
+    /// (the sync rule's destination), both resolved like RsyncStart's relative_path
+    DeleteFile {
+        request_id: String,
+        path: String,
+        scope: String,
+    },
+    /// Initial-sync offer: every file the server would send, so the client can
+    /// answer with a ManifestDiff instead of receiving each one
+    Manifest {
+        request_id: String,
+        entries: Vec<ManifestEntry>,
+        /// Hash the entries' checksums were computed with
+        checksum_algo: ChecksumAlgo,
+    },
+    /// Create an empty source directory, so clients get the full layout and not only
+    /// the directories that hold files; `relative_path` is resolved like RsyncStart's
+    CreateDir {
+        request_id: String,
+        relative_path: String,
+        mode: Option<u32>, // Unix permissions for the directories the client creates
+    },
+    /// Connection check answered with a Pong, where a Ping gets the client's full Status
+    Keepalive {
+        nonce: u64,
+    },
+    /// Terminate the command a client is running for the Execute with this request_id
+    Kill {
+        request_id: String,
+    },
+    /// Hash the local copy of a synced file and compare it with `expected_checksum`;
+    /// `relative_path` is resolved like RsyncStart's
+    VerifyFile {
+        request_id: String,
+        relative_path: String,
+        expected_checksum: String,
+        checksum_algo: ChecksumAlgo,
+    },
+}
+
+/// Hash used for file checksums; the server picks one and names it in every message
+/// that carries checksums, so both sides agree
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgo {
+    /// Several times faster than SHA-256 on large files
+    #[default]
+    Blake3,
+    /// For deployments that require a SHA-2 digest
+    Sha256,
+}
+
+/// One file in an initial-sync manifest; `path` is resolved like RsyncStart's relative_path
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    pub mtime: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -89,6 +200,22 @@ pub struct ClientState {
     pub last_sync: Option<u64>,
     pub running_processes: Vec<String>,
     pub pending_transfers: u32,
+    /// Commands started for Execute requests that haven't exited yet
+    pub exec_processes: Vec<ExecProcess>,
+    /// Bytes available on the filesystem syncs land on, if the client could tell
+    pub free_space: Option<u64>,
+}
+
+/// A command a client is running for an Execute request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecProcess {
+    /// The Execute's request_id, which KillProcess takes
+    pub request_id: String,
+    pub binary: String,
+    pub args: Vec<String>,
+    pub pid: Option<u32>,
+    /// Unix time the process was spawned
+    pub started_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -96,28 +223,146 @@ pub enum LocalCommand {
     Status,
     Ping {
         target: String,
+        /// Session to ping when several clients share the target hostname
+        session_id: Option<String>,
     },
     ListClients,
+    /// Ask one client for its current state and wait for the answer
+    ClientStatus {
+        hostname: String,
+        /// Session to query when several clients share the hostname
+        session_id: Option<String>,
+    },
     Shutdown,
+    /// Stop accepting new sessions, syncs and watches, let in-flight transfers finish,
+    /// then shut down
+    Drain,
     SyncFile {
         file: String,
         destination: String,
+        /// Succeed as long as at least one client received the sync; otherwise any
+        /// failed recipient fails the command
+        allow_partial: bool,
+        /// Overwrite copies that clients running with --no-clobber-local would keep
+        /// because they were edited there
+        force: bool,
+    },
+    /// Sync every file under `root` matching the patterns, each to `destination` joined
+    /// with its path relative to `root`; the one-shot counterpart of WatchDirectory
+    SyncTree {
+        root: String,
+        destination: String,
+        include_patterns: Vec<String>,
+        exclude_patterns: Vec<String>,
+        /// Applied per file, as for SyncFile
+        allow_partial: bool,
+        /// As for SyncFile
+        force: bool,
     },
     Execute {
         target: String,
         binary: String,
         args: Vec<String>,
+        /// Session to execute on when several clients share the target hostname
+        session_id: Option<String>,
+        /// Relay the command's output as ExecOutput responses, ending with ExecExit,
+        /// instead of answering as soon as the command is sent
+        stream: bool,
+        /// Directory on the client to run in, instead of the client's own
+        working_dir: Option<String>,
+        /// Variables set for the command, taking precedence over `VAR=value` args
+        env: HashMap<String, String>,
     },
     WatchDirectory {
         path: String,
         recursive: bool,
         include_patterns: Vec<String>,
         exclude_patterns: Vec<String>,
+        /// Directory that patterns and synced relative paths are resolved against,
+        /// when `path` is a narrower subdirectory of it (defaults to `path`)
+        relative_to: Option<String>,
+        /// Match include/exclude patterns ignoring case (e.g. `*.exe` matches `GAME.EXE`)
+        case_insensitive: bool,
+        /// Confirm filesystem events are delivered for the new watch before reporting success
+        verify_events: bool,
+        /// Client-side directory to sync matched files into, with the include pattern's
+        /// literal base stripped (defaults to each file's path relative to the watch)
+        destination: Option<String>,
+        /// Ancestor of `path` that client paths are relative to, so watching `src` with
+        /// base `.` syncs `src/main.rs` rather than `main.rs` (ignored with a destination)
+        base: Option<String>,
+        /// Milliseconds a newly created file must stay unchanged before it is synced (0 = at once)
+        settle_ms: u64,
+        /// Changes under this watch sync before those of lower-priority watches
+        priority: i32,
     },
     UnwatchDirectory {
         path: String,
+        /// Remove only the route syncing to this destination, as a WatchDirectory that
+        /// joined the watch added it; the watch itself goes once no route is left
+        destination: Option<String>,
+    },
+    /// Report which files removing a watch would stop syncing, without removing it.
+    /// Answered with an UnwatchPreview.
+    PreviewUnwatch {
+        path: String,
+    },
+    /// Hold changes under a watch instead of syncing them
+    PauseWatch {
+        path: String,
+    },
+    /// Resume a paused watch, syncing files whose content changed while it was paused
+    ResumeWatch {
+        path: String,
+    },
+    /// Change an active watch in place; fields left as None keep their current value.
+    /// Answered with a one-entry WatchList holding the updated watch.
+    UpdateWatch {
+        path: String,
+        destination: Option<String>,
+        include_patterns: Option<Vec<String>>,
+        exclude_patterns: Option<Vec<String>>,
     },
     ListWatches,
+    /// Roll a new launcher binary out to every connected client: sync `file`
+    /// to `staging_path` on each client, then execute it to swap and restart.
+    SelfUpdate {
+        file: String,
+        staging_path: String,
+    },
+    /// Bundle server state, clients, watches, in-flight transfers and recent log
+    /// lines into one report for troubleshooting
+    Diagnostics,
+    /// List the commands a client is running for Execute requests
+    ListProcesses {
+        target: String,
+        /// Session to query when several clients share the target hostname
+        session_id: Option<String>,
+    },
+    /// Terminate the command a client is running for the Execute `request_id`
+    KillProcess {
+        target: String,
+        /// Session to signal when several clients share the target hostname
+        session_id: Option<String>,
+        request_id: String,
+    },
+    /// Have clients hash their copy of a synced file and report whether it matches
+    /// `expected_checksum`, without transferring anything
+    VerifyFile {
+        /// Hostname to check; None checks every connected client
+        target: Option<String>,
+        relative_path: String,
+        expected_checksum: String,
+        checksum_algo: ChecksumAlgo,
+    },
+    /// Any other command, labelled so its answer can be matched up when several
+    /// commands are pipelined over one control channel. The channel stays open
+    /// after a tagged command, and every response to it comes back as Tagged
+    /// with the same request_id
+    Tagged {
+        request_id: String,
+        command: Box<LocalCommand>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -127,6 +372,90 @@ pub struct ClientInfo {
     pub session_id: String,
     pub connected_at: u64,
     pub last_heartbeat: u64,
+    /// Comment of the authorized_keys entry the client authenticated with
+    pub auth_key_label: Option<String>,
+    /// Most recent failure sending to this client (e.g. stalled on a slow link)
+    pub last_error: Option<String>,
+    /// Most recent rsync transfer this client completed
+    pub last_transfer: Option<TransferInfo>,
+    /// Heartbeats whose sequence skipped ahead or restarted on this session
+    pub heartbeat_gaps: u64,
+    /// Messages waiting in the server's send queue for this client
+    pub send_queue_depth: u64,
+    /// Most messages that have waited in the queue at once
+    pub send_queue_peak: u64,
+    /// Messages the queue holds before the client is evicted
+    pub send_queue_capacity: u64,
+    /// Rates of the transfers this session completed
+    pub transfer_rates: TransferRates,
+}
+
+/// Running totals of completed transfers, for average and peak sync rates
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TransferRates {
+    /// Transfers timed so far; ones too quick to time aren't counted
+    pub transfers: u64,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    /// Fastest single transfer, in bytes per second
+    pub peak: f64,
+}
+
+impl TransferRates {
+    pub fn record(&mut self, transfer: &TransferInfo) {
+        let Some(rate) = transfer.rate() else {
+            return;
+        };
+        self.transfers += 1;
+        self.bytes += transfer.bytes_transferred;
+        self.duration_ms += transfer.duration_ms;
+        self.peak = self.peak.max(rate);
+    }
+
+    /// Bytes per second over all timed transfers, weighted by how long each took
+    pub fn average(&self) -> Option<f64> {
+        (self.duration_ms > 0).then(|| self.bytes as f64 * 1000.0 / self.duration_ms as f64)
+    }
+}
+
+/// Connections and transfers the server dropped or rejected since it started, by cause
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    /// Sessions dropped because a frame or message on one of their channels didn't parse
+    pub parse_errors: u64,
+    /// Transfers a client rejected because the result didn't match the server's checksum
+    pub checksum_failures: u64,
+    /// Connections whose key wasn't accepted
+    pub auth_rejects: u64,
+    /// Clients dropped because their send queue filled or a send failed
+    pub evictions: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferInfo {
+    pub path: String,
+    /// Delta bytes actually sent, as reported by the client; compare with the file size
+    pub bytes_transferred: u64,
+    /// Size of the source file, if the server still held it when the client reported
+    pub file_size: Option<u64>,
+    /// How long the client took to sync the file
+    pub duration_ms: u64,
+}
+
+impl TransferInfo {
+    /// Bytes transferred per second; None for a transfer too quick to time
+    pub fn rate(&self) -> Option<f64> {
+        (self.duration_ms > 0).then(|| self.bytes_transferred as f64 * 1000.0 / self.duration_ms as f64)
+    }
+
+    /// Bytes on the wire as a fraction of the file size (below 1.0 means rsync saved
+    /// bandwidth); None when the size is unknown or the file is empty
+    pub fn wire_ratio(&self) -> Option<f64> {
+        match self.file_size {
+            Some(size) if size > 0 => Some(self.bytes_transferred as f64 / size as f64),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -135,6 +464,71 @@ pub struct WatchInfo {
     pub recursive: bool,
     pub include_patterns: Vec<String>,
     pub exclude_patterns: Vec<String>,
+    /// Client-side directory matched files are synced into, if set
+    pub destination: Option<String>,
+    /// Raw filesystem events for files the watch matches
+    pub events_seen: u64,
+    /// Events left after debouncing and checksum deduplication
+    pub events_passed: u64,
+    pub syncs_triggered: u64,
+    /// Seconds since the watch last triggered a sync
+    pub last_triggered: Option<u64>,
+    /// Changes are held until the watch is resumed
+    pub paused: bool,
+    /// The watched directory was removed; watching resumes if it reappears
+    pub broken: bool,
+}
+
+/// A sync whose file data the server still holds for recipients yet to report back
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InFlightTransfer {
+    pub request_id: String,
+    pub path: String,
+    /// Sessions the server is still waiting on for RsyncComplete
+    pub pending_sessions: Vec<String>,
+}
+
+/// Everything a `diagnostics` report collects from the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiagnosticsReport {
+    pub hostname: String,
+    pub version: String,
+    pub uptime: u64,
+    /// The server is finishing in-flight work before shutting down
+    pub draining: bool,
+    pub clients: Vec<ClientInfo>,
+    /// Rates of every transfer completed since the server started
+    pub transfer_rates: TransferRates,
+    pub watches: Vec<WatchInfo>,
+    pub transfers: Vec<InFlightTransfer>,
+    /// The server's most recent log lines, oldest first
+    pub logs: Vec<String>,
+}
+
+/// One client's answer to a VerifyFile
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientVerifyResult {
+    pub hostname: String,
+    pub matches: bool,
+    pub actual_checksum: Option<String>,
+    /// Why the client couldn't hash its copy, or never answered
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecipientFailure {
+    pub hostname: String,
+    pub error: String,
+}
+
+/// One file's outcome within a SyncTreeReport
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileSyncResult {
+    pub file: String,
+    pub delivered: Vec<String>,
+    pub failed: Vec<RecipientFailure>,
+    /// Set when the server could not send the file at all (e.g. it vanished mid-sync)
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -150,6 +544,10 @@ pub enum LocalResponse {
         version: String,
         uptime: u64,
         clients: Vec<ClientInfo>,
+        /// Rates of every transfer completed since the server started
+        transfer_rates: TransferRates,
+        /// Drops and rejections since the server started
+        error_counts: ErrorCounts,
     },
     ClientList {
         clients: Vec<ClientInfo>,
@@ -157,6 +555,64 @@ pub enum LocalResponse {
     WatchList {
         watches: Vec<WatchInfo>,
     },
+    /// Per-recipient outcome of a broadcast sync; `accepted` says whether it
+    /// satisfied the requested partial/strict policy
+    SyncReport {
+        file: String,
+        delivered: Vec<String>,
+        failed: Vec<RecipientFailure>,
+        accepted: bool,
+    },
+    /// Per-file outcome of a SyncTree; `accepted` holds only if every file satisfied
+    /// the requested partial/strict policy
+    SyncTreeReport {
+        root: String,
+        files: Vec<FileSyncResult>,
+        accepted: bool,
+    },
+    ClientState {
+        hostname: String,
+        state: ClientState,
+    },
+    /// A chunk of a streamed Execute's output, as the client read it
+    ExecOutput {
+        stderr: bool,
+        data: Vec<u8>,
+    },
+    /// Last response to a streamed Execute
+    ExecExit {
+        exit_code: i32,
+        error: Option<String>,
+    },
+    Diagnostics {
+        report: DiagnosticsReport,
+    },
+    ProcessList {
+        hostname: String,
+        processes: Vec<ExecProcess>,
+    },
+    /// Per-client outcome of a VerifyFile
+    VerifyReport {
+        path: String,
+        results: Vec<ClientVerifyResult>,
+    },
+    /// Reply to a WatchDirectory; `joined` says the directory was already watched and
+    /// the watch became another route of that one
+    Watching {
+        message: String,
+        joined: bool,
+    },
+    /// Files under a watch, split by whether another watch also matches them
+    UnwatchPreview {
+        path: String,
+        stops_syncing: Vec<String>,
+        still_covered: Vec<String>,
+    },
+    /// The answer to a Tagged command, carrying its request_id
+    Tagged {
+        request_id: String,
+        response: Box<LocalResponse>,
+    },
 }
 
 // Rsync protocol messages
@@ -171,6 +627,12 @@ pub struct RsyncStart {
     pub mtime: u64,
     pub block_size: u32,
     pub mode: u32, // Unix file permissions (0o755, 0o644, etc.)
+    pub dir_mode: Option<u32>, // Unix permissions for parent directories the client creates
+    pub whole_file: bool, // Small file: the server answers the handshake with its content, no signature
+    pub checksum_algo: ChecksumAlgo, // Hash `checksum` was computed with
+    pub atomic_replace: bool, // Stage at `<dest>.new` and rename it over the destination
+    pub priority: i32, // The sending rule's priority
+    pub force: bool, // Replace the file even if it was edited on the client
 }
 
 /// Client reports sync completion on control channel
@@ -182,190 +644,179 @@ pub struct RsyncComplete {
     pub checksum: String,
     pub bytes_transferred: u64,
     pub error: Option<String>,
+    pub duration_ms: u64,
+    pub queued: bool,
+}
+
+// Exec channel messages
+
+/// Client reports a command's result in the MSG_EXEC_EXIT frame that ends an exec channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecExit {
+    pub exit_code: i32,
+    pub error: Option<String>,
+}
+
+impl ExecExit {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        encode("ExecExit", self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        decode("ExecExit", bytes)
+    }
 }
 
 impl LocalCommand {
-    pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self).context("Failed to serialize LocalCommand")
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.to_bytes_as(WireFormat::default())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).context("Failed to deserialize LocalCommand")
+    pub fn to_bytes_as(&self, format: WireFormat) -> Result<Vec<u8>, ProtocolError> {
+        format.encode("LocalCommand", self)
     }
 
-    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let bytes = self.to_bytes()?;
-        let len = (bytes.len() + 1) as u32; // +1 for type byte
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_bytes_as(bytes, WireFormat::default())
+    }
 
-        if len as usize > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
+    pub fn from_bytes_as(bytes: &[u8], format: WireFormat) -> Result<Self, ProtocolError> {
+        format.decode("LocalCommand", bytes)
+    }
 
-        writer
-            .write_all(&len.to_be_bytes())
-            .context("Failed to write message length")?;
-        writer
-            .write_all(&[MESSAGE_TYPE_LOCAL_COMMAND])
-            .context("Failed to write message type")?;
-        writer
-            .write_all(&bytes)
-            .context("Failed to write message body")?;
-        writer.flush().context("Failed to flush writer")?;
+    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        self.write_framed_as(writer, WireFormat::default())
+    }
 
+    /// Write the message with its body in `format`; `read_framed` and MessageBuffer
+    /// accept either format
+    pub fn write_framed_as<W: Write>(&self, writer: &mut W, format: WireFormat) -> Result<(), ProtocolError> {
+        let len = write_message(writer, MESSAGE_TYPE_LOCAL_COMMAND, format, &self.to_bytes_as(format)?)?;
         log::trace!("Sent LocalCommand: {} bytes", len);
         Ok(())
     }
 
-    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut len_bytes = [0u8; 4];
-        reader
-            .read_exact(&mut len_bytes)
-            .context("Failed to read message length")?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
-
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
-
-        let mut type_byte = [0u8; 1];
-        reader
-            .read_exact(&mut type_byte)
-            .context("Failed to read message type")?;
-
-        if type_byte[0] != MESSAGE_TYPE_LOCAL_COMMAND {
-            anyhow::bail!(
-                "Invalid message type: expected {}, got {}",
-                MESSAGE_TYPE_LOCAL_COMMAND,
-                type_byte[0]
-            );
-        }
-
-        let mut buf = vec![0u8; len - 1]; // -1 for type byte
-        reader
-            .read_exact(&mut buf)
-            .context("Failed to read message body")?;
-
-        let msg = Self::from_bytes(&buf)?;
-        log::trace!("Received LocalCommand: {} bytes", len);
+    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let (body, format) = read_message(reader, MESSAGE_TYPE_LOCAL_COMMAND)?;
+        let msg = Self::from_bytes_as(&body, format)?;
+        log::trace!("Received LocalCommand: {} bytes", body.len() + 1);
         Ok(msg)
     }
 }
 
 impl LocalResponse {
-    pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self).context("Failed to serialize LocalResponse")
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.to_bytes_as(WireFormat::default())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).context("Failed to deserialize LocalResponse")
+    pub fn to_bytes_as(&self, format: WireFormat) -> Result<Vec<u8>, ProtocolError> {
+        format.encode("LocalResponse", self)
     }
 
-    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let bytes = self.to_bytes()?;
-        let len = (bytes.len() + 1) as u32; // +1 for type byte
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_bytes_as(bytes, WireFormat::default())
+    }
 
-        if len as usize > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
+    pub fn from_bytes_as(bytes: &[u8], format: WireFormat) -> Result<Self, ProtocolError> {
+        format.decode("LocalResponse", bytes)
+    }
 
-        writer
-            .write_all(&len.to_be_bytes())
-            .context("Failed to write message length")?;
-        writer
-            .write_all(&[MESSAGE_TYPE_LOCAL_RESPONSE])
-            .context("Failed to write message type")?;
-        writer
-            .write_all(&bytes)
-            .context("Failed to write message body")?;
-        writer.flush().context("Failed to flush writer")?;
+    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        self.write_framed_as(writer, WireFormat::default())
+    }
 
+    /// Write the message with its body in `format`; `read_framed` and MessageBuffer
+    /// accept either format
+    pub fn write_framed_as<W: Write>(&self, writer: &mut W, format: WireFormat) -> Result<(), ProtocolError> {
+        let len = write_message(writer, MESSAGE_TYPE_LOCAL_RESPONSE, format, &self.to_bytes_as(format)?)?;
         log::trace!("Sent LocalResponse: {} bytes", len);
         Ok(())
     }
 
-    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut len_bytes = [0u8; 4];
-        reader
-            .read_exact(&mut len_bytes)
-            .context("Failed to read message length")?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
-
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
-
-        let mut type_byte = [0u8; 1];
-        reader
-            .read_exact(&mut type_byte)
-            .context("Failed to read message type")?;
-
-        if type_byte[0] != MESSAGE_TYPE_LOCAL_RESPONSE {
-            anyhow::bail!(
-                "Invalid message type: expected {}, got {}",
-                MESSAGE_TYPE_LOCAL_RESPONSE,
-                type_byte[0]
-            );
-        }
-
-        let mut buf = vec![0u8; len - 1]; // -1 for type byte
-        reader
-            .read_exact(&mut buf)
-            .context("Failed to read message body")?;
-
-        let msg = Self::from_bytes(&buf)?;
-        log::trace!("Received LocalResponse: {} bytes", len);
+    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let (body, format) = read_message(reader, MESSAGE_TYPE_LOCAL_RESPONSE)?;
+        let msg = Self::from_bytes_as(&body, format)?;
+        log::trace!("Received LocalResponse: {} bytes", body.len() + 1);
         Ok(msg)
     }
 }
 
 impl ClientMessage {
-    pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self).context("Failed to serialize ClientMessage")
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.to_bytes_as(WireFormat::default())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).context("Failed to deserialize ClientMessage")
+    pub fn to_bytes_as(&self, format: WireFormat) -> Result<Vec<u8>, ProtocolError> {
+        format.encode("ClientMessage", self)
     }
 
-    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let bytes = self.to_bytes()?;
-        let len = (bytes.len() + 1) as u32; // +1 for type byte
+    /// An ExecComplete that fits in one message: when stdout and stderr together would
+    /// push it past MAX_MESSAGE_SIZE, each is cut (the shorter one only if it must) and
+    /// marked with EXEC_OUTPUT_TRUNCATED, so the exit code and partial output still arrive
+    pub fn exec_complete(
+        request_id: String,
+        exit_code: i32,
+        mut stdout: String,
+        mut stderr: String,
+        error: Option<String>,
+    ) -> Result<Self, ProtocolError> {
+        let mut msg = ClientMessage::ExecComplete {
+            request_id,
+            exit_code,
+            stdout: String::new(),
+            stderr: String::new(),
+            error,
+        };
+        // Everything but the output's bytes: the type byte, the other fields, and the
+        // strings' length prefixes, which in MessagePack grow by up to 4 bytes each
+        let overhead = 1 + msg
+            .to_bytes()?
+            .len()
+            .max(msg.to_bytes_as(WireFormat::MessagePack)?.len() + 8);
+        let budget = MAX_MESSAGE_SIZE.saturating_sub(overhead);
+
+        if stdout.len() + stderr.len() > budget {
+            let room = budget.saturating_sub(2 * EXEC_OUTPUT_TRUNCATED.len());
+            let half = room / 2;
+            let (stdout_room, stderr_room) = if stdout.len() <= half {
+                (stdout.len(), room - stdout.len())
+            } else if stderr.len() <= half {
+                (room - stderr.len(), stderr.len())
+            } else {
+                (half, room - half)
+            };
+            truncate_output(&mut stdout, stdout_room);
+            truncate_output(&mut stderr, stderr_room);
+        }
 
-        if len as usize > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
+        if let ClientMessage::ExecComplete {
+            stdout: ref mut out,
+            stderr: ref mut err,
+            ..
+        } = msg
+        {
+            *out = stdout;
+            *err = stderr;
         }
+        Ok(msg)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_bytes_as(bytes, WireFormat::default())
+    }
+
+    pub fn from_bytes_as(bytes: &[u8], format: WireFormat) -> Result<Self, ProtocolError> {
+        format.decode("ClientMessage", bytes)
+    }
 
-        writer
-            .write_all(&len.to_be_bytes())
-            .context("Failed to write message length")?;
-        writer
-            .write_all(&[MESSAGE_TYPE_CLIENT])
-            .context("Failed to write message type")?;
-        writer
-            .write_all(&bytes)
-            .context("Failed to write message body")?;
-        writer.flush().context("Failed to flush writer")?;
+    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        self.write_framed_as(writer, WireFormat::default())
+    }
 
+    /// Write the message with its body in `format`; `read_framed` and MessageBuffer
+    /// accept either format
+    pub fn write_framed_as<W: Write>(&self, writer: &mut W, format: WireFormat) -> Result<(), ProtocolError> {
+        let len = write_message(writer, MESSAGE_TYPE_CLIENT, format, &self.to_bytes_as(format)?)?;
         log::trace!(
             "Sent ClientMessage: {} bytes, type: {:?}",
             len,
@@ -374,44 +825,12 @@ impl ClientMessage {
         Ok(())
     }
 
-    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut len_bytes = [0u8; 4];
-        reader
-            .read_exact(&mut len_bytes)
-            .context("Failed to read message length")?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
-
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
-
-        let mut type_byte = [0u8; 1];
-        reader
-            .read_exact(&mut type_byte)
-            .context("Failed to read message type")?;
-
-        if type_byte[0] != MESSAGE_TYPE_CLIENT {
-            anyhow::bail!(
-                "Invalid message type: expected {}, got {}",
-                MESSAGE_TYPE_CLIENT,
-                type_byte[0]
-            );
-        }
-
-        let mut buf = vec![0u8; len - 1]; // -1 for type byte
-        reader
-            .read_exact(&mut buf)
-            .context("Failed to read message body")?;
-
-        let msg = Self::from_bytes(&buf)?;
-
+    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let (body, format) = read_message(reader, MESSAGE_TYPE_CLIENT)?;
+        let msg = Self::from_bytes_as(&body, format)?;
         log::trace!(
             "Received ClientMessage: {} bytes, type: {:?}",
-            len,
+            body.len() + 1,
             msg.message_type()
         );
         Ok(msg)
@@ -425,42 +844,55 @@ impl ClientMessage {
             ClientMessage::ExecComplete { .. } => "ExecComplete",
             ClientMessage::Status { .. } => "Status",
             ClientMessage::Error { .. } => "Error",
+            ClientMessage::ManifestDiff { .. } => "ManifestDiff",
+            ClientMessage::Pong { .. } => "Pong",
+            ClientMessage::RequestResync { .. } => "RequestResync",
+            ClientMessage::VerifyResult { .. } => "VerifyResult",
+        }
+    }
+
+    /// The `MSG_*` frame type for this variant
+    pub fn frame_type(&self) -> u16 {
+        match self {
+            ClientMessage::Register { .. } => MSG_CLIENT_REGISTER,
+            ClientMessage::Heartbeat { .. } => MSG_CLIENT_HEARTBEAT,
+            ClientMessage::RsyncComplete { .. } => MSG_RSYNC_COMPLETE,
+            ClientMessage::ExecComplete { .. } => MSG_CLIENT_EXEC_COMPLETE,
+            ClientMessage::Status { .. } => MSG_CLIENT_STATUS,
+            ClientMessage::Error { .. } => MSG_CLIENT_ERROR,
+            ClientMessage::ManifestDiff { .. } => MSG_CLIENT_MANIFEST_DIFF,
+            ClientMessage::Pong { .. } => MSG_CLIENT_PONG,
+            ClientMessage::RequestResync { .. } => MSG_CLIENT_REQUEST_RESYNC,
+            ClientMessage::VerifyResult { .. } => MSG_CLIENT_VERIFY_RESULT,
         }
     }
 }
 
 impl ServerMessage {
-    pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self).context("Failed to serialize ServerMessage")
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.to_bytes_as(WireFormat::default())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).context("Failed to deserialize ServerMessage")
+    pub fn to_bytes_as(&self, format: WireFormat) -> Result<Vec<u8>, ProtocolError> {
+        format.encode("ServerMessage", self)
     }
 
-    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let bytes = self.to_bytes()?;
-        let len = (bytes.len() + 1) as u32; // +1 for type byte
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_bytes_as(bytes, WireFormat::default())
+    }
 
-        if len as usize > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
+    pub fn from_bytes_as(bytes: &[u8], format: WireFormat) -> Result<Self, ProtocolError> {
+        format.decode("ServerMessage", bytes)
+    }
 
-        writer
-            .write_all(&len.to_be_bytes())
-            .context("Failed to write message length")?;
-        writer
-            .write_all(&[MESSAGE_TYPE_SERVER])
-            .context("Failed to write message type")?;
-        writer
-            .write_all(&bytes)
-            .context("Failed to write message body")?;
-        writer.flush().context("Failed to flush writer")?;
+    pub fn write_framed<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        self.write_framed_as(writer, WireFormat::default())
+    }
 
+    /// Write the message with its body in `format`; `read_framed` and MessageBuffer
+    /// accept either format
+    pub fn write_framed_as<W: Write>(&self, writer: &mut W, format: WireFormat) -> Result<(), ProtocolError> {
+        let len = write_message(writer, MESSAGE_TYPE_SERVER, format, &self.to_bytes_as(format)?)?;
         log::trace!(
             "Sent ServerMessage: {} bytes, type: {:?}",
             len,
@@ -469,44 +901,12 @@ impl ServerMessage {
         Ok(())
     }
 
-    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut len_bytes = [0u8; 4];
-        reader
-            .read_exact(&mut len_bytes)
-            .context("Failed to read message length")?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
-
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!(
-                "Message too large: {} bytes (max: {})",
-                len,
-                MAX_MESSAGE_SIZE
-            );
-        }
-
-        let mut type_byte = [0u8; 1];
-        reader
-            .read_exact(&mut type_byte)
-            .context("Failed to read message type")?;
-
-        if type_byte[0] != MESSAGE_TYPE_SERVER {
-            anyhow::bail!(
-                "Invalid message type: expected {}, got {}",
-                MESSAGE_TYPE_SERVER,
-                type_byte[0]
-            );
-        }
-
-        let mut buf = vec![0u8; len - 1]; // -1 for type byte
-        reader
-            .read_exact(&mut buf)
-            .context("Failed to read message body")?;
-
-        let msg = Self::from_bytes(&buf)?;
-
+    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let (body, format) = read_message(reader, MESSAGE_TYPE_SERVER)?;
+        let msg = Self::from_bytes_as(&body, format)?;
         log::trace!(
             "Received ServerMessage: {} bytes, type: {:?}",
-            len,
+            body.len() + 1,
             msg.message_type()
         );
         Ok(msg)
@@ -519,11 +919,38 @@ impl ServerMessage {
             ServerMessage::Execute { .. } => "Execute",
             ServerMessage::Ping { .. } => "Ping",
             ServerMessage::Shutdown { .. } => "Shutdown",
+            ServerMessage::DeleteFile { .. } => "DeleteFile",
+            ServerMessage::Manifest { .. } => "Manifest",
+            ServerMessage::CreateDir { .. } => "CreateDir",
+            ServerMessage::Keepalive { .. } => "Keepalive",
+            ServerMessage::Kill { .. } => "Kill",
+            ServerMessage::VerifyFile { .. } => "VerifyFile",
+        }
+    }
+
+    /// The `MSG_*` frame type for this variant
+    pub fn frame_type(&self) -> u16 {
+        match self {
+            ServerMessage::Welcome { .. } => MSG_SERVER_WELCOME,
+            ServerMessage::RsyncStart { .. } => MSG_RSYNC_START,
+            ServerMessage::Execute { .. } => MSG_SERVER_EXECUTE,
+            ServerMessage::Ping { .. } => MSG_SERVER_PING,
+            ServerMessage::Shutdown { .. } => MSG_SERVER_SHUTDOWN,
+            ServerMessage::DeleteFile { .. } => MSG_SERVER_DELETE_FILE,
+            ServerMessage::Manifest { .. } => MSG_SERVER_MANIFEST,
+            ServerMessage::CreateDir { .. } => MSG_SERVER_CREATE_DIR,
+            ServerMessage::Keepalive { .. } => MSG_SERVER_KEEPALIVE,
+            ServerMessage::Kill { .. } => MSG_SERVER_KILL,
+            ServerMessage::VerifyFile { .. } => MSG_SERVER_VERIFY_FILE,
         }
     }
 }
 
-const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+/// Largest message, type byte included, that framing writes or accepts
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Appended to stdout or stderr that `ClientMessage::exec_complete` cut short
+pub const EXEC_OUTPUT_TRUNCATED: &str = "\n[output truncated]\n";
 
 // Message type discriminators
 const MESSAGE_TYPE_CLIENT: u8 = 0x01;
@@ -531,84 +958,114 @@ const MESSAGE_TYPE_SERVER: u8 = 0x02;
 const MESSAGE_TYPE_LOCAL_COMMAND: u8 = 0x03;
 const MESSAGE_TYPE_LOCAL_RESPONSE: u8 = 0x04;
 
-pub struct MessageBuffer {
-    buffer: BytesMut,
+fn encode<T: Serialize>(kind: &'static str, value: &T) -> Result<Vec<u8>, ProtocolError> {
+    WireFormat::default().encode(kind, value)
 }
 
-impl MessageBuffer {
-    pub fn new() -> Self {
-        Self {
-            buffer: BytesMut::with_capacity(4096),
-        }
-    }
-
-    pub fn try_parse_client_message(&mut self) -> Result<Option<ClientMessage>> {
-        if self.buffer.len() < 5 {
-            return Ok(None);
-        }
-
-        let len = u32::from_be_bytes([
-            self.buffer[0],
-            self.buffer[1],
-            self.buffer[2],
-            self.buffer[3],
-        ]) as usize;
-
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!("Message too large: {} bytes", len);
-        }
-
-        if self.buffer.len() < 4 + len {
-            return Ok(None);
-        }
+fn decode<T: DeserializeOwned>(kind: &'static str, bytes: &[u8]) -> Result<T, ProtocolError> {
+    WireFormat::default().decode(kind, bytes)
+}
 
-        // Check type byte
-        if self.buffer[4] != MESSAGE_TYPE_CLIENT {
-            return Ok(None);
-        }
+/// Cut `text` to at most `limit` bytes, on a character boundary, and mark it
+fn truncate_output(text: &mut String, limit: usize) {
+    if text.len() > limit {
+        text.truncate(text.floor_char_boundary(limit));
+        text.push_str(EXEC_OUTPUT_TRUNCATED);
+    }
+}
 
-        self.buffer.advance(4); // Skip length
-        self.buffer.advance(1); // Skip type byte
-        let data = self.buffer.split_to(len - 1); // -1 for type byte
-        let msg = ClientMessage::from_bytes(&data)?;
+/// A message length prefix counts the type byte, so it can't be zero
+fn check_message_len(len: usize) -> Result<(), ProtocolError> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::TooLarge {
+            kind: "Message",
+            len,
+            max: MAX_MESSAGE_SIZE,
+        });
+    }
+    if len < 1 {
+        return Err(ProtocolError::TooSmall {
+            kind: "Message",
+            len,
+            min: 1,
+        });
+    }
+    Ok(())
+}
 
-        Ok(Some(msg))
+/// Write `body` as a message of `message_type`: its length (type byte included), the
+/// type byte tagged with the body's format, then the body. Returns the length.
+fn write_message<W: Write>(
+    writer: &mut W,
+    message_type: u8,
+    format: WireFormat,
+    body: &[u8],
+) -> Result<usize, ProtocolError> {
+    let len = body.len() + 1;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::TooLarge {
+            kind: "Message",
+            len,
+            max: MAX_MESSAGE_SIZE,
+        });
     }
 
-    pub fn try_parse_server_message(&mut self) -> Result<Option<ServerMessage>> {
-        if self.buffer.len() < 5 {
-            return Ok(None);
-        }
+    writer.write_all(&(len as u32).to_be_bytes())?;
+    writer.write_all(&[format.tag(message_type)])?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(len)
+}
 
-        let len = u32::from_be_bytes([
-            self.buffer[0],
-            self.buffer[1],
-            self.buffer[2],
-            self.buffer[3],
-        ]) as usize;
+/// Read the body of one message written by `write_message`, which must be of
+/// `message_type`, and the format it is in
+fn read_message<R: Read>(reader: &mut R, message_type: u8) -> Result<(Vec<u8>, WireFormat), ProtocolError> {
+    let reading = |e| ProtocolError::reading("message", e);
+
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes).map_err(reading)?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    check_message_len(len)?;
+
+    let mut type_byte = [0u8; 1];
+    reader.read_exact(&mut type_byte).map_err(reading)?;
+    let (actual, format) = WireFormat::untag(type_byte[0]);
+    if actual != message_type {
+        return Err(ProtocolError::WrongType {
+            expected: message_type,
+            actual,
+        });
+    }
 
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!("Message too large: {} bytes", len);
-        }
+    let mut body = vec![0u8; len - 1]; // -1 for type byte
+    reader.read_exact(&mut body).map_err(reading)?;
+    Ok((body, format))
+}
 
-        if self.buffer.len() < 4 + len {
-            return Ok(None);
-        }
+pub struct MessageBuffer {
+    buffer: BytesMut,
+    /// Format of the last message parsed
+    last_format: WireFormat,
+}
 
-        // Check type byte
-        if self.buffer[4] != MESSAGE_TYPE_SERVER {
-            return Ok(None);
+impl MessageBuffer {
+    pub fn new() -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(4096),
+            last_format: WireFormat::default(),
         }
+    }
 
-        self.buffer.advance(4); // Skip length
-        self.buffer.advance(1); // Skip type byte
-        let data = self.buffer.split_to(len - 1); // -1 for type byte
-        let msg = ServerMessage::from_bytes(&data)?;
-
-        Ok(Some(msg))
+    /// Format the last parsed message was in, for replying in kind
+    pub fn last_format(&self) -> WireFormat {
+        self.last_format
     }
 
-    pub fn try_parse_local_command(&mut self) -> Result<Option<LocalCommand>> {
+    /// Remove the next message's body from the buffer once all of it has arrived
+    ///
+    /// Returns Ok(None) while the message is incomplete. A message of another type is
+    /// an error as soon as its type byte is in, and is left in the buffer.
+    fn try_take(&mut self, message_type: u8) -> Result<Option<(BytesMut, WireFormat)>, ProtocolError> {
         if self.buffer.len() < 5 {
             return Ok(None);
         }
@@ -619,59 +1076,49 @@ impl MessageBuffer {
             self.buffer[2],
             self.buffer[3],
         ]) as usize;
+        check_message_len(len)?;
 
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!("Message too large: {} bytes", len);
+        // Check type byte
+        let (actual, format) = WireFormat::untag(self.buffer[4]);
+        if actual != message_type {
+            return Err(ProtocolError::WrongType {
+                expected: message_type,
+                actual,
+            });
         }
 
         if self.buffer.len() < 4 + len {
             return Ok(None);
         }
 
-        // Check type byte
-        if self.buffer[4] != MESSAGE_TYPE_LOCAL_COMMAND {
-            return Ok(None);
-        }
-
         self.buffer.advance(4); // Skip length
         self.buffer.advance(1); // Skip type byte
-        let data = self.buffer.split_to(len - 1); // -1 for type byte
-        let msg = LocalCommand::from_bytes(&data)?;
-
-        Ok(Some(msg))
+        self.last_format = format;
+        Ok(Some((self.buffer.split_to(len - 1), format))) // -1 for type byte
     }
 
-    pub fn try_parse_local_response(&mut self) -> Result<Option<LocalResponse>> {
-        if self.buffer.len() < 5 {
-            return Ok(None);
-        }
-
-        let len = u32::from_be_bytes([
-            self.buffer[0],
-            self.buffer[1],
-            self.buffer[2],
-            self.buffer[3],
-        ]) as usize;
-
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!("Message too large: {} bytes", len);
-        }
-
-        if self.buffer.len() < 4 + len {
-            return Ok(None);
-        }
+    pub fn try_parse_client_message(&mut self) -> Result<Option<ClientMessage>, ProtocolError> {
+        self.try_take(MESSAGE_TYPE_CLIENT)?
+            .map(|(body, format)| ClientMessage::from_bytes_as(&body, format))
+            .transpose()
+    }
 
-        // Check type byte
-        if self.buffer[4] != MESSAGE_TYPE_LOCAL_RESPONSE {
-            return Ok(None);
-        }
+    pub fn try_parse_server_message(&mut self) -> Result<Option<ServerMessage>, ProtocolError> {
+        self.try_take(MESSAGE_TYPE_SERVER)?
+            .map(|(body, format)| ServerMessage::from_bytes_as(&body, format))
+            .transpose()
+    }
 
-        self.buffer.advance(4); // Skip length
-        self.buffer.advance(1); // Skip type byte
-        let data = self.buffer.split_to(len - 1); // -1 for type byte
-        let msg = LocalResponse::from_bytes(&data)?;
+    pub fn try_parse_local_command(&mut self) -> Result<Option<LocalCommand>, ProtocolError> {
+        self.try_take(MESSAGE_TYPE_LOCAL_COMMAND)?
+            .map(|(body, format)| LocalCommand::from_bytes_as(&body, format))
+            .transpose()
+    }
 
-        Ok(Some(msg))
+    pub fn try_parse_local_response(&mut self) -> Result<Option<LocalResponse>, ProtocolError> {
+        self.try_take(MESSAGE_TYPE_LOCAL_RESPONSE)?
+            .map(|(body, format)| LocalResponse::from_bytes_as(&body, format))
+            .transpose()
     }
 
     pub fn append(&mut self, data: &[u8]) {
@@ -699,6 +1146,8 @@ mod tests {
             hostname: "test-host".to_string(),
             platform: "linux".to_string(),
             initial_sync: true,
+            resume_token: Some("token".to_string()),
+            capabilities: capabilities::supported(),
         };
 
         let bytes = msg.to_bytes().unwrap();
@@ -709,10 +1158,14 @@ mod tests {
                 hostname,
                 platform,
                 initial_sync,
+                resume_token,
+                capabilities,
             } => {
                 assert_eq!(hostname, "test-host");
                 assert_eq!(platform, "linux");
-                assert_eq!(initial_sync, true);
+                assert!(initial_sync);
+                assert_eq!(resume_token.as_deref(), Some("token"));
+                assert!(capabilities::has(&capabilities, capabilities::BLAKE3));
             }
             _ => panic!("Wrong message type"),
         }
@@ -723,6 +1176,8 @@ mod tests {
         let msg = ServerMessage::Welcome {
             server_version: "1.0.0".to_string(),
             session_id: "session123".to_string(),
+            resume_token: "token".to_string(),
+            resumed: true,
         };
 
         let bytes = msg.to_bytes().unwrap();
@@ -732,14 +1187,697 @@ mod tests {
             ServerMessage::Welcome {
                 server_version,
                 session_id,
+                resume_token,
+                resumed,
             } => {
                 assert_eq!(server_version, "1.0.0");
                 assert_eq!(session_id, "session123");
+                assert_eq!(resume_token, "token");
+                assert!(resumed);
             }
             _ => panic!("Wrong message type"),
         }
     }
 
+    #[test]
+    fn test_manifest_serialization() {
+        let entry = ManifestEntry {
+            path: "bin/app.exe".to_string(),
+            size: 42,
+            checksum: "abcd".to_string(),
+            mtime: 1_700_000_000,
+        };
+        let msg = ServerMessage::Manifest {
+            request_id: "manifest-1".to_string(),
+            entries: vec![entry.clone()],
+            checksum_algo: ChecksumAlgo::Sha256,
+        };
+
+        let bytes = msg.to_bytes().unwrap();
+        match ServerMessage::from_bytes(&bytes).unwrap() {
+            ServerMessage::Manifest {
+                request_id,
+                entries,
+                checksum_algo,
+            } => {
+                assert_eq!(request_id, "manifest-1");
+                assert_eq!(entries, vec![entry]);
+                assert_eq!(checksum_algo, ChecksumAlgo::Sha256);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_exec_complete_fits_in_one_message() {
+        let small = ClientMessage::exec_complete("exec-1".to_string(), 0, "ok\n".to_string(), String::new(), None)
+            .unwrap();
+        assert!(matches!(small, ClientMessage::ExecComplete { ref stdout, .. } if stdout == "ok\n"));
+
+        // Multi-byte characters, so a cut at an arbitrary byte would split one
+        let stdout = "é".repeat(MAX_MESSAGE_SIZE / 2);
+        let stderr = "warning\n".to_string();
+        let msg = ClientMessage::exec_complete(
+            "exec-2".to_string(),
+            3,
+            stdout,
+            stderr.clone(),
+            Some("exit 3".to_string()),
+        )
+        .unwrap();
+
+        let mut buf = Vec::new();
+        msg.write_framed(&mut buf).unwrap();
+        assert!(buf.len() <= MAX_MESSAGE_SIZE + 4);
+        match ClientMessage::read_framed(&mut std::io::Cursor::new(buf)).unwrap() {
+            ClientMessage::ExecComplete {
+                exit_code,
+                stdout: out,
+                stderr: err,
+                ..
+            } => {
+                assert_eq!(exit_code, 3);
+                assert!(out.ends_with(EXEC_OUTPUT_TRUNCATED));
+                assert!(out.len() > MAX_MESSAGE_SIZE / 2);
+                // The short stream is kept whole
+                assert_eq!(err, stderr);
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        let both = ClientMessage::exec_complete(
+            "exec-3".to_string(),
+            0,
+            "o".repeat(MAX_MESSAGE_SIZE),
+            "e".repeat(MAX_MESSAGE_SIZE),
+            None,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        both.write_framed(&mut buf).unwrap();
+        match both {
+            ClientMessage::ExecComplete { stdout, stderr, .. } => {
+                assert!(stdout.ends_with(EXEC_OUTPUT_TRUNCATED));
+                assert!(stderr.ends_with(EXEC_OUTPUT_TRUNCATED));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exec_exit_serialization() {
+        let exit = ExecExit {
+            exit_code: 124,
+            error: Some("Timed out after 5s".to_string()),
+        };
+        let bytes = exit.to_bytes().unwrap();
+        assert_eq!(ExecExit::from_bytes(&bytes).unwrap(), exit);
+    }
+
+    #[test]
+    fn test_sync_report_serialization() {
+        let response = LocalResponse::SyncReport {
+            file: "app.bin".to_string(),
+            delivered: vec!["laptop".to_string()],
+            failed: vec![RecipientFailure {
+                hostname: "desktop".to_string(),
+                error: "channel closed".to_string(),
+            }],
+            accepted: false,
+        };
+
+        let bytes = response.to_bytes().unwrap();
+        let deserialized = LocalResponse::from_bytes(&bytes).unwrap();
+
+        match deserialized {
+            LocalResponse::SyncReport {
+                file,
+                delivered,
+                failed,
+                accepted,
+            } => {
+                assert_eq!(file, "app.bin");
+                assert_eq!(delivered, vec!["laptop".to_string()]);
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].hostname, "desktop");
+                assert!(!accepted);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_sync_tree_report_serialization() {
+        let response = LocalResponse::SyncTreeReport {
+            root: "assets".to_string(),
+            files: vec![FileSyncResult {
+                file: "assets/ui/button.png".to_string(),
+                delivered: vec!["laptop".to_string()],
+                failed: Vec::new(),
+                error: None,
+            }],
+            accepted: true,
+        };
+
+        let bytes = response.to_bytes().unwrap();
+        match LocalResponse::from_bytes(&bytes).unwrap() {
+            LocalResponse::SyncTreeReport { root, files, accepted } => {
+                assert_eq!(root, "assets");
+                assert_eq!(files.len(), 1);
+                assert_eq!(files[0].file, "assets/ui/button.png");
+                assert_eq!(files[0].delivered, vec!["laptop".to_string()]);
+                assert!(accepted);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_diagnostics_serialization() {
+        let response = LocalResponse::Diagnostics {
+            report: DiagnosticsReport {
+                hostname: "build-box".to_string(),
+                version: "1.0".to_string(),
+                uptime: 3600,
+                draining: false,
+                clients: Vec::new(),
+                transfer_rates: TransferRates::default(),
+                watches: Vec::new(),
+                transfers: vec![InFlightTransfer {
+                    request_id: "rsync-1".to_string(),
+                    path: "/project/game.exe".to_string(),
+                    pending_sessions: vec!["session-1".to_string()],
+                }],
+                logs: vec!["[1760700000.000 INFO launcher] started".to_string()],
+            },
+        };
+
+        let bytes = response.to_bytes().unwrap();
+        match LocalResponse::from_bytes(&bytes).unwrap() {
+            LocalResponse::Diagnostics { report } => {
+                assert_eq!(report.hostname, "build-box");
+                assert_eq!(report.uptime, 3600);
+                assert_eq!(report.transfers[0].path, "/project/game.exe");
+                assert_eq!(report.transfers[0].pending_sessions, vec!["session-1".to_string()]);
+                assert_eq!(report.logs.len(), 1);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_exec_output_keeps_raw_bytes() {
+        // Streamed output is relayed byte-for-byte, not as text
+        let response = LocalResponse::ExecOutput {
+            stderr: true,
+            data: vec![0x1b, b'[', b'3', b'1', b'm', 0xff],
+        };
+
+        let bytes = response.to_bytes().unwrap();
+        match LocalResponse::from_bytes(&bytes).unwrap() {
+            LocalResponse::ExecOutput { stderr, data } => {
+                assert!(stderr);
+                assert_eq!(data, vec![0x1b, b'[', b'3', b'1', b'm', 0xff]);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    // One sample per variant. Adding a variant breaks the `*_variant_index` match;
+    // give it the next index, bump the `*_VARIANTS` count, and add a sample here.
+    fn client_samples() -> Vec<ClientMessage> {
+        let id = || "req".to_string();
+        vec![
+            ClientMessage::Register {
+                hostname: "host".to_string(),
+                platform: "linux".to_string(),
+                initial_sync: true,
+                resume_token: None,
+                capabilities: vec![],
+            },
+            ClientMessage::Heartbeat { timestamp: 0, sequence: 0 },
+            ClientMessage::RsyncComplete {
+                request_id: id(),
+                path: "a".to_string(),
+                success: true,
+                checksum: String::new(),
+                bytes_transferred: 0,
+                error: None,
+                duration_ms: 0,
+                queued: false,
+            },
+            ClientMessage::ExecComplete {
+                request_id: id(),
+                exit_code: 0,
+                stdout: String::new(),
+                stderr: String::new(),
+                error: None,
+            },
+            ClientMessage::Status {
+                request_id: id(),
+                state: ClientState {
+                    connected_since: 0,
+                    last_sync: None,
+                    running_processes: Vec::new(),
+                    pending_transfers: 0,
+                    exec_processes: Vec::new(),
+                    free_space: None,
+                },
+            },
+            ClientMessage::Error { request_id: None, message: String::new() },
+            ClientMessage::ManifestDiff { request_id: id(), needed: Vec::new() },
+            ClientMessage::Pong { nonce: 1 },
+            ClientMessage::RequestResync { relative_path: "bin/game.exe".to_string() },
+            ClientMessage::VerifyResult {
+                request_id: id(),
+                matches: false,
+                actual_checksum: Some("abc".to_string()),
+                error: None,
+            },
+        ]
+    }
+
+    fn client_variant_index(msg: &ClientMessage) -> usize {
+        match msg {
+            ClientMessage::Register { .. } => 0,
+            ClientMessage::Heartbeat { .. } => 1,
+            ClientMessage::RsyncComplete { .. } => 2,
+            ClientMessage::ExecComplete { .. } => 3,
+            ClientMessage::Status { .. } => 4,
+            ClientMessage::Error { .. } => 5,
+            ClientMessage::ManifestDiff { .. } => 6,
+            ClientMessage::Pong { .. } => 7,
+            ClientMessage::RequestResync { .. } => 8,
+            ClientMessage::VerifyResult { .. } => 9,
+        }
+    }
+    const CLIENT_VARIANTS: usize = 10;
+
+    fn server_samples() -> Vec<ServerMessage> {
+        let id = || "req".to_string();
+        vec![
+            ServerMessage::Welcome {
+                server_version: "1.0".to_string(),
+                session_id: id(),
+                resume_token: id(),
+                resumed: false,
+            },
+            ServerMessage::RsyncStart {
+                request_id: id(),
+                relative_path: "a".to_string(),
+                size: 0,
+                checksum: String::new(),
+                mtime: 0,
+                block_size: 0,
+                mode: 0o644,
+                dir_mode: None,
+                whole_file: false,
+                checksum_algo: ChecksumAlgo::Blake3,
+                atomic_replace: false,
+                priority: 0,
+                force: false,
+            },
+            ServerMessage::Execute {
+                request_id: id(),
+                binary: "true".to_string(),
+                args: Vec::new(),
+                working_dir: None,
+                env: HashMap::new(),
+                self_update: false,
+            },
+            ServerMessage::Ping { request_id: id() },
+            ServerMessage::Shutdown { message: None },
+            ServerMessage::DeleteFile { request_id: id(), path: "a".to_string(), scope: ".".to_string() },
+            ServerMessage::Manifest {
+                request_id: id(),
+                entries: Vec::new(),
+                checksum_algo: ChecksumAlgo::Blake3,
+            },
+            ServerMessage::CreateDir {
+                request_id: id(),
+                relative_path: "logs".to_string(),
+                mode: Some(0o755),
+            },
+            ServerMessage::Keepalive { nonce: 1 },
+            ServerMessage::Kill { request_id: id() },
+            ServerMessage::VerifyFile {
+                request_id: id(),
+                relative_path: "bin/game.exe".to_string(),
+                expected_checksum: "abc".to_string(),
+                checksum_algo: ChecksumAlgo::Blake3,
+            },
+        ]
+    }
+
+    fn server_variant_index(msg: &ServerMessage) -> usize {
+        match msg {
+            ServerMessage::Welcome { .. } => 0,
+            ServerMessage::RsyncStart { .. } => 1,
+            ServerMessage::Execute { .. } => 2,
+            ServerMessage::Ping { .. } => 3,
+            ServerMessage::Shutdown { .. } => 4,
+            ServerMessage::DeleteFile { .. } => 5,
+            ServerMessage::Manifest { .. } => 6,
+            ServerMessage::CreateDir { .. } => 7,
+            ServerMessage::Keepalive { .. } => 8,
+            ServerMessage::Kill { .. } => 9,
+            ServerMessage::VerifyFile { .. } => 10,
+        }
+    }
+    const SERVER_VARIANTS: usize = 11;
+
+    // Every variant maps to its own MSG_* constant whose name agrees with message_type()
+    fn check_frame_types(
+        samples: &[(usize, u16, &'static str)],
+        variants: usize,
+        seen: &mut std::collections::HashSet<u16>,
+    ) {
+        let mut indices: Vec<_> = samples.iter().map(|(index, _, _)| *index).collect();
+        indices.sort();
+        assert_eq!(indices, (0..variants).collect::<Vec<_>>(), "one sample per variant");
+
+        for &(_, frame_type, message_type) in samples {
+            let name = message_type_name(frame_type);
+            assert!(
+                name.ends_with(message_type),
+                "{} maps to 0x{:04x} ({})",
+                message_type,
+                frame_type,
+                name
+            );
+            assert!(seen.insert(frame_type), "0x{:04x} used by two variants", frame_type);
+        }
+    }
+
+    #[test]
+    fn test_frame_types_cover_every_variant() {
+        let mut seen = std::collections::HashSet::new();
+        let client: Vec<_> = client_samples()
+            .iter()
+            .map(|msg| (client_variant_index(msg), msg.frame_type(), msg.message_type()))
+            .collect();
+        check_frame_types(&client, CLIENT_VARIANTS, &mut seen);
+
+        let server: Vec<_> = server_samples()
+            .iter()
+            .map(|msg| (server_variant_index(msg), msg.frame_type(), msg.message_type()))
+            .collect();
+        check_frame_types(&server, SERVER_VARIANTS, &mut seen);
+    }
+
+    fn local_command_samples() -> Vec<LocalCommand> {
+        let host = || "host".to_string();
+        let path = || "/src/bin".to_string();
+        vec![
+            LocalCommand::Status,
+            LocalCommand::Ping { target: host(), session_id: None },
+            LocalCommand::ListClients,
+            LocalCommand::ClientStatus { hostname: host(), session_id: Some("session".to_string()) },
+            LocalCommand::Shutdown,
+            LocalCommand::Drain,
+            LocalCommand::SyncFile { file: path(), destination: "bin".to_string(), allow_partial: true, force: true },
+            LocalCommand::SyncTree {
+                root: path(),
+                destination: "bin".to_string(),
+                include_patterns: vec!["*.exe".to_string()],
+                exclude_patterns: Vec::new(),
+                allow_partial: false,
+                force: false,
+            },
+            LocalCommand::Execute {
+                target: host(),
+                binary: "sh".to_string(),
+                args: vec!["-c".to_string(), "true".to_string()],
+                session_id: None,
+                stream: true,
+                working_dir: Some("/tmp".to_string()),
+                env: HashMap::from([("RUST_LOG".to_string(), "debug".to_string())]),
+            },
+            LocalCommand::WatchDirectory {
+                path: path(),
+                recursive: true,
+                include_patterns: vec!["*.exe".to_string()],
+                exclude_patterns: vec!["*.pdb".to_string()],
+                relative_to: None,
+                case_insensitive: false,
+                verify_events: true,
+                destination: Some("bin".to_string()),
+                base: None,
+                settle_ms: 500,
+                priority: -5,
+            },
+            LocalCommand::UnwatchDirectory { path: path(), destination: Some("bin/".to_string()) },
+            LocalCommand::PreviewUnwatch { path: path() },
+            LocalCommand::PauseWatch { path: path() },
+            LocalCommand::ResumeWatch { path: path() },
+            LocalCommand::UpdateWatch {
+                path: path(),
+                destination: None,
+                include_patterns: Some(vec!["*.dll".to_string()]),
+                exclude_patterns: None,
+            },
+            LocalCommand::ListWatches,
+            LocalCommand::SelfUpdate { file: path(), staging_path: "launcher.new".to_string() },
+            LocalCommand::Diagnostics,
+            LocalCommand::ListProcesses { target: host(), session_id: None },
+            LocalCommand::KillProcess { target: host(), session_id: None, request_id: "req".to_string() },
+            LocalCommand::VerifyFile {
+                target: None,
+                relative_path: "bin/game.exe".to_string(),
+                expected_checksum: "abc".to_string(),
+                checksum_algo: ChecksumAlgo::Sha256,
+            },
+            LocalCommand::Tagged { request_id: "req".to_string(), command: Box::new(LocalCommand::ListWatches) },
+        ]
+    }
+
+    fn local_response_samples() -> Vec<LocalResponse> {
+        let client = ClientInfo {
+            hostname: "host".to_string(),
+            platform: "linux".to_string(),
+            session_id: "session".to_string(),
+            connected_at: 10,
+            last_heartbeat: 1,
+            auth_key_label: Some("me@laptop".to_string()),
+            last_error: None,
+            last_transfer: Some(TransferInfo {
+                path: "game.pak".to_string(),
+                bytes_transferred: 1024,
+                file_size: Some(4096),
+                duration_ms: 20,
+            }),
+            heartbeat_gaps: 0,
+            send_queue_depth: 0,
+            send_queue_peak: 3,
+            send_queue_capacity: 1024,
+            transfer_rates: TransferRates::default(),
+        };
+        let watch = WatchInfo {
+            path: "/src/bin".to_string(),
+            recursive: true,
+            include_patterns: vec!["*.exe".to_string()],
+            exclude_patterns: Vec::new(),
+            destination: None,
+            events_seen: 3,
+            events_passed: 2,
+            syncs_triggered: 1,
+            last_triggered: Some(5),
+            paused: false,
+            broken: true,
+        };
+        let failure = || RecipientFailure { hostname: "host".to_string(), error: "stalled".to_string() };
+        let process = ExecProcess {
+            request_id: "req".to_string(),
+            binary: "game".to_string(),
+            args: Vec::new(),
+            pid: Some(42),
+            started_at: 0,
+        };
+        vec![
+            LocalResponse::Success { message: "ok".to_string() },
+            LocalResponse::Error { message: "no".to_string() },
+            LocalResponse::Status {
+                hostname: "server".to_string(),
+                version: "1.0".to_string(),
+                uptime: 60,
+                clients: vec![client.clone()],
+                transfer_rates: TransferRates { transfers: 1, bytes: 1024, duration_ms: 20, peak: 51200.0 },
+                error_counts: ErrorCounts { parse_errors: 1, checksum_failures: 2, auth_rejects: 3, evictions: 4 },
+            },
+            LocalResponse::ClientList { clients: vec![client.clone()] },
+            LocalResponse::WatchList { watches: vec![watch.clone()] },
+            LocalResponse::SyncReport {
+                file: "game.exe".to_string(),
+                delivered: vec!["host".to_string()],
+                failed: vec![failure()],
+                accepted: false,
+            },
+            LocalResponse::SyncTreeReport {
+                root: "/src/bin".to_string(),
+                files: vec![FileSyncResult {
+                    file: "game.exe".to_string(),
+                    delivered: Vec::new(),
+                    failed: vec![failure()],
+                    error: None,
+                }],
+                accepted: false,
+            },
+            LocalResponse::ClientState {
+                hostname: "host".to_string(),
+                state: ClientState {
+                    connected_since: 0,
+                    last_sync: Some(1),
+                    running_processes: vec!["game".to_string()],
+                    pending_transfers: 2,
+                    exec_processes: vec![process.clone()],
+                    free_space: Some(1 << 40),
+                },
+            },
+            LocalResponse::ExecOutput { stderr: true, data: vec![0, 159, 146, 150, 255] },
+            LocalResponse::ExecExit { exit_code: -1, error: Some("Killed on request".to_string()) },
+            LocalResponse::Diagnostics {
+                report: DiagnosticsReport {
+                    hostname: "server".to_string(),
+                    version: "1.0".to_string(),
+                    uptime: 60,
+                    draining: false,
+                    clients: vec![client],
+                    transfer_rates: TransferRates::default(),
+                    watches: vec![watch],
+                    transfers: vec![InFlightTransfer {
+                        request_id: "req".to_string(),
+                        path: "game.exe".to_string(),
+                        pending_sessions: vec!["session".to_string()],
+                    }],
+                    logs: vec!["started".to_string()],
+                },
+            },
+            LocalResponse::ProcessList { hostname: "host".to_string(), processes: vec![process] },
+            LocalResponse::VerifyReport {
+                path: "bin/game.exe".to_string(),
+                results: vec![ClientVerifyResult {
+                    hostname: "host".to_string(),
+                    matches: false,
+                    actual_checksum: Some("def".to_string()),
+                    error: None,
+                }],
+            },
+            LocalResponse::Watching { message: "Watching /src".to_string(), joined: true },
+            LocalResponse::UnwatchPreview {
+                path: "/src".to_string(),
+                stops_syncing: vec!["/src/bin/game.pdb".to_string()],
+                still_covered: vec!["/src/bin/game.exe".to_string()],
+            },
+            LocalResponse::Tagged {
+                request_id: "req".to_string(),
+                response: Box::new(LocalResponse::Success { message: "ok".to_string() }),
+            },
+        ]
+    }
+
+    // Frame every sample in `format`, parse it back through a MessageBuffer and
+    // read_framed, and check it encodes to the same bytes as the original
+    macro_rules! check_round_trips {
+        ($samples:expr, $kind:ty, $parse:ident, $format:expr) => {
+            for msg in $samples {
+                let mut framed = Vec::new();
+                msg.write_framed_as(&mut framed, $format).unwrap();
+                assert_eq!(framed[4] & wire_format::MSGPACK_FLAG != 0, $format == WireFormat::MessagePack);
+
+                let mut buffer = MessageBuffer::new();
+                buffer.append(&framed);
+                let parsed = buffer.$parse().unwrap().expect("a whole message");
+                assert_eq!(buffer.last_format(), $format);
+                let read = <$kind>::read_framed(&mut &framed[..]).unwrap();
+
+                let expected = msg.to_bytes_as($format).unwrap();
+                assert_eq!(parsed.to_bytes_as($format).unwrap(), expected, "{:?}", msg);
+                assert_eq!(read.to_bytes_as($format).unwrap(), expected, "{:?}", msg);
+            }
+        };
+    }
+
+    #[test]
+    fn test_every_message_round_trips_in_both_formats() {
+        for format in [WireFormat::Bincode, WireFormat::MessagePack] {
+            check_round_trips!(client_samples(), ClientMessage, try_parse_client_message, format);
+            check_round_trips!(server_samples(), ServerMessage, try_parse_server_message, format);
+            check_round_trips!(local_command_samples(), LocalCommand, try_parse_local_command, format);
+            check_round_trips!(local_response_samples(), LocalResponse, try_parse_local_response, format);
+        }
+    }
+
+    #[test]
+    fn test_formats_mix_on_one_stream() {
+        let heartbeat = ClientMessage::Heartbeat { timestamp: 7, sequence: 1 };
+        assert_eq!(heartbeat.to_bytes().unwrap(), heartbeat.to_bytes_as(WireFormat::Bincode).unwrap());
+
+        let mut stream = Vec::new();
+        heartbeat.write_framed(&mut stream).unwrap();
+        heartbeat.write_framed_as(&mut stream, WireFormat::MessagePack).unwrap();
+        assert_eq!(stream[4], MESSAGE_TYPE_CLIENT);
+
+        let mut buffer = MessageBuffer::new();
+        buffer.append(&stream);
+        for format in [WireFormat::Bincode, WireFormat::MessagePack] {
+            match buffer.try_parse_client_message().unwrap() {
+                Some(ClientMessage::Heartbeat { timestamp: 7, sequence: 1 }) => {}
+                other => panic!("Unexpected message: {:?}", other),
+            }
+            assert_eq!(buffer.last_format(), format);
+        }
+
+        // A flagged message of another kind is still the wrong type
+        let mut buffer = MessageBuffer::new();
+        let mut framed = Vec::new();
+        LocalResponse::Success { message: String::new() }
+            .write_framed_as(&mut framed, WireFormat::MessagePack)
+            .unwrap();
+        buffer.append(&framed);
+        assert!(matches!(
+            buffer.try_parse_local_command(),
+            Err(ProtocolError::WrongType {
+                expected: MESSAGE_TYPE_LOCAL_COMMAND,
+                actual: MESSAGE_TYPE_LOCAL_RESPONSE
+            })
+        ));
+    }
+
+    #[test]
+    fn test_transfer_wire_ratio() {
+        let transfer = |bytes_transferred, file_size| TransferInfo {
+            path: "game.pak".to_string(),
+            bytes_transferred,
+            file_size,
+            duration_ms: 0,
+        };
+        assert_eq!(transfer(256, Some(1024)).wire_ratio(), Some(0.25));
+        assert_eq!(transfer(8, Some(0)).wire_ratio(), None);
+        assert_eq!(transfer(256, None).wire_ratio(), None);
+    }
+
+    #[test]
+    fn test_transfer_rates() {
+        let transfer = |bytes_transferred, duration_ms| TransferInfo {
+            path: "game.pak".to_string(),
+            bytes_transferred,
+            file_size: None,
+            duration_ms,
+        };
+        assert_eq!(transfer(4096, 2000).rate(), Some(2048.0));
+        assert_eq!(transfer(4096, 0).rate(), None);
+
+        let mut rates = TransferRates::default();
+        assert_eq!(rates.average(), None);
+        rates.record(&transfer(1000, 1000));
+        rates.record(&transfer(9000, 1000));
+        // Too quick to time, so left out of both the average and the peak
+        rates.record(&transfer(1_000_000, 0));
+        assert_eq!(rates.transfers, 2);
+        assert_eq!(rates.average(), Some(5000.0));
+        assert_eq!(rates.peak, 9000.0);
+    }
+
     #[test]
     fn test_message_framing() {
         let msg = ClientMessage::Heartbeat {
@@ -788,4 +1926,66 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+
+    #[test]
+    fn test_read_framed_errors() {
+        let ping = ServerMessage::Ping {
+            request_id: "req123".to_string(),
+        };
+        let mut buf = Vec::new();
+        ping.write_framed(&mut buf).unwrap();
+
+        // A server message where a local response belongs
+        let err = LocalResponse::read_framed(&mut std::io::Cursor::new(&buf)).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::WrongType {
+                expected: MESSAGE_TYPE_LOCAL_RESPONSE,
+                actual: MESSAGE_TYPE_SERVER
+            }
+        ));
+
+        let truncated = &buf[..buf.len() - 1];
+        let err = ServerMessage::read_framed(&mut std::io::Cursor::new(truncated)).unwrap_err();
+        assert!(matches!(err, ProtocolError::Incomplete { .. }));
+
+        let mut huge = ((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes().to_vec();
+        huge.push(MESSAGE_TYPE_SERVER);
+        let err = ServerMessage::read_framed(&mut std::io::Cursor::new(huge)).unwrap_err();
+        assert!(matches!(err, ProtocolError::TooLarge { .. }));
+        assert!(err.to_string().contains("too large"));
+
+        let mut garbage = 3u32.to_be_bytes().to_vec();
+        garbage.extend([MESSAGE_TYPE_SERVER, 0xff, 0xff]);
+        let err = ServerMessage::read_framed(&mut std::io::Cursor::new(garbage)).unwrap_err();
+        assert!(matches!(err, ProtocolError::Decode { kind: "ServerMessage", .. }));
+    }
+
+    #[test]
+    fn test_message_buffer_errors() {
+        let cmd = LocalCommand::ListClients;
+        let mut buf = Vec::new();
+        cmd.write_framed(&mut buf).unwrap();
+
+        // The type byte alone is enough to tell a message of another kind
+        let mut msg_buf = MessageBuffer::new();
+        msg_buf.append(&buf[..5]);
+        assert!(matches!(
+            msg_buf.try_parse_client_message(),
+            Err(ProtocolError::WrongType { .. })
+        ));
+        assert!(msg_buf.try_parse_local_command().unwrap().is_none());
+        msg_buf.append(&buf[5..]);
+        assert!(matches!(
+            msg_buf.try_parse_local_command(),
+            Ok(Some(LocalCommand::ListClients))
+        ));
+
+        let mut msg_buf = MessageBuffer::new();
+        msg_buf.append(&[0, 0, 0, 0, MESSAGE_TYPE_CLIENT]);
+        assert!(matches!(
+            msg_buf.try_parse_client_message(),
+            Err(ProtocolError::TooSmall { .. })
+        ));
+    }
 }
@@ -5,6 +5,10 @@ pub const MSG_CLIENT_FILE_RECEIVED: u16 = 0x0003; // Deprecated, use MSG_RSYNC_C
 pub const MSG_CLIENT_EXEC_COMPLETE: u16 = 0x0004;
 pub const MSG_CLIENT_STATUS: u16 = 0x0005;
 pub const MSG_CLIENT_ERROR: u16 = 0x0006;
+pub const MSG_CLIENT_MANIFEST_DIFF: u16 = 0x0007;
+pub const MSG_CLIENT_PONG: u16 = 0x0008;
+pub const MSG_CLIENT_REQUEST_RESYNC: u16 = 0x0009;
+pub const MSG_CLIENT_VERIFY_RESULT: u16 = 0x000A;
 
 // Control Messages - Server to Client (0x0010 - 0x001F)
 pub const MSG_SERVER_WELCOME: u16 = 0x0010;
@@ -12,6 +16,12 @@ pub const MSG_SERVER_SYNC_FILE: u16 = 0x0011; // Deprecated, use MSG_RSYNC_START
 pub const MSG_SERVER_EXECUTE: u16 = 0x0012;
 pub const MSG_SERVER_PING: u16 = 0x0013;
 pub const MSG_SERVER_SHUTDOWN: u16 = 0x0014;
+pub const MSG_SERVER_DELETE_FILE: u16 = 0x0015;
+pub const MSG_SERVER_MANIFEST: u16 = 0x0016;
+pub const MSG_SERVER_CREATE_DIR: u16 = 0x0017;
+pub const MSG_SERVER_KEEPALIVE: u16 = 0x0018;
+pub const MSG_SERVER_KILL: u16 = 0x0019;
+pub const MSG_SERVER_VERIFY_FILE: u16 = 0x001A;
 
 // Rsync Messages (0x0100 - 0x01FF)
 pub const MSG_RSYNC_START: u16 = 0x0100; // Control channel: initiate sync
@@ -45,12 +55,22 @@ pub fn message_type_name(msg_type: u16) -> &'static str {
         MSG_CLIENT_EXEC_COMPLETE => "ClientExecComplete",
         MSG_CLIENT_STATUS => "ClientStatus",
         MSG_CLIENT_ERROR => "ClientError",
+        MSG_CLIENT_MANIFEST_DIFF => "ClientManifestDiff",
+        MSG_CLIENT_PONG => "ClientPong",
+        MSG_CLIENT_REQUEST_RESYNC => "ClientRequestResync",
+        MSG_CLIENT_VERIFY_RESULT => "ClientVerifyResult",
 
         MSG_SERVER_WELCOME => "ServerWelcome",
         MSG_SERVER_SYNC_FILE => "ServerSyncFile",
         MSG_SERVER_EXECUTE => "ServerExecute",
         MSG_SERVER_PING => "ServerPing",
         MSG_SERVER_SHUTDOWN => "ServerShutdown",
+        MSG_SERVER_DELETE_FILE => "ServerDeleteFile",
+        MSG_SERVER_MANIFEST => "ServerManifest",
+        MSG_SERVER_CREATE_DIR => "ServerCreateDir",
+        MSG_SERVER_KEEPALIVE => "ServerKeepalive",
+        MSG_SERVER_KILL => "ServerKill",
+        MSG_SERVER_VERIFY_FILE => "ServerVerifyFile",
 
         MSG_RSYNC_START => "RsyncStart",
         MSG_RSYNC_COMPLETE => "RsyncComplete",
@@ -100,11 +120,21 @@ mod tests {
             MSG_CLIENT_EXEC_COMPLETE,
             MSG_CLIENT_STATUS,
             MSG_CLIENT_ERROR,
+            MSG_CLIENT_MANIFEST_DIFF,
+            MSG_CLIENT_PONG,
+            MSG_CLIENT_REQUEST_RESYNC,
+            MSG_CLIENT_VERIFY_RESULT,
             MSG_SERVER_WELCOME,
             MSG_SERVER_SYNC_FILE,
             MSG_SERVER_EXECUTE,
             MSG_SERVER_PING,
             MSG_SERVER_SHUTDOWN,
+            MSG_SERVER_DELETE_FILE,
+            MSG_SERVER_MANIFEST,
+            MSG_SERVER_CREATE_DIR,
+            MSG_SERVER_KEEPALIVE,
+            MSG_SERVER_KILL,
+            MSG_SERVER_VERIFY_FILE,
             MSG_RSYNC_START,
             MSG_RSYNC_COMPLETE,
             MSG_RSYNC_SIGNATURE,
@@ -0,0 +1,80 @@
+// Serialization formats for message bodies
+//
+// Bincode is compact but tied to Rust's serde data model and to the exact field order
+// of both peers. MessagePack with named fields can be produced and read from other
+// languages, and tolerates fields being added. Each message says which format its
+// body is in through the high bit of its type byte, so readers accept either without
+// configuration and peers can change formats independently.
+
+use crate::ProtocolError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Set in a message's type byte when its body is MessagePack
+pub const MSGPACK_FLAG: u8 = 0x80;
+
+/// Encoding of a message body
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// Smallest messages; the default
+    #[default]
+    Bincode,
+    /// MessagePack maps keyed by field name, for clients not written in Rust
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl WireFormat {
+    /// `message_type` with this format's flag set
+    pub(crate) fn tag(self, message_type: u8) -> u8 {
+        match self {
+            WireFormat::Bincode => message_type,
+            WireFormat::MessagePack => message_type | MSGPACK_FLAG,
+        }
+    }
+
+    /// Split a type byte into the message type and the format of its body
+    pub(crate) fn untag(type_byte: u8) -> (u8, Self) {
+        if type_byte & MSGPACK_FLAG != 0 {
+            (type_byte & !MSGPACK_FLAG, WireFormat::MessagePack)
+        } else {
+            (type_byte, WireFormat::Bincode)
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize>(self, kind: &'static str, value: &T) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            WireFormat::Bincode => bincode::serialize(value).map_err(|source| ProtocolError::Encode {
+                kind,
+                source: source.into(),
+            }),
+            WireFormat::MessagePack => rmp_serde::to_vec_named(value).map_err(|source| ProtocolError::Encode {
+                kind,
+                source: source.into(),
+            }),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, kind: &'static str, bytes: &[u8]) -> Result<T, ProtocolError> {
+        match self {
+            WireFormat::Bincode => bincode::deserialize(bytes).map_err(|source| ProtocolError::Decode {
+                kind,
+                source: source.into(),
+            }),
+            WireFormat::MessagePack => rmp_serde::from_slice(bytes).map_err(|source| ProtocolError::Decode {
+                kind,
+                source: source.into(),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WireFormat::Bincode => "bincode",
+            WireFormat::MessagePack => "msgpack",
+        })
+    }
+}